@@ -0,0 +1,165 @@
+use std::path::{Path, PathBuf};
+
+use crate::config;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BindError {
+    #[error("extra_binds{}: {host_path:?}: {err}", demo_suffix(demo_id))]
+    HostPath {
+        demo_id: Option<String>,
+        host_path: String,
+        err: std::io::Error,
+    },
+    #[error("extra_binds{}: the container path {container_path:?} must be absolute and outside of the workdir", demo_suffix(demo_id))]
+    ContainerPath {
+        demo_id: Option<String>,
+        container_path: String,
+    },
+}
+
+fn demo_suffix(demo_id: &Option<String>) -> String {
+    demo_id
+        .as_ref()
+        .map_or(String::new(), |demo_id| format!(" of demo {demo_id}"))
+}
+
+/// Checks that the host paths of `binds` exist and that their container
+/// paths don't hide the workdir, returning the canonical host paths.
+fn check(
+    config: &config::Config,
+    demo_id: Option<&str>,
+    binds: &[config::ExtraBind],
+) -> Result<Vec<PathBuf>, BindError> {
+    let workdir = Path::new(&config.exec_workdir_in_docker);
+    let mut host_paths = Vec::new();
+    for bind in binds {
+        let container_path = Path::new(&bind.container_path);
+        if !container_path.is_absolute()
+            || container_path.starts_with(workdir)
+            || workdir.starts_with(container_path)
+        {
+            return Err(BindError::ContainerPath {
+                demo_id: demo_id.map(str::to_string),
+                container_path: bind.container_path.clone(),
+            });
+        }
+        let host_path =
+            std::fs::canonicalize(&bind.host_path).map_err(|err| BindError::HostPath {
+                demo_id: demo_id.map(str::to_string),
+                host_path: bind.host_path.clone(),
+                err,
+            })?;
+        host_paths.push(host_path);
+    }
+    Ok(host_paths)
+}
+
+/// Checks the `extra_binds` of the config and of the demo configs.
+pub fn validate(config: &config::Config) -> Result<(), BindError> {
+    check(config, None, &config.extra_binds)?;
+    for (demo_id, demo) in &config.demos {
+        check(config, Some(demo_id), &demo.extra_binds)?;
+    }
+    Ok(())
+}
+
+/// Docker bind specifications of the `extra_binds` of a demo, with the
+/// canonical host paths.
+pub fn docker_binds(
+    config: &config::Config,
+    demo: &config::DemoConfig,
+) -> Result<Vec<String>, BindError> {
+    let binds = config.extra_binds.iter().chain(&demo.extra_binds);
+    let binds = binds.cloned().collect::<Vec<_>>();
+    let host_paths = check(config, None, &binds)?;
+    let specs = binds.iter().zip(host_paths).map(|(bind, host_path)| {
+        let mode = if bind.readonly { "ro" } else { "rw" };
+        format!("{}:{}:{mode}", host_path.display(), bind.container_path)
+    });
+    Ok(specs.collect())
+}
+
+/// Refuses to start with `extra_binds` which would mount nothing.
+pub fn binds_check() -> rocket::fairing::AdHoc {
+    rocket::fairing::AdHoc::try_on_ignite("Extra binds check", |rocket| {
+        Box::pin(async move {
+            if let Some(config) = rocket.state::<config::Config>() {
+                if let Err(err) = validate(config) {
+                    tracing::error!("{err}");
+                    return Err(rocket);
+                }
+            }
+            Ok(rocket)
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ExtraBind;
+
+    fn bind(host_path: &Path, container_path: &str) -> ExtraBind {
+        ExtraBind {
+            host_path: host_path.to_str().unwrap().into(),
+            container_path: container_path.into(),
+            readonly: true,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config::test_config();
+        config.extra_binds = vec![bind(dir.path(), "/data")];
+        assert!(validate(&config).is_ok());
+
+        config.extra_binds = vec![bind(&dir.path().join("typo"), "/data")];
+        let err = validate(&config).unwrap_err();
+        assert!(matches!(err, BindError::HostPath { demo_id: None, .. }));
+
+        config.extra_binds = Vec::new();
+        for container_path in ["data", "/", "/workdir", "/workdir/data"] {
+            let demo = config::DemoConfig {
+                extra_binds: vec![bind(dir.path(), container_path)],
+                ..Default::default()
+            };
+            config.demos.insert("t001".into(), demo);
+            let err = validate(&config).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("extra_binds of demo t001: the container path {container_path:?} must be absolute and outside of the workdir")
+            );
+        }
+    }
+
+    #[test]
+    fn test_docker_binds() {
+        let dir = tempfile::tempdir().unwrap();
+        let weights = dir.path().join("weights");
+        std::fs::create_dir(&weights).unwrap();
+        let mut config = config::test_config();
+        // not canonical
+        config.extra_binds = vec![bind(&weights.join(".."), "/data")];
+        let demo = config::DemoConfig {
+            extra_binds: vec![ExtraBind {
+                readonly: false,
+                ..bind(&weights, "/weights")
+            }],
+            ..Default::default()
+        };
+
+        let dir = dir.path().canonicalize().unwrap();
+        assert_eq!(
+            docker_binds(&config, &config::DemoConfig::default()).unwrap(),
+            vec![format!("{}:/data:ro", dir.display())]
+        );
+        assert_eq!(
+            docker_binds(&config, &demo).unwrap(),
+            vec![
+                format!("{}:/data:ro", dir.display()),
+                format!("{}/weights:/weights:rw", dir.display()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use bollard::container::{ListContainersOptions, RemoveContainerOptions};
+use bollard::image::{ListImagesOptions, RemoveImageOptions};
+use clap::{Args, Parser, Subcommand};
+use rocket::figment::Figment;
+
+use crate::binds;
+use crate::config;
+use crate::daemon;
+use crate::devices;
+use crate::numa;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "The IPOL DemoRunner module (docker)")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum Command {
+    /// Start the HTTP server (the default).
+    Serve,
+    /// Remove what the runs and the compilations of this instance left behind.
+    Cleanup(CleanupArgs),
+    /// Load the configuration and check it against the host.
+    ValidateConfig,
+    /// Check that the node is able to compile and run demos.
+    Selftest,
+}
+
+#[derive(Debug, Default, PartialEq, Args)]
+pub struct CleanupArgs {
+    /// Remove the execution containers of this instance.
+    #[arg(long)]
+    pub containers: bool,
+    /// Remove the dangling images of this instance.
+    #[arg(long)]
+    pub images: bool,
+    /// Remove the files of the upload sessions, which don't survive a restart.
+    #[arg(long)]
+    pub uploads: bool,
+    /// Only list what would be removed.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CliError {
+    #[error("invalid configuration: {0}")]
+    Config(#[from] Box<rocket::figment::Error>),
+    #[error("{0}")]
+    Numa(#[from] numa::NumaError),
+    #[error("{0}")]
+    Devices(#[from] devices::DeviceError),
+    #[error("{0}")]
+    Binds(#[from] binds::BindError),
+    #[error("docker: {0}")]
+    Docker(#[from] bollard::errors::Error),
+    #[error("io: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("{0} check(s) failed")]
+    Selftest(usize),
+}
+
+fn load_config(figment: &Figment) -> Result<config::Config, CliError> {
+    figment
+        .extract()
+        .map_err(|err| CliError::Config(Box::new(err)))
+}
+
+/// Runs a maintenance command, without the HTTP server.
+pub async fn run(command: Command, figment: &Figment) -> Result<(), CliError> {
+    let config = load_config(figment)?;
+    match command {
+        Command::Serve => unreachable!("the server is launched by main"),
+        Command::Cleanup(args) => cleanup(&config, &args).await,
+        Command::ValidateConfig => validate_config(&config),
+        Command::Selftest => selftest(&config).await,
+    }
+}
+
+fn validate_config(config: &config::Config) -> Result<(), CliError> {
+    numa::validate(config, &numa::discover_nodes())?;
+    devices::validate(config)?;
+    binds::validate(config)?;
+    println!("the configuration is valid");
+    Ok(())
+}
+
+async fn cleanup(config: &config::Config, args: &CleanupArgs) -> Result<(), CliError> {
+    let verb = if args.dry_run {
+        "would remove"
+    } else {
+        "removed"
+    };
+    if args.containers {
+        let docker = daemon::connect(config)?;
+        let instance_filter = config.instance_label_filter();
+        let filters = HashMap::from([
+            ("name", vec![config.docker_exec_prefix.as_str()]),
+            ("label", vec![instance_filter.as_str()]),
+        ]);
+        let containers = docker
+            .list_containers(Some(ListContainersOptions {
+                all: true,
+                filters,
+                ..Default::default()
+            }))
+            .await?;
+        for container in containers {
+            let id = container.id.unwrap_or_default();
+            if !args.dry_run {
+                let options = Some(RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                });
+                docker.remove_container(&id, options).await?;
+            }
+            println!(
+                "{verb} container {id} {:?}",
+                container.names.unwrap_or_default()
+            );
+        }
+    }
+    if args.images {
+        let docker = daemon::connect(config)?;
+        let instance_filter = config.instance_label_filter();
+        let filters = HashMap::from([
+            ("dangling", vec!["true"]),
+            ("label", vec![instance_filter.as_str()]),
+        ]);
+        let images = docker
+            .list_images(Some(ListImagesOptions {
+                filters,
+                ..Default::default()
+            }))
+            .await?;
+        for image in images {
+            if !args.dry_run {
+                let options = Some(RemoveImageOptions {
+                    force: true,
+                    ..Default::default()
+                });
+                docker.remove_image(&image.id, options, None).await?;
+            }
+            println!("{verb} image {}", image.id);
+        }
+    }
+    if args.uploads {
+        for path in stale_uploads(Path::new(&config.upload_root))? {
+            if !args.dry_run {
+                std::fs::remove_file(&path)?;
+            }
+            println!("{verb} upload {path:?}");
+        }
+    }
+    Ok(())
+}
+
+/// Files left in the upload directory, whose sessions were lost with the server.
+fn stale_uploads(upload_root: &Path) -> std::io::Result<Vec<std::path::PathBuf>> {
+    if !upload_root.exists() {
+        return Ok(Vec::new());
+    }
+    let mut uploads = Vec::new();
+    for entry in std::fs::read_dir(upload_root)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            uploads.push(entry.path());
+        }
+    }
+    uploads.sort();
+    Ok(uploads)
+}
+
+async fn selftest(config: &config::Config) -> Result<(), CliError> {
+    let mut failures = 0;
+    let mut report = |check: &str, result: Result<String, String>| match result {
+        Ok(details) => println!("ok   {check}: {details}"),
+        Err(err) => {
+            println!("FAIL {check}: {err}");
+            failures += 1;
+        }
+    };
+
+    let docker = match daemon::connect(config) {
+        Ok(docker) => docker.version().await.map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    let docker = docker.map(|version| format!("version {}", version.version.unwrap_or_default()));
+    report("docker", docker);
+
+    for (name, dir) in [
+        ("compilation_root", &config.compilation_root),
+        ("upload_root", &config.upload_root),
+    ] {
+        report(name, check_writable(Path::new(dir)));
+    }
+
+    let tmpdir = std::env::temp_dir();
+    let space = fs2::available_space(&tmpdir).map_err(|err| err.to_string());
+    let space = space.and_then(|space| {
+        if space < config.disk_space_floor {
+            Err(format!(
+                "{space} bytes available in {tmpdir:?}, below disk_space_floor"
+            ))
+        } else {
+            Ok(format!("{space} bytes available in {tmpdir:?}"))
+        }
+    });
+    report("disk space", space);
+
+    match failures {
+        0 => Ok(()),
+        failures => Err(CliError::Selftest(failures)),
+    }
+}
+
+fn check_writable(dir: &Path) -> Result<String, String> {
+    std::fs::create_dir_all(dir).map_err(|err| format!("{dir:?}: {err}"))?;
+    tempfile::tempfile_in(dir).map_err(|err| format!("{dir:?} is not writable: {err}"))?;
+    Ok(format!("{dir:?} is writable"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        let parse = |args: &[&str]| Cli::try_parse_from(args).map(|cli| cli.command);
+        assert_eq!(parse(&["ipol-demorunner"]).unwrap(), None);
+        assert_eq!(
+            parse(&["ipol-demorunner", "serve"]).unwrap(),
+            Some(Command::Serve)
+        );
+        assert_eq!(
+            parse(&["ipol-demorunner", "cleanup", "--containers", "--dry-run"]).unwrap(),
+            Some(Command::Cleanup(CleanupArgs {
+                containers: true,
+                dry_run: true,
+                ..Default::default()
+            }))
+        );
+        assert_eq!(
+            parse(&["ipol-demorunner", "validate-config"]).unwrap(),
+            Some(Command::ValidateConfig)
+        );
+        assert!(parse(&["ipol-demorunner", "cleanup", "--everything"]).is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_validate_config() {
+        let figment = rocket::Config::figment();
+        assert!(run(Command::ValidateConfig, &figment).await.is_ok());
+
+        let figment = rocket::Config::figment().merge(("max_timeout", "forever"));
+        assert!(matches!(
+            run(Command::ValidateConfig, &figment).await,
+            Err(CliError::Config(_))
+        ));
+
+        let ulimits = serde_json::json!([{ "name": "files", "soft": 1024, "hard": 1024 }]);
+        let figment = rocket::Config::figment().merge(("ulimits", ulimits));
+        assert!(matches!(
+            run(Command::ValidateConfig, &figment).await,
+            Err(CliError::Config(_))
+        ));
+
+        let figment = rocket::Config::figment().merge(("demos.t001.numa_node", 4096));
+        assert!(matches!(
+            run(Command::ValidateConfig, &figment).await,
+            Err(CliError::Numa(_))
+        ));
+
+        let devices = serde_json::json!([{ "path_on_host": "/dev/video-missing" }]);
+        let figment = rocket::Config::figment().merge(("demos.t001.devices", devices));
+        assert!(matches!(
+            run(Command::ValidateConfig, &figment).await,
+            Err(CliError::Devices(_))
+        ));
+
+        let extra_binds = serde_json::json!([{ "host_path": "/typo", "container_path": "/data" }]);
+        let figment = rocket::Config::figment().merge(("extra_binds", extra_binds));
+        assert!(matches!(
+            run(Command::ValidateConfig, &figment).await,
+            Err(CliError::Binds(_))
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn test_cleanup_uploads() {
+        let upload_root = tempfile::tempdir().unwrap();
+        std::fs::write(upload_root.path().join("session"), "partial").unwrap();
+        std::fs::create_dir(upload_root.path().join("dir")).unwrap();
+        let figment = rocket::Config::figment().merge(("upload_root", upload_root.path()));
+        let cleanup = |dry_run| {
+            Command::Cleanup(CleanupArgs {
+                uploads: true,
+                dry_run,
+                ..Default::default()
+            })
+        };
+
+        run(cleanup(true), &figment).await.unwrap();
+        assert!(upload_root.path().join("session").exists());
+        run(cleanup(false), &figment).await.unwrap();
+        assert!(!upload_root.path().join("session").exists());
+        assert!(upload_root.path().join("dir").exists());
+    }
+
+    /// Builds an empty image labeled with an instance, left dangling.
+    async fn build_labeled_image(docker: &bollard::Docker, instance_id: &str) -> String {
+        use futures_util::stream::StreamExt;
+
+        let dockerfile = b"FROM scratch\nCMD [\"none\"]\n";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(dockerfile.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut ar = tar::Builder::new(Vec::new());
+        ar.append_data(&mut header, "Dockerfile", &dockerfile[..])
+            .unwrap();
+        let tar = ar.into_inner().unwrap();
+
+        let options = bollard::image::BuildImageOptions {
+            dockerfile: "Dockerfile".to_string(),
+            labels: HashMap::from([(config::INSTANCE_LABEL.to_string(), instance_id.to_string())]),
+            rm: true,
+            ..Default::default()
+        };
+        let mut stream = docker.build_image(options, None, Some(tar.into()));
+        let mut id = None;
+        while let Some(info) = stream.next().await {
+            if let Some(aux) = info.unwrap().aux {
+                id = aux.id.or(id);
+            }
+        }
+        id.unwrap()
+    }
+
+    async fn create_labeled_container(
+        docker: &bollard::Docker,
+        name: &str,
+        image: &str,
+        instance_id: &str,
+    ) {
+        let options = Some(RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        });
+        let _ = docker.remove_container(name, options).await;
+        let options = Some(bollard::container::CreateContainerOptions {
+            name,
+            platform: None,
+        });
+        let container_config = bollard::container::Config {
+            image: Some(image),
+            labels: Some(HashMap::from([(config::INSTANCE_LABEL, instance_id)])),
+            ..Default::default()
+        };
+        docker
+            .create_container(options, container_config)
+            .await
+            .unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn test_cleanup_leaves_other_instances() {
+        let figment = rocket::Config::figment().merge(("instance_id", "test_cleanup"));
+        let config: config::Config = figment.extract().unwrap();
+        let docker = daemon::connect(&config).unwrap();
+        let own_image = build_labeled_image(&docker, "test_cleanup").await;
+        let foreign_image = build_labeled_image(&docker, "test_cleanup_foreign").await;
+        let own = format!("{}test_cleanup_own", config.docker_exec_prefix);
+        let foreign = format!("{}test_cleanup_foreign", config.docker_exec_prefix);
+        create_labeled_container(&docker, &own, &own_image, "test_cleanup").await;
+        create_labeled_container(&docker, &foreign, &foreign_image, "test_cleanup_foreign").await;
+
+        let cleanup = Command::Cleanup(CleanupArgs {
+            containers: true,
+            images: true,
+            ..Default::default()
+        });
+        run(cleanup, &figment).await.unwrap();
+        assert!(docker.inspect_container(&own, None).await.is_err());
+        assert!(docker.inspect_image(&own_image).await.is_err());
+        // with the same name prefix, but of another instance
+        assert!(docker.inspect_container(&foreign, None).await.is_ok());
+        assert!(docker.inspect_image(&foreign_image).await.is_ok());
+
+        let options = Some(RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        });
+        docker.remove_container(&foreign, options).await.unwrap();
+        docker
+            .remove_image(&foreign_image, None, None)
+            .await
+            .unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn test_selftest_unwritable_root() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let file = tmpdir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let figment = rocket::Config::figment().merge(("compilation_root", &file));
+        assert!(matches!(
+            run(Command::Selftest, &figment).await,
+            Err(CliError::Selftest(_))
+        ));
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use bollard::auth::DockerCredentials;
 use bollard::image::{ListImagesOptions, RemoveImageOptions};
@@ -11,22 +12,26 @@ use rocket::serde::json::Json;
 use rocket::serde::{Deserialize, Serialize};
 use rocket::tokio::fs;
 use rocket::tokio::io::AsyncWriteExt;
+use rocket::tokio::sync::{OwnedMutexGuard, OwnedSemaphorePermit, Semaphore};
 use rocket::{tokio, State};
 
-use bollard::{image::BuildImageOptions, Docker};
+use bollard::image::BuildImageOptions;
 
 use futures_util::stream::StreamExt;
 use git2::{
     CertificateCheckStatus::{CertificateOk, CertificatePassthrough},
     Repository,
 };
+use schemars::JsonSchema;
 use secrecy::{ExposeSecret, SecretString};
 use serde::Serializer;
 use ssh_key::Fingerprint;
 use tar::Builder;
 
 use crate::config;
+use crate::daemon;
 use crate::model::*;
+use crate::names::{self, NameError};
 
 fn serialize_secret<S>(secret: &SecretString, serializer: S) -> Result<S::Ok, S::Error>
 where
@@ -73,12 +78,126 @@ pub struct CompilationRequest {
     ssh_key: Option<SSHKeyPair>,
 }
 
+/// Image to build from the shared sources of a `VariantsCompilationRequest`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BuildVariant {
+    demo_id: DemoID,
+    /// `ARG`s of the dockerfile.
+    #[serde(default)]
+    build_args: HashMap<String, String>,
+    /// Stage of the dockerfile to build, instead of the last one.
+    target: Option<String>,
+}
+
+/// Compilation of several demos sharing one repository and revision.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct VariantsCompilationRequest {
+    ddl_build: DDLBuild,
+    #[serde(rename = "ssh_keys")]
+    ssh_key: Option<SSHKeyPair>,
+    variants: Vec<BuildVariant>,
+}
+
+/// Outcome of the compilation of a variant, independent of the other variants.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct VariantResult {
+    demo_id: DemoID,
+    /// Why the variant failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<CompilationResponse>,
+}
+
+/// Category of a compilation failure, telling the failures to retry later
+/// from the ones to be fixed in the demo.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CompilationErrorCode {
+    /// The git repository could not be cloned or fetched.
+    GitUnreachable,
+    /// The revision is not in the git repository.
+    RevNotFound,
+    /// The dockerfile is not in the sources.
+    DockerfileMissing,
+    /// The docker build failed.
+    BuildFailed,
+    /// The docker daemon did not answer in time.
+    Timeout,
+    /// The node ran out of disk space.
+    DiskFull,
+    /// The demo is not built by this node, per its allowlist or denylist.
+    DemoNotAllowed,
+    /// Any other failure of the node.
+    Internal,
+}
+
+impl CompilationErrorCode {
+    /// Whether the failure comes from the demo rather than from the node.
+    fn is_user_error(self) -> bool {
+        match self {
+            CompilationErrorCode::RevNotFound
+            | CompilationErrorCode::DockerfileMissing
+            | CompilationErrorCode::BuildFailed => true,
+            CompilationErrorCode::GitUnreachable
+            | CompilationErrorCode::Timeout
+            | CompilationErrorCode::DiskFull
+            | CompilationErrorCode::DemoNotAllowed
+            | CompilationErrorCode::Internal => false,
+        }
+    }
+}
+
+/// Failure of a compilation.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
 pub struct CompilationResponse {
+    /// Why the compilation failed.
     #[serde(rename = "detail")]
     message: String,
+    /// Category of the failure.
+    error_code: CompilationErrorCode,
+    /// Log of the docker build, when the build itself failed.
     #[serde(skip_serializing_if = "Option::is_none")]
     buildlog: Option<String>,
+    /// Step of the dockerfile which failed, when the build itself failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failed_step: Option<String>,
+    /// Last lines of the build log, when the build itself failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log_tail: Option<String>,
+}
+
+impl From<CompilationError> for CompilationResponse {
+    fn from(err: CompilationError) -> Self {
+        match err {
+            CompilationError::BuildError(ref buildlog) => CompilationResponse {
+                message: err.to_string(),
+                error_code: err.error_code(),
+                buildlog: Some(buildlog.clone()),
+                failed_step: failed_step(buildlog),
+                log_tail: Some(log_tail(buildlog)),
+            },
+            _ => CompilationResponse {
+                message: err.to_string(),
+                error_code: err.error_code(),
+                buildlog: None,
+                failed_step: None,
+                log_tail: None,
+            },
+        }
+    }
+}
+
+impl CompilationResponse {
+    fn into_status(self) -> status::Custom<Json<CompilationResponse>> {
+        // like for the executions, the failures of the demo are not failures of the node
+        let status = if self.error_code == CompilationErrorCode::DemoNotAllowed {
+            Status::Forbidden
+        } else if self.error_code.is_user_error() {
+            Status::UnprocessableEntity
+        } else {
+            Status::InternalServerError
+        };
+        status::Custom(status, Json(self))
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -91,8 +210,141 @@ enum CompilationError {
     Docker(#[from] bollard::errors::Error),
     #[error("ipol-demorunner/git: {0}")]
     Git(#[from] git2::Error),
+    #[error("ipol-demorunner/git: {0}")]
+    GitUnreachable(git2::Error),
+    #[error("ipol-demorunner/git: {0}")]
+    RevNotFound(git2::Error),
     #[error("Couldn't find dockerfile: {0}")]
     MissingDockerfile(String),
+    #[error("ipol-demorunner/names: {0}")]
+    Name(#[from] NameError),
+    #[error("The demo {0} is not built by this instance")]
+    DemoNotAllowed(DemoID),
+}
+
+impl CompilationError {
+    fn error_code(&self) -> CompilationErrorCode {
+        match self {
+            CompilationError::BuildError(buildlog) if is_disk_full(buildlog) => {
+                CompilationErrorCode::DiskFull
+            }
+            CompilationError::BuildError(_) => CompilationErrorCode::BuildFailed,
+            CompilationError::IO(err) if err.kind() == std::io::ErrorKind::StorageFull => {
+                CompilationErrorCode::DiskFull
+            }
+            CompilationError::Docker(bollard::errors::Error::RequestTimeoutError) => {
+                CompilationErrorCode::Timeout
+            }
+            CompilationError::GitUnreachable(_) => CompilationErrorCode::GitUnreachable,
+            CompilationError::RevNotFound(_) => CompilationErrorCode::RevNotFound,
+            CompilationError::MissingDockerfile(_) => CompilationErrorCode::DockerfileMissing,
+            CompilationError::DemoNotAllowed(_) => CompilationErrorCode::DemoNotAllowed,
+            CompilationError::IO(_)
+            | CompilationError::Docker(_)
+            | CompilationError::Git(_)
+            | CompilationError::Name(_) => CompilationErrorCode::Internal,
+        }
+    }
+}
+
+/// Whether an image can be reused by this instance: one of its own, or one
+/// built before the images were labeled with their instance.
+fn reusable_image(labels: &HashMap<String, String>, instance_id: &str) -> bool {
+    labels
+        .get(config::INSTANCE_LABEL)
+        .is_none_or(|id| id == instance_id)
+}
+
+/// Serializes the compilations of each demo, which share its compilation
+/// directory, and bounds the simultaneous compilations to `max_concurrent_builds`.
+#[derive(Debug)]
+pub struct BuildLocks {
+    demos: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    builds: Arc<Semaphore>,
+}
+
+/// The locks of a compilation, released when dropped.
+#[derive(Debug)]
+struct BuildGuard {
+    _demos: Vec<OwnedMutexGuard<()>>,
+    _build: OwnedSemaphorePermit,
+}
+
+impl BuildLocks {
+    /// `max_builds` of 0 means no limit.
+    pub fn new(max_builds: usize) -> Self {
+        let permits = if max_builds == 0 {
+            Semaphore::MAX_PERMITS
+        } else {
+            max_builds
+        };
+        BuildLocks {
+            demos: Mutex::default(),
+            builds: Arc::new(Semaphore::new(permits)),
+        }
+    }
+
+    /// Waits for the compilation directories of the demos, locked in sorted
+    /// order so that two requests sharing some demos can't deadlock, then for
+    /// a build slot.
+    async fn lock<'a>(&self, demo_ids: impl IntoIterator<Item = &'a DemoID>) -> BuildGuard {
+        let mut demo_ids = demo_ids
+            .into_iter()
+            .map(|demo_id| demo_id.as_ref().clone())
+            .collect::<Vec<_>>();
+        demo_ids.sort();
+        demo_ids.dedup();
+        let mut demos = Vec::new();
+        for demo_id in demo_ids {
+            let lock = self
+                .demos
+                .lock()
+                .unwrap()
+                .entry(demo_id)
+                .or_default()
+                .clone();
+            demos.push(lock.lock_owned().await);
+        }
+        let build = self.builds.clone().acquire_owned().await;
+        BuildGuard {
+            _demos: demos,
+            _build: build.expect("the build semaphore is never closed"),
+        }
+    }
+}
+
+/// Manages the build locks, with `max_concurrent_builds`.
+pub fn build_locks() -> rocket::fairing::AdHoc {
+    rocket::fairing::AdHoc::on_ignite("Build locks", |rocket| {
+        Box::pin(async move {
+            let max = rocket
+                .state::<config::Config>()
+                .map_or(0, |config| config.max_concurrent_builds);
+            rocket.manage(BuildLocks::new(max))
+        })
+    })
+}
+
+/// Number of lines of the build log given in `log_tail`.
+const LOG_TAIL_LINES: usize = 50;
+
+fn log_tail(buildlog: &str) -> String {
+    let lines = buildlog.lines().collect::<Vec<_>>();
+    lines[lines.len().saturating_sub(LOG_TAIL_LINES)..].join("\n")
+}
+
+/// The last step started by the docker build, such as `Step 2/3 : RUN make`.
+fn failed_step(buildlog: &str) -> Option<String> {
+    buildlog
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("Step "))
+        .last()
+        .map(String::from)
+}
+
+fn is_disk_full(buildlog: &str) -> bool {
+    buildlog.contains("no space left on device")
 }
 
 fn url_of_git_repository(srcdir: &Path) -> Option<String> {
@@ -225,19 +477,27 @@ fn prepare_git(
         let fo = get_fetch_options();
         let mut builder = git2::build::RepoBuilder::new();
         builder.fetch_options(fo);
-        builder.clone(url, path)?
+        builder
+            .clone(url, path)
+            .map_err(CompilationError::GitUnreachable)?
     };
 
     {
         tracing::debug!("fetching origin");
         let mut fo = get_fetch_options();
         let mut remote = repo.find_remote("origin")?;
-        remote.fetch::<&str>(&[], Some(&mut fo), None)?;
+        remote
+            .fetch::<&str>(&[], Some(&mut fo), None)
+            .map_err(CompilationError::GitUnreachable)?;
     }
 
     // TODO: support "master" as rev instead of "origin/master"?
     tracing::debug!("revparsing {rev}");
-    let commit_id = repo.revparse_single(rev)?.peel_to_commit()?.id();
+    let commit_id = repo
+        .revparse_single(rev)
+        .and_then(|object| object.peel_to_commit())
+        .map_err(CompilationError::RevNotFound)?
+        .id();
     repo.set_head_detached(commit_id)?;
 
     tracing::debug!("checking out {rev} = {commit_id}");
@@ -251,53 +511,176 @@ fn prepare_git(
     Ok(commit_id.to_string())
 }
 
-#[tracing::instrument(skip(req, config))]
+/// Clones or updates the sources of a build, returning the checked out commit.
+async fn fetch_sources(
+    srcdir: &Path,
+    ddl_build: &DDLBuild,
+    ssh_key: Option<SSHKeyPair>,
+) -> Result<String, CompilationError> {
+    let srcdir = srcdir.to_path_buf();
+    let ddl_build = ddl_build.clone();
+    let git_fetcher = GitFetcher::builder().ssh_key(ssh_key).build()?;
+    tokio::task::spawn_blocking(move || {
+        prepare_git(
+            &git_fetcher,
+            &srcdir,
+            &ddl_build.url,
+            ddl_build.ssh_fingerprint,
+            &ddl_build.rev,
+        )
+    })
+    .await
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Interrupted, e))?
+}
+
+#[tracing::instrument(skip(req, config, locks))]
 async fn ensure_compilation_inner(
     demo_id: DemoID,
     req: &CompilationRequest,
     config: &State<config::Config>,
+    locks: &BuildLocks,
 ) -> Result<(), CompilationError> {
     tracing::debug!("{req:?}");
+    if !config.demo_allowed(&demo_id) {
+        return Err(CompilationError::DemoNotAllowed(demo_id));
+    }
+    let _guard = locks.lock([&demo_id]).await;
 
-    let compilation_path = PathBuf::from(&config.compilation_root).join(demo_id.as_ref());
+    let compilation_path = names::compilation_dir(config, &demo_id)?;
     let srcdir = PathBuf::from(&compilation_path).join("src");
     let logfile = PathBuf::from(&compilation_path).join("build.log");
     fs::create_dir_all(&compilation_path).await?;
     let mut buildlog = fs::File::create(logfile).await?;
 
-    let git_rev = {
-        let srcdir = srcdir.clone();
-        let ddl_build = req.ddl_build.clone();
-        let git_fetcher = GitFetcher::builder().ssh_key(req.ssh_key.clone()).build()?;
-        tokio::task::spawn_blocking(move || {
-            prepare_git(
-                &git_fetcher,
-                &srcdir,
-                &ddl_build.url,
-                ddl_build.ssh_fingerprint,
-                &ddl_build.rev,
-            )
-        })
-        .await
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Interrupted, e))??
+    let git_rev = fetch_sources(&srcdir, &req.ddl_build, req.ssh_key.clone()).await?;
+    let variant = BuildVariant {
+        demo_id,
+        build_args: HashMap::new(),
+        target: None,
     };
+    let dockerfile = &req.ddl_build.dockerfile;
+    build_image(
+        config,
+        &variant,
+        &srcdir,
+        dockerfile,
+        git_rev,
+        &mut buildlog,
+    )
+    .await
+}
 
-    let dockerfile_path = PathBuf::from(&srcdir).join(&req.ddl_build.dockerfile);
-    if !dockerfile_path.exists() {
-        tracing::warn!("could not find the dockerfile at {dockerfile_path:?}");
-        return Err(CompilationError::MissingDockerfile(
-            req.ddl_build.dockerfile.clone(),
-        ));
+/// Fetches the sources once, into the directory of the first variant, and
+/// builds the variants one after the other.
+///
+/// The directories of all the variants stay locked until the last build, the
+/// shared sources being those of the first one.
+#[tracing::instrument(skip(req, config, locks))]
+async fn ensure_variants_inner(
+    req: &VariantsCompilationRequest,
+    config: &config::Config,
+    locks: &BuildLocks,
+) -> Result<Vec<VariantResult>, CompilationError> {
+    tracing::debug!("{req:?}");
+
+    let Some(first) = req.variants.first() else {
+        return Ok(Vec::new());
+    };
+    let _guard = locks
+        .lock(req.variants.iter().map(|variant| &variant.demo_id))
+        .await;
+    let compilation_path = names::compilation_dir(config, &first.demo_id)?;
+    let shared_srcdir = PathBuf::from(&compilation_path).join("src");
+    fs::create_dir_all(&compilation_path).await?;
+    let git_rev = fetch_sources(&shared_srcdir, &req.ddl_build, req.ssh_key.clone()).await?;
+
+    let mut results = Vec::new();
+    for variant in &req.variants {
+        let dockerfile = &req.ddl_build.dockerfile;
+        let result = build_variant(config, variant, dockerfile, &shared_srcdir, &git_rev).await;
+        if let Err(err) = &result {
+            tracing::info!("the variant {} failed: {err}", variant.demo_id);
+        }
+        results.push(VariantResult {
+            demo_id: variant.demo_id.clone(),
+            error: result.err().map(CompilationResponse::from),
+        });
+    }
+    Ok(results)
+}
+
+/// Copies a checkout, with its git directory, replacing the destination.
+fn copy_sources(from: &Path, to: &Path) -> std::io::Result<()> {
+    if to.exists() {
+        std::fs::remove_dir_all(to)?;
+    }
+    for entry in walkdir::WalkDir::new(from) {
+        let entry = entry?;
+        let path = to.join(entry.path().strip_prefix(from).unwrap_or(entry.path()));
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&path)?;
+        } else if entry.file_type().is_symlink() {
+            std::os::unix::fs::symlink(std::fs::read_link(entry.path())?, &path)?;
+        } else {
+            std::fs::copy(entry.path(), &path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Builds a variant from a copy of the shared sources, so that the
+/// compilation directory of each demo holds the sources of its image.
+async fn build_variant(
+    config: &config::Config,
+    variant: &BuildVariant,
+    dockerfile: &str,
+    shared_srcdir: &Path,
+    git_rev: &str,
+) -> Result<(), CompilationError> {
+    if !config.demo_allowed(&variant.demo_id) {
+        return Err(CompilationError::DemoNotAllowed(variant.demo_id.clone()));
+    }
+    let compilation_path = names::compilation_dir(config, &variant.demo_id)?;
+    let srcdir = PathBuf::from(&compilation_path).join("src");
+    let logfile = PathBuf::from(&compilation_path).join("build.log");
+    fs::create_dir_all(&compilation_path).await?;
+    let mut buildlog = fs::File::create(logfile).await?;
+
+    if srcdir != shared_srcdir {
+        let (from, to) = (shared_srcdir.to_path_buf(), srcdir.clone());
+        tokio::task::spawn_blocking(move || copy_sources(&from, &to))
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Interrupted, e))??;
     }
+    let git_rev = git_rev.to_string();
+    build_image(config, variant, &srcdir, dockerfile, git_rev, &mut buildlog).await
+}
+
+/// Whether a dockerfile path stays in the sources, being relative and without `..`.
+pub fn dockerfile_in_sources(dockerfile: &str) -> bool {
+    let mut components = Path::new(dockerfile).components().peekable();
+    components.peek().is_some()
+        && components.all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
 
-    let docker = Docker::connect_with_local_defaults()?;
+/// Builds the image of a demo from its checked out sources, unless it already exists.
+async fn build_image(
+    config: &config::Config,
+    variant: &BuildVariant,
+    srcdir: &Path,
+    dockerfile: &str,
+    git_rev: String,
+    buildlog: &mut fs::File,
+) -> Result<(), CompilationError> {
+    let image_name = names::image_name(config, &variant.demo_id)?;
+    let dockerfile_path = PathBuf::from(&srcdir).join(dockerfile);
+    if !dockerfile_in_sources(dockerfile) || !dockerfile_path.exists() {
+        tracing::warn!("could not find the dockerfile at {dockerfile_path:?}");
+        return Err(CompilationError::MissingDockerfile(dockerfile.to_string()));
+    }
 
-    let registry = config
-        .registry_url
-        .as_ref()
-        .map_or(String::new(), |url| (url.clone() + "/"));
+    let docker = daemon::connect(config)?;
 
-    let image_name = format!("{}{}{}", registry, config.docker_image_prefix, &demo_id);
     let image_name_with_tag = format!("{}:{}", image_name, git_rev);
 
     let mut pulled = true;
@@ -330,12 +713,15 @@ async fn ensure_compilation_inner(
 
     let filters: HashMap<&str, Vec<&str>> =
         HashMap::from([("reference", vec![image_name.as_ref()])]);
-    let current_images = docker
+    let mut current_images = docker
         .list_images(Some(ListImagesOptions {
             filters,
             ..Default::default()
         }))
         .await?;
+    // only consider the images of this instance, so that another demorunner
+    // sharing the docker host never sees its images reused or removed
+    current_images.retain(|img| reusable_image(&img.labels, &config.instance_id));
 
     if current_images
         .iter()
@@ -355,17 +741,23 @@ async fn ensure_compilation_inner(
     }
 
     let build_image_options = BuildImageOptions {
-        dockerfile: req.ddl_build.dockerfile.clone(),
+        dockerfile: dockerfile.to_string(),
         t: image_name_with_tag.clone(),
         q: false,
         rm: true,
         forcerm: true,
+        labels: HashMap::from([(
+            config::INSTANCE_LABEL.to_string(),
+            config.instance_id.clone(),
+        )]),
+        buildargs: variant.build_args.clone(),
+        target: variant.target.clone().unwrap_or_default(),
         ..Default::default()
     };
 
     let tar: Bytes = {
         tracing::debug!("building the tar containing the source code");
-        let srcdir = srcdir.clone();
+        let srcdir = srcdir.to_path_buf();
         tokio::task::spawn_blocking(move || -> Result<Bytes, CompilationError> {
             let mut ar = Builder::new(Vec::new());
             // respect the symlink of the source code (so keep symlinks as-is),
@@ -478,31 +870,39 @@ pub async fn ensure_compilation(
     demo_id: DemoID,
     req: Json<CompilationRequest>,
     config: &State<config::Config>,
+    locks: &State<BuildLocks>,
 ) -> Result<status::Custom<()>, status::Custom<Json<CompilationResponse>>> {
-    let response = match ensure_compilation_inner(demo_id, &req, config).await {
+    let response = match ensure_compilation_inner(demo_id, &req, config, locks).await {
         Ok(()) => {
             return Ok(status::Custom(Status::Created, ()));
         }
-        Err(err) => match err {
-            CompilationError::BuildError(ref buildlog) => CompilationResponse {
-                message: err.to_string(),
-                buildlog: Some(buildlog.clone()),
-            },
-            _ => CompilationResponse {
-                message: err.to_string(),
-                buildlog: None,
-            },
-        },
+        Err(err) => CompilationResponse::from(err),
     };
     dbg!(&response);
-    Err(status::Custom(Status::InternalServerError, Json(response)))
+    Err(response.into_status())
+}
+
+/// Compiles several variants of one repository and revision, cloned once.
+///
+/// The failure of a variant is reported in its result; the request fails
+/// only when the sources can't be fetched.
+#[post("/compilations", data = "<req>")]
+pub async fn ensure_compilations(
+    req: Json<VariantsCompilationRequest>,
+    config: &State<config::Config>,
+    locks: &State<BuildLocks>,
+) -> Result<Json<Vec<VariantResult>>, status::Custom<Json<CompilationResponse>>> {
+    match ensure_variants_inner(&req, config, locks).await {
+        Ok(results) => Ok(Json(results)),
+        Err(err) => Err(CompilationResponse::from(err).into_status()),
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::main_rocket;
     use crate::test::GIT_URL;
+    use crate::{main_rocket, rocket_from_figment};
     use rocket::http::ContentType;
     use rocket::local::blocking::Client;
 
@@ -523,9 +923,11 @@ mod test {
                 assert!(response.into_string().is_none());
                 Ok(())
             }
-            500 => {
+            code @ (422 | 500) => {
                 assert_eq!(response.content_type(), Some(ContentType::JSON));
-                Err(response.into_json().unwrap())
+                let response: CompilationResponse = response.into_json().unwrap();
+                assert_eq!(code == 422, response.error_code.is_user_error());
+                Err(response)
             }
             _ => {
                 panic!()
@@ -568,7 +970,10 @@ mod test {
             response,
             Err(CompilationResponse {
                 message: "Couldn't find dockerfile: missing".into(),
+                error_code: CompilationErrorCode::DockerfileMissing,
                 buildlog: None,
+                failed_step: None,
+                log_tail: None,
             })
         );
     }
@@ -592,11 +997,24 @@ mod test {
             Err(CompilationResponse {
                 message: "ipol-demorunner/git: revspec 'invalid' not found; class=Reference (4); code=NotFound (-3)"
                     .into(),
+                error_code: CompilationErrorCode::RevNotFound,
                 buildlog: None,
+                failed_step: None,
+                log_tail: None,
             })
         );
     }
 
+    #[test]
+    fn test_dockerfile_in_sources() {
+        assert!(dockerfile_in_sources(".ipol/Dockerfile"));
+        assert!(dockerfile_in_sources("./Dockerfile"));
+        assert!(!dockerfile_in_sources(""));
+        assert!(!dockerfile_in_sources("/etc/passwd"));
+        assert!(!dockerfile_in_sources("../other/Dockerfile"));
+        assert!(!dockerfile_in_sources(".ipol/../../Dockerfile"));
+    }
+
     #[test]
     #[tracing_test::traced_test]
     fn test_compilation_invalid_dockerfile() {
@@ -632,9 +1050,115 @@ mod test {
         let response = ask_compilation("t005", &request);
         let r = response.unwrap_err();
         assert_eq!(r.message, "Compilation error");
+        assert_eq!(r.error_code, CompilationErrorCode::BuildFailed);
+        assert!(r.failed_step.unwrap().starts_with("Step "));
+        assert!(!r.log_tail.unwrap().is_empty());
         assert!(!r.buildlog.unwrap().is_empty());
     }
 
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_compilation_git_unreachable() {
+        let request = CompilationRequest {
+            ddl_build: DDLBuild {
+                url: "https://github.com/kidanger/invalid-git".into(),
+                ssh_fingerprint: None,
+                rev: "master".into(),
+                dockerfile: ".ipol/Dockerfile".into(),
+            },
+            ssh_key: None,
+        };
+
+        let response = ask_compilation("t006", &request);
+        let r = response.unwrap_err();
+        assert_eq!(r.error_code, CompilationErrorCode::GitUnreachable);
+        assert!(
+            r.message.starts_with("ipol-demorunner/git: "),
+            "{}",
+            r.message
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_compilation_variants() {
+        let variant = |demo_id: &str, target: Option<&str>| BuildVariant {
+            demo_id: DemoID::try_from(demo_id).unwrap(),
+            build_args: HashMap::from([("VARIANT".into(), demo_id.into())]),
+            target: target.map(String::from),
+        };
+        let request = VariantsCompilationRequest {
+            ddl_build: DDLBuild {
+                url: GIT_URL.into(),
+                ssh_fingerprint: None,
+                rev: "69b4dbc2ff9c3102c3b86639ed1ab608a6b5ba79".into(),
+                dockerfile: ".ipol/Dockerfile".into(),
+            },
+            ssh_key: None,
+            variants: vec![
+                variant("t007", None),
+                variant("t008", Some("missing-stage")),
+                variant("t009", None),
+            ],
+        };
+
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let response = client
+            .post("/compilations")
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&request).unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let results: Vec<VariantResult> = response.into_json().unwrap();
+        let demo_ids = results.iter().map(|r| r.demo_id.to_string());
+        assert_eq!(demo_ids.collect::<Vec<_>>(), ["t007", "t008", "t009"]);
+        assert_eq!(results[0].error, None);
+        let error = results[1].error.as_ref().unwrap();
+        assert_eq!(error.error_code, CompilationErrorCode::BuildFailed);
+        assert_eq!(results[2].error, None);
+
+        // each variant has its image and the sources it was built from
+        let config = crate::config::test_config();
+        let docker = daemon::connect(&config).unwrap();
+        let rev = "69b4dbc2ff9c3102c3b86639ed1ab608a6b5ba79";
+        for demo_id in ["t007", "t009"] {
+            let demo_id = DemoID::try_from(demo_id).unwrap();
+            let srcdir = names::compilation_dir(&config, &demo_id)
+                .unwrap()
+                .join("src");
+            assert_eq!(get_git_revision(&srcdir).unwrap(), rev);
+            let image_name = names::image_name(&config, &demo_id).unwrap();
+            let image = format!("{image_name}:{rev}");
+            rocket::execute(docker.inspect_image(&image)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_error_code() {
+        let buildlog = "Step 1/3 : FROM debian\n ---> 1234\nStep 2/3 : RUN make\nerror\n";
+        let err = CompilationError::BuildError(buildlog.into());
+        assert_eq!(err.error_code(), CompilationErrorCode::BuildFailed);
+        assert_eq!(failed_step(buildlog).unwrap(), "Step 2/3 : RUN make");
+        assert_eq!(failed_step("error"), None);
+
+        let err = CompilationError::BuildError("write /x: no space left on device".into());
+        assert_eq!(err.error_code(), CompilationErrorCode::DiskFull);
+        let err = CompilationError::IO(std::io::ErrorKind::StorageFull.into());
+        assert_eq!(err.error_code(), CompilationErrorCode::DiskFull);
+        let err = CompilationError::IO(std::io::ErrorKind::PermissionDenied.into());
+        assert_eq!(err.error_code(), CompilationErrorCode::Internal);
+        let err = CompilationError::Docker(bollard::errors::Error::RequestTimeoutError);
+        assert_eq!(err.error_code(), CompilationErrorCode::Timeout);
+    }
+
+    #[test]
+    fn test_log_tail() {
+        let buildlog = (0..100).map(|i| format!("{i}\n")).collect::<String>();
+        let tail = log_tail(&buildlog);
+        assert_eq!(tail.lines().count(), LOG_TAIL_LINES);
+        assert!(tail.ends_with("99"));
+    }
+
     #[test]
     #[tracing_test::traced_test]
     fn test_url_of_git_repository() {
@@ -699,6 +1223,91 @@ mod test {
         let url = "https://github.com/kidanger/invalid-git";
         let r = prepare_git(&git_fetcher, path, url, None, "master");
         dbg!(&r);
-        assert!(r.is_err());
+        assert!(matches!(r, Err(CompilationError::GitUnreachable(_))));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_compilation_demo_not_allowed() {
+        let figment = rocket::Config::figment()
+            .merge(("demo_allowlist", ["t001", "t002"]))
+            .merge(("demo_denylist", ["t002"]));
+        let client = Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+        let request = CompilationRequest {
+            ddl_build: DDLBuild {
+                url: GIT_URL.into(),
+                ssh_fingerprint: None,
+                rev: "69b4dbc2ff9c3102c3b86639ed1ab608a6b5ba79".into(),
+                dockerfile: ".ipol/Dockerfile".into(),
+            },
+            ssh_key: None,
+        };
+        // refused before fetching anything
+        for demo_id in ["t002", "t003"] {
+            let response = client
+                .post(format!("/compilations/{demo_id}"))
+                .header(ContentType::JSON)
+                .body(serde_json::to_string(&request).unwrap())
+                .dispatch();
+            assert_eq!(response.status(), Status::Forbidden);
+            let response: CompilationResponse = response.into_json().unwrap();
+            assert_eq!(response.error_code, CompilationErrorCode::DemoNotAllowed);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_build_locks() {
+        use std::future::Future;
+        use std::time::Duration;
+
+        async fn locked(guard: impl Future<Output = BuildGuard>) -> bool {
+            tokio::time::timeout(Duration::from_millis(100), guard)
+                .await
+                .is_err()
+        }
+        let demo_id = |id: &str| DemoID::try_from(id).unwrap();
+        let (t001, t002, t003) = (demo_id("t001"), demo_id("t002"), demo_id("t003"));
+
+        let locks = BuildLocks::new(0);
+        let guard = locks.lock([&t002, &t001]).await;
+        assert!(locked(locks.lock([&t001])).await);
+        assert!(locked(locks.lock([&t003, &t002])).await);
+        assert!(!locked(locks.lock([&t003])).await);
+        drop(guard);
+        assert!(!locked(locks.lock([&t001, &t002])).await);
+
+        // the demos are locked in the same order whatever the request
+        let locks = Arc::new(BuildLocks::new(0));
+        let tasks = [[&t001, &t002], [&t002, &t001]].map(|demo_ids| {
+            let (locks, demo_ids) = (locks.clone(), demo_ids.map(DemoID::clone));
+            tokio::spawn(async move {
+                for _ in 0..100 {
+                    let _guard = locks.lock(&demo_ids).await;
+                    tokio::task::yield_now().await;
+                }
+            })
+        });
+        for task in tasks {
+            tokio::time::timeout(Duration::from_secs(5), task)
+                .await
+                .expect("no deadlock")
+                .unwrap();
+        }
+
+        // max_concurrent_builds
+        let locks = BuildLocks::new(1);
+        let guard = locks.lock([&t001]).await;
+        assert!(locked(locks.lock([&t002])).await);
+        drop(guard);
+        assert!(!locked(locks.lock([&t002])).await);
+    }
+
+    #[test]
+    fn test_reusable_image() {
+        let labels = HashMap::from([(config::INSTANCE_LABEL.to_string(), "prod".to_string())]);
+        assert!(reusable_image(&labels, "prod"));
+        assert!(!reusable_image(&labels, "staging"));
+        // built before the labels
+        assert!(reusable_image(&HashMap::new(), "prod"));
     }
 }
@@ -1,6 +1,13 @@
+use std::collections::HashMap;
+
 use rocket::serde::Deserialize;
 
-use crate::model::RunParams;
+use crate::model::{ArrayFormat, DemoID, RunParams};
+
+/// Docker label identifying the demorunner instance owning a container or an image.
+pub const INSTANCE_LABEL: &str = "org.ipol.instance";
+/// Docker label holding the key of the run of an execution container.
+pub const KEY_LABEL: &str = "org.ipol.key";
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
@@ -11,16 +18,503 @@ pub struct Config {
     pub user_uid_gid: String,
     #[serde(default = "five_minutes")]
     pub max_timeout: u64,
+    #[serde(default = "one_second")]
+    pub min_timeout: u64,
+    #[serde(default = "five_seconds")]
+    pub estimated_postprocess: u64,
+    #[serde(default = "default_clock_skew_factor")]
+    pub clock_skew_factor: f64,
+    #[serde(default = "five_seconds")]
+    pub clock_skew_offset: u64,
+    #[serde(default)]
+    pub max_memory_mb: Option<u64>,
+    #[serde(default)]
+    pub swap_mb: u64,
+    #[serde(default)]
+    pub cpu_limit: Option<f64>,
+    #[serde(default)]
+    pub cpuset_cpus: Option<String>,
+    #[serde(default)]
+    pub cpu_quota: Option<i64>,
+    #[serde(default)]
+    pub cpu_period: Option<i64>,
+    #[serde(default = "default_pids_limit")]
+    pub pids_limit: u64,
+    #[serde(default = "default_network_mode")]
+    pub network_mode: String,
+    #[serde(default)]
+    pub network_disabled: bool,
+    #[serde(default)]
+    pub readonly_rootfs: bool,
+    #[serde(default)]
+    pub docker_runtime: Option<String>,
+    #[serde(default)]
+    pub shell: Shell,
+    #[serde(default)]
+    pub param_array_format: ArrayFormat,
+    #[serde(default = "default_cap_drop")]
+    pub cap_drop: Vec<String>,
+    #[serde(default)]
+    pub cap_add: Vec<String>,
+    #[serde(default)]
+    pub security_opt: Vec<String>,
+    #[serde(default)]
+    pub extra_binds: Vec<ExtraBind>,
+    #[serde(default)]
+    pub devices: Vec<HostDevice>,
+    #[serde(default)]
+    pub tmpfs: Option<ScratchTmpfs>,
+    #[serde(default)]
+    pub shm_size_mb: Option<u64>,
+    #[serde(default = "one_minute")]
+    pub docker_call_timeout: u64,
+    #[serde(default = "five_seconds")]
+    pub startup_grace: u64,
+    #[serde(default = "five_seconds")]
+    pub stop_grace_seconds: u64,
+    #[serde(default)]
+    pub ulimits: Vec<Ulimit>,
     pub gpus: Vec<String>,
     #[serde(default)]
+    pub gpu_wait: u64,
+    #[serde(default)]
     pub env_vars: RunParams,
     pub registry_url: Option<String>,
+    #[serde(default = "default_instance_id")]
+    pub instance_id: String,
+    #[serde(default)]
+    pub docker_api_version: Option<String>,
+    #[serde(default)]
+    pub docker_min_version: Option<String>,
+    #[serde(default = "default_upload_root")]
+    pub upload_root: String,
+    #[serde(default = "one_day")]
+    pub upload_ttl: u64,
+    #[serde(default = "one_minute")]
+    pub cleanup_timeout: u64,
+    #[serde(default)]
+    pub zip_root: Option<String>,
+    #[serde(default)]
+    pub zip_compression_level: Option<u8>,
+    #[serde(default)]
+    pub max_logfile_bytes: Option<u64>,
+    #[serde(default)]
+    pub logfile_truncation: LogfileTruncation,
+    #[serde(default = "default_disk_space_multiplier")]
+    pub disk_space_multiplier: f64,
+    #[serde(default = "one_hundred_megabytes")]
+    pub disk_space_floor: u64,
+    #[serde(default = "one_minute")]
+    pub retry_hint_default: u64,
+    #[serde(default)]
+    pub max_concurrent_executions: usize,
+    #[serde(default)]
+    pub queue_wait: u64,
+    #[serde(default)]
+    pub max_concurrent_builds: usize,
+    #[serde(default = "default_true")]
+    pub diagnostic_hints: bool,
+    #[serde(default)]
+    pub normalize_filenames: bool,
+    #[serde(default)]
+    pub allow_empty_inputs: bool,
+    #[serde(default)]
+    pub max_input_file_bytes: u64,
+    #[serde(default)]
+    pub max_total_input_bytes: u64,
+    #[serde(default)]
+    pub max_output_bytes: u64,
+    #[serde(default)]
+    pub strict_output: bool,
+    #[serde(default)]
+    pub strict_output_severity: StrictOutputSeverity,
+    #[serde(default = "one_hundred_megabytes")]
+    pub diff_hash_max_bytes: u64,
+    #[serde(default)]
+    pub selinux_relabel: SelinuxRelabel,
+    #[serde(default)]
+    pub numa_balancing: bool,
+    /// Only demos accepted by this instance, all of them when unset.
+    #[serde(default)]
+    pub demo_allowlist: Option<Vec<DemoID>>,
+    /// Demos refused by this instance, even when in the allowlist.
+    #[serde(default)]
+    pub demo_denylist: Vec<DemoID>,
+    #[serde(default)]
+    pub demos: HashMap<String, DemoConfig>,
+}
+
+/// Settings specific to a demo, in a `[demos.<demo_id>]` table.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DemoConfig {
+    /// NUMA node to pin the runs to.
+    pub numa_node: Option<u32>,
+    /// CPUs to pin the runs to, in the cpuset list format (e.g. `0-3,8`).
+    pub cpuset_cpus: Option<String>,
+    /// Memory nodes to pin the runs to, in the cpuset list format.
+    pub cpuset_mems: Option<String>,
+    /// Values of the parameters missing from the requests.
+    #[serde(default)]
+    pub defaults: RunParams,
+    /// User to run as instead of `user_uid_gid`.
+    pub user: Option<DemoUser>,
+    /// Allow `user = "image-default"` for an image configured to run as root.
+    #[serde(default)]
+    pub allow_root: bool,
+    /// Network of the runs instead of `network_mode`.
+    pub network_mode: Option<String>,
+    /// Instead of `readonly_rootfs`, e.g. `false` for a demo writing into its install dir.
+    pub readonly_rootfs: Option<bool>,
+    /// Container runtime of the runs instead of `docker_runtime`.
+    pub docker_runtime: Option<String>,
+    /// Shell running the `ddl_run` instead of `shell`, e.g. for an image without bash.
+    pub shell: Option<Shell>,
+    /// Format of the array parameters instead of `param_array_format`.
+    pub param_array_format: Option<ArrayFormat>,
+    /// Runs of the demo executed at the same time, e.g. 1 for a demo using a whole GPU,
+    /// on top of the global `max_concurrent_executions`.
+    pub max_concurrent_executions: Option<usize>,
+    /// Directories of the host mounted into the runs, in addition to the `extra_binds` of all demos.
+    #[serde(default)]
+    pub extra_binds: Vec<ExtraBind>,
+    /// Devices of the host attached to the runs, in addition to the `devices` of all demos;
+    /// the requests can't ask for them.
+    #[serde(default)]
+    pub devices: Vec<HostDevice>,
+    /// Capabilities given back to the runs, in addition to the `cap_add` of all demos.
+    #[serde(default)]
+    pub cap_add: Vec<String>,
+    /// Security options of the runs, in addition to the `security_opt` of all demos.
+    #[serde(default)]
+    pub security_opt: Vec<String>,
+}
+
+/// A directory or a file of the host mounted into the runs, such as the
+/// weights of a model or a reference dataset.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ExtraBind {
+    pub host_path: String,
+    /// Absolute path in the container, outside of `exec_workdir_in_docker`.
+    pub container_path: String,
+    #[serde(default = "default_true")]
+    pub readonly: bool,
+}
+
+/// A tmpfs mounted into the runs for their intermediate files, which never
+/// reach the disk of the host nor the results.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ScratchTmpfs {
+    /// Absolute path in the container, outside of `exec_workdir_in_docker`.
+    #[serde(default = "default_scratch_path")]
+    pub path: String,
+    pub size_mb: u64,
+}
+
+impl ScratchTmpfs {
+    /// Mount options of the tmpfs, for `HostConfig.tmpfs`.
+    pub fn options(&self) -> String {
+        format!("rw,nosuid,nodev,size={}m", self.size_mb)
+    }
+}
+
+/// A device of the host attached to the runs, like `docker run --device`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct HostDevice {
+    /// Character or block device of the host, e.g. `/dev/video0`.
+    pub path_on_host: String,
+    /// Path of the device in the container, the one of the host by default.
+    pub path_in_container: Option<String>,
+    /// Cgroup permissions of the device, some of `rwm`.
+    #[serde(default = "default_cgroup_permissions")]
+    pub cgroup_permissions: String,
+}
+
+impl HostDevice {
+    pub fn path_in_container(&self) -> &str {
+        self.path_in_container
+            .as_deref()
+            .unwrap_or(&self.path_on_host)
+    }
+}
+
+/// User of the containers of a demo: `"image-default"` for the one configured
+/// in the image, or an explicit `uid:gid`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "String")]
+pub enum DemoUser {
+    ImageDefault,
+    UidGid(String),
+}
+
+impl From<String> for DemoUser {
+    fn from(user: String) -> Self {
+        if user == "image-default" {
+            DemoUser::ImageDefault
+        } else {
+            DemoUser::UidGid(user)
+        }
+    }
+}
+
+/// What to keep of `stdout.txt`/`stderr.txt` once `max_logfile_bytes` is reached.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogfileTruncation {
+    /// Keep the beginning of the log.
+    #[default]
+    Truncate,
+    /// Keep the beginning and the end of the log.
+    HeadTail,
+}
+
+/// What becomes of a run which wrote outside the workdir, with `strict_output`.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StrictOutputSeverity {
+    /// List the paths in the warnings.
+    #[default]
+    Warn,
+    /// Fail the run.
+    Fail,
+}
+
+/// Limit of a resource of the runs, as set by `ulimit`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Ulimit {
+    pub name: UlimitName,
+    pub soft: i64,
+    pub hard: i64,
+}
+
+/// Name of a resource known to docker, such as `nofile`, checked when the config is loaded.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct UlimitName(String);
+
+/// Program and arguments running the `ddl_run` as their last argument,
+/// checked when the config is loaded to have at least the program.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "Vec<String>")]
+pub struct Shell(Vec<String>);
+
+impl Default for Shell {
+    fn default() -> Self {
+        Shell(vec!["/bin/bash".into(), "-c".into()])
+    }
+}
+
+impl TryFrom<Vec<String>> for Shell {
+    type Error = String;
+
+    fn try_from(argv: Vec<String>) -> Result<Self, Self::Error> {
+        if argv.first().is_none_or(String::is_empty) {
+            return Err("the shell needs a program, e.g. [\"/bin/sh\", \"-c\"]".into());
+        }
+        Ok(Shell(argv))
+    }
+}
+
+impl Shell {
+    /// Command running a script with this shell.
+    pub fn command<'a>(&'a self, script: &'a str) -> Vec<&'a str> {
+        let mut command = self.0.iter().map(String::as_str).collect::<Vec<_>>();
+        command.push(script);
+        command
+    }
+}
+
+/// Resources of the `--ulimit` option of docker.
+const ULIMIT_NAMES: &[&str] = &[
+    "core",
+    "cpu",
+    "data",
+    "fsize",
+    "locks",
+    "memlock",
+    "msgqueue",
+    "nice",
+    "nofile",
+    "nproc",
+    "rss",
+    "rtprio",
+    "rttime",
+    "sigpending",
+    "stack",
+];
+
+impl TryFrom<String> for UlimitName {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        if !ULIMIT_NAMES.contains(&name.as_str()) {
+            return Err(format!(
+                "unknown ulimit {name:?}, expected one of {}",
+                ULIMIT_NAMES.join(", ")
+            ));
+        }
+        Ok(UlimitName(name))
+    }
+}
+
+impl AsRef<str> for UlimitName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// SELinux relabeling of the run directory bound into the containers.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SelinuxRelabel {
+    /// No relabeling, for the hosts without SELinux.
+    #[default]
+    Off,
+    /// Relabel with a label shared between the containers (`:z`).
+    Shared,
+    /// Relabel with a label private to the container (`:Z`).
+    Private,
+}
+
+impl SelinuxRelabel {
+    /// Suffix of a docker bind specification.
+    pub fn bind_suffix(self) -> &'static str {
+        match self {
+            SelinuxRelabel::Off => "",
+            SelinuxRelabel::Shared => ":z",
+            SelinuxRelabel::Private => ":Z",
+        }
+    }
 }
 
 const fn five_minutes() -> u64 {
     5 * 60
 }
 
+const fn one_second() -> u64 {
+    1
+}
+
+const fn five_seconds() -> u64 {
+    5
+}
+
+const fn one_minute() -> u64 {
+    60
+}
+
+const fn one_day() -> u64 {
+    24 * 60 * 60
+}
+
+const fn default_true() -> bool {
+    true
+}
+
+const fn default_disk_space_multiplier() -> f64 {
+    3.0
+}
+
+const fn default_clock_skew_factor() -> f64 {
+    2.0
+}
+
+const fn default_pids_limit() -> u64 {
+    2048
+}
+
+fn default_cgroup_permissions() -> String {
+    "rwm".into()
+}
+
+const fn one_hundred_megabytes() -> u64 {
+    100_000_000
+}
+
+fn default_upload_root() -> String {
+    "./uploads/".into()
+}
+
+fn default_scratch_path() -> String {
+    "/scratch".into()
+}
+
+fn default_cap_drop() -> Vec<String> {
+    vec!["ALL".into()]
+}
+
+fn default_network_mode() -> String {
+    "none".into()
+}
+
+fn default_instance_id() -> String {
+    "default".into()
+}
+
+impl Config {
+    /// Settings of a demo, the defaults if it has no table of its own.
+    pub fn demo(&self, demo_id: &DemoID) -> DemoConfig {
+        self.demos
+            .get(demo_id.as_ref())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Network of the runs of a demo.
+    pub fn network_mode(&self, demo_id: &DemoID) -> String {
+        self.demo(demo_id)
+            .network_mode
+            .unwrap_or_else(|| self.network_mode.clone())
+    }
+
+    /// Whether the root filesystem of the runs of a demo is read-only.
+    pub fn readonly_rootfs(&self, demo_id: &DemoID) -> bool {
+        self.demo(demo_id)
+            .readonly_rootfs
+            .unwrap_or(self.readonly_rootfs)
+    }
+
+    /// Container runtime of the runs of a demo, the default one of docker if unset.
+    pub fn docker_runtime(&self, demo_id: &DemoID) -> Option<String> {
+        self.demo(demo_id)
+            .docker_runtime
+            .or_else(|| self.docker_runtime.clone())
+    }
+
+    /// Format of the array parameters in the environment of the runs of a demo.
+    pub fn param_array_format(&self, demo_id: &DemoID) -> ArrayFormat {
+        self.demo(demo_id)
+            .param_array_format
+            .unwrap_or(self.param_array_format)
+    }
+
+    /// Shell running the `ddl_run` of a demo.
+    pub fn shell(&self, demo_id: &DemoID) -> Shell {
+        self.demo(demo_id)
+            .shell
+            .unwrap_or_else(|| self.shell.clone())
+    }
+
+    /// Whether this instance runs and builds a demo, per the allowlist and
+    /// the denylist.
+    pub fn demo_allowed(&self, demo_id: &DemoID) -> bool {
+        let allowed = self
+            .demo_allowlist
+            .as_ref()
+            .is_none_or(|allowlist| allowlist.contains(demo_id));
+        allowed && !self.demo_denylist.contains(demo_id)
+    }
+
+    /// Docker `label` filter matching the containers and images of this instance.
+    pub fn instance_label_filter(&self) -> String {
+        format!("{}={}", INSTANCE_LABEL, self.instance_id)
+    }
+}
+
+#[cfg(test)]
+pub fn test_config() -> Config {
+    rocket::Config::figment().extract().unwrap()
+}
+
 pub fn load_rocket_config() -> rocket::fairing::AdHoc {
     rocket::fairing::AdHoc::config::<Config>()
 }
@@ -0,0 +1,249 @@
+use std::cmp::Ordering;
+use std::sync::OnceLock;
+
+use bollard::{ClientVersion, Docker};
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/// Socket of the docker daemon, unless `DOCKER_HOST` is set.
+const DEFAULT_SOCKET: &str = "unix:///var/run/docker.sock";
+
+/// Timeout of the requests to the docker daemon, in seconds.
+const DEFAULT_TIMEOUT: u64 = 120;
+
+/// API version negotiated with the daemon at startup, when not pinned by the config.
+static NEGOTIATED_API_VERSION: OnceLock<ClientVersion> = OnceLock::new();
+
+/// Parses a docker API version such as `1.43`.
+pub fn parse_api_version(version: &str) -> Option<ClientVersion> {
+    let (major, minor) = version.trim().split_once('.')?;
+    Some(ClientVersion {
+        major_version: major.parse().ok()?,
+        minor_version: minor.parse().ok()?,
+    })
+}
+
+fn format_api_version(version: &ClientVersion) -> String {
+    format!("{}.{}", version.major_version, version.minor_version)
+}
+
+/// Compares dotted versions such as `24.0.7` numerically, ignoring suffixes
+/// like `-ce` and treating the missing components as 0.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let components = |version: &str| -> Vec<u64> {
+        version
+            .split('.')
+            .map(|component| {
+                let digits = component
+                    .chars()
+                    .take_while(char::is_ascii_digit)
+                    .collect::<String>();
+                digits.parse().unwrap_or(0)
+            })
+            .collect()
+    };
+    let (a, b) = (components(a), components(b));
+    for i in 0..a.len().max(b.len()) {
+        let ordering = a.get(i).unwrap_or(&0).cmp(b.get(i).unwrap_or(&0));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Connects to the docker daemon with the API version of the config, or
+/// the one negotiated at startup.
+pub fn connect(config: &config::Config) -> Result<Docker, bollard::errors::Error> {
+    let pinned = config
+        .docker_api_version
+        .as_deref()
+        .and_then(parse_api_version);
+    let Some(version) = pinned.or_else(|| NEGOTIATED_API_VERSION.get().cloned()) else {
+        return Docker::connect_with_local_defaults();
+    };
+    let host = std::env::var("DOCKER_HOST").unwrap_or_else(|_| DEFAULT_SOCKET.into());
+    Docker::connect_with_local(&host, DEFAULT_TIMEOUT, &version)
+}
+
+/// Whether the daemon rejected a call because of the API version, with its message.
+pub fn version_mismatch(err: &bollard::errors::Error) -> Option<&str> {
+    match err {
+        bollard::errors::Error::DockerResponseServerError {
+            status_code: 400,
+            message,
+        } if message.contains("API version") || message.contains("client version") => Some(message),
+        _ => None,
+    }
+}
+
+/// Whether the daemon rejected a container because of its `runtime`, which
+/// it doesn't know (a 400 or a 500 depending on the docker version).
+pub fn unknown_runtime(err: &bollard::errors::Error) -> bool {
+    matches!(
+        err,
+        bollard::errors::Error::DockerResponseServerError { message, .. }
+            if message.contains("unknown or invalid runtime name")
+    )
+}
+
+/// What is known of the docker daemon, reported by `/health`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct DaemonStatus {
+    /// API version used to talk to the daemon.
+    pub api_version: Option<String>,
+    /// Version of the docker engine.
+    pub daemon_version: Option<String>,
+    /// `docker_min_version` of the config.
+    pub min_version: Option<String>,
+    /// Whether the daemon is older than `min_version`.
+    pub outdated: bool,
+    /// Why the daemon could not be reached.
+    pub error: Option<String>,
+}
+
+impl DaemonStatus {
+    fn new(config: &config::Config, api_version: String, daemon_version: Option<String>) -> Self {
+        let min_version = config.docker_min_version.clone();
+        let outdated = match (&daemon_version, &min_version) {
+            (Some(daemon), Some(min)) => compare_versions(daemon, min) == Ordering::Less,
+            _ => false,
+        };
+        DaemonStatus {
+            api_version: Some(api_version),
+            daemon_version,
+            min_version,
+            outdated,
+            error: None,
+        }
+    }
+}
+
+async fn check(config: &config::Config) -> Result<DaemonStatus, bollard::errors::Error> {
+    let docker = match config.docker_api_version.as_deref() {
+        Some(_) => connect(config)?,
+        None => {
+            let docker = Docker::connect_with_local_defaults()?;
+            let docker = docker.negotiate_version().await?;
+            NEGOTIATED_API_VERSION.get_or_init(|| docker.client_version());
+            docker
+        }
+    };
+    let version = docker.version().await?;
+    let api_version = format_api_version(&docker.client_version());
+    Ok(DaemonStatus::new(config, api_version, version.version))
+}
+
+/// Negotiates the docker API version at startup and reports the daemon version,
+/// warning when it is older than `docker_min_version`.
+pub fn daemon_check() -> rocket::fairing::AdHoc {
+    rocket::fairing::AdHoc::try_on_ignite("Docker daemon check", |rocket| {
+        Box::pin(async move {
+            let Some(config) = rocket.state::<config::Config>() else {
+                return Ok(rocket);
+            };
+            if let Some(version) = &config.docker_api_version {
+                if parse_api_version(version).is_none() {
+                    tracing::error!(
+                        "invalid docker_api_version {version:?}, expected e.g. \"1.43\""
+                    );
+                    return Err(rocket);
+                }
+            }
+            let status = match check(config).await {
+                Ok(status) => {
+                    tracing::info!(
+                        "docker daemon {:?}, API version {:?}",
+                        status.daemon_version,
+                        status.api_version
+                    );
+                    if status.outdated {
+                        tracing::warn!(
+                            "the docker daemon {:?} is older than docker_min_version {:?}",
+                            status.daemon_version,
+                            status.min_version
+                        );
+                    }
+                    status
+                }
+                Err(err) => {
+                    tracing::warn!("couldn't check the docker daemon: {err}");
+                    DaemonStatus {
+                        min_version: config.docker_min_version.clone(),
+                        error: Some(err.to_string()),
+                        ..Default::default()
+                    }
+                }
+            };
+            Ok(rocket.manage(status))
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_api_version() {
+        let version = parse_api_version("1.43").unwrap();
+        assert_eq!((version.major_version, version.minor_version), (1, 43));
+        assert_eq!(format_api_version(&version), "1.43");
+        assert!(parse_api_version("1").is_none());
+        assert!(parse_api_version("v1.43").is_none());
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("24.0.7", "24.0"), Ordering::Greater);
+        assert_eq!(compare_versions("20.10.24", "24.0"), Ordering::Less);
+        assert_eq!(compare_versions("24.0", "24.0.0"), Ordering::Equal);
+        assert_eq!(
+            compare_versions("19.03.15-ce", "19.03.2"),
+            Ordering::Greater
+        );
+    }
+
+    #[test]
+    fn test_daemon_status_outdated() {
+        let mut config = config::test_config();
+        let status = DaemonStatus::new(&config, "1.43".into(), Some("20.10.24".into()));
+        assert!(!status.outdated);
+
+        config.docker_min_version = Some("24.0".into());
+        let status = DaemonStatus::new(&config, "1.43".into(), Some("20.10.24".into()));
+        assert!(status.outdated);
+        assert_eq!(status.min_version, Some("24.0".into()));
+        let status = DaemonStatus::new(&config, "1.47".into(), Some("27.3.1".into()));
+        assert!(!status.outdated);
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let too_new = bollard::errors::Error::DockerResponseServerError {
+            status_code: 400,
+            message: "client version 1.47 is too new. Maximum supported API version is 1.43".into(),
+        };
+        assert!(version_mismatch(&too_new).unwrap().contains("1.47"));
+        let missing = bollard::errors::Error::DockerResponseServerError {
+            status_code: 404,
+            message: "No such container: x".into(),
+        };
+        assert_eq!(version_mismatch(&missing), None);
+    }
+
+    #[test]
+    fn test_unknown_runtime() {
+        let unknown = bollard::errors::Error::DockerResponseServerError {
+            status_code: 500,
+            message: "unknown or invalid runtime name: kata".into(),
+        };
+        assert!(unknown_runtime(&unknown));
+        let missing = bollard::errors::Error::DockerResponseServerError {
+            status_code: 404,
+            message: "No such image: ipol/t001".into(),
+        };
+        assert!(!unknown_runtime(&missing));
+    }
+}
@@ -0,0 +1,224 @@
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use bollard::models::DeviceMapping;
+
+use crate::config;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeviceError {
+    #[error("{}{path:?} is not a character or block device", prefix(demo_id))]
+    NotADevice {
+        demo_id: Option<String>,
+        path: String,
+    },
+    #[error("{}{path:?}: {err}", prefix(demo_id))]
+    Missing {
+        demo_id: Option<String>,
+        path: String,
+        err: std::io::Error,
+    },
+    #[error(
+        "{}invalid cgroup permissions {permissions:?} for {path:?}, expected some of rwm",
+        prefix(demo_id)
+    )]
+    InvalidPermissions {
+        demo_id: Option<String>,
+        path: String,
+        permissions: String,
+    },
+}
+
+fn prefix(demo_id: &Option<String>) -> String {
+    match demo_id {
+        Some(demo_id) => format!("demo {demo_id}: "),
+        None => "devices: ".into(),
+    }
+}
+
+/// Checks that `devices` are character or block devices of the host, with
+/// valid cgroup permissions.
+fn check(demo_id: Option<&str>, devices: &[config::HostDevice]) -> Result<(), DeviceError> {
+    for device in devices {
+        let path = &device.path_on_host;
+        let permissions = &device.cgroup_permissions;
+        if permissions.is_empty() || !permissions.chars().all(|c| "rwm".contains(c)) {
+            return Err(DeviceError::InvalidPermissions {
+                demo_id: demo_id.map(str::to_string),
+                path: path.clone(),
+                permissions: permissions.clone(),
+            });
+        }
+        let file_type = std::fs::metadata(Path::new(path))
+            .map_err(|err| DeviceError::Missing {
+                demo_id: demo_id.map(str::to_string),
+                path: path.clone(),
+                err,
+            })?
+            .file_type();
+        if !file_type.is_char_device() && !file_type.is_block_device() {
+            return Err(DeviceError::NotADevice {
+                demo_id: demo_id.map(str::to_string),
+                path: path.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Checks the `devices` of the config and of the demo configs.
+pub fn validate(config: &config::Config) -> Result<(), DeviceError> {
+    check(None, &config.devices)?;
+    for (demo_id, demo) in &config.demos {
+        check(Some(demo_id), &demo.devices)?;
+    }
+    Ok(())
+}
+
+/// Devices of the host attached to the runs of a demo.
+pub fn devices<'c>(
+    config: &'c config::Config,
+    demo: &'c config::DemoConfig,
+) -> impl Iterator<Item = &'c config::HostDevice> {
+    config.devices.iter().chain(&demo.devices)
+}
+
+/// Devices of the host given to the runs of a demo, for `HostConfig.devices`,
+/// checked again as a device may have been unplugged since the startup.
+pub fn device_mappings(
+    config: &config::Config,
+    demo: &config::DemoConfig,
+) -> Result<Option<Vec<DeviceMapping>>, DeviceError> {
+    let devices = devices(config, demo).cloned().collect::<Vec<_>>();
+    if devices.is_empty() {
+        return Ok(None);
+    }
+    check(None, &devices)?;
+    let mappings = devices.iter().map(|device| DeviceMapping {
+        path_on_host: Some(device.path_on_host.clone()),
+        path_in_container: Some(device.path_in_container().to_string()),
+        cgroup_permissions: Some(device.cgroup_permissions.clone()),
+    });
+    Ok(Some(mappings.collect()))
+}
+
+/// Refuses to start with devices which can't be attached.
+pub fn device_check() -> rocket::fairing::AdHoc {
+    rocket::fairing::AdHoc::try_on_ignite("Device check", |rocket| {
+        Box::pin(async move {
+            if let Some(config) = rocket.state::<config::Config>() {
+                if let Err(err) = validate(config) {
+                    tracing::error!("{err}");
+                    return Err(rocket);
+                }
+            }
+            Ok(rocket)
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::HostDevice;
+
+    fn config_with(device: HostDevice) -> config::Config {
+        let mut config = config::test_config();
+        let demo = config::DemoConfig {
+            devices: vec![device],
+            ..Default::default()
+        };
+        config.demos.insert("t001".into(), demo);
+        config
+    }
+
+    #[test]
+    fn test_validate() {
+        let null = HostDevice {
+            path_on_host: "/dev/null".into(),
+            path_in_container: None,
+            cgroup_permissions: "rw".into(),
+        };
+        assert!(validate(&config_with(null.clone())).is_ok());
+
+        let missing = HostDevice {
+            path_on_host: "/dev/video-missing".into(),
+            ..null.clone()
+        };
+        let err = validate(&config_with(missing)).unwrap_err();
+        assert!(matches!(err, DeviceError::Missing { .. }));
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap().to_string();
+        let file = HostDevice {
+            path_on_host: path.clone(),
+            ..null.clone()
+        };
+        let err = validate(&config_with(file)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("demo t001: {path:?} is not a character or block device")
+        );
+        let permissions = HostDevice {
+            cgroup_permissions: "rwx".into(),
+            ..null
+        };
+        let err = validate(&config_with(permissions)).unwrap_err();
+        assert!(matches!(err, DeviceError::InvalidPermissions { .. }));
+
+        let mut config = config::test_config();
+        config.devices = vec![HostDevice {
+            path_on_host: "/dev/dri/renderD-missing".into(),
+            path_in_container: None,
+            cgroup_permissions: "rw".into(),
+        }];
+        let err = validate(&config).unwrap_err();
+        assert!(matches!(err, DeviceError::Missing { demo_id: None, .. }));
+    }
+
+    #[test]
+    fn test_device_mappings() {
+        let mut config = config::test_config();
+        let none = device_mappings(&config, &config::DemoConfig::default()).unwrap();
+        assert_eq!(none, None);
+
+        let device = HostDevice {
+            path_on_host: "/dev/null".into(),
+            path_in_container: Some("/dev/capture".into()),
+            cgroup_permissions: "r".into(),
+        };
+        config.devices = vec![HostDevice {
+            path_on_host: "/dev/zero".into(),
+            path_in_container: None,
+            cgroup_permissions: "rwm".into(),
+        }];
+        let demo = config::DemoConfig {
+            devices: vec![device],
+            ..Default::default()
+        };
+        let mappings = device_mappings(&config, &demo).unwrap().unwrap();
+        assert_eq!(
+            mappings,
+            vec![
+                DeviceMapping {
+                    path_on_host: Some("/dev/zero".into()),
+                    path_in_container: Some("/dev/zero".into()),
+                    cgroup_permissions: Some("rwm".into()),
+                },
+                DeviceMapping {
+                    path_on_host: Some("/dev/null".into()),
+                    path_in_container: Some("/dev/capture".into()),
+                    cgroup_permissions: Some("r".into()),
+                }
+            ]
+        );
+
+        // unplugged since the startup
+        config.devices[0].path_on_host = "/dev/dri/renderD-missing".into();
+        let err = device_mappings(&config, &demo).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("devices: \"/dev/dri/renderD-missing\": "),
+            "{err}"
+        );
+    }
+}
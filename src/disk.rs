@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+/// Disk space reserved by the runs in progress, so that simultaneous runs
+/// cannot collectively exceed the free space of the node.
+pub struct DiskReservations {
+    reserved: Arc<Mutex<Reserved>>,
+    available_space: fn(&Path) -> std::io::Result<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Reserved {
+    bytes: u64,
+    /// Bytes and expected end of each run, by reservation.
+    runs: HashMap<u64, (u64, Instant)>,
+    next_id: u64,
+}
+
+impl Default for DiskReservations {
+    fn default() -> Self {
+        Self {
+            reserved: Arc::default(),
+            available_space: fs2::available_space::<&Path>,
+        }
+    }
+}
+
+/// Space reserved for a run, released when dropped.
+#[derive(Debug)]
+pub struct DiskReservation {
+    reserved: Arc<Mutex<Reserved>>,
+    id: u64,
+    bytes: u64,
+}
+
+impl Drop for DiskReservation {
+    fn drop(&mut self) {
+        let mut reserved = self.reserved.lock().unwrap();
+        reserved.bytes = reserved.bytes.saturating_sub(self.bytes);
+        reserved.runs.remove(&self.id);
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DiskError {
+    /// With the time until the runs in progress are expected to release
+    /// enough space, if they can.
+    #[error("IPOLNodeDiskFull: {required} bytes required, {available} bytes available")]
+    Full {
+        required: u64,
+        available: u64,
+        retry_hint: Option<Duration>,
+    },
+    #[error("io: {0}")]
+    IO(#[from] std::io::Error),
+}
+
+/// Time until the runs, ending by their expected end, release `missing` bytes,
+/// or `None` if they don't hold that much.
+fn retry_hint(
+    now: Instant,
+    runs: impl Iterator<Item = (u64, Instant)>,
+    missing: u64,
+) -> Option<Duration> {
+    let mut runs = runs.collect::<Vec<_>>();
+    runs.sort_by_key(|&(_, end)| end);
+    let mut released = 0u64;
+    for (bytes, end) in runs {
+        released = released.saturating_add(bytes);
+        if released >= missing {
+            return Some(end.saturating_duration_since(now));
+        }
+    }
+    None
+}
+
+/// Estimates the space needed by a run from the size of its inputs.
+pub fn estimate_required_space(config: &config::Config, inputs_size: u64) -> u64 {
+    ((inputs_size as f64 * config.disk_space_multiplier) as u64)
+        .saturating_add(config.disk_space_floor)
+}
+
+impl DiskReservations {
+    #[cfg(test)]
+    fn with_available_space(available_space: fn(&Path) -> std::io::Result<u64>) -> Self {
+        Self {
+            available_space,
+            ..Default::default()
+        }
+    }
+
+    pub fn reserved(&self) -> u64 {
+        self.reserved.lock().unwrap().bytes
+    }
+
+    /// Free space on the filesystem of `path`, not counting the reservations.
+    pub fn available(&self, path: &Path) -> std::io::Result<u64> {
+        let free = (self.available_space)(path)?;
+        Ok(free.saturating_sub(self.reserved()))
+    }
+
+    /// Reserves space for a run expected to end by `expected_end`.
+    pub fn reserve(
+        &self,
+        path: &Path,
+        bytes: u64,
+        expected_end: Instant,
+    ) -> Result<DiskReservation, DiskError> {
+        let free = (self.available_space)(path)?;
+        let mut reserved = self.reserved.lock().unwrap();
+        let available = free.saturating_sub(reserved.bytes);
+        if bytes > available {
+            let runs = reserved.runs.values().copied();
+            return Err(DiskError::Full {
+                required: bytes,
+                available,
+                retry_hint: retry_hint(Instant::now(), runs, bytes - available),
+            });
+        }
+        let id = reserved.next_id;
+        reserved.next_id += 1;
+        reserved.bytes += bytes;
+        reserved.runs.insert(id, (bytes, expected_end));
+        Ok(DiskReservation {
+            reserved: self.reserved.clone(),
+            id,
+            bytes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn one_kilobyte(_: &Path) -> std::io::Result<u64> {
+        Ok(1000)
+    }
+
+    #[test]
+    fn test_reservations() {
+        let reservations = DiskReservations::with_available_space(one_kilobyte);
+        let path = Path::new("/");
+
+        let end = Instant::now() + Duration::from_secs(60);
+
+        let first = reservations.reserve(path, 600, end).unwrap();
+        assert_eq!(reservations.reserved(), 600);
+        assert_eq!(reservations.available(path).unwrap(), 400);
+        let Err(DiskError::Full {
+            required: 600,
+            available: 400,
+            retry_hint: Some(retry_hint),
+        }) = reservations.reserve(path, 600, end)
+        else {
+            panic!("the second reservation should fail");
+        };
+        assert!(retry_hint > Duration::from_secs(50) && retry_hint <= Duration::from_secs(60));
+        assert!(matches!(
+            reservations.reserve(path, 2000, end),
+            Err(DiskError::Full {
+                retry_hint: None,
+                ..
+            })
+        ));
+
+        drop(first);
+        assert_eq!(reservations.reserved(), 0);
+        assert!(reservations.reserve(path, 600, end).is_ok());
+        assert_eq!(reservations.reserved(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_reservations() {
+        let reservations = DiskReservations::with_available_space(one_kilobyte);
+        let path = Path::new("/");
+
+        let admitted = std::thread::scope(|s| {
+            let handles = (0..10)
+                .map(|_| s.spawn(|| reservations.reserve(path, 300, Instant::now()).ok()))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .filter_map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(admitted.len(), 3);
+        assert_eq!(reservations.reserved(), 900);
+
+        drop(admitted);
+        assert_eq!(reservations.reserved(), 0);
+    }
+
+    #[test]
+    fn test_retry_hint() {
+        let now = Instant::now();
+        let after = |secs| now + Duration::from_secs(secs);
+        let runs = [(100, after(30)), (300, after(10)), (200, after(20))];
+
+        assert_eq!(
+            retry_hint(now, runs.into_iter(), 250),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            retry_hint(now, runs.into_iter(), 400),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            retry_hint(now, runs.into_iter(), 450),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(retry_hint(now, runs.into_iter(), 601), None);
+        assert_eq!(retry_hint(now, std::iter::empty(), 1), None);
+        // a run past its expected end
+        let late = [(100, now)];
+        assert_eq!(
+            retry_hint(after(5), late.into_iter(), 50),
+            Some(Duration::ZERO)
+        );
+    }
+}
@@ -1,28 +1,67 @@
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
-use bollard::models::DeviceRequest;
+use bollard::models::{ContainerStateStatusEnum, DeviceRequest, ResourcesUlimits};
 use bollard::service::HostConfig;
+use rocket::http::uri::fmt::{Formatter, Query, UriDisplay};
 use rocket::response::Responder;
 
 use rocket::serde::{Deserialize, Serialize};
 use rocket::tokio::fs;
 use rocket::tokio::io::AsyncWriteExt;
+use rocket::tokio::sync::mpsc::{self, error::TrySendError};
+use rocket::tokio::task::JoinHandle;
 use rocket::tokio::time::error::Elapsed;
 use rocket::tokio::time::{timeout_at, Instant};
+use schemars::JsonSchema;
 
 use bollard::container::{
-    Config, CreateContainerOptions, InspectContainerOptions, LogOutput, LogsOptions,
-    RemoveContainerOptions,
+    AttachContainerOptions, AttachContainerResults, Config, CreateContainerOptions,
+    InspectContainerOptions, ListContainersOptions, LogOutput, LogsOptions, RemoveContainerOptions,
+    StopContainerOptions,
 };
 use bollard::Docker;
 
-use futures_util::stream::StreamExt;
+use futures_util::stream::{Stream, StreamExt};
 
+use crate::binds::{self, BindError};
 use crate::compilation::get_git_revision;
 use crate::config;
+use crate::daemon;
+use crate::devices::{self, DeviceError};
+use crate::disk::{estimate_required_space, DiskError, DiskReservation, DiskReservations};
+use crate::gpus::{self, GpuAllocator, GpuError, GpuSelection};
 use crate::model::*;
+use crate::names::{self, NameError};
+use crate::numa::{Cpuset, NumaAssignments};
+use crate::upload::{UploadError, UploadSessions};
+
+mod adjustments;
+mod criteria;
+mod diagnostics;
+mod filenames;
+mod logfile;
+pub mod logs;
+mod manifest;
+mod resources;
+mod snapshot;
+mod steps;
+mod timing;
+pub mod validate;
+
+use adjustments::{Adjustment, AdjustmentReason, Adjustments};
+use criteria::{CriteriaError, SuccessCriteria};
+use diagnostics::diagnostic_hints;
+use filenames::FilenameNormalizer;
+use logfile::CappedLogFile;
+use resources::Resources;
+use snapshot::{OutputMode, Snapshot};
+use steps::StepStatus;
+use timing::{timed_call, ApiTimeout, StartClock, StartLatency, Timing};
 
 #[derive(Debug)]
 pub struct ExecAndWaitRequest<'a, 'b> {
@@ -31,41 +70,242 @@ pub struct ExecAndWaitRequest<'a, 'b> {
     params: RunParams,
     ddl_run: DDLRun,
     timeout: Option<u64>,
+    options: ExecAndWaitOptions,
     inputs: &'b mut [rocket::fs::TempFile<'a>],
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-struct AlgoInfo {
+/// Optional query fields of an execution request.
+#[derive(Debug, Clone, Default, FromForm, UriDisplayQuery)]
+pub struct ExecAndWaitOptions {
+    zip_root: Option<String>,
+    /// Completed upload sessions to use as inputs, in addition to the uploaded files.
+    upload_ids: Vec<String>,
+    /// Write the environment of the container into `environment.txt`.
+    debug_env: bool,
+    /// RFC3339 timestamp by which the results are expected, as an alternative to `timeout`.
+    deadline: Option<String>,
+    /// Checks of the results of a run which exited with 0.
+    success_criteria: SuccessCriteria,
+    /// Rename the result files which can't be extracted on Windows, overriding the config.
+    normalize_filenames: Option<bool>,
+    /// `full` (default) or `diff`, to only zip the files added or modified by the run.
+    output_mode: Option<OutputMode>,
+    /// JSON map from the exit codes of the demo to the messages shown to the users,
+    /// e.g. `{"3": "the image is too dark"}`.
+    exit_code_messages: Option<String>,
+    /// List the files written outside the workdir, overriding the config.
+    strict_output: Option<bool>,
+    /// Memory limit of the run in MB, at most `max_memory_mb`.
+    memory: Option<u64>,
+    /// Number of CPUs of the run (fractional), at most `cpu_limit`.
+    cpus: Option<f64>,
+    /// Accept an empty `ddl_run`, to get the inputs back.
+    allow_empty_run: bool,
+    /// Size of `/dev/shm` in MB, at most `shm_size_mb`.
+    shm_size: Option<u64>,
+    /// `zip` (default) or `tar`.
+    output_format: Option<OutputFormat>,
+    /// Relative weight of the run when the CPUs are contended (1024 by default).
+    cpu_shares: Option<i64>,
+    /// GPUs of the run among the configured ones, as a count or `device=<ids>`,
+    /// any free one by default.
+    gpus: Option<GpuSelection>,
+    /// Refused: the devices of a run are only attached by the config of its demo.
+    devices: Option<String>,
+    /// Disable the networking of the container; `false` is refused when the config disables it.
+    network_disabled: Option<bool>,
+    /// Write `manifest.json`, listing the result files with their sizes and content types.
+    manifest: bool,
+    /// From 1 to 10 (5 by default), the waiting runs of higher priority getting
+    /// the free execution slots first.
+    priority: Option<u8>,
+    /// JSON array of the arguments of the run, such as `["/bin/demo", "-v"]`, executed
+    /// without a shell (no globbing, no redirection) instead of `ddl_run`.
+    ddl_run_exec: Option<String>,
+    /// Text written to the standard input of the run, which then reads an end of file.
+    stdin: Option<String>,
+    /// Uploaded file written to the standard input of the run, instead of `stdin`.
+    stdin_file: Option<String>,
+}
+
+/// Information about the run of the algorithm.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct AlgoInfo {
+    /// Why the algorithm failed.
     #[serde(skip_serializing_if = "Option::is_none")]
     error_message: Option<String>,
+    /// Run time of the algorithm, in seconds.
     #[serde(skip_serializing_if = "Option::is_none")]
     run_time: Option<f64>,
+    /// End of the output, when the error message comes from `exit_code_messages`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    /// Exit codes and run times of the steps which ended, when `ddl_run` is a list of commands.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    steps: Vec<StepStatus>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+/// Summary of an execution, saved as `exec_info.json` in the results.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
 pub struct ExecInfo {
+    /// Key of the run.
+    #[schemars(with = "String")]
     key: RunKey,
+    /// Parameters of the run.
     params: RunParams,
+    /// `OK` or `KO`.
     status: String,
+    /// Why the execution failed.
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
+    /// Information about the run of the algorithm.
     algo_info: AlgoInfo,
+    /// Issues which did not prevent the execution.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+    /// CPUs the run was pinned to, in the cpuset list format.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpuset: Option<String>,
+    /// Parameters missing from the request, taken from the defaults of the demo.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    defaults_applied: Vec<String>,
+    /// User configured in the image, when the demo runs as the image default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image_user: Option<String>,
+    /// Values of the request which were changed before the run.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    adjustments: Vec<Adjustment>,
+    /// Number of the uploaded files accepted, for the client to check that none was dropped.
+    #[serde(default)]
+    inputs_accepted: usize,
+    /// Files written by the run outside the workdir, with `strict_output`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outside_changes: Option<Vec<String>>,
+    /// Size of `/dev/shm` in the run, in MB, unless left to docker.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shm_size_mb: Option<u64>,
+    /// Seconds after which a refused run may be retried, also sent as `Retry-After`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_hint_seconds: Option<u64>,
+    /// Device ids of the GPUs assigned to the run.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    gpus: Vec<String>,
+    /// Devices of the host attached to the run, by their paths in the container.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    devices: Vec<String>,
+}
+
+/// What is learnt during an execution, whether it succeeds or not.
+#[derive(Debug, Default)]
+struct ExecReport {
+    warnings: Vec<String>,
+    /// Disk space held until the run directory is removed.
+    disk_reservation: Option<DiskReservation>,
+    cpuset: Option<String>,
+    defaults_applied: Vec<String>,
+    image_user: Option<String>,
+    adjustments: Adjustments,
+    /// Files of the run directory before the run, for `output_mode=diff`.
+    snapshot: Option<Snapshot>,
+    inputs_accepted: usize,
+    outside_changes: Option<Vec<String>>,
+    shm_size_mb: Option<u64>,
+    /// Time spent waiting on the docker daemon.
+    docker_api_time: Duration,
+    retry_hint_seconds: Option<u64>,
+    gpus: Vec<String>,
+    devices: Vec<String>,
+    /// Resources sampled during the run.
+    resources: Resources,
+    steps: Vec<StepStatus>,
+    start_clock: StartClock,
+    /// Set once the container started.
+    start_latency: Option<StartLatency>,
 }
 
 #[derive(Debug, thiserror::Error)]
 enum ExecError {
     #[error("Non-zero exit code ({0}): {1}")]
     NonZeroExitCode(i64, String),
+    #[error("Non-zero exit code ({code}) at step {step}: {output}")]
+    StepFailed {
+        step: usize,
+        code: i64,
+        output: String,
+    },
+    #[error("{code}: {message}")]
+    AlgorithmReported {
+        code: i64,
+        message: String,
+        detail: String,
+    },
+    #[error("invalid exit_code_messages: {0}")]
+    InvalidExitCodeMessages(String),
+    #[error("invalid ddl_run_exec: {0}")]
+    InvalidRunExec(String),
+    #[error("invalid stdin_file: {0:?} is not an uploaded file of the run")]
+    InvalidStdinFile(String),
+    #[error("{0}")]
+    Name(#[from] NameError),
     #[error("{0}")]
     IO(#[from] std::io::Error),
     #[error("{0}")]
-    Docker(#[from] bollard::errors::Error),
+    Docker(bollard::errors::Error),
+    #[error("IPOLDockerVersionMismatch: {0}")]
+    DockerVersion(String),
+    #[error("IPOLMemoryError: the run exceeded its memory limit of {0} MB")]
+    OutOfMemory(u64),
+    #[error("IPOLMemoryError: the run was killed for lack of memory")]
+    OomKilled,
+    #[error("IPOLPidsLimit: the run was stopped for reaching its limit of {0} processes")]
+    PidsLimit(u64),
+    #[error("IPOLCancelled: the run was cancelled")]
+    Cancelled,
+    #[error("IPOLDockerApiTimeout: {0}")]
+    DockerApiTimeout(#[from] ApiTimeout),
     #[error("IPOLTimeoutError: Execution timeout")]
     Timeout(#[from] Elapsed),
+    #[error("IPOLTimeoutError: the container didn't start before the timeout")]
+    StartupTimeout,
+    #[error("IPOLDeadlineExceeded: The deadline cannot be met")]
+    DeadlineExceeded,
+    #[error("Invalid deadline: {0}")]
+    InvalidDeadline(#[from] chrono::ParseError),
     #[error("zip: {0}")]
     Zip(#[from] zip::result::ZipError),
     #[error("ipol-demorunner/exec/git: {0}")]
     Git(#[from] git2::Error),
+    #[error("ipol-demorunner/exec/upload: {0}")]
+    Upload(#[from] UploadError),
+    #[error("{0}")]
+    Disk(#[from] DiskError),
+    #[error("{0}")]
+    Gpu(#[from] GpuError),
+    #[error("{0}")]
+    Bind(#[from] BindError),
+    #[error("{0}")]
+    Device(#[from] DeviceError),
+    #[error("IPOLInputTooLarge: {0}")]
+    InputTooLarge(String),
+    #[error("IPOLOutputTooLarge: the results are {0} bytes, above max_output_bytes")]
+    OutputTooLarge(u64),
+    #[error("{0}")]
+    SuccessCriteria(#[from] CriteriaError),
+    #[error("the run wrote outside the workdir: {}", .0.join(", "))]
+    StrictOutput(Vec<String>),
+    #[error("the image runs as root ({0:?}) but the demo doesn't set allow_root")]
+    RootImageUser(String),
+    #[error("IPOLRuntimeUnavailable: runtime {0} not available")]
+    RuntimeUnavailable(String),
+}
+
+impl From<bollard::errors::Error> for ExecError {
+    fn from(err: bollard::errors::Error) -> Self {
+        match daemon::version_mismatch(&err) {
+            Some(message) => ExecError::DockerVersion(message.to_string()),
+            None => ExecError::Docker(err),
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -78,25 +318,197 @@ pub enum ExecAndWaitInternalError {
     Zip(#[from] zip::result::ZipError),
     #[error("json: {0}")]
     Json(#[from] serde_json::error::Error),
+    #[error("invalid input: {0}")]
+    Input(#[from] InputError),
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("invalid parameters: {}", describe_param_errors(.0))]
+    InvalidParams(Vec<ParamError>),
+    #[error("the demo {0} is not run by this instance")]
+    DemoNotAllowed(DemoID),
+    /// A failure of the run which doesn't give results to send.
+    #[error("{0}")]
+    Exec(ExecError),
+    #[error("{max} executions{} already in progress", of_demo(demo_id))]
+    TooManyExecutions {
+        /// Set when the limit is the one of the demo.
+        demo_id: Option<String>,
+        max: usize,
+        retry_after: u64,
+    },
+}
+
+fn describe_param_errors(errors: &[ParamError]) -> String {
+    errors
+        .iter()
+        .map(|error| format!("{}: {}", error.field, error.message))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn of_demo(demo_id: &Option<String>) -> String {
+    demo_id
+        .as_ref()
+        .map_or(String::new(), |demo_id| format!(" of demo {demo_id}"))
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error_code: &'static str,
+    detail: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<ParamError>,
+    /// Seconds after which a refused run may be retried, as in `Retry-After`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_hint_seconds: Option<u64>,
 }
 
 impl<'r> Responder<'r, 'static> for ExecAndWaitInternalError {
     fn respond_to(self, req: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        let json_error = match &self {
+            ExecAndWaitInternalError::InvalidRequest(_) => {
+                Some(("invalid_request", rocket::http::Status::UnprocessableEntity))
+            }
+            ExecAndWaitInternalError::InvalidParams(_) => {
+                Some(("invalid_params", rocket::http::Status::BadRequest))
+            }
+            ExecAndWaitInternalError::DemoNotAllowed(_) => {
+                Some(("demo_not_allowed", rocket::http::Status::Forbidden))
+            }
+            ExecAndWaitInternalError::Exec(ExecError::OutputTooLarge(_)) => Some((
+                "output_too_large",
+                rocket::http::Status::InternalServerError,
+            )),
+            ExecAndWaitInternalError::TooManyExecutions { .. } => Some((
+                "too_many_executions",
+                rocket::http::Status::ServiceUnavailable,
+            )),
+            _ => None,
+        };
+        if let Some((error_code, status)) = json_error {
+            let retry_after = match &self {
+                ExecAndWaitInternalError::TooManyExecutions { retry_after, .. } => {
+                    Some(*retry_after)
+                }
+                _ => None,
+            };
+            let errors = match &self {
+                ExecAndWaitInternalError::InvalidParams(errors) => errors.clone(),
+                _ => Vec::new(),
+            };
+            let detail = match self {
+                ExecAndWaitInternalError::InvalidRequest(detail) => detail,
+                err => err.to_string(),
+            };
+            let response = ErrorResponse {
+                error_code,
+                detail,
+                errors,
+                retry_hint_seconds: retry_after,
+            };
+            let mut response =
+                rocket::Response::build_from(rocket::serde::json::Json(response).respond_to(req)?);
+            if let Some(retry_after) = retry_after {
+                response.raw_header("Retry-After", retry_after.to_string());
+            }
+            return response.status(status).ok();
+        }
+        let status = match self {
+            ExecAndWaitInternalError::Input(_) => rocket::http::Status::UnprocessableEntity,
+            _ => rocket::http::Status::InternalServerError,
+        };
         let string = self.to_string();
         rocket::Response::build_from(string.respond_to(req)?)
-            .status(rocket::http::Status::InternalServerError)
+            .status(status)
             .ok()
     }
 }
 
-#[tracing::instrument(skip(dir))]
-fn zip_dir_into_bytes(dir: &std::path::Path) -> Result<Vec<u8>, ExecAndWaitInternalError> {
-    let writer = std::io::Cursor::new(Vec::new());
-    let mut zip = zip::ZipWriter::new(writer);
-    let options = zip::write::SimpleFileOptions::default()
-        .compression_method(zip::CompressionMethod::Stored)
-        .unix_permissions(0o644);
+/// An uploaded file rejected unless `allow_empty_inputs`, by its index among the files.
+#[derive(Debug, thiserror::Error)]
+pub enum InputError {
+    #[error("input {0} has no filename")]
+    MissingName(usize),
+    #[error("input {index} ({filename:?}) is empty")]
+    Empty { index: usize, filename: String },
+}
+
+/// Expands the `{demo_id}` and `{key}` placeholders of a zip root template,
+/// and keeps only safe path components.
+fn expand_zip_root(template: &str, demo_id: &DemoID, key: &RunKey) -> Option<String> {
+    let expanded = template
+        .replace("{demo_id}", demo_id.as_ref())
+        .replace("{key}", key.as_ref());
+    let root = expanded
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != "." && *c != "..")
+        .map(|c| {
+            c.chars()
+                .map(|ch| {
+                    if ch.is_ascii_alphanumeric() || "-_.".contains(ch) {
+                        ch
+                    } else {
+                        '_'
+                    }
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    (!root.is_empty()).then_some(root)
+}
+
+/// Format of the archive of the results.
+#[derive(Debug, Clone, Copy, Default, PartialEq, FromFormField)]
+pub enum OutputFormat {
+    #[default]
+    Zip,
+    /// Uncompressed, to be piped into `tar -x`.
+    Tar,
+}
+
+impl UriDisplay<Query> for OutputFormat {
+    fn fmt(&self, f: &mut Formatter<'_, Query>) -> std::fmt::Result {
+        f.write_value(match self {
+            OutputFormat::Zip => "zip",
+            OutputFormat::Tar => "tar",
+        })
+    }
+}
+
+/// A file or a directory of the results, with its name in the archive.
+struct ArchiveEntry {
+    name: String,
+    path: PathBuf,
+    is_dir: bool,
+}
 
+/// The entries of an archive of `dir`, and the file of the warnings about them.
+struct ArchiveContent {
+    entries: Vec<ArchiveEntry>,
+    /// Name and content of `zip_warnings.txt`.
+    warnings: Option<(String, String)>,
+}
+
+/// Lists the content of `dir` to archive, optionally under a `root` folder.
+///
+/// With `normalize_filenames`, the names which can't be extracted on Windows
+/// are renamed, the renames being listed in `zip_warnings.txt`.
+/// With `only`, the other files and the directories are left out, and are not
+/// renamed nor listed in the warnings.
+fn archive_content(
+    dir: &std::path::Path,
+    root: Option<&str>,
+    normalize_filenames: bool,
+    only: Option<&HashSet<String>>,
+) -> Result<ArchiveContent, ExecAndWaitInternalError> {
+    let with_root = |name: &str| match root {
+        Some(root) => format!("{root}/{name}"),
+        None => name.to_string(),
+    };
+    let mut normalizer = normalize_filenames.then(FilenameNormalizer::default);
+
+    let mut entries = Vec::new();
     for file in walkdir::WalkDir::new(dir)
         .into_iter()
         .filter_map(Result::ok)
@@ -108,29 +520,277 @@ fn zip_dir_into_bytes(dir: &std::path::Path) -> Result<Vec<u8>, ExecAndWaitInter
         if name_in_zip.is_empty() {
             continue;
         }
+        if only.is_some_and(|only| !only.contains(name_in_zip)) {
+            continue;
+        }
+        let name_in_zip = match &mut normalizer {
+            Some(normalizer) => normalizer.normalize(name_in_zip, file.file_type().is_dir()),
+            None => name_in_zip.to_string(),
+        };
+        let name_in_zip = with_root(&name_in_zip);
 
-        if file.file_type().is_file() {
-            if let Ok(mut file) = std::fs::File::open(filename) {
-                zip.start_file(name_in_zip.to_string(), options)?;
-                std::io::copy(&mut file, &mut zip)?;
-                tracing::debug!("copy {filename:?} -> {name_in_zip:?}");
-            }
-        } else if file.file_type().is_dir() {
-            zip.add_directory(name_in_zip.to_string(), options).ok();
-            tracing::debug!("add directory {name_in_zip:?}");
+        if file.file_type().is_file() || file.file_type().is_dir() {
+            entries.push(ArchiveEntry {
+                name: name_in_zip,
+                path: filename.to_path_buf(),
+                is_dir: file.file_type().is_dir(),
+            });
         }
     }
 
+    let warnings = normalizer.and_then(|normalizer| normalizer.warnings());
+    let warnings = warnings.map(|warnings| (with_root("zip_warnings.txt"), warnings));
+    Ok(ArchiveContent { entries, warnings })
+}
+
+/// Zips the content of `dir`, see `archive_content`.
+///
+/// The files are stored, unless a `compression_level` (0 to 9) is given to deflate them.
+#[tracing::instrument(skip(dir, only))]
+fn zip_dir_into_bytes(
+    dir: &std::path::Path,
+    root: Option<&str>,
+    normalize_filenames: bool,
+    only: Option<&HashSet<String>>,
+    compression_level: Option<u8>,
+) -> Result<Vec<u8>, ExecAndWaitInternalError> {
+    let content = archive_content(dir, root, normalize_filenames, only)?;
+    let writer = std::io::Cursor::new(Vec::new());
+    let mut zip = zip::ZipWriter::new(writer);
+    let options = zip::write::SimpleFileOptions::default().unix_permissions(0o644);
+    let options = match compression_level {
+        Some(level) => options
+            .compression_method(zip::CompressionMethod::Deflated)
+            .compression_level(Some(i64::from(level.min(9)))),
+        None => options.compression_method(zip::CompressionMethod::Stored),
+    };
+
+    if let Some(root) = root {
+        zip.add_directory(root, options)?;
+    }
+    for entry in content.entries {
+        if entry.is_dir {
+            zip.add_directory(entry.name.as_str(), options).ok();
+            tracing::debug!("add directory {:?}", entry.name);
+        } else if let Ok(mut file) = std::fs::File::open(&entry.path) {
+            zip.start_file(entry.name.as_str(), options)?;
+            std::io::copy(&mut file, &mut zip)?;
+            tracing::debug!("copy {:?} -> {:?}", entry.path, entry.name);
+        }
+    }
+
+    if let Some((name, warnings)) = content.warnings {
+        zip.start_file(name, options)?;
+        std::io::copy(&mut warnings.as_bytes(), &mut zip)?;
+    }
+
     Ok(zip.finish()?.into_inner())
 }
 
+/// Archives the content of `dir` as an uncompressed tar, see `archive_content`.
+#[tracing::instrument(skip(dir, only))]
+fn tar_dir_into_bytes(
+    dir: &std::path::Path,
+    root: Option<&str>,
+    normalize_filenames: bool,
+    only: Option<&HashSet<String>>,
+) -> Result<Vec<u8>, ExecAndWaitInternalError> {
+    let content = archive_content(dir, root, normalize_filenames, only)?;
+    let mut tar = tar::Builder::new(Vec::new());
+
+    if let Some(root) = root {
+        tar.append_dir(root, dir)?;
+    }
+    for entry in content.entries {
+        if entry.is_dir {
+            tar.append_dir(&entry.name, &entry.path)?;
+            tracing::debug!("add directory {:?}", entry.name);
+        } else if let Ok(mut file) = std::fs::File::open(&entry.path) {
+            tar.append_file(&entry.name, &mut file)?;
+            tracing::debug!("copy {:?} -> {:?}", entry.path, entry.name);
+        }
+    }
+
+    if let Some((name, warnings)) = content.warnings {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(warnings.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, name, warnings.as_bytes())?;
+    }
+
+    Ok(tar.into_inner()?)
+}
+
+fn input_filename<'a>(input: &'a rocket::fs::TempFile<'_>) -> Option<&'a str> {
+    input
+        .raw_name()
+        .map(|filename| filename.dangerous_unsafe_unsanitized_raw().as_str())
+        .filter(|filename| !filename.is_empty())
+}
+
+/// Rejects an empty script or list of steps, which would give an empty result,
+/// unless `allow_empty`.
+fn check_ddl_run(ddl_run: &str, allow_empty: bool) -> Result<(), ExecAndWaitInternalError> {
+    let no_steps = steps::parse(ddl_run).is_some_and(|steps| steps.is_empty());
+    if (ddl_run.trim().is_empty() || no_steps) && !allow_empty {
+        return Err(ExecAndWaitInternalError::InvalidRequest(
+            "ddl_run is empty, set allow_empty_run=true to only collect the inputs".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that a request gives either `ddl_run` or `ddl_run_exec`.
+fn check_run_command(
+    ddl_run: Option<&str>,
+    options: &ExecAndWaitOptions,
+) -> Result<(), ExecAndWaitInternalError> {
+    match (ddl_run, options.ddl_run_exec.as_deref()) {
+        (Some(_), Some(_)) => Err(ExecAndWaitInternalError::InvalidRequest(
+            "ddl_run and ddl_run_exec are mutually exclusive".into(),
+        )),
+        (None, None) => Err(ExecAndWaitInternalError::InvalidRequest(
+            "ddl_run or ddl_run_exec is required".into(),
+        )),
+        (Some(ddl_run), None) => check_ddl_run(ddl_run, options.allow_empty_run),
+        (None, Some(json)) => parse_ddl_run_exec(Some(json))
+            .map(|_| ())
+            .map_err(|err| ExecAndWaitInternalError::InvalidRequest(err.to_string())),
+    }
+}
+
+/// Rejects the devices asked by a request, which only the config of a demo can attach.
+fn check_devices(devices: Option<&str>) -> Result<(), ExecAndWaitInternalError> {
+    if devices.is_some() {
+        return Err(ExecAndWaitInternalError::InvalidRequest(
+            "devices can't be requested, they are attached by the config of the demo".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects a request enabling the network which `network_disabled` disables.
+fn check_network_disabled(
+    config: &config::Config,
+    network_disabled: Option<bool>,
+) -> Result<(), ExecAndWaitInternalError> {
+    if config.network_disabled && network_disabled == Some(false) {
+        return Err(ExecAndWaitInternalError::InvalidRequest(
+            "network_disabled can't be false, the network is disabled by the config".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects a zero timeout, which would expire before the container starts.
+fn check_timeout(timeout: Option<u64>) -> Result<(), ExecAndWaitInternalError> {
+    if timeout == Some(0) {
+        return Err(ExecAndWaitInternalError::InvalidRequest(
+            "timeout must be at least 1 second".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects a request giving both `stdin` and `stdin_file`.
+fn check_stdin(options: &ExecAndWaitOptions) -> Result<(), ExecAndWaitInternalError> {
+    if options.stdin.is_some() && options.stdin_file.is_some() {
+        return Err(ExecAndWaitInternalError::InvalidRequest(
+            "stdin and stdin_file are mutually exclusive".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects a priority out of 1 to 10.
+fn check_priority(priority: Option<u8>) -> Result<(), ExecAndWaitInternalError> {
+    if priority.is_some_and(|priority| !(1..=10).contains(&priority)) {
+        return Err(ExecAndWaitInternalError::InvalidRequest(
+            "priority must be between 1 and 10".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the uploaded files, returning the number of them which will be saved.
+///
+/// The files without a filename or without content are rejected, unless
+/// `allow_empty` where they are only reported in the warnings.
+fn check_inputs(
+    inputs: &[rocket::fs::TempFile<'_>],
+    allow_empty: bool,
+    warnings: &mut Vec<String>,
+) -> Result<usize, InputError> {
+    let mut accepted = 0;
+    for (index, input) in inputs.iter().enumerate() {
+        let (err, saved) = match input_filename(input) {
+            None => (InputError::MissingName(index), false),
+            Some(filename) if input.len() == 0 => {
+                let filename = filename.to_string();
+                (InputError::Empty { index, filename }, true)
+            }
+            Some(_) => {
+                accepted += 1;
+                continue;
+            }
+        };
+        if !allow_empty {
+            return Err(err);
+        }
+        if saved {
+            accepted += 1;
+            warnings.push(err.to_string());
+        } else {
+            warnings.push(format!("{err}, it was not saved"));
+        }
+    }
+    Ok(accepted)
+}
+
+/// A size in MB in bytes, as docker takes it, `None` when out of its range.
+fn mb_to_bytes(mb: u64) -> Option<i64> {
+    mb.checked_mul(1024 * 1024)
+        .and_then(|bytes| i64::try_from(bytes).ok())
+}
+
+/// Rejects a memory limit which, with the swap, is out of the range of docker.
+fn check_memory(
+    config: &config::Config,
+    memory: Option<u64>,
+) -> Result<(), ExecAndWaitInternalError> {
+    let out_of_range = |mb: u64| {
+        mb.checked_add(config.swap_mb)
+            .and_then(mb_to_bytes)
+            .is_none()
+    };
+    if memory.is_some_and(out_of_range) {
+        let max = (i64::MAX as u64 / (1024 * 1024)).saturating_sub(config.swap_mb);
+        return Err(ExecAndWaitInternalError::InvalidRequest(format!(
+            "memory must be at most {max} MB"
+        )));
+    }
+    Ok(())
+}
+
+/// Rejects a size of `/dev/shm` out of the range of docker.
+fn check_shm_size(shm_size: Option<u64>) -> Result<(), ExecAndWaitInternalError> {
+    if shm_size.is_some_and(|mb| mb_to_bytes(mb).is_none()) {
+        let max = i64::MAX as u64 / (1024 * 1024);
+        return Err(ExecAndWaitInternalError::InvalidRequest(format!(
+            "shm_size must be at most {max} MB"
+        )));
+    }
+    Ok(())
+}
+
+/// Saves an input into the run directory, returning its path relative to it.
 #[tracing::instrument(skip(input, outdir))]
 async fn save_input<'a>(
     input: &mut rocket::fs::TempFile<'a>,
     outdir: &Path,
-) -> Result<(), ExecError> {
-    if let Some(filename) = input.raw_name() {
-        let filename = filename.dangerous_unsafe_unsanitized_raw().as_str();
+) -> Result<Option<PathBuf>, ExecError> {
+    if let Some(filename) = input_filename(input) {
         let filename = std::path::Path::new(filename);
 
         let dst = safe_path::scoped_join(outdir, filename)?;
@@ -140,18 +800,268 @@ async fn save_input<'a>(
         let size = input.len();
         tracing::debug!("saving input {filename:?} ({size} bytes) to {dst:?}");
         input.persist_to(dst).await?;
+        return Ok(Some(filename.to_path_buf()));
+    }
+    Ok(None)
+}
+
+#[tracing::instrument(skip(uploads, outdir))]
+async fn take_upload(
+    uploads: &UploadSessions,
+    id: &str,
+    outdir: &Path,
+) -> Result<PathBuf, ExecError> {
+    let (path, filename) = uploads.take_completed(id).await?;
+    let dst = safe_path::scoped_join(outdir, &filename)?;
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    tracing::debug!("moving upload {path:?} to {dst:?}");
+    if fs::rename(&path, &dst).await.is_err() {
+        // the upload root may be on another filesystem
+        fs::copy(&path, &dst).await?;
+        fs::remove_file(&path).await?;
+    }
+    Ok(PathBuf::from(filename))
+}
+
+/// Merges the parameters given as `param_<name>` form fields into the ones
+/// given as JSON, which take precedence.
+fn merge_params(json: Option<RunParams>, form: RunParams, report: &mut ExecReport) -> RunParams {
+    let Some(json) = json else {
+        return form;
+    };
+    if !json.is_empty() && !form.is_empty() {
+        let mut conflicts = form
+            .keys()
+            .filter(|name| json.contains_key(*name))
+            .cloned()
+            .collect::<Vec<_>>();
+        conflicts.sort();
+        let mut warning =
+            "parameters were given both as JSON and as param_ form fields".to_string();
+        if !conflicts.is_empty() {
+            let conflicts = conflicts.join(", ");
+            warning.push_str(&format!(", the JSON values were kept for: {conflicts}"));
+        }
+        report.warnings.push(warning);
+    }
+    form.into_iter().chain(json).collect()
+}
+
+/// Completes the parameters of a request with the defaults of the demo,
+/// returning the names of the parameters which were taken from the defaults.
+fn apply_defaults(params: &mut RunParams, defaults: &RunParams) -> Vec<String> {
+    let mut applied = Vec::new();
+    for (name, value) in defaults {
+        if !params.contains_key(name) {
+            params.insert(name.clone(), value.clone());
+            applied.push(name.clone());
+        }
+    }
+    applied.sort();
+    applied
+}
+
+/// Saves the uploaded files and the completed uploads into the run directory,
+/// returning their paths relative to it.
+///
+/// Each saved file is checked against `max_input_file_bytes`, and all of them
+/// against `max_total_input_bytes`.
+async fn save_inputs<'a, 'b>(
+    req: &mut ExecAndWaitRequest<'a, 'b>,
+    config: &config::Config,
+    uploads: &UploadSessions,
+    outdir: &Path,
+) -> Result<Vec<PathBuf>, ExecError> {
+    let mut inputs = Vec::new();
+    let mut total = 0;
+    for input in &mut *req.inputs {
+        if let Some(input) = save_input(input, outdir).await? {
+            let size = fs::metadata(outdir.join(&input)).await?.len();
+            total += size;
+            check_input_size(config, &input, size, total)?;
+            inputs.push(input);
+        }
+    }
+    for id in &req.options.upload_ids {
+        let input = take_upload(uploads, id, outdir).await?;
+        let size = fs::metadata(outdir.join(&input)).await?.len();
+        total += size;
+        check_input_size(config, &input, size, total)?;
+        inputs.push(input);
+    }
+    Ok(inputs)
+}
+
+/// Checks the size of a saved input, and the `total` size of the inputs saved
+/// so far, against the limits of the config (0 for no limit).
+fn check_input_size(
+    config: &config::Config,
+    input: &Path,
+    size: u64,
+    total: u64,
+) -> Result<(), ExecError> {
+    let max_file = config.max_input_file_bytes;
+    if max_file > 0 && size > max_file {
+        return Err(ExecError::InputTooLarge(format!(
+            "input {input:?} is {size} bytes, above max_input_file_bytes ({max_file})"
+        )));
+    }
+    let max_total = config.max_total_input_bytes;
+    if max_total > 0 && total > max_total {
+        return Err(ExecError::InputTooLarge(format!(
+            "the inputs reach {total} bytes with {input:?}, above max_total_input_bytes ({max_total})"
+        )));
+    }
+    Ok(())
+}
+
+/// Files written by the runner at the root of the run directory, next to the results.
+const RUNNER_FILES: &[&str] = &[
+    "exec_info.json",
+    "resources.json",
+    "timing.json",
+    "environment.txt",
+    "changes.json",
+    "manifest.json",
+];
+
+/// Checks the size of the results against `max_bytes` (0 for no limit),
+/// before they are buffered into an archive.
+///
+/// The files of the runner don't count, the limit being the one of the run.
+fn check_output_size(dir: &Path, max_bytes: u64) -> Result<(), ExecError> {
+    if max_bytes == 0 {
+        return Ok(());
+    }
+    let bytes = walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let name = entry.file_name().to_str().unwrap_or_default();
+            !(entry.depth() == 1 && RUNNER_FILES.contains(&name))
+        })
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum::<u64>();
+    if bytes > max_bytes {
+        return Err(ExecError::OutputTooLarge(bytes));
     }
     Ok(())
 }
 
-fn get_device_requests(config: &config::Config) -> Option<Vec<DeviceRequest>> {
-    if config.gpus.is_empty() {
+/// Removes the content of a directory, returning the number of bytes freed.
+fn empty_dir(dir: &Path) -> std::io::Result<u64> {
+    let mut bytes = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        bytes += walkdir::WalkDir::new(&path)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter_map(|entry| entry.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum::<u64>();
+        if entry.file_type()?.is_dir() {
+            std::fs::remove_dir_all(&path)?;
+        } else {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(bytes)
+}
+
+/// Checks the user configured in an image, returning it (`root` if unset).
+fn check_image_user(image_user: Option<&str>, allow_root: bool) -> Result<String, ExecError> {
+    let image_user = image_user.filter(|user| !user.is_empty()).unwrap_or("root");
+    let name = image_user.split(':').next().unwrap_or_default();
+    if (name == "root" || name == "0") && !allow_root {
+        return Err(ExecError::RootImageUser(image_user.to_string()));
+    }
+    Ok(image_user.to_string())
+}
+
+/// Maximum size of the JSON of `exit_code_messages`.
+const EXIT_CODE_MESSAGES_MAX_BYTES: usize = 16 * 1024;
+
+/// Number of lines at the end of the output kept as the detail of a reported error.
+const DETAIL_LINES: usize = 50;
+
+/// Parses the arguments of `ddl_run_exec`, the first one being the program.
+fn parse_ddl_run_exec(json: Option<&str>) -> Result<Option<Vec<String>>, ExecError> {
+    let Some(json) = json else {
+        return Ok(None);
+    };
+    let argv: Vec<String> =
+        serde_json::from_str(json).map_err(|err| ExecError::InvalidRunExec(err.to_string()))?;
+    if argv.first().is_none_or(|program| program.trim().is_empty()) {
+        return Err(ExecError::InvalidRunExec(
+            "expected a program, then its arguments".into(),
+        ));
+    }
+    Ok(Some(argv))
+}
+
+fn parse_exit_code_messages(json: Option<&str>) -> Result<HashMap<i64, String>, ExecError> {
+    let Some(json) = json else {
+        return Ok(HashMap::new());
+    };
+    if json.len() > EXIT_CODE_MESSAGES_MAX_BYTES {
+        return Err(ExecError::InvalidExitCodeMessages(format!(
+            "larger than {EXIT_CODE_MESSAGES_MAX_BYTES} bytes"
+        )));
+    }
+    serde_json::from_str(json).map_err(|err| ExecError::InvalidExitCodeMessages(err.to_string()))
+}
+
+fn output_tail(output: &str) -> String {
+    let lines = output.lines().collect::<Vec<_>>();
+    lines[lines.len().saturating_sub(DETAIL_LINES)..].join("\n")
+}
+
+/// Builds the environment of the container, the variables of the config taking
+/// precedence over the parameters of the request.
+///
+/// The parameters reach `ddl_run` only through the environment, so that the
+/// shell never parses their values.
+fn build_env(req: &ExecAndWaitRequest, config: &config::Config) -> Vec<String> {
+    req.params
+        .clone()
+        .into_iter()
+        .chain(config.env_vars.clone())
+        .collect::<RunParams>()
+        .to_env_vec(
+            &req.demo_id,
+            &req.key,
+            config.param_array_format(&req.demo_id),
+        )
+}
+
+/// Renders the environment one variable per line (sorted like `env | sort`),
+/// with the values of the variables coming from the config redacted.
+fn render_environment(env: &[String], secrets: &RunParams) -> String {
+    let mut lines = env
+        .iter()
+        .map(|var| match var.split_once('=') {
+            Some((name, _)) if secrets.contains_key(name) => format!("{name}=<redacted>"),
+            _ => var.clone(),
+        })
+        .collect::<Vec<_>>();
+    lines.sort();
+    lines.iter().map(|line| format!("{line}\n")).collect()
+}
+
+fn get_device_requests(gpus: &[String]) -> Option<Vec<DeviceRequest>> {
+    if gpus.is_empty() {
         None
     } else {
         Some(vec![DeviceRequest {
             driver: None,
             count: None,
-            device_ids: Some(config.gpus.clone()),
+            device_ids: Some(gpus.to_vec()),
             capabilities: Some(vec![vec!["gpu".into()]]),
             options: None,
         }])
@@ -161,18 +1071,60 @@ fn get_device_requests(config: &config::Config) -> Option<Vec<DeviceRequest>> {
 fn get_docker_binds(config: &config::Config, outdir: &Path) -> Option<Vec<String>> {
     let exec_mountpoint = &config.exec_workdir_in_docker;
     Some(vec![format!(
-        "{}:{}",
+        "{}:{}{}",
         outdir.to_str().unwrap(),
         exec_mountpoint,
+        config.selinux_relabel.bind_suffix(),
     )])
 }
 
-fn get_docker_host_config(config: &config::Config, outdir: &Path) -> HostConfig {
-    let device_requests = get_device_requests(config);
+/// Options of the tmpfs on `/tmp` of the runs with a read-only root filesystem.
+const READONLY_ROOTFS_TMPFS: &str = "rw,nosuid,nodev,size=64m";
+
+/// Period of the CFS scheduler of docker, in microseconds.
+const DEFAULT_CPU_PERIOD: i64 = 100_000;
+
+fn get_docker_host_config(
+    config: &config::Config,
+    outdir: &Path,
+    cpuset: Option<&Cpuset>,
+    memory_mb: Option<u64>,
+    cpus: Option<f64>,
+    cpu_shares: Option<i64>,
+) -> HostConfig {
     let binds = get_docker_binds(config, outdir);
+    let memory = memory_mb.and_then(mb_to_bytes);
+    // the swap limit includes the memory
+    let memory_swap = memory_mb
+        .and_then(|mb| mb.checked_add(config.swap_mb))
+        .and_then(mb_to_bytes);
+    let ulimits = config.ulimits.iter().map(|ulimit| ResourcesUlimits {
+        name: Some(ulimit.name.as_ref().to_string()),
+        soft: Some(ulimit.soft),
+        hard: Some(ulimit.hard),
+    });
+    let ulimits = ulimits.collect::<Vec<_>>();
+    // docker refuses nano_cpus along with a quota, so the CPUs become a quota
+    let (nano_cpus, cpu_quota) = match config.cpu_quota {
+        Some(quota) => {
+            let period = config.cpu_period.unwrap_or(DEFAULT_CPU_PERIOD);
+            let cpus_quota = cpus.map(|cpus| (cpus * period as f64) as i64);
+            (None, Some(cpus_quota.map_or(quota, |v| quota.min(v))))
+        }
+        None => (cpus.map(|cpus| (cpus * 1e9) as i64), None),
+    };
     HostConfig {
         binds,
-        device_requests,
+        cpuset_cpus: cpuset.map(|cpuset| cpuset.cpus.clone()),
+        cpuset_mems: cpuset.and_then(|cpuset| cpuset.mems.clone()),
+        memory,
+        memory_swap,
+        nano_cpus,
+        cpu_quota,
+        cpu_period: config.cpu_quota.and(config.cpu_period),
+        cpu_shares,
+        pids_limit: Some(config.pids_limit as i64).filter(|&limit| limit > 0),
+        ulimits: (!ulimits.is_empty()).then_some(ulimits),
         ..Default::default()
     }
 }
@@ -187,94 +1139,392 @@ async fn remove_container(docker: Docker, name: &str) -> Result<(), bollard::err
     docker.remove_container(name, options).await
 }
 
-fn compute_timeout_deadline(config: &config::Config, req_timeout: Option<u64>) -> Instant {
-    let max_timeout = config.max_timeout;
-    let timeout = req_timeout.map_or(max_timeout, |v| max_timeout.min(v));
-    Instant::now() + Duration::from_secs(timeout)
-}
-
-#[tracing::instrument(skip(docker, deadline, outdir))]
-async fn read_logs_with_timeout(
+/// Stops a run which timed out with a SIGTERM, letting it write its partial
+/// results for `grace` seconds before docker kills it.
+async fn stop_gracefully(
     docker: &Docker,
-    deadline: Instant,
     id: &str,
-    outdir: &Path,
-) -> Result<String, ExecError> {
-    let mut output = String::new();
-
-    let mut stderr = fs::File::create(outdir.join("stderr.txt")).await?;
-    let mut stdout = fs::File::create(outdir.join("stdout.txt")).await?;
-    timeout_at(deadline, async {
-        let options = Some(LogsOptions::<String> {
-            follow: true,
-            stdout: true,
-            stderr: true,
-            ..Default::default()
-        });
-        let mut logs = docker.logs(id, options);
-        while let Some(msg) = logs.next().await {
-            match msg {
-                Ok(LogOutput::StdOut { message }) => {
-                    tracing::info!("stdout: {message:#?}");
-                    stdout.write_all(&message).await?;
-                    output.push_str(&String::from_utf8_lossy(&message));
-                }
-                Ok(LogOutput::StdErr { message }) => {
-                    tracing::info!("stderr: {message:#?}");
-                    stderr.write_all(&message).await?;
-                    output.push_str(&String::from_utf8_lossy(&message));
-                }
-                Ok(LogOutput::StdIn { message }) => {
-                    tracing::info!("stdin: {message:#?}");
-                }
-                Ok(LogOutput::Console { message }) => {
-                    tracing::info!("console: {message:#?}");
-                }
-                Err(e) => {
-                    tracing::error!("{:?}", e);
-                }
-            };
-        }
-        Ok::<(), ExecError>(())
-    })
-    .await??;
-
-    stdout.flush().await?;
-    stderr.flush().await?;
-    Ok(output)
+    grace: u64,
+    call_timeout: Duration,
+    api_time: &mut Duration,
+) {
+    if grace == 0 {
+        return;
+    }
+    let options = Some(StopContainerOptions { t: grace as i64 });
+    let stop = docker.stop_container(id, options);
+    let call_timeout = call_timeout + Duration::from_secs(grace);
+    match timed_call("stop_container", call_timeout, api_time, stop).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => tracing::warn!("couldn't stop the container {id}: {err}"),
+        Err(err) => tracing::warn!("{err}"),
+    }
 }
 
-#[tracing::instrument(skip(req, config, outdir))]
-async fn exec_and_wait_inner<'a, 'b>(
-    req: &mut ExecAndWaitRequest<'a, 'b>,
+fn resolve_timeout(
     config: &config::Config,
-    outdir: &std::path::Path,
+    req_timeout: Option<u64>,
+    adjustments: &mut Adjustments,
+) -> Duration {
+    let max_timeout = config.max_timeout;
+    let timeout = req_timeout.map_or(max_timeout, |v| max_timeout.min(v));
+    if let Some(req_timeout) = req_timeout.filter(|&v| v > max_timeout) {
+        adjustments.record("timeout", req_timeout, timeout, AdjustmentReason::ConfigMax);
+    }
+    Duration::from_secs(timeout)
+}
+
+/// Memory limit of a run in MB: the one of the request, at most `max_memory_mb`.
+fn resolve_memory(
+    config: &config::Config,
+    req_memory: Option<u64>,
+    adjustments: &mut Adjustments,
+) -> Option<u64> {
+    let Some(max_memory) = config.max_memory_mb else {
+        return req_memory;
+    };
+    let memory = req_memory.map_or(max_memory, |v| max_memory.min(v));
+    if let Some(req_memory) = req_memory.filter(|&v| v > max_memory) {
+        adjustments.record("memory", req_memory, memory, AdjustmentReason::ConfigMax);
+    }
+    Some(memory)
+}
+
+/// Size of `/dev/shm` of a run in MB: the one of the request, at most `shm_size_mb`.
+fn resolve_shm_size(
+    config: &config::Config,
+    req_shm_size: Option<u64>,
+    adjustments: &mut Adjustments,
+) -> Option<u64> {
+    let Some(max_shm_size) = config.shm_size_mb else {
+        return req_shm_size;
+    };
+    let shm_size = req_shm_size.map_or(max_shm_size, |v| max_shm_size.min(v));
+    if let Some(req_shm_size) = req_shm_size.filter(|&v| v > max_shm_size) {
+        adjustments.record(
+            "shm_size",
+            req_shm_size,
+            shm_size,
+            AdjustmentReason::ConfigMax,
+        );
+    }
+    Some(shm_size)
+}
+
+/// Number of CPUs of a run: the one of the request, at most `cpu_limit`.
+fn resolve_cpus(
+    config: &config::Config,
+    req_cpus: Option<f64>,
+    adjustments: &mut Adjustments,
+) -> Option<f64> {
+    let req_cpus = req_cpus.filter(|cpus| cpus.is_finite() && *cpus > 0.0);
+    let Some(cpu_limit) = config.cpu_limit else {
+        return req_cpus;
+    };
+    let cpus = req_cpus.map_or(cpu_limit, |v| cpu_limit.min(v));
+    if let Some(req_cpus) = req_cpus.filter(|&v| v > cpu_limit) {
+        adjustments.record("cpus", req_cpus, cpus, AdjustmentReason::ConfigMax);
+    }
+    Some(cpus)
+}
+
+/// Converts the deadline of the client into a timeout for the run, keeping
+/// `estimated_postprocess` seconds to send back the results.
+///
+/// The timeout is bounded by `min_timeout` and `max_timeout` so that a skewed
+/// clock cannot produce absurd values.
+fn timeout_from_deadline(
+    config: &config::Config,
+    deadline: &str,
+    now: chrono::DateTime<chrono::Utc>,
+    adjustments: &mut Adjustments,
+) -> Result<Duration, ExecError> {
+    let deadline = chrono::DateTime::parse_from_rfc3339(deadline)?;
+    let postprocess = Duration::from_secs(config.estimated_postprocess);
+    let remaining = (deadline.with_timezone(&chrono::Utc) - now)
+        .to_std()
+        .ok()
+        .and_then(|remaining| remaining.checked_sub(postprocess))
+        .filter(|remaining| !remaining.is_zero())
+        .ok_or(ExecError::DeadlineExceeded)?;
+    let min_timeout = Duration::from_secs(config.min_timeout);
+    let max_timeout = Duration::from_secs(config.max_timeout);
+    let timeout = remaining.min(max_timeout).max(min_timeout);
+    if timeout != remaining {
+        let reason = if remaining > max_timeout {
+            AdjustmentReason::ConfigMax
+        } else {
+            AdjustmentReason::ConfigMin
+        };
+        let effective = now + timeout + postprocess;
+        adjustments.record(
+            "deadline",
+            deadline.to_rfc3339(),
+            effective.to_rfc3339(),
+            reason,
+        );
+    }
+    Ok(timeout)
+}
+
+/// Capacity (in chunks) of the channel between the docker log stream and the log files.
+const LOG_CHANNEL_CAPACITY: usize = 1024;
+
+fn log_output_len(output: &LogOutput) -> usize {
+    match output {
+        LogOutput::StdOut { message }
+        | LogOutput::StdErr { message }
+        | LogOutput::StdIn { message }
+        | LogOutput::Console { message } => message.len(),
+    }
+}
+
+/// Drains the log stream into a bounded channel from a dedicated task, so that slow
+/// writes of the log files never delay the consumption of the container's logs.
+///
+/// When the channel is full, the intermediate chunks are dropped (and counted in
+/// `dropped_bytes`) but the latest one is kept aside, so that both the head and
+/// the tail of the logs are preserved.
+fn spawn_log_pump<S>(
+    mut logs: S,
+    capacity: usize,
+    dropped_bytes: Arc<AtomicU64>,
+) -> (mpsc::Receiver<LogOutput>, JoinHandle<()>)
+where
+    S: Stream<Item = Result<LogOutput, bollard::errors::Error>> + Send + Unpin + 'static,
+{
+    let (tx, rx) = mpsc::channel(capacity);
+    let pump = rocket::tokio::spawn(async move {
+        let mut pending = None;
+        while let Some(msg) = logs.next().await {
+            let output = match msg {
+                Ok(output) => output,
+                Err(e) => {
+                    tracing::error!("{:?}", e);
+                    continue;
+                }
+            };
+            if let Some(previous) = pending.take() {
+                match tx.try_send(previous) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(previous)) => {
+                        let len = log_output_len(&previous) as u64;
+                        dropped_bytes.fetch_add(len, Ordering::Relaxed);
+                    }
+                    Err(TrySendError::Closed(_)) => return,
+                }
+            }
+            match tx.try_send(output) {
+                Ok(()) => {}
+                Err(TrySendError::Full(output)) => pending = Some(output),
+                Err(TrySendError::Closed(_)) => return,
+            }
+        }
+        if let Some(previous) = pending {
+            tx.send(previous).await.ok();
+        }
+    });
+    (rx, pump)
+}
+
+#[tracing::instrument(skip(docker, config, deadline, outdir, report))]
+async fn read_logs_with_timeout(
+    docker: &Docker,
+    config: &config::Config,
+    deadline: Instant,
+    id: &str,
+    outdir: &Path,
+    report: &mut ExecReport,
+) -> Result<String, ExecError> {
+    let mut output = String::new();
+
+    let max_bytes = config.max_logfile_bytes;
+    let strategy = config.logfile_truncation;
+    let mut stderr = CappedLogFile::create(&outdir.join("stderr.txt"), max_bytes, strategy).await?;
+    let mut stdout = CappedLogFile::create(&outdir.join("stdout.txt"), max_bytes, strategy).await?;
+
+    let options = Some(LogsOptions::<String> {
+        follow: true,
+        stdout: true,
+        stderr: true,
+        ..Default::default()
+    });
+    let dropped_bytes = Arc::new(AtomicU64::new(0));
+    let (mut rx, pump) = spawn_log_pump(
+        docker.logs(id, options),
+        LOG_CHANNEL_CAPACITY,
+        dropped_bytes.clone(),
+    );
+
+    let logs = timeout_at(deadline, async {
+        while let Some(log) = rx.recv().await {
+            match log {
+                LogOutput::StdOut { message } => {
+                    tracing::info!("stdout: {message:#?}");
+                    stdout.write_all(&message).await?;
+                    output.push_str(&String::from_utf8_lossy(&message));
+                }
+                LogOutput::StdErr { message } => {
+                    tracing::info!("stderr: {message:#?}");
+                    stderr.write_all(&message).await?;
+                    output.push_str(&String::from_utf8_lossy(&message));
+                }
+                LogOutput::StdIn { message } => {
+                    tracing::info!("stdin: {message:#?}");
+                }
+                LogOutput::Console { message } => {
+                    tracing::info!("console: {message:#?}");
+                }
+            };
+        }
+        Ok::<(), ExecError>(())
+    })
+    .await;
+    pump.abort();
+
+    let dropped_bytes = dropped_bytes.load(Ordering::Relaxed);
+    if dropped_bytes > 0 {
+        tracing::warn!("{dropped_bytes} bytes of logs were dropped");
+        report.warnings.push(format!(
+            "{dropped_bytes} bytes of logs were dropped because they could not be saved fast enough"
+        ));
+    }
+
+    // finish the files even on timeout, so that the partial logs are kept
+    stdout.finish().await?;
+    stderr.finish().await?;
+    logs??;
+    Ok(output)
+}
+
+/// Awaits a step of the setup of a run, creating or starting its container,
+/// failing with `StartupTimeout` past `setup_deadline`.
+async fn within_setup_deadline<T>(
+    setup_deadline: Instant,
+    step: impl std::future::Future<Output = Result<T, ExecError>>,
+) -> Result<T, ExecError> {
+    match timeout_at(setup_deadline, step).await {
+        Ok(result) => result,
+        Err(_) => Err(ExecError::StartupTimeout),
+    }
+}
+
+/// Seconds after which a run refused for lack of disk space or of execution slots
+/// may be retried: when the runs in progress are expected to free enough, or
+/// `retry_hint_default`.
+fn retry_hint_seconds(config: &config::Config, retry_hint: Option<Duration>) -> u64 {
+    match retry_hint {
+        Some(retry_hint) => retry_hint.as_secs_f64().ceil().max(1.0) as u64,
+        None => config.retry_hint_default,
+    }
+}
+
+/// Reserves the disk space needed by a run, estimated from the size of its inputs,
+/// until its `expected_end`.
+async fn reserve_disk_space<'a, 'b>(
+    req: &ExecAndWaitRequest<'a, 'b>,
+    config: &config::Config,
+    uploads: &UploadSessions,
+    disks: &DiskReservations,
+    outdir: &Path,
+    expected_end: Instant,
+) -> Result<DiskReservation, ExecError> {
+    let mut inputs_size = req.inputs.iter().map(|input| input.len()).sum::<u64>();
+    for id in &req.options.upload_ids {
+        inputs_size += uploads.size(id).await?;
+    }
+    let required = estimate_required_space(config, inputs_size);
+    let reservation = disks.reserve(outdir, required, expected_end.into_std())?;
+    tracing::debug!("reserved {required} bytes for {inputs_size} bytes of inputs");
+    Ok(reservation)
+}
+
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(req, config, uploads, disks, numa, gpus, outdir, report))]
+async fn exec_and_wait_inner<'a, 'b>(
+    req: &mut ExecAndWaitRequest<'a, 'b>,
+    config: &config::Config,
+    uploads: &UploadSessions,
+    disks: &DiskReservations,
+    numa: &NumaAssignments,
+    gpus: &GpuAllocator,
+    outdir: &std::path::Path,
+    report: &mut ExecReport,
 ) -> Result<Duration, ExecError> {
     tracing::debug!("{req:?}");
 
-    let docker = Docker::connect_with_local_defaults()?;
+    let client_deadline = match &req.options.deadline {
+        Some(deadline) => {
+            let now = chrono::Utc::now();
+            let timeout = timeout_from_deadline(config, deadline, now, &mut report.adjustments)?;
+            Some(Instant::now() + timeout)
+        }
+        None => None,
+    };
+    let timeout = resolve_timeout(config, req.timeout, &mut report.adjustments);
+    let memory = resolve_memory(config, req.options.memory, &mut report.adjustments);
+    let cpus = resolve_cpus(config, req.options.cpus, &mut report.adjustments);
+    report.shm_size_mb = resolve_shm_size(config, req.options.shm_size, &mut report.adjustments);
+    let src_path = names::compilation_dir(config, &req.demo_id)?.join("src");
+    let image_name = names::image_name(config, &req.demo_id)?;
+    let name = names::container_name(config, &req.demo_id, &req.key)?;
+
+    let success_criteria = req.options.success_criteria.compile()?;
+    let exit_code_messages = parse_exit_code_messages(req.options.exit_code_messages.as_deref())?;
+    let argv = parse_ddl_run_exec(req.options.ddl_run_exec.as_deref())?;
+
+    // released by the cleanup of the run directory, or on any early return
+    let mut expected_end = Instant::now() + timeout;
+    if let Some(client_deadline) = client_deadline {
+        expected_end = expected_end.min(client_deadline);
+    }
+    let reservation = reserve_disk_space(req, config, uploads, disks, outdir, expected_end).await?;
+    report.disk_reservation = Some(reservation);
+
+    // released once the container is removed, or on any early return
+    let gpu_demand = gpus::demand(config, req.options.gpus.as_ref())?;
+    let gpu_wait = Duration::from_secs(config.gpu_wait);
+    let gpu_allocation = gpus.allocate(&gpu_demand, gpu_wait).await?;
+    report.gpus = gpu_allocation.ids.clone();
+
+    let docker = daemon::connect(config)?;
+    let call_timeout = Duration::from_secs(config.docker_call_timeout);
 
     // canonicalize for docker volumes
     let outdir = fs::canonicalize(outdir).await?;
 
-    for input in &mut *req.inputs {
-        save_input(input, &outdir).await?;
+    let saving = Instant::now();
+    let inputs = match save_inputs(req, config, uploads, &outdir).await {
+        Ok(inputs) => {
+            report.start_clock.inputs = saving.elapsed();
+            inputs
+        }
+        Err(err) => {
+            // don't leave the inputs persisted before the failure behind
+            match empty_dir(&outdir) {
+                Ok(bytes) => tracing::info!("removed {bytes} bytes of inputs after a failure"),
+                Err(err) => tracing::warn!("couldn't empty the run directory {outdir:?}: {err}"),
+            }
+            return Err(err);
+        }
+    };
+    let stdin = match (&req.options.stdin, &req.options.stdin_file) {
+        (Some(text), _) => Some(text.clone().into_bytes()),
+        (None, Some(name)) => {
+            if !inputs.iter().any(|input| input == Path::new(name)) {
+                return Err(ExecError::InvalidStdinFile(name.clone()));
+            }
+            Some(fs::read(outdir.join(name)).await?)
+        }
+        (None, None) => None,
+    };
+    if req.options.output_mode == Some(OutputMode::Diff) {
+        report.snapshot = Some(Snapshot::take(&outdir, config.diff_hash_max_bytes)?);
     }
 
     // TODO/IPOL: it would be better if the git_rev were provided in the payload
-    let src_path = PathBuf::from(&config.compilation_root)
-        .join(req.demo_id.as_ref())
-        .join("src");
     let git_rev = get_git_revision(&src_path)?;
-
-    let registry = config
-        .registry_url
-        .as_ref()
-        .map_or(String::new(), |url| (url.clone() + "/"));
-    let image_name = format!(
-        "{}{}{}:{}",
-        registry, config.docker_image_prefix, &req.demo_id, git_rev
-    );
+    let image_name = format!("{image_name}:{git_rev}");
 
     if config.registry_url.is_some() {
         let mut stream = docker.create_image(
@@ -285,41 +1535,152 @@ async fn exec_and_wait_inner<'a, 'b>(
             None,
             None,
         );
-        while let Some(msg) = stream.next().await {
-            if let Err(err) = msg {
-                warn!("exec/pull: {}", err);
+        let pull = async {
+            while let Some(msg) = stream.next().await {
+                if let Err(err) = msg {
+                    warn!("exec/pull: {}", err);
+                }
             }
-        }
+        };
+        let api_time = &mut report.docker_api_time;
+        timed_call("pull", call_timeout, api_time, pull).await?;
     }
 
-    let name = format!("{}{}-{}", config.docker_exec_prefix, &req.demo_id, req.key);
     let options = Some(CreateContainerOptions {
         name: name.as_str(),
         platform: None,
     });
 
-    let env = req
-        .params
-        .clone()
-        .into_iter()
-        .chain(config.env_vars.clone().into_iter())
-        .collect::<RunParams>()
-        .to_env_vec(&req.demo_id, &req.key);
+    let demo = config.demo(&req.demo_id);
+    let user = match &demo.user {
+        Some(config::DemoUser::ImageDefault) => {
+            let inspect = docker.inspect_image(&image_name);
+            let api_time = &mut report.docker_api_time;
+            let image = timed_call("inspect_image", call_timeout, api_time, inspect).await??;
+            let image_user = image.config.and_then(|image_config| image_config.user);
+            let image_user = check_image_user(image_user.as_deref(), demo.allow_root)?;
+            tracing::debug!("running as the user of the image {image_user:?}");
+            report.image_user = Some(image_user);
+            None
+        }
+        Some(config::DemoUser::UidGid(user)) => Some(user.as_str()),
+        None => Some(config.user_uid_gid.as_str()),
+    };
+
+    // the assignment to a NUMA node is kept until the end of the run
+    let cpuset = numa.cpuset(config, &req.demo_id);
+    let cpuset = cpuset.as_ref().map(|(cpuset, _)| cpuset);
+    let mut env = build_env(req, config);
+    if let Some(cpuset) = cpuset {
+        tracing::debug!("pinning the run to {cpuset:?}");
+        env.push(format!("IPOL_CPUSET={}", cpuset.cpus));
+        report.cpuset = Some(cpuset.cpus.clone());
+    }
+    if req.options.debug_env {
+        let environment = render_environment(&env, &config.env_vars);
+        fs::write(outdir.join("environment.txt"), environment).await?;
+    }
     let env = env.iter().map(|s| s as &str).collect();
     let exec_mountpoint = &config.exec_workdir_in_docker;
-    let host_config = get_docker_host_config(config, &outdir);
+    let mut host_config = get_docker_host_config(
+        config,
+        &outdir,
+        cpuset,
+        memory,
+        cpus,
+        req.options.cpu_shares,
+    );
+    host_config.network_mode = Some(config.network_mode(&req.demo_id));
+    host_config.runtime = config.docker_runtime(&req.demo_id);
+    let runtime = host_config.runtime.clone();
+    let mut tmpfs = HashMap::new();
+    if config.readonly_rootfs(&req.demo_id) {
+        host_config.readonly_rootfs = Some(true);
+        tmpfs.insert("/tmp".into(), READONLY_ROOTFS_TMPFS.into());
+    }
+    if let Some(scratch) = &config.tmpfs {
+        tmpfs.insert(scratch.path.clone(), scratch.options());
+    }
+    host_config.tmpfs = Some(tmpfs).filter(|tmpfs| !tmpfs.is_empty());
+    host_config.shm_size = report.shm_size_mb.and_then(mb_to_bytes);
+    host_config.device_requests = get_device_requests(&report.gpus);
+    let demo = config.demo(&req.demo_id);
+    if let Some(binds) = host_config.binds.as_mut() {
+        binds.extend(binds::docker_binds(config, &demo)?);
+    }
+    host_config.devices = devices::device_mappings(config, &demo)?;
+    let non_empty = |values: Vec<String>| Some(values).filter(|values| !values.is_empty());
+    host_config.cap_drop = non_empty(config.cap_drop.clone());
+    host_config.cap_add = non_empty(
+        config
+            .cap_add
+            .iter()
+            .chain(&demo.cap_add)
+            .cloned()
+            .collect(),
+    );
+    let security_opt = config.security_opt.iter().chain(&demo.security_opt);
+    host_config.security_opt = non_empty(security_opt.cloned().collect());
+    report.devices = devices::devices(config, &demo)
+        .map(|device| device.path_in_container().to_string())
+        .collect();
+    let labels = HashMap::from([
+        (config::INSTANCE_LABEL, config.instance_id.as_str()),
+        (config::KEY_LABEL, req.key.as_ref().as_str()),
+    ]);
+    let shell = config.shell(&req.demo_id);
+    let script = steps::parse(&req.ddl_run).map(|steps| steps::script(&steps));
     let container_config = Config {
         image: Some(image_name.as_str()),
-        user: Some(&config.user_uid_gid),
-        cmd: Some(vec!["/bin/bash", "-c", req.ddl_run.as_str()]),
+        labels: Some(labels),
+        user,
+        cmd: Some(match &argv {
+            Some(argv) => argv.iter().map(String::as_str).collect(),
+            None => shell.command(script.as_deref().unwrap_or(&req.ddl_run)),
+        }),
         env: Some(env),
         working_dir: Some(exec_mountpoint),
         host_config: Some(host_config),
+        network_disabled: Some(
+            config.network_disabled || req.options.network_disabled == Some(true),
+        ),
+        // closed by docker once the data is written
+        open_stdin: Some(stdin.is_some()),
+        stdin_once: Some(stdin.is_some()),
+        attach_stdin: Some(stdin.is_some()),
         ..Default::default()
     };
 
+    // the setup may overrun the timeout by startup_grace, then the run times out without starting
+    let mut setup_deadline = Instant::now() + timeout;
+    if let Some(client_deadline) = client_deadline {
+        setup_deadline = setup_deadline.min(client_deadline);
+    }
+    setup_deadline += Duration::from_secs(config.startup_grace);
+
     tracing::debug!(name = name, image_name = image_name);
-    let id = docker.create_container(options, container_config).await?.id;
+    let create = docker.create_container(options, container_config);
+    let api_time = &mut report.docker_api_time;
+    let create = timed_call("create_container", call_timeout, api_time, create);
+    let create = within_setup_deadline(setup_deadline, async { Ok(create.await??) }).await;
+    let id = match create {
+        Ok(response) => response.id,
+        Err(ExecError::StartupTimeout) => {
+            // the daemon may still create it
+            let (docker, name) = (docker.clone(), name.clone());
+            rocket::tokio::spawn(async move {
+                if let Err(e) = remove_container(docker, &name).await {
+                    tracing::debug!("{:?}", e);
+                }
+            });
+            return Err(ExecError::StartupTimeout);
+        }
+        Err(ExecError::Docker(err)) if daemon::unknown_runtime(&err) => {
+            let runtime = runtime.unwrap_or_default();
+            return Err(ExecError::RuntimeUnavailable(runtime));
+        }
+        Err(err) => return Err(err),
+    };
     tracing::debug!(id = id);
 
     scopeguard::defer! {
@@ -329,38 +1690,405 @@ async fn exec_and_wait_inner<'a, 'b>(
             if let Err(e) = remove_container(docker, &name).await {
                 tracing::error!("{:?}", e);
             }
+            drop(gpu_allocation);
         });
     }
 
+    // attached before the start so that the run can't miss its input, and only
+    // to the stdin since the output is read from the logs
+    let stdin = match stdin {
+        Some(data) => {
+            let options = AttachContainerOptions::<String> {
+                stdin: Some(true),
+                stream: Some(true),
+                ..Default::default()
+            };
+            let attach = docker.attach_container(&id, Some(options));
+            let api_time = &mut report.docker_api_time;
+            let attach = timed_call("attach_container", call_timeout, api_time, attach);
+            let attached = within_setup_deadline(setup_deadline, async { Ok(attach.await??) });
+            Some((attached.await?, data))
+        }
+        None => None,
+    };
+
     tracing::debug!("starting container {id:?}");
-    docker.start_container::<String>(&id, None).await?;
+    let run_start = Instant::now();
+    let start = docker.start_container::<String>(&id, None);
+    let api_time = &mut report.docker_api_time;
+    let start = timed_call("start_container", call_timeout, api_time, start);
+    within_setup_deadline(setup_deadline, async { Ok(start.await??) }).await?;
+    report.start_latency = report.start_clock.latency(report.docker_api_time);
+    // a run which doesn't read its stdin mustn't block the collection of its logs
+    let stdin_writer =
+        stdin.map(|(attached, data)| rocket::tokio::spawn(write_stdin(attached, data)));
+    scopeguard::defer! {
+        if let Some(stdin_writer) = &stdin_writer {
+            stdin_writer.abort();
+        }
+    }
+    let pids_limit = Some(config.pids_limit).filter(|&limit| limit > 0);
+    let sampling = resources::spawn_sampling(docker.clone(), id.clone(), pids_limit);
 
-    let deadline = compute_timeout_deadline(config, req.timeout);
-    let output = read_logs_with_timeout(&docker, deadline, &id, &outdir).await?;
+    let mut deadline = Instant::now() + timeout;
+    if let Some(client_deadline) = client_deadline {
+        deadline = deadline.min(client_deadline);
+    }
+    let logs = read_logs_with_timeout(&docker, config, deadline, &id, &outdir, report).await;
+    let mut output = match logs {
+        Err(ExecError::Timeout(elapsed)) => {
+            // the partial results are sent, so they are let be finished
+            let (grace, api_time) = (config.stop_grace_seconds, &mut report.docker_api_time);
+            stop_gracefully(&docker, &id, grace, call_timeout, api_time).await;
+            return Err(ExecError::Timeout(elapsed));
+        }
+        logs => logs?,
+    };
+    let run_window = run_start.elapsed();
+    let resources = save_resources(sampling, &outdir).await;
+    report.resources = resources.clone();
 
     let options = Some(InspectContainerOptions::default());
-    let inspect_response = docker.inspect_container(&name, options).await?;
+    let inspect = docker.inspect_container(&name, options);
+    let api_time = &mut report.docker_api_time;
+    let inspect_response =
+        match timed_call("inspect_container", call_timeout, api_time, inspect).await? {
+            // removed by cancel_exec, which ended the logs
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => return Err(ExecError::Cancelled),
+            result => result?,
+        };
+    let status = inspect_response
+        .state
+        .as_ref()
+        .and_then(|state| state.status.as_ref());
+    if status == Some(&ContainerStateStatusEnum::REMOVING) {
+        return Err(ExecError::Cancelled);
+    }
+
+    let strict_output = req.options.strict_output.unwrap_or(config.strict_output);
+    if strict_output {
+        let changes = docker.container_changes(&id);
+        let api_time = &mut report.docker_api_time;
+        let changes = timed_call("container_changes", call_timeout, api_time, changes).await??;
+        let changes = changes.unwrap_or_default();
+        let changes = changes.into_iter().map(|change| change.path);
+        let changes = outside_changes(changes, &config.exec_workdir_in_docker);
+        if !changes.is_empty() {
+            tracing::info!("the run wrote outside the workdir: {changes:?}");
+        }
+        report.outside_changes = Some(changes);
+    }
 
-    let mut duration = None;
+    let mut docker_duration = None;
     if let Some(state) = inspect_response.state {
+        // a process of the run may have been killed without making it fail
+        if state.oom_killed == Some(true) {
+            return Err(match memory {
+                Some(memory) => ExecError::OutOfMemory(memory),
+                None => ExecError::OomKilled,
+            });
+        }
+        if resources.reached_pids_limit(pids_limit) {
+            return Err(ExecError::PidsLimit(config.pids_limit));
+        }
         if let Some(exit_code) = state.exit_code {
             if exit_code != 0 {
                 tracing::debug!("container exited with code {exit_code}");
+                if config.diagnostic_hints {
+                    let workdir = &config.exec_workdir_in_docker;
+                    for hint in diagnostic_hints(&output, workdir, &inputs) {
+                        output.push('\n');
+                        output.push_str(&hint);
+                    }
+                }
+                if let Some(message) = exit_code_messages.get(&exit_code) {
+                    return Err(ExecError::AlgorithmReported {
+                        code: exit_code,
+                        message: message.clone(),
+                        detail: output_tail(&output),
+                    });
+                }
                 return Err(ExecError::NonZeroExitCode(exit_code, output));
             }
         }
 
         if let (Some(start), Some(end)) = (state.started_at, state.finished_at) {
-            let timezone = chrono::FixedOffset::east_opt(0).unwrap();
-            let now = chrono::Utc::now().with_timezone(&timezone);
-            let start = chrono::DateTime::parse_from_rfc3339(&start).unwrap_or(now);
-            let end = chrono::DateTime::parse_from_rfc3339(&end).unwrap_or(now);
-            duration = (end - start).to_std().ok();
+            let start = chrono::DateTime::parse_from_rfc3339(&start).ok();
+            let end = chrono::DateTime::parse_from_rfc3339(&end).ok();
+            if let (Some(start), Some(end)) = (start, end) {
+                docker_duration = (end - start).to_std().ok();
+            }
+        }
+    }
+
+    let mut timing = timing::reconcile(
+        docker_duration,
+        run_window,
+        config.clock_skew_factor,
+        Duration::from_secs(config.clock_skew_offset),
+    );
+    if timing.skewed() {
+        tracing::warn!(
+            "the docker run time {:?}s disagrees with the monotonic one {}s, the host clock may have jumped",
+            timing.docker_run_time,
+            timing.monotonic_run_time
+        );
+        report.warnings.push(format!(
+            "the run time reported by docker ({:.3}s) was replaced by the one measured by the demorunner ({:.3}s)",
+            timing.docker_run_time.unwrap_or_default(),
+            timing.monotonic_run_time
+        ));
+    }
+    timing.docker_api_seconds = report.docker_api_time.as_secs_f64();
+    timing.start_latency = report.start_latency.clone();
+    save_timing(&timing, &outdir).await;
+
+    if let Some(changes) = report.outside_changes.as_ref().filter(|c| !c.is_empty()) {
+        match config.strict_output_severity {
+            config::StrictOutputSeverity::Warn => report.warnings.push(format!(
+                "the run wrote outside the workdir, these files are lost: {}",
+                changes.join(", ")
+            )),
+            config::StrictOutputSeverity::Fail => {
+                return Err(ExecError::StrictOutput(changes.clone()));
+            }
+        }
+    }
+
+    success_criteria.check(&outdir, &output)?;
+
+    Ok(Duration::from_secs_f64(timing.run_time))
+}
+
+/// Keeps the paths of the changes of a container which are outside the workdir,
+/// leaving out the directories only changed because of a change below them.
+fn outside_changes(changes: impl Iterator<Item = String>, workdir: &str) -> Vec<String> {
+    let workdir = Path::new(workdir);
+    let changes = changes
+        .filter(|path| {
+            let path = Path::new(path);
+            !path.starts_with(workdir) && !workdir.starts_with(path)
+        })
+        .collect::<Vec<_>>();
+    let mut outside = changes
+        .iter()
+        .filter(|path| {
+            let path = Path::new(path);
+            !changes
+                .iter()
+                .any(|other| Path::new(other) != path && Path::new(other).starts_with(path))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    outside.sort();
+    outside
+}
+
+/// Writes the data of `stdin` to the run, then closes its standard input.
+async fn write_stdin(attached: AttachContainerResults, data: Vec<u8>) {
+    let AttachContainerResults { mut input, output } = attached;
+    let written = async {
+        input.write_all(&data).await?;
+        input.shutdown().await
+    };
+    if let Err(err) = written.await {
+        tracing::debug!("couldn't write the stdin of the run: {err}");
+    }
+    // the connection is kept open until the data is written
+    drop(output);
+}
+
+/// Writes the run time and where it comes from into `timing.json`.
+async fn save_timing(timing: &Timing, outdir: &Path) {
+    let timing = serde_json::to_string_pretty(timing).unwrap_or_default();
+    if let Err(err) = fs::write(outdir.join("timing.json"), timing).await {
+        tracing::warn!("couldn't write timing.json: {err}");
+    }
+}
+
+/// Time left to the stats sampling to notice that the container stopped.
+const SAMPLING_GRACE: Duration = Duration::from_secs(2);
+
+/// Writes the resources used by the run into `resources.json`.
+async fn save_resources(sampling: JoinHandle<Resources>, outdir: &Path) -> Resources {
+    let resources = match rocket::tokio::time::timeout(SAMPLING_GRACE, sampling).await {
+        Ok(Ok(resources)) => resources,
+        Ok(Err(err)) => {
+            tracing::warn!("the stats sampling failed: {err}");
+            Resources::default()
+        }
+        Err(_) => {
+            tracing::warn!("the stats sampling didn't stop with the container");
+            Resources::default()
+        }
+    };
+    let json = serde_json::to_string_pretty(&resources).unwrap_or_default();
+    if let Err(err) = fs::write(outdir.join("resources.json"), json).await {
+        tracing::warn!("couldn't write resources.json: {err}");
+    }
+    resources
+}
+
+fn belongs_to_instance(labels: Option<&HashMap<String, String>>, instance_id: &str) -> bool {
+    labels
+        .and_then(|labels| labels.get(config::INSTANCE_LABEL))
+        .is_some_and(|id| id == instance_id)
+}
+
+async fn warn_about_foreign_containers(
+    config: &config::Config,
+) -> Result<(), bollard::errors::Error> {
+    let docker = daemon::connect(config)?;
+    let filters = HashMap::from([("name", vec![config.docker_exec_prefix.as_str()])]);
+    let containers = docker
+        .list_containers(Some(ListContainersOptions {
+            filters,
+            ..Default::default()
+        }))
+        .await?;
+
+    let prefix = format!("/{}", config.docker_exec_prefix);
+    for container in containers {
+        let names = container.names.unwrap_or_default();
+        if !names.iter().any(|name| name.starts_with(&prefix)) {
+            continue;
+        }
+        if !belongs_to_instance(container.labels.as_ref(), &config.instance_id) {
+            let instance = container
+                .labels
+                .as_ref()
+                .and_then(|labels| labels.get(config::INSTANCE_LABEL));
+            tracing::warn!(
+                "container {names:?} uses the prefix {:?} but belongs to the instance {instance:?}, not to {:?}; check that the demorunners sharing this docker host use distinct prefixes",
+                config.docker_exec_prefix,
+                config.instance_id
+            );
         }
     }
+    Ok(())
+}
+
+/// Notes when the requests are received, before their bodies, for the start latency of the runs.
+pub fn request_timer() -> rocket::fairing::AdHoc {
+    rocket::fairing::AdHoc::on_request("Request timer", |req, _| {
+        Box::pin(async move {
+            req.local_cache(|| timing::ReceivedAt(std::time::Instant::now()));
+        })
+    })
+}
+
+/// Warns at startup when running containers with our name prefix belong to another instance.
+pub fn instance_check() -> rocket::fairing::AdHoc {
+    rocket::fairing::AdHoc::on_liftoff("Instance check", |rocket| {
+        Box::pin(async move {
+            if let Some(config) = rocket.state::<config::Config>() {
+                if let Err(err) = warn_about_foreign_containers(config).await {
+                    tracing::warn!("couldn't list the running containers: {err}");
+                }
+            }
+        })
+    })
+}
+
+/// Where the kernel tells whether SELinux is enforcing.
+const SELINUX_ENFORCE: &str = "/sys/fs/selinux/enforce";
+
+fn selinux_enforcing(path: &Path) -> bool {
+    std::fs::read_to_string(path).is_ok_and(|enforce| enforce.trim() == "1")
+}
+
+/// Warns when SELinux is enforcing but the run directories are not relabeled,
+/// as the containers would then fail with permission errors.
+pub fn selinux_check() -> rocket::fairing::AdHoc {
+    rocket::fairing::AdHoc::on_liftoff("SELinux check", |rocket| {
+        Box::pin(async move {
+            if let Some(config) = rocket.state::<config::Config>() {
+                let relabel = config.selinux_relabel;
+                if relabel == config::SelinuxRelabel::Off
+                    && selinux_enforcing(Path::new(SELINUX_ENFORCE))
+                {
+                    tracing::warn!(
+                        "SELinux is enforcing but selinux_relabel is off, the containers won't be able to read their run directories"
+                    );
+                }
+            }
+        })
+    })
+}
+
+/// Number of attempts at removing a run directory before leaving it behind.
+const CLEANUP_ATTEMPTS: u32 = 3;
+
+/// Removes the run directory from a background task, so that a slow filesystem
+/// (e.g. an unlink storm on NFS) never delays the response.
+/// The disk reservation of the run is released once the removal is over.
+fn spawn_cleanup(
+    tmpdir: tempfile::TempDir,
+    timeout: Duration,
+    disk_reservation: Option<DiskReservation>,
+) -> JoinHandle<()> {
+    rocket::tokio::spawn(async move {
+        let _disk_reservation = disk_reservation;
+        let path = tmpdir.path().to_path_buf();
+        let start = Instant::now();
+        for attempt in 1..=CLEANUP_ATTEMPTS {
+            match rocket::tokio::time::timeout(timeout, fs::remove_dir_all(&path)).await {
+                Ok(Ok(())) => {
+                    let elapsed = start.elapsed();
+                    tracing::info!("removed the run directory {path:?} in {elapsed:?}");
+                    // nothing is left to be removed by the drop of the TempDir
+                    return;
+                }
+                Ok(Err(err)) => {
+                    tracing::warn!("couldn't remove {path:?} (attempt {attempt}): {err}");
+                }
+                Err(_) => {
+                    tracing::warn!("removing {path:?} timed out (attempt {attempt})");
+                }
+            }
+        }
+        let elapsed = start.elapsed();
+        tracing::error!("gave up removing the run directory {path:?} after {elapsed:?}");
+        // do not block on a last removal attempt in the drop of the TempDir
+        std::mem::forget(tmpdir);
+    })
+}
 
-    let duration = duration.unwrap_or_default();
-    Ok(duration)
+/// Lists the changes of the run directory since `before` into `changes.json`,
+/// returning the files to be zipped.
+fn save_changes(
+    before: &Snapshot,
+    outdir: &Path,
+    hash_max_bytes: u64,
+) -> Result<HashSet<String>, ExecAndWaitInternalError> {
+    let after = Snapshot::take(outdir, hash_max_bytes)?;
+    let changes = before.diff(&after);
+    std::fs::write(
+        outdir.join("changes.json"),
+        serde_json::to_string_pretty(&changes)?,
+    )?;
+    let mut changed_files = changes.changed_files();
+    changed_files.insert("changes.json".into());
+    Ok(changed_files)
+}
+
+/// Lists the files to be archived into `manifest.json`, with their content
+/// types sniffed from their first bytes.
+fn save_manifest(
+    outdir: &Path,
+    only: Option<&mut HashSet<String>>,
+) -> Result<(), ExecAndWaitInternalError> {
+    let entries = manifest::entries(outdir, only.as_deref());
+    std::fs::write(
+        outdir.join("manifest.json"),
+        serde_json::to_string_pretty(&entries)?,
+    )?;
+    if let Some(only) = only {
+        only.insert("manifest.json".into());
+    }
+    Ok(())
 }
 
 async fn save_exec_info(
@@ -375,21 +2103,80 @@ async fn save_exec_info(
 }
 
 pub mod http {
-    use rocket::form::Form;
+    use std::time::Duration;
+
+    use rocket::form::{self, DataField, Form, FromForm, ValueField};
+    use rocket::http::{ContentType, Status};
+    use rocket::response::{status, Responder};
     use rocket::serde::json::Json;
+    use rocket::serde::{Deserialize, Serialize};
     use rocket::State;
 
+    use super::adjustments::Adjustments;
+    use super::timing::{ReceivedAt, StartClock};
     use super::{
-        exec_and_wait_inner, save_exec_info, zip_dir_into_bytes, AlgoInfo,
-        ExecAndWaitInternalError, ExecAndWaitRequest, ExecError, ExecInfo,
+        apply_defaults, check_devices, check_memory, check_network_disabled, check_output_size,
+        check_priority, check_run_command, check_shm_size, check_stdin, check_timeout,
+        exec_and_wait_inner, expand_zip_root, merge_params, remove_container, resolve_timeout,
+        retry_hint_seconds, save_changes, save_exec_info, save_manifest, spawn_cleanup,
+        tar_dir_into_bytes, zip_dir_into_bytes, AlgoInfo, CriteriaError, ExecAndWaitInternalError,
+        ExecAndWaitOptions, ExecAndWaitRequest, ExecError, ExecInfo, ExecReport, OutputFormat,
     };
     use crate::config;
-    use crate::model::{DDLRun, DemoID, RunKey, RunParams};
+    use crate::daemon;
+    use crate::disk::{DiskError, DiskReservations};
+    use crate::gpus::{self, GpuAllocator, GpuError};
+    use crate::model::{DDLRun, DemoID, ParamValue, RunKey, RunParams, RunParamsSchema};
+    use crate::names;
+    use crate::numa::NumaAssignments;
+    use crate::queue::{DemoExecutionSlots, ExecutionSlots, QueueFull, DEFAULT_PRIORITY};
+    use crate::upload::UploadSessions;
 
-    #[derive(Responder)]
-    #[response(status = 200, content_type = "application/zip")]
     pub struct ExecAndWaitResponse {
-        zip: Vec<u8>,
+        archive: Vec<u8>,
+        format: OutputFormat,
+        /// Compact list of the adjusted request values, for `X-Adjustments`.
+        adjustments: Option<String>,
+        /// Time spent waiting on the docker daemon, for `X-Docker-API-Seconds`.
+        docker_api_seconds: f64,
+        /// Seconds after which a refused run may be retried, for `Retry-After`.
+        retry_after: Option<u64>,
+        /// CPU time of the run, for `X-CPU-Usage-NS`.
+        cpu_usage_ns: Option<u64>,
+        /// Highest memory usage of the run, for `X-Peak-Memory-Bytes`.
+        peak_memory_bytes: Option<u64>,
+        /// Time from the receipt of the request to the start of the container,
+        /// for `X-Start-Latency-Seconds`.
+        start_latency_seconds: Option<f64>,
+    }
+
+    impl<'r> Responder<'r, 'static> for ExecAndWaitResponse {
+        fn respond_to(self, req: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+            let mut response = rocket::Response::build_from(self.archive.respond_to(req)?);
+            response.header(match self.format {
+                OutputFormat::Zip => ContentType::ZIP,
+                OutputFormat::Tar => ContentType::TAR,
+            });
+            if let Some(adjustments) = self.adjustments {
+                response.raw_header("X-Adjustments", adjustments);
+            }
+            let docker_api_seconds = format!("{:.3}", self.docker_api_seconds);
+            response.raw_header("X-Docker-API-Seconds", docker_api_seconds);
+            if let Some(retry_after) = self.retry_after {
+                response.raw_header("Retry-After", retry_after.to_string());
+            }
+            if let Some(cpu_usage_ns) = self.cpu_usage_ns {
+                response.raw_header("X-CPU-Usage-NS", cpu_usage_ns.to_string());
+            }
+            if let Some(peak_memory_bytes) = self.peak_memory_bytes {
+                response.raw_header("X-Peak-Memory-Bytes", peak_memory_bytes.to_string());
+            }
+            if let Some(start_latency_seconds) = self.start_latency_seconds {
+                let start_latency_seconds = format!("{start_latency_seconds:.3}");
+                response.raw_header("X-Start-Latency-Seconds", start_latency_seconds);
+            }
+            response.ok()
+        }
     }
 
     // for some reasons, we need to use a dedicated struct
@@ -397,87 +2184,395 @@ pub mod http {
     pub struct Files<'r> {
         files: Vec<rocket::fs::TempFile<'r>>,
     }
-    #[tracing::instrument(skip(config, ddl_run, timeout, parameters, inputs))]
-    #[post(
-        "/exec_and_wait/<demo_id>?<key>&<ddl_run>&<timeout>&<parameters>",
-        data = "<inputs>"
-    )]
-    pub async fn exec_and_wait<'a>(
-        demo_id: DemoID,
-        key: RunKey,
-        ddl_run: DDLRun,
-        timeout: Option<u64>,
-        parameters: Json<RunParams>,
-        inputs: Form<Files<'a>>,
-        config: &State<config::Config>,
-    ) -> Result<ExecAndWaitResponse, ExecAndWaitInternalError> {
-        let tmpdir = tempfile::TempDir::new()?;
-        let outdir = tmpdir.path();
-        tracing::debug!("{inputs:?}");
 
-        let mut inputs = inputs.into_inner().files;
-        let mut req = ExecAndWaitRequest {
-            demo_id,
-            key,
-            ddl_run,
+    const PARAM_PREFIX: &str = "param_";
+
+    /// The uploaded files, and the parameters given as `param_<name>` form fields.
+    #[derive(Debug)]
+    pub struct Inputs<'r> {
+        files: Vec<rocket::fs::TempFile<'r>>,
+        params: RunParams,
+    }
+
+    #[rocket::async_trait]
+    impl<'r> FromForm<'r> for Inputs<'r> {
+        type Context = (<Files<'r> as FromForm<'r>>::Context, RunParams);
+
+        fn init(opts: form::Options) -> Self::Context {
+            (Files::init(opts), RunParams::new())
+        }
+
+        fn push_value((files, params): &mut Self::Context, field: ValueField<'r>) {
+            match field.name.source().as_str().strip_prefix(PARAM_PREFIX) {
+                Some(name) if !name.is_empty() => {
+                    params.insert(name.into(), ParamValue::infer(field.value));
+                }
+                _ => Files::push_value(files, field),
+            }
+        }
+
+        async fn push_data((files, _): &mut Self::Context, field: DataField<'r, '_>) {
+            Files::push_data(files, field).await
+        }
+
+        fn push_error((files, _): &mut Self::Context, error: form::Error<'r>) {
+            Files::push_error(files, error)
+        }
+
+        fn finalize((files, params): Self::Context) -> form::Result<'r, Self> {
+            let files = Files::finalize(files)?.files;
+            Ok(Inputs { files, params })
+        }
+    }
+    #[allow(clippy::too_many_arguments)]
+    #[tracing::instrument(skip(
+        config,
+        uploads,
+        disks,
+        numa,
+        gpus,
+        slots,
+        demo_slots,
+        ddl_run,
+        timeout,
+        parameters,
+        params_schema,
+        options,
+        inputs
+    ))]
+    #[post(
+        "/exec_and_wait/<demo_id>?<key>&<ddl_run>&<timeout>&<parameters>&<params_schema>&<options..>",
+        data = "<inputs>"
+    )]
+    pub async fn exec_and_wait<'a>(
+        demo_id: DemoID,
+        key: RunKey,
+        ddl_run: Option<DDLRun>,
+        timeout: Option<u64>,
+        parameters: Option<Json<RunParams>>,
+        params_schema: Option<Json<RunParamsSchema>>,
+        options: ExecAndWaitOptions,
+        inputs: Form<Inputs<'a>>,
+        config: &State<config::Config>,
+        uploads: &State<UploadSessions>,
+        disks: &State<DiskReservations>,
+        numa: &State<NumaAssignments>,
+        gpus: &State<GpuAllocator>,
+        slots: &State<ExecutionSlots>,
+        demo_slots: &State<DemoExecutionSlots>,
+        received_at: ReceivedAt,
+    ) -> Result<ExecAndWaitResponse, ExecAndWaitInternalError> {
+        let handler_start = std::time::Instant::now();
+        if !config.demo_allowed(&demo_id) {
+            return Err(ExecAndWaitInternalError::DemoNotAllowed(demo_id));
+        }
+        check_run_command(ddl_run.as_deref(), &options)?;
+        check_timeout(timeout)?;
+        check_devices(options.devices.as_deref())?;
+        check_network_disabled(config, options.network_disabled)?;
+        check_priority(options.priority)?;
+        check_stdin(&options)?;
+        check_memory(config, options.memory)?;
+        check_shm_size(options.shm_size)?;
+        gpus::demand(config, options.gpus.as_ref())
+            .map_err(|err| ExecAndWaitInternalError::InvalidRequest(err.to_string()))?;
+        tracing::debug!("{inputs:?}");
+        let Inputs { mut files, params } = inputs.into_inner();
+        let mut report = ExecReport::default();
+        report.inputs_accepted =
+            check_inputs(&files, config.allow_empty_inputs, &mut report.warnings)?;
+        let mut params = merge_params(parameters.map(|p| p.0), params, &mut report);
+        report.defaults_applied = apply_defaults(&mut params, &config.demo(&demo_id).defaults);
+        if let Some(schema) = params_schema {
+            let errors = schema.check(&params);
+            if !errors.is_empty() {
+                return Err(ExecAndWaitInternalError::InvalidParams(errors));
+            }
+        }
+        // held until the response is ready, the one of the demo first not to
+        // hold a global slot while waiting for it
+        let priority = options.priority.unwrap_or(DEFAULT_PRIORITY);
+        let queue_wait = Duration::from_secs(config.queue_wait);
+        let run_time = resolve_timeout(config, timeout, &mut Adjustments::default());
+        let too_many = |demo_id: Option<&DemoID>, err: QueueFull| {
+            ExecAndWaitInternalError::TooManyExecutions {
+                demo_id: demo_id.map(|demo_id| demo_id.as_ref().clone()),
+                max: err.max,
+                retry_after: retry_hint_seconds(config, err.retry_after),
+            }
+        };
+        let _demo_slot = match demo_slots.get(demo_id.as_ref()) {
+            Some(demo_slots) => Some(
+                demo_slots
+                    .acquire(
+                        priority,
+                        demo_id.as_ref(),
+                        key.as_ref(),
+                        queue_wait,
+                        run_time,
+                    )
+                    .await
+                    .map_err(|err| too_many(Some(&demo_id), err))?,
+            ),
+            None => None,
+        };
+        let _slot = slots
+            .acquire(
+                priority,
+                demo_id.as_ref(),
+                key.as_ref(),
+                queue_wait,
+                run_time,
+            )
+            .await
+            .map_err(|err| too_many(None, err))?;
+        let queue_wait = handler_start.elapsed();
+        let tmpdir = tempfile::TempDir::new()?;
+        let outdir = tmpdir.path();
+
+        report.start_clock = StartClock {
+            received_at: Some(received_at.0),
+            upload: handler_start.saturating_duration_since(received_at.0),
+            queue_wait,
+            ..Default::default()
+        };
+        let mut req = ExecAndWaitRequest {
+            demo_id,
+            key,
+            ddl_run: ddl_run.unwrap_or_default(),
             timeout,
-            params: parameters.0,
-            inputs: &mut inputs,
+            params,
+            options,
+            inputs: &mut files,
         };
 
-        let state = exec_and_wait_inner(&mut req, config, outdir).await;
+        let state = exec_and_wait_inner(
+            &mut req,
+            config,
+            uploads,
+            disks,
+            numa,
+            gpus,
+            outdir,
+            &mut report,
+        )
+        .await;
+        // the step which failed is the last one which ended
+        let state = if steps::parse(&req.ddl_run).is_some() {
+            report.steps = steps::take_statuses(outdir).await;
+            state.map_err(|err| match (err, report.steps.last()) {
+                (ExecError::NonZeroExitCode(code, output), Some(failed)) => ExecError::StepFailed {
+                    step: failed.step,
+                    code,
+                    output,
+                },
+                (err, _) => err,
+            })
+        } else {
+            state
+        };
+        let zip_root = req
+            .options
+            .zip_root
+            .as_ref()
+            .or(config.zip_root.as_ref())
+            .and_then(|template| expand_zip_root(template, &req.demo_id, &req.key));
+        let normalize_filenames = req.options.normalize_filenames;
+        let format = req.options.output_format.unwrap_or_default();
+        let manifest = req.options.manifest;
+        let adjustments = report.adjustments.header();
+        let docker_api_seconds = report.docker_api_time.as_secs_f64();
+        if let Err(ExecError::Disk(DiskError::Full { retry_hint, .. })) = &state {
+            report.retry_hint_seconds = Some(retry_hint_seconds(config, *retry_hint));
+        }
+        let retry_after = report.retry_hint_seconds;
+        let cpu_usage_ns = report.resources.cpu_usage_ns;
+        let peak_memory_bytes = report.resources.peak_memory_bytes;
+        let start_latency_seconds = report.start_latency.as_ref().map(|latency| latency.total);
         let key = req.key;
         let params = req.params;
-        let exec_info = match state {
-            Ok(duration) => ExecInfo {
-                key,
-                params,
-                status: "OK".into(),
-                error: None,
-                algo_info: AlgoInfo {
-                    error_message: None,
+        // remove the temporary files of the inputs which were not persisted
+        drop(files);
+        let (error, algo_info) = match state {
+            Ok(duration) => (
+                None,
+                AlgoInfo {
                     run_time: Some(duration.as_secs_f64()),
+                    ..Default::default()
                 },
-            },
+            ),
             Err(err) => match err {
-                ExecError::Timeout(_) => ExecInfo {
-                    key,
-                    params,
-                    status: "KO".into(),
-                    error: Some("IPOLTimeoutError".into()),
-                    algo_info: AlgoInfo {
+                ExecError::Timeout(_)
+                | ExecError::StartupTimeout
+                | ExecError::OutOfMemory(_)
+                | ExecError::OomKilled
+                | ExecError::PidsLimit(_)
+                | ExecError::Cancelled
+                | ExecError::DockerApiTimeout(_)
+                | ExecError::DeadlineExceeded
+                | ExecError::DockerVersion(_)
+                | ExecError::Disk(DiskError::Full { .. })
+                | ExecError::Gpu(GpuError::Unavailable(_))
+                | ExecError::InputTooLarge(_)
+                | ExecError::RuntimeUnavailable(_)
+                | ExecError::StrictOutput(_)
+                | ExecError::SuccessCriteria(
+                    CriteriaError::MissingFile(_) | CriteriaError::UnmatchedOutput(_),
+                ) => (
+                    Some(match err {
+                        ExecError::Timeout(_) | ExecError::StartupTimeout => {
+                            "IPOLTimeoutError".into()
+                        }
+                        ExecError::OutOfMemory(_) | ExecError::OomKilled => {
+                            "IPOLMemoryError".into()
+                        }
+                        ExecError::PidsLimit(_) => "IPOLPidsLimit".into(),
+                        ExecError::Cancelled => "IPOLCancelled".into(),
+                        ExecError::DockerApiTimeout(_) => "IPOLDockerApiTimeout".into(),
+                        ExecError::DeadlineExceeded => "IPOLDeadlineExceeded".into(),
+                        ExecError::DockerVersion(_) => "IPOLDockerVersionMismatch".into(),
+                        ExecError::Disk(_) => "IPOLNodeDiskFull".into(),
+                        ExecError::Gpu(_) => "IPOLNoGpuAvailable".into(),
+                        ExecError::InputTooLarge(_) => "IPOLInputTooLarge".into(),
+                        ExecError::RuntimeUnavailable(_) => "IPOLRuntimeUnavailable".into(),
+                        ExecError::StrictOutput(_) => "strict_output_violated".into(),
+                        _ => "success_criteria_not_met".into(),
+                    }),
+                    AlgoInfo {
                         error_message: Some(err.to_string()),
-                        run_time: None,
+                        // the algorithm didn't start
+                        run_time: matches!(err, ExecError::StartupTimeout).then_some(0.0),
+                        ..Default::default()
                     },
-                },
-                _ => ExecInfo {
-                    key,
-                    params,
-                    status: "KO".into(),
-                    error: Some(err.to_string()),
-                    algo_info: AlgoInfo {
+                ),
+                ExecError::AlgorithmReported {
+                    code,
+                    message,
+                    detail,
+                } => (
+                    Some("algorithm_reported".into()),
+                    AlgoInfo {
+                        error_message: Some(format!("{code}: {message}")),
+                        detail: Some(detail),
+                        ..Default::default()
+                    },
+                ),
+                _ => (
+                    Some(err.to_string()),
+                    AlgoInfo {
                         error_message: Some(err.to_string()),
-                        run_time: None,
+                        ..Default::default()
                     },
-                },
+                ),
             },
         };
+        let exec_info = ExecInfo {
+            key,
+            params,
+            status: if error.is_none() { "OK" } else { "KO" }.into(),
+            error,
+            algo_info: AlgoInfo {
+                steps: report.steps,
+                ..algo_info
+            },
+            warnings: report.warnings,
+            cpuset: report.cpuset,
+            defaults_applied: report.defaults_applied,
+            image_user: report.image_user,
+            adjustments: report.adjustments.into_inner(),
+            inputs_accepted: report.inputs_accepted,
+            outside_changes: report.outside_changes,
+            shm_size_mb: report.shm_size_mb,
+            retry_hint_seconds: report.retry_hint_seconds,
+            gpus: report.gpus,
+            devices: report.devices,
+        };
 
         save_exec_info(&exec_info, outdir).await?;
-        let zip = zip_dir_into_bytes(outdir)?;
-        let size = zip.len();
-        tracing::info!("sending zip ({size} bytes)");
-        Ok(ExecAndWaitResponse { zip })
+        let mut changed_files = match &report.snapshot {
+            Some(before) => Some(save_changes(before, outdir, config.diff_hash_max_bytes)?),
+            None => None,
+        };
+        if manifest {
+            save_manifest(outdir, changed_files.as_mut())?;
+        }
+        let cleanup_timeout = Duration::from_secs(config.cleanup_timeout);
+        if let Err(err) = check_output_size(outdir, config.max_output_bytes) {
+            tracing::warn!("not sending the results: {err}");
+            spawn_cleanup(tmpdir, cleanup_timeout, report.disk_reservation);
+            return Err(ExecAndWaitInternalError::Exec(err));
+        }
+        let normalize_filenames = normalize_filenames.unwrap_or(config.normalize_filenames);
+        let (root, only) = (zip_root.as_deref(), changed_files.as_ref());
+        let archive = match format {
+            OutputFormat::Zip => {
+                let level = config.zip_compression_level;
+                zip_dir_into_bytes(outdir, root, normalize_filenames, only, level)?
+            }
+            OutputFormat::Tar => tar_dir_into_bytes(outdir, root, normalize_filenames, only)?,
+        };
+        let size = archive.len();
+        tracing::info!("sending {format:?} archive ({size} bytes)");
+        spawn_cleanup(tmpdir, cleanup_timeout, report.disk_reservation);
+        Ok(ExecAndWaitResponse {
+            archive,
+            format,
+            adjustments,
+            docker_api_seconds,
+            retry_after,
+            cpu_usage_ns,
+            peak_memory_bytes,
+            start_latency_seconds,
+        })
+    }
+
+    /// Body of the responses of `cancel_exec`.
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct CancelResponse {
+        detail: String,
+    }
+
+    /// Cancels a run by removing its container, making its pending
+    /// `exec_and_wait` fail with `IPOLCancelled`.
+    #[delete("/exec_and_wait/<demo_id>/<key>")]
+    pub async fn cancel_exec(
+        demo_id: DemoID,
+        key: RunKey,
+        config: &State<config::Config>,
+    ) -> status::Custom<Json<CancelResponse>> {
+        let response = |code, detail: String| status::Custom(code, Json(CancelResponse { detail }));
+        let name = match names::container_name(config, &demo_id, &key) {
+            Ok(name) => name,
+            Err(err) => return response(Status::BadRequest, err.to_string()),
+        };
+        let docker = match daemon::connect(config) {
+            Ok(docker) => docker,
+            Err(err) => return response(Status::InternalServerError, err.to_string()),
+        };
+        match remove_container(docker, &name).await {
+            Ok(()) => {
+                tracing::info!("cancelled the run {name}");
+                response(Status::Ok, format!("the run {key} was cancelled"))
+            }
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => response(
+                Status::NotFound,
+                format!("no run {key} of the demo {demo_id}"),
+            ),
+            Err(err) => response(Status::InternalServerError, err.to_string()),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::main_rocket;
+    use crate::{main_rocket, rocket_from_figment};
     use rocket::http::{ContentType, Status};
     use rocket::local::blocking::Client;
     use rocket::serde::json::Json;
+    use rocket::{Build, Rocket};
 
     fn extract_exec_info(resp: &[u8]) -> ExecInfo {
         let reader = std::io::Cursor::new(resp);
@@ -487,21 +2582,32 @@ mod test {
     }
 
     fn ask_exec(req: &ExecAndWaitRequest) -> ExecInfo {
-        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        extract_exec_info(&ask_exec_zip(main_rocket(), req))
+    }
+
+    fn ask_exec_zip(rocket: Rocket<Build>, req: &ExecAndWaitRequest) -> Vec<u8> {
+        let client = Client::tracked(rocket).expect("valid rocket instance");
 
         let uri = uri!(super::http::exec_and_wait(
             demo_id = &req.demo_id,
             key = &req.key,
             ddl_run = &req.ddl_run,
             parameters = &req.params,
+            params_schema = _,
             timeout = req.timeout,
+            options = &req.options,
         ));
 
         let response = client.post(uri).header(ContentType::Form).dispatch();
         assert_eq!(response.status(), Status::Ok);
         assert_eq!(response.content_type(), Some(ContentType::ZIP));
-        let bytes = response.into_bytes().unwrap();
-        extract_exec_info(&bytes)
+        response.into_bytes().unwrap()
+    }
+
+    fn read_zip_file(zip: &[u8], name: &str) -> String {
+        let reader = std::io::Cursor::new(zip);
+        let mut zip = zip::ZipArchive::new(reader).unwrap();
+        std::io::read_to_string(zip.by_name(name).unwrap()).unwrap()
     }
 
     #[test]
@@ -520,6 +2626,7 @@ mod test {
                 ("param space".into(), ParamValue::String("hi world".into())),
             ]),
             timeout: Some(10),
+            options: ExecAndWaitOptions::default(),
             inputs: &mut [],
         };
 
@@ -541,6 +2648,7 @@ mod test {
             ddl_run: "echo a; exit 5; echo b;".into(),
             params: RunParams::new(),
             timeout: Some(10),
+            options: ExecAndWaitOptions::default(),
             inputs: &mut [],
         };
 
@@ -565,6 +2673,7 @@ mod test {
             ddl_run: "sleep 2".into(),
             params: RunParams::new(),
             timeout: Some(1),
+            options: ExecAndWaitOptions::default(),
             inputs: &mut [],
         };
 
@@ -580,6 +2689,29 @@ mod test {
         assert!(exec_info.algo_info.run_time.is_none());
     }
 
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_timeout_cleanup() {
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from("test_exec_and_wait_timeout_cleanup").unwrap(),
+            ddl_run: "trap 'echo partial > cleanup.txt; exit 143' TERM; sleep 30 & wait $!".into(),
+            params: RunParams::new(),
+            timeout: Some(1),
+            options: ExecAndWaitOptions::default(),
+            inputs: &mut [],
+        };
+
+        let start = std::time::Instant::now();
+        let zip = ask_exec_zip(main_rocket(), &req);
+        // stopped by its trap, before the end of the grace period
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let exec_info = extract_exec_info(&zip);
+        assert_eq!(exec_info.status, "KO");
+        assert_eq!(exec_info.error, Some("IPOLTimeoutError".into()));
+        assert_eq!(read_zip_file(&zip, "cleanup.txt"), "partial\n");
+    }
+
     #[test]
     #[tracing_test::traced_test]
     fn test_exec_and_wait_run_time() {
@@ -589,15 +2721,2470 @@ mod test {
             ddl_run: "sleep 2".into(),
             params: RunParams::new(),
             timeout: Some(10),
+            options: ExecAndWaitOptions::default(),
             inputs: &mut [],
         };
 
-        let exec_info = ask_exec(&req);
+        let zip = ask_exec_zip(main_rocket(), &req);
+        let exec_info = extract_exec_info(&zip);
         assert_eq!(exec_info.status, "OK");
         assert_eq!(exec_info.key, req.key);
         assert_eq!(exec_info.params, req.params);
         assert_eq!(exec_info.error, None);
         assert_eq!(exec_info.algo_info.error_message, None);
         assert!(exec_info.algo_info.run_time > Some(1.5));
+
+        let timing = read_zip_file(&zip, "timing.json");
+        let timing = serde_json::from_str::<Timing>(&timing).unwrap();
+        assert_eq!(timing.source, timing::TimingSource::Docker);
+        let run_time = exec_info.algo_info.run_time.unwrap();
+        assert!((run_time - timing.run_time).abs() < 1e-6);
+        assert!(timing.monotonic_run_time >= timing.run_time);
+        // at least the creation, the start and the inspection of the container
+        assert!(timing.docker_api_seconds > 0.0);
+        assert!(timing.docker_api_seconds < timing.monotonic_run_time);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_max_logfile_bytes() {
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from("test_exec_and_wait_max_logfile_bytes").unwrap(),
+            ddl_run: "yes | head -c 100000".into(),
+            params: RunParams::new(),
+            timeout: Some(10),
+            options: ExecAndWaitOptions::default(),
+            inputs: &mut [],
+        };
+
+        let figment = rocket::Config::figment()
+            .merge(("max_logfile_bytes", 1000))
+            .merge(("logfile_truncation", "head_tail"));
+        let zip = ask_exec_zip(rocket_from_figment(figment), &req);
+        assert_eq!(extract_exec_info(&zip).status, "OK");
+
+        let stdout = read_zip_file(&zip, "stdout.txt");
+        let marker = "\n[ipol-demorunner: 99000 bytes of log truncated]\n";
+        assert!(stdout.contains(marker));
+        assert_eq!(stdout.len(), 1000 + marker.len());
+        assert!(stdout.starts_with("y\ny\n"));
+        assert!(stdout.ends_with("y\ny\n"));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_node_disk_full() {
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from("test_exec_and_wait_node_disk_full").unwrap(),
+            ddl_run: "true".into(),
+            params: RunParams::new(),
+            timeout: Some(10),
+            options: ExecAndWaitOptions::default(),
+            inputs: &mut [],
+        };
+
+        let figment = rocket::Config::figment().merge(("disk_space_floor", u64::MAX));
+        let exec_info = extract_exec_info(&ask_exec_zip(rocket_from_figment(figment), &req));
+        assert_eq!(exec_info.status, "KO");
+        assert_eq!(exec_info.error, Some("IPOLNodeDiskFull".into()));
+        // no run in progress can free that much
+        assert_eq!(exec_info.retry_hint_seconds, Some(60));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_retry_after() {
+        let figment = rocket::Config::figment()
+            .merge(("disk_space_floor", u64::MAX))
+            .merge(("retry_hint_default", 42));
+        let client = Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+        let uri = "/exec_and_wait/t001?key=test_exec_and_wait_retry_after&ddl_run=true";
+        let response = client.post(uri).header(ContentType::Form).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Retry-After"), Some("42"));
+        let exec_info = extract_exec_info(&response.into_bytes().unwrap());
+        assert_eq!(exec_info.retry_hint_seconds, Some(42));
+
+        // only the refused runs get a hint
+        let response = client
+            .post("/exec_and_wait/t001?key=test_exec_and_wait_retry_after&ddl_run=")
+            .header(ContentType::Form)
+            .dispatch();
+        assert_eq!(response.headers().get_one("Retry-After"), None);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_gpus() {
+        use crate::queue::ExecutionSlots;
+
+        let figment = rocket::Config::figment()
+            .merge(("gpus", serde_json::json!(["0", "1"])))
+            .merge(("max_concurrent_executions", 1));
+        let client = Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+        let post = |gpus: &str| {
+            let uri = format!("/exec_and_wait/t001?key=test_exec_and_wait_gpus&ddl_run=true{gpus}");
+            client.post(uri).header(ContentType::Form).dispatch()
+        };
+
+        // refused before waiting for an execution slot
+        let slots = client.rocket().state::<ExecutionSlots>().unwrap();
+        let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+        let slot = runtime
+            .block_on(slots.acquire(5, "t001", "held", Duration::ZERO, Duration::ZERO))
+            .unwrap();
+        for (gpus, detail) in [
+            ("&gpus=3", "3 GPUs requested, only 2 configured"),
+            ("&gpus=device%3D0%2C7", "GPUs not in the configured pool: 7"),
+        ] {
+            let response = post(gpus);
+            assert_eq!(response.status(), Status::UnprocessableEntity);
+            let error: serde_json::Value = response.into_json().unwrap();
+            assert_eq!(error["error_code"], "invalid_request");
+            assert_eq!(error["detail"], detail);
+        }
+        drop(slot);
+
+        // GPUs held by other runs, the run fails before any docker call
+        let allocator = client.rocket().state::<GpuAllocator>().unwrap();
+        let pool = vec!["0".to_string(), "1".to_string()];
+        let demand = gpus::GpuDemand {
+            candidates: pool,
+            count: 1,
+        };
+        let first = runtime
+            .block_on(allocator.allocate(&demand, Duration::ZERO))
+            .unwrap();
+        assert_eq!(first.ids, vec!["0"]);
+        let response = post("&gpus=device%3D0");
+        assert_eq!(response.status(), Status::Ok);
+        let exec_info = extract_exec_info(&response.into_bytes().unwrap());
+        assert_eq!(exec_info.status, "KO");
+        assert_eq!(exec_info.error, Some("IPOLNoGpuAvailable".into()));
+        assert!(exec_info.gpus.is_empty());
+
+        // whether the host has GPUs or not, the free one is assigned
+        let exec_info = extract_exec_info(&post("").into_bytes().unwrap());
+        assert_eq!(exec_info.gpus, vec!["1"]);
+        drop(first);
+    }
+
+    #[rocket::async_test]
+    async fn test_within_setup_deadline() {
+        let step = |latency| async move {
+            rocket::tokio::time::sleep(latency).await;
+            Ok::<_, ExecError>("started")
+        };
+
+        // a slow start finishing within the grace
+        let setup_deadline = Instant::now() + Duration::from_millis(500);
+        let result = within_setup_deadline(setup_deadline, step(Duration::from_millis(100))).await;
+        assert_eq!(result.unwrap(), "started");
+
+        // a start still in flight past the grace
+        let start = Instant::now();
+        let setup_deadline = start + Duration::from_millis(100);
+        let result = within_setup_deadline(setup_deadline, step(Duration::from_secs(5))).await;
+        assert!(matches!(result, Err(ExecError::StartupTimeout)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "IPOLTimeoutError: the container didn't start before the timeout"
+        );
+
+        // the errors of the step come first
+        let failing = async { Err::<(), _>(ExecError::Cancelled) };
+        let result = within_setup_deadline(Instant::now(), failing).await;
+        assert!(matches!(result, Err(ExecError::Cancelled)));
+    }
+
+    #[test]
+    fn test_retry_hint_seconds() {
+        let config = config::test_config();
+        assert_eq!(retry_hint_seconds(&config, None), config.retry_hint_default);
+        assert_eq!(retry_hint_seconds(&config, Some(Duration::ZERO)), 1);
+        assert_eq!(
+            retry_hint_seconds(&config, Some(Duration::from_millis(2100))),
+            3
+        );
+    }
+
+    #[test]
+    fn test_merge_params() {
+        let json = RunParams::from([
+            ("x".into(), ParamValue::PosInt(1)),
+            ("y".into(), ParamValue::String("json".into())),
+        ]);
+        let form = RunParams::from([
+            ("y".into(), ParamValue::String("form".into())),
+            ("z".into(), ParamValue::Bool(true)),
+        ]);
+
+        let mut report = ExecReport::default();
+        let params = merge_params(None, form.clone(), &mut report);
+        assert_eq!(params, form);
+        let params = merge_params(Some(json.clone()), RunParams::new(), &mut report);
+        assert_eq!(params, json);
+        assert!(report.warnings.is_empty());
+
+        let params = merge_params(Some(json), form, &mut report);
+        assert_eq!(
+            params,
+            RunParams::from([
+                ("x".into(), ParamValue::PosInt(1)),
+                ("y".into(), ParamValue::String("json".into())),
+                ("z".into(), ParamValue::Bool(true)),
+            ])
+        );
+        assert_eq!(
+            report.warnings,
+            vec!["parameters were given both as JSON and as param_ form fields, the JSON values were kept for: y"]
+        );
+    }
+
+    fn multipart_content_type() -> ContentType {
+        ContentType::new("multipart", "form-data").with_params(("boundary", "BOUNDARY"))
+    }
+
+    /// Body of a multipart form uploading `files`, given by filename and content.
+    fn multipart_files(files: &[(Option<&str>, &str)]) -> String {
+        files
+            .iter()
+            .map(|(filename, content)| {
+                let filename = filename.map_or(String::new(), |f| format!("; filename=\"{f}\""));
+                format!(
+                    "--BOUNDARY\r\n\
+                     Content-Disposition: form-data; name=\"files\"{filename}\r\n\
+                     Content-Type: text/plain\r\n\r\n\
+                     {content}\r\n"
+                )
+            })
+            .collect::<String>()
+            + "--BOUNDARY--\r\n"
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_failed_persistence() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        // the third input can't be saved over the directory created by the second
+        let files = [
+            (Some("x.txt"), "x"),
+            (Some("sub/y.txt"), "y"),
+            (Some("sub"), "z"),
+        ];
+        let uri = "/exec_and_wait/t001?key=test_exec_and_wait_failed_persistence&ddl_run=true&parameters=%7B%7D";
+        let response = client
+            .post(uri)
+            .header(multipart_content_type())
+            .body(multipart_files(&files))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let zip = response.into_bytes().unwrap();
+        assert_eq!(extract_exec_info(&zip).status, "KO");
+        // the inputs saved before the failure were removed
+        assert_eq!(zip_entries(&zip), vec!["exec_info.json"]);
+    }
+
+    #[test]
+    fn test_check_inputs() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let uri = "/exec_and_wait/t001?key=test_check_inputs&ddl_run=true";
+        let post = |files: &[(Option<&str>, &str)]| {
+            client
+                .post(uri)
+                .header(multipart_content_type())
+                .body(multipart_files(files))
+                .dispatch()
+        };
+
+        let response = post(&[(Some("a.txt"), "a"), (Some("b.txt"), "")]);
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let error = response.into_string().unwrap();
+        assert!(error.contains("input 1 (\"b.txt\") is empty"), "{error}");
+
+        let response = post(&[(None, "a")]);
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let error = response.into_string().unwrap();
+        assert!(error.contains("input 0 has no filename"), "{error}");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_input_too_large() {
+        let post = |figment: rocket::figment::Figment, files: &[(Option<&str>, &str)]| {
+            let client =
+                Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+            let uri = "/exec_and_wait/t001?key=test_exec_and_wait_input_too_large&ddl_run=true";
+            let response = client
+                .post(uri)
+                .header(multipart_content_type())
+                .body(multipart_files(files))
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            extract_exec_info(&response.into_bytes().unwrap())
+        };
+        let large = "x".repeat(2000);
+
+        let figment = rocket::Config::figment().merge(("max_input_file_bytes", 1000));
+        let exec_info = post(figment, &[(Some("a.txt"), "a"), (Some("b.txt"), &large)]);
+        assert_eq!(exec_info.status, "KO");
+        assert_eq!(exec_info.error, Some("IPOLInputTooLarge".into()));
+        assert_eq!(
+            exec_info.algo_info.error_message.unwrap(),
+            "IPOLInputTooLarge: input \"b.txt\" is 2000 bytes, above max_input_file_bytes (1000)"
+        );
+
+        let figment = rocket::Config::figment().merge(("max_total_input_bytes", 3000));
+        let exec_info = post(figment, &[(Some("a.txt"), &large), (Some("b.txt"), &large)]);
+        assert_eq!(exec_info.error, Some("IPOLInputTooLarge".into()));
+        let error_message = exec_info.algo_info.error_message.unwrap();
+        assert!(
+            error_message.contains("4000 bytes with \"b.txt\""),
+            "{error_message}"
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_stdin() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let post = |query: &str, files: &[(Option<&str>, &str)]| {
+            let uri =
+                format!("/exec_and_wait/t001?key=test_exec_and_wait_stdin&timeout=10&{query}");
+            client
+                .post(uri)
+                .header(multipart_content_type())
+                .body(multipart_files(files))
+                .dispatch()
+        };
+
+        let response = post("ddl_run=cat%20%3E%20out.txt&stdin=hello", &[]);
+        assert_eq!(response.status(), Status::Ok);
+        let zip = response.into_bytes().unwrap();
+        assert_eq!(extract_exec_info(&zip).status, "OK");
+        assert_eq!(read_zip_file(&zip, "out.txt"), "hello");
+
+        // the run sees the end of the input
+        let files = [(Some("input.txt"), "a\nb\n")];
+        let response = post("ddl_run=wc%20-l&stdin_file=input.txt", &files);
+        let zip = response.into_bytes().unwrap();
+        assert_eq!(extract_exec_info(&zip).status, "OK");
+        assert_eq!(read_zip_file(&zip, "stdout.txt").trim(), "2");
+
+        // a run ignoring its input
+        let response = post("ddl_run=echo%20done&stdin=ignored", &[]);
+        let zip = response.into_bytes().unwrap();
+        assert_eq!(extract_exec_info(&zip).status, "OK");
+        assert_eq!(read_zip_file(&zip, "stdout.txt"), "done\n");
+
+        let response = post("ddl_run=cat&stdin_file=../../etc/passwd", &files);
+        let exec_info = extract_exec_info(&response.into_bytes().unwrap());
+        assert_eq!(exec_info.status, "KO");
+        assert!(exec_info.error.unwrap().contains("invalid stdin_file"));
+
+        let response = post("ddl_run=cat&stdin=a&stdin_file=input.txt", &files);
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_params_schema() {
+        let figment = rocket::Config::figment()
+            .merge(("demos.t001.defaults", serde_json::json!({"sigma": 500})));
+        let client = Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+        let post = |parameters: serde_json::Value| {
+            let schema = serde_json::json!({
+                "sigma": {"min": 0, "max": 100},
+                "scale": {"min": 1, "max": 4, "step": 0.5},
+            });
+            let uri = format!(
+                "/exec_and_wait/t001?key=test_exec_and_wait_params_schema&ddl_run=true\
+                 &timeout=10&parameters={}&params_schema={}",
+                rocket::http::RawStr::new(&parameters.to_string()).percent_encode(),
+                rocket::http::RawStr::new(&schema.to_string()).percent_encode(),
+            );
+            client.post(uri).header(ContentType::Form).dispatch()
+        };
+
+        let response = post(serde_json::json!({"sigma": 15, "scale": 2.5}));
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            extract_exec_info(&response.into_bytes().unwrap()).status,
+            "OK"
+        );
+
+        // the defaults of the demo are checked too
+        let response = post(serde_json::json!({"scale": 2.25}));
+        assert_eq!(response.status(), Status::BadRequest);
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["error_code"], "invalid_params");
+        assert_eq!(
+            body["errors"],
+            serde_json::json!([
+                {"field": "scale", "message": "2.25 is not a multiple of 0.5 from 1"},
+                {"field": "sigma", "message": "500 is above the maximum 100"},
+            ])
+        );
+        assert!(body["detail"].as_str().unwrap().contains("sigma: 500"));
+    }
+
+    fn ask_exec_argv(key: &str, argv: &[&str], params: RunParams) -> (Status, Vec<u8>) {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let options = ExecAndWaitOptions {
+            ddl_run_exec: Some(serde_json::to_string(argv).unwrap()),
+            ..Default::default()
+        };
+        let uri = uri!(super::http::exec_and_wait(
+            demo_id = DemoID::try_from("t001").unwrap(),
+            key = RunKey::try_from(key).unwrap(),
+            ddl_run = _,
+            parameters = &params,
+            params_schema = _,
+            timeout = Some(10),
+            options = &options,
+        ));
+        let response = client.post(uri).header(ContentType::Form).dispatch();
+        (response.status(), response.into_bytes().unwrap())
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_ddl_run_exec() {
+        // an explicit shell still interprets its script
+        let params = RunParams::from([("x".into(), ParamValue::String("a b*".into()))]);
+        let argv = ["/bin/sh", "-c", "echo \"$x\" > out.txt"];
+        let (status, zip) = ask_exec_argv("test_exec_and_wait_ddl_run_exec_sh", &argv, params);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(extract_exec_info(&zip).status, "OK");
+        assert_eq!(read_zip_file(&zip, "out.txt"), "a b*\n");
+
+        // no globbing, no redirection and no expansion without it
+        let argv = ["/bin/echo", "*.txt", ">", "$x"];
+        let params = RunParams::from([("x".into(), ParamValue::PosInt(1))]);
+        let (status, zip) = ask_exec_argv("test_exec_and_wait_ddl_run_exec", &argv, params);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(extract_exec_info(&zip).status, "OK");
+        assert_eq!(read_zip_file(&zip, "stdout.txt"), "*.txt > $x\n");
+
+        let argv = ["/bin/sh", "-c", "exit 3"];
+        let (_, zip) = ask_exec_argv(
+            "test_exec_and_wait_ddl_run_exec_exit",
+            &argv,
+            RunParams::new(),
+        );
+        let exec_info = extract_exec_info(&zip);
+        assert_eq!(exec_info.status, "KO");
+        assert!(exec_info.error.unwrap().contains("Non-zero exit code (3)"));
+
+        let (status, _) = ask_exec_argv(
+            "test_exec_and_wait_ddl_run_exec_empty",
+            &[],
+            RunParams::new(),
+        );
+        assert_eq!(status, Status::UnprocessableEntity);
+
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        for query in [
+            "ddl_run=true&ddl_run_exec=%5B%22true%22%5D",
+            "ddl_run_exec=true",
+            "timeout=10",
+        ] {
+            let uri = format!("/exec_and_wait/t001?key=test_exec_and_wait_ddl_run_exec&{query}");
+            let response = client.post(uri).header(ContentType::Form).dispatch();
+            assert_eq!(response.status(), Status::UnprocessableEntity, "{query}");
+            let error: serde_json::Value = response.into_json().unwrap();
+            assert!(error["detail"].as_str().unwrap().contains("ddl_run"));
+        }
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_array_params() {
+        let params: RunParams = serde_json::from_str(r#"{"k": [[3, 5], 7, "a b"]}"#).unwrap();
+        let ask = |key: &str, figment: rocket::figment::Figment| {
+            let req = ExecAndWaitRequest {
+                demo_id: DemoID::try_from("t001").unwrap(),
+                key: RunKey::try_from(key).unwrap(),
+                ddl_run: "printf '%s' \"$k\" > k.txt".into(),
+                params: params.clone(),
+                timeout: Some(10),
+                options: ExecAndWaitOptions::default(),
+                inputs: &mut [],
+            };
+            let zip = ask_exec_zip(rocket_from_figment(figment), &req);
+            let exec_info = extract_exec_info(&zip);
+            assert_eq!(exec_info.status, "OK");
+            assert_eq!(exec_info.params["k"], params["k"]);
+            read_zip_file(&zip, "k.txt")
+        };
+
+        let k = ask("test_exec_and_wait_array_params", rocket::Config::figment());
+        assert_eq!(k, "[3,5] 7 a b");
+        let figment = rocket::Config::figment().merge(("demos.t001.param_array_format", "json"));
+        let k = ask("test_exec_and_wait_array_params_json", figment);
+        assert_eq!(k, r#"[[3,5],7,"a b"]"#);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_steps() {
+        let ask = |key: &str, steps: &[&str]| {
+            let req = ExecAndWaitRequest {
+                demo_id: DemoID::try_from("t001").unwrap(),
+                key: RunKey::try_from(key).unwrap(),
+                ddl_run: serde_json::to_string(steps).unwrap(),
+                params: RunParams::new(),
+                timeout: Some(10),
+                options: ExecAndWaitOptions::default(),
+                inputs: &mut [],
+            };
+            ask_exec_zip(main_rocket(), &req)
+        };
+
+        let steps = ["echo one > 1.txt", "echo two; exit 3", "echo three > 3.txt"];
+        let zip = ask("test_exec_and_wait_steps", &steps);
+        let exec_info = extract_exec_info(&zip);
+        assert_eq!(exec_info.status, "KO");
+        let error = exec_info.error.unwrap();
+        assert!(
+            error.starts_with("Non-zero exit code (3) at step 2: "),
+            "{error}"
+        );
+        let codes = exec_info
+            .algo_info
+            .steps
+            .iter()
+            .map(|status| (status.step, status.exit_code))
+            .collect::<Vec<_>>();
+        assert_eq!(codes, [(1, 0), (2, 3)]);
+        assert_eq!(read_zip_file(&zip, "1.txt"), "one\n");
+        let entries = zip_entries(&zip);
+        assert!(!entries.iter().any(|name| name == "3.txt"), "{entries:?}");
+        assert!(!entries.iter().any(|name| name.contains("ipol_steps")));
+
+        // the steps share the run directory, not the shell
+        let steps = [
+            "sleep 1; x=1; echo a > a.txt",
+            "test -z \"$x\" && cat a.txt",
+        ];
+        let zip = ask("test_exec_and_wait_steps_ok", &steps);
+        let exec_info = extract_exec_info(&zip);
+        assert_eq!(exec_info.status, "OK");
+        assert_eq!(read_zip_file(&zip, "stdout.txt"), "a\n");
+        let steps = exec_info.algo_info.steps;
+        assert_eq!(steps.len(), 2);
+        assert!(steps[0].run_time >= 0.9, "{steps:?}");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_shell() {
+        let ask = |key: &str, ddl_run: &str, figment: rocket::figment::Figment| {
+            let req = ExecAndWaitRequest {
+                demo_id: DemoID::try_from("t001").unwrap(),
+                key: RunKey::try_from(key).unwrap(),
+                ddl_run: ddl_run.into(),
+                params: RunParams::new(),
+                timeout: Some(10),
+                options: ExecAndWaitOptions::default(),
+                inputs: &mut [],
+            };
+            let zip = ask_exec_zip(rocket_from_figment(figment), &req);
+            assert_eq!(extract_exec_info(&zip).status, "OK");
+            read_zip_file(&zip, "stdout.txt")
+        };
+
+        let stdout = ask(
+            "test_exec_and_wait_shell_default",
+            "echo $0",
+            rocket::Config::figment(),
+        );
+        assert_eq!(stdout, "/bin/bash\n");
+        let figment = rocket::Config::figment().merge(("shell", ["/bin/sh", "-c"]));
+        let stdout = ask("test_exec_and_wait_shell", "echo $0", figment.clone());
+        assert_eq!(stdout, "/bin/sh\n");
+        // the shell of the demo wins
+        let figment = figment.merge(("demos.t001.shell", ["/bin/bash", "-c"]));
+        let stdout = ask("test_exec_and_wait_shell_demo", "echo $0", figment);
+        assert_eq!(stdout, "/bin/bash\n");
+
+        // the same command under sh -c and in the exec form
+        let figment = rocket::Config::figment().merge(("shell", ["/bin/sh", "-c"]));
+        let shell = ask(
+            "test_exec_and_wait_shell_sh",
+            "printf '%s\\n' a 'b c'",
+            figment,
+        );
+        let argv = ["printf", "%s\\n", "a", "b c"];
+        let (status, zip) = ask_exec_argv("test_exec_and_wait_shell_exec", &argv, RunParams::new());
+        assert_eq!(status, Status::Ok);
+        assert_eq!(read_zip_file(&zip, "stdout.txt"), shell);
+        assert_eq!(shell, "a\nb c\n");
+
+        let figment = rocket::Config::figment().merge(("shell", Vec::<String>::new()));
+        assert!(figment.extract::<config::Config>().is_err());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_empty_run() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let post = |query: &str| {
+            let uri = format!("/exec_and_wait/t001?key=test_exec_and_wait_empty_run&{query}");
+            client.post(uri).header(ContentType::Form).dispatch()
+        };
+
+        for ddl_run in ["ddl_run=", "ddl_run=%20%0A%09"] {
+            let response = post(ddl_run);
+            assert_eq!(response.status(), Status::UnprocessableEntity);
+            assert_eq!(response.content_type(), Some(ContentType::JSON));
+            let error: serde_json::Value = response.into_json().unwrap();
+            assert_eq!(error["error_code"], "invalid_request");
+            assert!(error["detail"].as_str().unwrap().contains("ddl_run"));
+        }
+
+        let response = post("ddl_run=%20&allow_empty_run=true");
+        assert_eq!(response.status(), Status::Ok);
+        let exec_info = extract_exec_info(&response.into_bytes().unwrap());
+        assert_eq!(exec_info.status, "OK");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_devices() {
+        let devices = serde_json::json!([
+            { "path_on_host": "/dev/null", "path_in_container": "/dev/ipol-null" }
+        ]);
+        let figment = rocket::Config::figment()
+            .merge(("demos.t001.devices", devices))
+            .merge((
+                "devices",
+                serde_json::json!([{ "path_on_host": "/dev/zero" }]),
+            ));
+        let client = Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+        let post = |query: &str| {
+            let uri = format!("/exec_and_wait/t001?key=test_exec_and_wait_devices&{query}");
+            client.post(uri).header(ContentType::Form).dispatch()
+        };
+
+        // test -c /dev/ipol-null && test -c /dev/zero
+        let response =
+            post("ddl_run=test%20-c%20%2Fdev%2Fipol-null%20%26%26%20test%20-c%20%2Fdev%2Fzero");
+        assert_eq!(response.status(), Status::Ok);
+        let exec_info = extract_exec_info(&response.into_bytes().unwrap());
+        assert_eq!(exec_info.status, "OK");
+        assert_eq!(exec_info.devices, vec!["/dev/zero", "/dev/ipol-null"]);
+
+        let response = post("ddl_run=true&devices=%2Fdev%2Fsda");
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let error: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(error["error_code"], "invalid_request");
+        assert!(error["detail"].as_str().unwrap().contains("devices"));
+    }
+
+    #[test]
+    fn test_check_output_size() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("a.txt"), "a".repeat(600)).unwrap();
+        std::fs::write(dir.path().join("sub/b.txt"), "b".repeat(600)).unwrap();
+
+        assert!(check_output_size(dir.path(), 0).is_ok());
+        assert!(check_output_size(dir.path(), 1200).is_ok());
+        assert!(matches!(
+            check_output_size(dir.path(), 1000),
+            Err(ExecError::OutputTooLarge(1200))
+        ));
+    }
+
+    #[test]
+    fn test_check_output_size_runner_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "a".repeat(600)).unwrap();
+        // only the files of the runner go over the limit
+        std::fs::write(dir.path().join("resources.json"), "r".repeat(600)).unwrap();
+        std::fs::write(dir.path().join("exec_info.json"), "e".repeat(600)).unwrap();
+        std::fs::write(dir.path().join("timing.json"), "t".repeat(600)).unwrap();
+        std::fs::write(dir.path().join("environment.txt"), "v".repeat(600)).unwrap();
+        assert!(check_output_size(dir.path(), 1000).is_ok());
+
+        // the same names written by the run count
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub/resources.json"), "r".repeat(600)).unwrap();
+        assert!(matches!(
+            check_output_size(dir.path(), 1000),
+            Err(ExecError::OutputTooLarge(1200))
+        ));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_output_too_large() {
+        let figment = rocket::Config::figment().merge(("max_output_bytes", 100_000));
+        let client = Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+        // head -c 200000 /dev/zero > big
+        let uri = "/exec_and_wait/t001?key=test_exec_and_wait_output_too_large\
+                   &ddl_run=head%20-c%20200000%20%2Fdev%2Fzero%20%3E%20big";
+        let response = client.post(uri).header(ContentType::Form).dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let error: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(error["error_code"], "output_too_large");
+        let detail = error["detail"].as_str().unwrap();
+        assert!(
+            detail.starts_with("IPOLOutputTooLarge: the results are 2"),
+            "{detail}"
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_zero_timeout() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let uri = "/exec_and_wait/t001?key=test_exec_and_wait_zero_timeout&ddl_run=true&timeout=0";
+        let response = client.post(uri).header(ContentType::Form).dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let error: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(error["error_code"], "invalid_request");
+        assert!(error["detail"].as_str().unwrap().contains("timeout"));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_demo_not_allowed() {
+        let figment = rocket::Config::figment()
+            .merge(("demo_allowlist", ["t001", "t002"]))
+            .merge(("demo_denylist", ["t002"]));
+        let client = Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+        let post = |demo_id: &str| {
+            let uri = format!(
+                "/exec_and_wait/{demo_id}?key=test_exec_and_wait_demo_not_allowed\
+                 &ddl_run=true&timeout=10"
+            );
+            client.post(uri).header(ContentType::Form).dispatch()
+        };
+
+        let response = post("t001");
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            extract_exec_info(&response.into_bytes().unwrap()).status,
+            "OK"
+        );
+        // denied, and missing from the allowlist
+        for demo_id in ["t002", "t003"] {
+            let response = post(demo_id);
+            assert_eq!(response.status(), Status::Forbidden);
+            let error: serde_json::Value = response.into_json().unwrap();
+            assert_eq!(error["error_code"], "demo_not_allowed");
+            assert!(error["detail"].as_str().unwrap().contains(demo_id));
+        }
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_inputs_accepted() {
+        // fail early, the inputs are counted anyway
+        let post = |figment: rocket::figment::Figment, files: &[(Option<&str>, &str)]| {
+            let figment = figment.merge(("disk_space_floor", u64::MAX));
+            let client =
+                Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+            let uri = "/exec_and_wait/t001?key=test_exec_and_wait_inputs_accepted&ddl_run=true";
+            let response = client
+                .post(uri)
+                .header(multipart_content_type())
+                .body(multipart_files(files))
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            extract_exec_info(&response.into_bytes().unwrap())
+        };
+
+        let exec_info = post(
+            rocket::Config::figment(),
+            &[(Some("a.txt"), "a"), (Some("b.txt"), "b")],
+        );
+        assert_eq!(exec_info.inputs_accepted, 2);
+        assert!(exec_info.warnings.is_empty());
+
+        let allow = rocket::Config::figment().merge(("allow_empty_inputs", true));
+        let exec_info = post(
+            allow,
+            &[(Some("a.txt"), "a"), (None, "b"), (Some("c.txt"), "")],
+        );
+        assert_eq!(exec_info.inputs_accepted, 2);
+        assert_eq!(
+            exec_info.warnings,
+            vec![
+                "input 1 has no filename, it was not saved",
+                "input 2 (\"c.txt\") is empty",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_dir() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        std::fs::write(tmpdir.path().join("a.txt"), "abc").unwrap();
+        std::fs::create_dir(tmpdir.path().join("b")).unwrap();
+        std::fs::write(tmpdir.path().join("b").join("c.txt"), "de").unwrap();
+
+        assert_eq!(empty_dir(tmpdir.path()).unwrap(), 5);
+        assert!(tmpdir.path().exists());
+        assert_eq!(std::fs::read_dir(tmpdir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_apply_defaults() {
+        let defaults = RunParams::from([
+            ("sigma".into(), ParamValue::Float(1.5)),
+            ("method".into(), ParamValue::String("fast".into())),
+        ]);
+        let mut params = RunParams::from([("sigma".into(), ParamValue::PosInt(3))]);
+
+        let applied = apply_defaults(&mut params, &defaults);
+        assert_eq!(applied, vec!["method"]);
+        assert_eq!(
+            params,
+            RunParams::from([
+                ("sigma".into(), ParamValue::PosInt(3)),
+                ("method".into(), ParamValue::String("fast".into())),
+            ])
+        );
+        assert!(apply_defaults(&mut params, &RunParams::new()).is_empty());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_defaults_applied() {
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from("test_exec_and_wait_defaults_applied").unwrap(),
+            ddl_run: "true".into(),
+            params: RunParams::from([("x".into(), ParamValue::PosInt(2))]),
+            timeout: Some(10),
+            options: ExecAndWaitOptions::default(),
+            inputs: &mut [],
+        };
+
+        // fail early, the parameters are echoed anyway
+        let figment = rocket::Config::figment()
+            .merge(("disk_space_floor", u64::MAX))
+            .merge((
+                "demos.t001.defaults",
+                RunParams::from([
+                    ("x".into(), ParamValue::PosInt(1)),
+                    ("y".into(), ParamValue::Bool(true)),
+                ]),
+            ));
+        let exec_info = extract_exec_info(&ask_exec_zip(rocket_from_figment(figment), &req));
+        assert_eq!(exec_info.defaults_applied, vec!["y"]);
+        assert_eq!(exec_info.params["x"], ParamValue::PosInt(2));
+        assert_eq!(exec_info.params["y"], ParamValue::Bool(true));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_form_params() {
+        // fail early, the parameters are echoed anyway
+        let figment = rocket::Config::figment().merge(("disk_space_floor", u64::MAX));
+        let client = Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+        let uri = "/exec_and_wait/t001?key=test_exec_and_wait_form_params&ddl_run=true\
+                   &parameters=%7B%22y%22%3A%22json%22%7D";
+        let response = client
+            .post(uri)
+            .header(ContentType::Form)
+            .body("param_w=1&param_x=-2&param_y=2.5&param_z=true&param_s=abc&param_v=007")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let exec_info = extract_exec_info(&response.into_bytes().unwrap());
+        assert_eq!(
+            exec_info.params,
+            RunParams::from([
+                ("w".into(), ParamValue::PosInt(1)),
+                ("x".into(), ParamValue::NegInt(-2)),
+                ("y".into(), ParamValue::String("json".into())),
+                ("z".into(), ParamValue::Bool(true)),
+                ("s".into(), ParamValue::String("abc".into())),
+                ("v".into(), ParamValue::String("007".into())),
+            ])
+        );
+        assert_eq!(exec_info.warnings.len(), 1);
+    }
+
+    fn zip_entries(zip: &[u8]) -> Vec<String> {
+        let reader = std::io::Cursor::new(zip);
+        let zip = zip::ZipArchive::new(reader).unwrap();
+        let mut names = zip.file_names().map(String::from).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_output_format() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let mut results = Vec::new();
+        for (format, content_type) in [("zip", ContentType::ZIP), ("tar", ContentType::TAR)] {
+            let uri = format!(
+                "/exec_and_wait/t001?key=test_exec_and_wait_output_format_{format}\
+                 &ddl_run=echo%20a%20%3E%20a.txt&output_format={format}"
+            );
+            let response = client.post(uri).header(ContentType::Form).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(response.content_type(), Some(content_type));
+            results.push(response.into_bytes().unwrap());
+        }
+        let names = |files: Vec<(String, String)>| {
+            files.into_iter().map(|(name, _)| name).collect::<Vec<_>>()
+        };
+        let zip_names = names(zip_files(&results[0]));
+        assert!(zip_names.contains(&"a.txt".to_string()), "{zip_names:?}");
+        assert!(zip_names.contains(&"exec_info.json".to_string()));
+        assert_eq!(names(tar_files(&results[1])), zip_names);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_debug_env() {
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from("test_exec_and_wait_debug_env").unwrap(),
+            ddl_run: "env | sort > env.txt".into(),
+            params: RunParams::from([
+                ("x".into(), ParamValue::PosInt(1)),
+                ("param space".into(), ParamValue::String("hi world".into())),
+            ]),
+            timeout: Some(10),
+            options: ExecAndWaitOptions {
+                debug_env: true,
+                ..Default::default()
+            },
+            inputs: &mut [],
+        };
+
+        let zip = ask_exec_zip(main_rocket(), &req);
+        assert_eq!(extract_exec_info(&zip).status, "OK");
+
+        let environment = read_zip_file(&zip, "environment.txt");
+        let container_env = read_zip_file(&zip, "env.txt");
+        assert!(environment.contains("x=1\n"));
+        assert!(environment.contains("IPOL_DEMOID=t001\n"));
+        for line in environment.lines() {
+            assert!(container_env.lines().any(|l| l == line), "{line}");
+        }
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_manifest() {
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from("test_exec_and_wait_manifest").unwrap(),
+            ddl_run: "printf '\\211PNG\\r\\n\\032\\nIHDR' > result.png; echo done > log.txt; \
+                      printf '\\000\\001\\002' > weights"
+                .into(),
+            params: RunParams::new(),
+            timeout: Some(10),
+            options: ExecAndWaitOptions {
+                manifest: true,
+                output_mode: Some(OutputMode::Diff),
+                ..Default::default()
+            },
+            inputs: &mut [],
+        };
+
+        let zip = ask_exec_zip(main_rocket(), &req);
+        assert_eq!(extract_exec_info(&zip).status, "OK");
+        assert!(zip_entries(&zip).contains(&"manifest.json".to_string()));
+        let manifest: Vec<manifest::ManifestEntry> =
+            serde_json::from_str(&read_zip_file(&zip, "manifest.json")).unwrap();
+        let content_type = |name: &str| {
+            let entry = manifest.iter().find(|entry| entry.name == name);
+            entry.map(|entry| entry.content_type.as_str())
+        };
+        assert_eq!(content_type("result.png"), Some("image/png"));
+        assert_eq!(content_type("log.txt"), Some("text/plain"));
+        assert_eq!(content_type("weights"), Some("application/octet-stream"));
+        assert_eq!(content_type("changes.json"), Some("text/plain"));
+        assert!(manifest.iter().all(|entry| entry.name != "manifest.json"));
+        let weights = manifest.iter().find(|entry| entry.name == "weights");
+        assert_eq!(weights.unwrap().size, 3);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_params_verbatim() {
+        let value = "$HOME `id` $(id) 'single' \"double\" back\\slash é字 \t end\n";
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from("test_exec_and_wait_params_verbatim").unwrap(),
+            ddl_run: "printf '%s' \"$s\" > s.txt".into(),
+            params: RunParams::from([("s".into(), ParamValue::String(value.into()))]),
+            timeout: Some(10),
+            options: ExecAndWaitOptions::default(),
+            inputs: &mut [],
+        };
+
+        let zip = ask_exec_zip(main_rocket(), &req);
+        assert_eq!(extract_exec_info(&zip).status, "OK");
+        assert_eq!(read_zip_file(&zip, "s.txt").as_bytes(), value.as_bytes());
+    }
+
+    #[test]
+    fn test_outside_changes() {
+        let changes = [
+            "/var",
+            "/var/tmp",
+            "/var/tmp/x",
+            "/workdir",
+            "/workdir/out.txt",
+            "/root",
+            "/root/.cache",
+        ];
+        let changes = changes.into_iter().map(String::from);
+        assert_eq!(
+            outside_changes(changes, "/workdir"),
+            vec!["/root/.cache", "/var/tmp/x"]
+        );
+        assert!(outside_changes(
+            ["/", "/workdir"].into_iter().map(String::from),
+            "/workdir/exec"
+        )
+        .is_empty());
+    }
+
+    fn strict_output_request(key: &str, ddl_run: &str) -> ExecAndWaitRequest<'static, 'static> {
+        ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from(key).unwrap(),
+            ddl_run: ddl_run.into(),
+            params: RunParams::new(),
+            timeout: Some(10),
+            options: ExecAndWaitOptions {
+                strict_output: Some(true),
+                ..Default::default()
+            },
+            inputs: &mut [],
+        }
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_strict_output_compliant() {
+        let req = strict_output_request("test_strict_output_compliant", "echo a > out.txt");
+        let exec_info = ask_exec(&req);
+        assert_eq!(exec_info.status, "OK");
+        assert_eq!(exec_info.outside_changes, Some(vec![]));
+        assert!(exec_info.warnings.is_empty());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_strict_output_outside() {
+        let req = strict_output_request("test_strict_output_outside", "echo a > /var/tmp/x");
+        let exec_info = ask_exec(&req);
+        assert_eq!(exec_info.status, "OK");
+        assert_eq!(exec_info.outside_changes, Some(vec!["/var/tmp/x".into()]));
+        assert!(exec_info.warnings[0].contains("/var/tmp/x"));
+
+        // the container of the first run may not be removed yet
+        let req = strict_output_request("test_strict_output_outside_fail", "echo a > /var/tmp/x");
+        let figment = rocket::Config::figment().merge(("strict_output_severity", "fail"));
+        let zip = ask_exec_zip(rocket_from_figment(figment), &req);
+        let exec_info = extract_exec_info(&zip);
+        assert_eq!(exec_info.status, "KO");
+        assert_eq!(exec_info.error.as_deref(), Some("strict_output_violated"));
+        assert_eq!(exec_info.outside_changes, Some(vec!["/var/tmp/x".into()]));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_resources() {
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from("test_exec_and_wait_resources").unwrap(),
+            // hold about 50MB for a few samples of the stats
+            ddl_run: "x=$(head -c 50000000 /dev/zero | tr '\\0' a); sleep 3; echo ${#x}".into(),
+            params: RunParams::new(),
+            timeout: Some(20),
+            options: ExecAndWaitOptions::default(),
+            inputs: &mut [],
+        };
+        let zip = ask_exec_zip(main_rocket(), &req);
+        assert_eq!(extract_exec_info(&zip).status, "OK");
+        let resources = read_zip_file(&zip, "resources.json");
+        let resources = serde_json::from_str::<Resources>(&resources).unwrap();
+        let peak_memory_bytes = resources.peak_memory_bytes.unwrap();
+        assert!(peak_memory_bytes > 40_000_000, "{peak_memory_bytes}");
+        assert!(peak_memory_bytes < 1_000_000_000, "{peak_memory_bytes}");
+        assert!(resources.cpu_seconds.unwrap() > 0.0);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_resource_headers() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        // long enough for a few samples of the stats
+        let uri = "/exec_and_wait/t001?key=test_exec_and_wait_resource_headers\
+                   &ddl_run=head%20-c%2010000000%20%2Fdev%2Furandom%20%7C%20md5sum%3B%20sleep%203";
+        let response = client.post(uri).header(ContentType::Form).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let header = |name| {
+            let value = response.headers().get_one(name).unwrap();
+            value.parse::<u64>().unwrap()
+        };
+        assert!(header("X-CPU-Usage-NS") > 0);
+        assert!(header("X-Peak-Memory-Bytes") > 0);
+
+        // not sampled when the run fails before starting
+        let figment = rocket::Config::figment().merge(("disk_space_floor", u64::MAX));
+        let client = Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+        let response = client.post(uri).header(ContentType::Form).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.headers().get_one("X-CPU-Usage-NS").is_none());
+        assert!(response.headers().get_one("X-Peak-Memory-Bytes").is_none());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_out_of_memory() {
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from("test_exec_and_wait_out_of_memory").unwrap(),
+            // hold about 200MB, well above the limit
+            ddl_run: "x=$(head -c 200000000 /dev/zero | tr '\\0' a); echo ${#x}".into(),
+            params: RunParams::new(),
+            timeout: Some(20),
+            options: ExecAndWaitOptions {
+                memory: Some(50),
+                ..Default::default()
+            },
+            inputs: &mut [],
+        };
+        let exec_info = ask_exec(&req);
+        assert_eq!(exec_info.status, "KO");
+        assert_eq!(exec_info.error.as_deref(), Some("IPOLMemoryError"));
+        assert_eq!(
+            exec_info.algo_info.error_message.as_deref(),
+            Some("IPOLMemoryError: the run exceeded its memory limit of 50 MB")
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_pids_limit() {
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from("test_exec_and_wait_pids_limit").unwrap(),
+            ddl_run: ":(){ :|:& };:".into(),
+            params: RunParams::new(),
+            timeout: Some(60),
+            options: ExecAndWaitOptions::default(),
+            inputs: &mut [],
+        };
+        let figment = rocket::Config::figment().merge(("pids_limit", 64));
+        let start = Instant::now();
+        let zip = ask_exec_zip(rocket_from_figment(figment), &req);
+        assert!(start.elapsed() < Duration::from_secs(30));
+        let exec_info = extract_exec_info(&zip);
+        assert_eq!(exec_info.status, "KO");
+        assert_eq!(exec_info.error.as_deref(), Some("IPOLPidsLimit"));
+        assert_eq!(
+            exec_info.algo_info.error_message.as_deref(),
+            Some("IPOLPidsLimit: the run was stopped for reaching its limit of 64 processes")
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_cancel_exec_unknown_key() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let response = client
+            .delete("/exec_and_wait/t001/test_cancel_exec_unknown_key")
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_cancel_exec() {
+        let start = Instant::now();
+        let run = std::thread::spawn(|| {
+            let req = ExecAndWaitRequest {
+                demo_id: DemoID::try_from("t001").unwrap(),
+                key: RunKey::try_from("test_cancel_exec").unwrap(),
+                ddl_run: "sleep 60".into(),
+                params: RunParams::new(),
+                timeout: Some(120),
+                options: ExecAndWaitOptions::default(),
+                inputs: &mut [],
+            };
+            ask_exec(&req)
+        });
+
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        loop {
+            let response = client
+                .delete("/exec_and_wait/t001/test_cancel_exec")
+                .dispatch();
+            if response.status() == Status::Ok {
+                break;
+            }
+            assert_eq!(response.status(), Status::NotFound);
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "the run didn't start"
+            );
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        let exec_info = run.join().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(30));
+        assert_eq!(exec_info.status, "KO");
+        assert_eq!(exec_info.error.as_deref(), Some("IPOLCancelled"));
+    }
+
+    #[rocket::async_test]
+    async fn test_exec_and_wait_max_concurrent_executions() {
+        use crate::queue::QueueDepth;
+        use rocket::local::asynchronous::Client;
+
+        let figment = rocket::Config::figment()
+            .merge(("max_concurrent_executions", 1))
+            .merge(("retry_hint_default", 42));
+        let client = Client::tracked(rocket_from_figment(figment))
+            .await
+            .expect("valid rocket instance");
+        let queue_depth = || async {
+            let response = client.get("/queue_depth").dispatch().await;
+            response.into_json::<QueueDepth>().await.unwrap().running
+        };
+        let first = client
+            .post("/exec_and_wait/t001?key=test_max_concurrent_executions_1&ddl_run=sleep%205&timeout=10")
+            .header(ContentType::Form)
+            .dispatch();
+        let second = async {
+            let start = Instant::now();
+            while queue_depth().await == 0 {
+                assert!(start.elapsed() < Duration::from_secs(5), "no slot taken");
+                rocket::tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            let response = client
+                .post("/exec_and_wait/t001?key=test_max_concurrent_executions_2&ddl_run=true")
+                .header(ContentType::Form)
+                .dispatch()
+                .await;
+            let retry_after = response.headers().get_one("Retry-After").map(String::from);
+            let status = response.status();
+            (status, retry_after, response.into_string().await.unwrap())
+        };
+        let (first, (status, retry_after, body)) = rocket::tokio::join!(first, second);
+        assert_eq!(first.status(), Status::Ok);
+        assert_eq!(status, Status::ServiceUnavailable);
+        // from the timeout of the run in progress, not `retry_hint_default`
+        let retry_after: u64 = retry_after.unwrap().parse().unwrap();
+        assert!((1..=10).contains(&retry_after), "{retry_after}");
+        assert!(body.contains("too_many_executions"), "{body}");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["retry_hint_seconds"], retry_after);
+        // released with the response
+        assert_eq!(queue_depth().await, 0);
+    }
+
+    #[rocket::async_test]
+    async fn test_exec_and_wait_demo_max_concurrent_executions() {
+        use crate::queue::QueueDepth;
+        use rocket::local::asynchronous::Client;
+
+        // no global limit
+        let figment = rocket::Config::figment().merge(("demos.t001.max_concurrent_executions", 1));
+        let client = Client::tracked(rocket_from_figment(figment))
+            .await
+            .expect("valid rocket instance");
+        let first = client
+            .post("/exec_and_wait/t001?key=test_demo_max_concurrent_executions_1&ddl_run=sleep%205")
+            .header(ContentType::Form)
+            .dispatch();
+        let second = async {
+            let start = Instant::now();
+            loop {
+                let response = client.get("/queue_depth").dispatch().await;
+                if response.into_json::<QueueDepth>().await.unwrap().running > 0 {
+                    break;
+                }
+                assert!(start.elapsed() < Duration::from_secs(5), "no slot taken");
+                rocket::tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            let response = client
+                .post("/exec_and_wait/t001?key=test_demo_max_concurrent_executions_2&ddl_run=true")
+                .header(ContentType::Form)
+                .dispatch()
+                .await;
+            (
+                response.status(),
+                response.into_json::<serde_json::Value>().await.unwrap(),
+            )
+        };
+        let (first, (status, error)) = rocket::tokio::join!(first, second);
+        assert_eq!(first.status(), Status::Ok);
+        assert_eq!(status, Status::ServiceUnavailable);
+        assert_eq!(error["error_code"], "too_many_executions");
+        assert_eq!(
+            error["detail"],
+            "1 executions of demo t001 already in progress"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_exec_and_wait_start_latency() {
+        use rocket::local::asynchronous::Client;
+
+        // the second run waits for the first one to end
+        let figment = rocket::Config::figment()
+            .merge(("max_concurrent_executions", 1))
+            .merge(("queue_wait", 10));
+        let client = Client::tracked(rocket_from_figment(figment))
+            .await
+            .expect("valid rocket instance");
+        let post = |key: &str, ddl_run: &str| {
+            let uri = format!("/exec_and_wait/t001?key={key}&ddl_run={ddl_run}");
+            client.post(uri).header(ContentType::Form).dispatch()
+        };
+        let first = post("test_exec_and_wait_start_latency_1", "sleep%202");
+        let second = async {
+            rocket::tokio::time::sleep(Duration::from_millis(500)).await;
+            post("test_exec_and_wait_start_latency_2", "true").await
+        };
+        let (first, second) = rocket::tokio::join!(first, second);
+        assert_eq!(first.status(), Status::Ok);
+        assert_eq!(second.status(), Status::Ok);
+        let header = second.headers().get_one("X-Start-Latency-Seconds");
+        let header = header.unwrap().parse::<f64>().unwrap();
+
+        let zip = second.into_bytes().await.unwrap();
+        let timing = read_zip_file(&zip, "timing.json");
+        let latency = serde_json::from_str::<Timing>(&timing)
+            .unwrap()
+            .start_latency
+            .unwrap();
+        assert!(
+            (header - latency.total).abs() < 1e-3,
+            "{header} {latency:?}"
+        );
+        // most of it waiting for the first run
+        assert!(latency.queue_wait > 1.0, "{latency:?}");
+        assert!(latency.docker_api > 0.0, "{latency:?}");
+        let components = [
+            latency.upload,
+            latency.queue_wait,
+            latency.inputs,
+            latency.docker_api,
+            latency.other,
+        ];
+        assert!(components.iter().all(|&component| component >= 0.0));
+        let sum = components.iter().sum::<f64>();
+        assert!((sum - latency.total).abs() < 0.01, "{latency:?}");
+        assert!(latency.queue_wait < latency.total);
+    }
+
+    #[rocket::async_test]
+    async fn test_exec_and_wait_queue_wait() {
+        use crate::queue::QueuedRun;
+        use rocket::local::asynchronous::Client;
+
+        let figment = rocket::Config::figment()
+            .merge(("max_concurrent_executions", 1))
+            .merge(("queue_wait", 30));
+        let client = Client::tracked(rocket_from_figment(figment))
+            .await
+            .expect("valid rocket instance");
+        let response = client
+            .post("/exec_and_wait/t001?key=test_queue_wait_invalid&ddl_run=true&priority=11")
+            .header(ContentType::Form)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+
+        let first = client
+            .post("/exec_and_wait/t001?key=test_queue_wait_1&ddl_run=sleep%203")
+            .header(ContentType::Form)
+            .dispatch();
+        let second = async {
+            rocket::tokio::time::sleep(Duration::from_millis(500)).await;
+            client
+                .post("/exec_and_wait/t001?key=test_queue_wait_2&ddl_run=true&priority=9")
+                .header(ContentType::Form)
+                .dispatch()
+                .await
+        };
+        let queue = async {
+            let start = Instant::now();
+            loop {
+                let response = client.get("/queue").dispatch().await;
+                let queue = response.into_json::<Vec<QueuedRun>>().await.unwrap();
+                if !queue.is_empty() {
+                    return queue;
+                }
+                assert!(start.elapsed() < Duration::from_secs(5), "nothing queued");
+                rocket::tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        let (first, second, queue) = rocket::tokio::join!(first, second, queue);
+        assert_eq!(
+            queue,
+            vec![QueuedRun {
+                demo_id: "t001".into(),
+                key: "test_queue_wait_2".into(),
+                priority: 9,
+            }]
+        );
+        assert_eq!(first.status(), Status::Ok);
+        // waited for the first run instead of being refused
+        assert_eq!(second.status(), Status::Ok);
+        let exec_info = extract_exec_info(&second.into_bytes().await.unwrap());
+        assert_eq!(exec_info.status, "OK");
+    }
+
+    fn ask_exec_network(key: &str, figment: rocket::figment::Figment) -> ExecInfo {
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from(key).unwrap(),
+            // curl may not be in the image
+            ddl_run: "timeout 10 bash -c 'exec 3<>/dev/tcp/github.com/443'".into(),
+            params: RunParams::new(),
+            timeout: Some(20),
+            options: ExecAndWaitOptions::default(),
+            inputs: &mut [],
+        };
+        extract_exec_info(&ask_exec_zip(rocket_from_figment(figment), &req))
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_network_none() {
+        let exec_info =
+            ask_exec_network("test_exec_and_wait_network_none", rocket::Config::figment());
+        assert_eq!(exec_info.status, "KO");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_network_bridge() {
+        let figment = rocket::Config::figment().merge(("network_mode", "bridge"));
+        let exec_info = ask_exec_network("test_exec_and_wait_network_bridge", figment);
+        assert_eq!(exec_info.status, "OK");
+
+        let figment = rocket::Config::figment().merge(("demos.t001.network_mode", "bridge"));
+        let exec_info = ask_exec_network("test_exec_and_wait_network_demo", figment);
+        assert_eq!(exec_info.status, "OK");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_network_disabled() {
+        let post = |figment: rocket::figment::Figment, query: &str| {
+            let figment = figment.merge(("network_mode", "bridge"));
+            let client =
+                Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+            // timeout 10 bash -c 'exec 3<>/dev/tcp/github.com/443'
+            let uri = format!(
+                "/exec_and_wait/t001?key=test_exec_and_wait_network_disabled&timeout=20\
+                 &ddl_run=timeout%2010%20bash%20-c%20%27exec%203%3C%3E%2Fdev%2Ftcp%2Fgithub.com%2F443%27\
+                 {query}"
+            );
+            let response = client.post(uri).header(ContentType::Form).dispatch();
+            (response.status(), response.into_bytes().unwrap())
+        };
+        let status = |(status, body): (Status, Vec<u8>)| {
+            assert_eq!(status, Status::Ok);
+            extract_exec_info(&body).status
+        };
+
+        let disabled = rocket::Config::figment().merge(("network_disabled", true));
+        assert_eq!(status(post(disabled.clone(), "")), "KO");
+        let enabled = rocket::Config::figment();
+        assert_eq!(
+            status(post(enabled.clone(), "&network_disabled=true")),
+            "KO"
+        );
+        assert_eq!(status(post(enabled, "")), "OK");
+
+        let (status, body) = post(disabled, "&network_disabled=false");
+        assert_eq!(status, Status::UnprocessableEntity);
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["error_code"], "invalid_request");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_readonly_rootfs() {
+        let ask = |key: &str, figment: rocket::figment::Figment| {
+            let req = ExecAndWaitRequest {
+                demo_id: DemoID::try_from("t001").unwrap(),
+                key: RunKey::try_from(key).unwrap(),
+                ddl_run: "for path in /usr/bin/x /var/tmp/x /tmp/x x; do \
+                          touch $path 2>/dev/null && echo $path >> written.txt; done; true"
+                    .into(),
+                params: RunParams::new(),
+                timeout: Some(10),
+                options: ExecAndWaitOptions::default(),
+                inputs: &mut [],
+            };
+            let zip = ask_exec_zip(rocket_from_figment(figment), &req);
+            assert_eq!(extract_exec_info(&zip).status, "OK");
+            read_zip_file(&zip, "written.txt")
+        };
+
+        let figment = rocket::Config::figment().merge(("readonly_rootfs", true));
+        let written = ask("test_exec_and_wait_readonly_rootfs", figment.clone());
+        assert_eq!(written, "/tmp/x\nx\n");
+
+        let figment = figment.merge(("demos.t001.readonly_rootfs", false));
+        let written = ask("test_exec_and_wait_readonly_rootfs_demo", figment);
+        assert_eq!(written, "/var/tmp/x\n/tmp/x\nx\n");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_capabilities() {
+        let ask = |key: &str, figment: rocket::figment::Figment| {
+            let req = ExecAndWaitRequest {
+                demo_id: DemoID::try_from("t001").unwrap(),
+                key: RunKey::try_from(key).unwrap(),
+                // capsh may not be in the image, the bounding set is what it prints
+                ddl_run: "grep CapBnd /proc/self/status > caps.txt".into(),
+                params: RunParams::new(),
+                timeout: Some(10),
+                options: ExecAndWaitOptions::default(),
+                inputs: &mut [],
+            };
+            let zip = ask_exec_zip(rocket_from_figment(figment), &req);
+            assert_eq!(extract_exec_info(&zip).status, "OK");
+            read_zip_file(&zip, "caps.txt")
+        };
+
+        let caps = ask("test_exec_and_wait_capabilities", rocket::Config::figment());
+        assert_eq!(caps, "CapBnd:\t0000000000000000\n");
+
+        let figment = rocket::Config::figment().merge(("demos.t001.cap_add", ["SYS_PTRACE"]));
+        let caps = ask("test_exec_and_wait_capabilities_demo", figment);
+        // CAP_SYS_PTRACE is the capability 19
+        assert_eq!(caps, "CapBnd:\t0000000000080000\n");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_docker_runtime() {
+        let ask = |key: &str, figment: rocket::figment::Figment| {
+            let req = ExecAndWaitRequest {
+                demo_id: DemoID::try_from("t001").unwrap(),
+                key: RunKey::try_from(key).unwrap(),
+                ddl_run: "true".into(),
+                params: RunParams::new(),
+                timeout: Some(10),
+                options: ExecAndWaitOptions::default(),
+                inputs: &mut [],
+            };
+            extract_exec_info(&ask_exec_zip(rocket_from_figment(figment), &req))
+        };
+
+        // the default runtime of docker
+        let exec_info = ask(
+            "test_exec_and_wait_docker_runtime",
+            rocket::Config::figment(),
+        );
+        assert_eq!(exec_info.status, "OK");
+
+        let figment = rocket::Config::figment().merge(("docker_runtime", "ipol-missing-runtime"));
+        let exec_info = ask("test_exec_and_wait_docker_runtime_missing", figment.clone());
+        assert_eq!(exec_info.status, "KO");
+        assert_eq!(exec_info.error.as_deref(), Some("IPOLRuntimeUnavailable"));
+        assert_eq!(
+            exec_info.algo_info.error_message.as_deref(),
+            Some("IPOLRuntimeUnavailable: runtime ipol-missing-runtime not available")
+        );
+
+        let figment = figment.merge(("demos.t001.docker_runtime", "runc"));
+        let exec_info = ask("test_exec_and_wait_docker_runtime_demo", figment);
+        assert_eq!(exec_info.status, "OK");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_tmpfs() {
+        let figment = rocket::Config::figment()
+            .merge(("tmpfs", serde_json::json!({ "size_mb": 16 })))
+            // along with the tmpfs on /tmp
+            .merge(("readonly_rootfs", true));
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from("test_exec_and_wait_tmpfs").unwrap(),
+            ddl_run: "head -c 1000000 /dev/zero > /scratch/intermediate.raw \
+                      && touch /tmp/x && ls /scratch > listing.txt"
+                .into(),
+            params: RunParams::new(),
+            timeout: Some(10),
+            options: ExecAndWaitOptions::default(),
+            inputs: &mut [],
+        };
+        let zip = ask_exec_zip(rocket_from_figment(figment), &req);
+        assert_eq!(extract_exec_info(&zip).status, "OK");
+        assert_eq!(read_zip_file(&zip, "listing.txt"), "intermediate.raw\n");
+        let entries = zip_entries(&zip);
+        assert!(
+            entries.iter().all(|name| !name.contains("intermediate")),
+            "{entries:?}"
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_extra_binds() {
+        let dataset = tempfile::tempdir().unwrap();
+        std::fs::write(dataset.path().join("weights.txt"), "0.5 0.25").unwrap();
+        let extra_binds = serde_json::json!([
+            { "host_path": dataset.path(), "container_path": "/data" }
+        ]);
+        let figment = rocket::Config::figment().merge(("demos.t001.extra_binds", extra_binds));
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from("test_exec_and_wait_extra_binds").unwrap(),
+            ddl_run: "cp /data/weights.txt .; touch /data/x 2>/dev/null && touch writable; true"
+                .into(),
+            params: RunParams::new(),
+            timeout: Some(10),
+            options: ExecAndWaitOptions::default(),
+            inputs: &mut [],
+        };
+        let zip = ask_exec_zip(rocket_from_figment(figment), &req);
+        assert_eq!(extract_exec_info(&zip).status, "OK");
+        assert_eq!(read_zip_file(&zip, "weights.txt"), "0.5 0.25");
+        assert!(!zip_entries(&zip).contains(&"writable".to_string()));
+        assert!(!dataset.path().join("x").exists());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_ulimits() {
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from("test_exec_and_wait_ulimits").unwrap(),
+            ddl_run: "ulimit -n > nofile.txt; ulimit -Hn >> nofile.txt".into(),
+            params: RunParams::new(),
+            timeout: Some(10),
+            options: ExecAndWaitOptions::default(),
+            inputs: &mut [],
+        };
+        let ulimits = serde_json::json!([{ "name": "nofile", "soft": 256, "hard": 512 }]);
+        let figment = rocket::Config::figment().merge(("ulimits", ulimits));
+        let zip = ask_exec_zip(rocket_from_figment(figment), &req);
+        assert_eq!(extract_exec_info(&zip).status, "OK");
+        assert_eq!(read_zip_file(&zip, "nofile.txt"), "256\n512\n");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_cpuset() {
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from("test_exec_and_wait_cpuset").unwrap(),
+            ddl_run: "nproc > nproc.txt".into(),
+            params: RunParams::new(),
+            timeout: Some(10),
+            options: ExecAndWaitOptions {
+                cpus: Some(0.5),
+                ..Default::default()
+            },
+            inputs: &mut [],
+        };
+        let figment = rocket::Config::figment().merge(("cpuset_cpus", "0"));
+        let zip = ask_exec_zip(rocket_from_figment(figment), &req);
+        let exec_info = extract_exec_info(&zip);
+        assert_eq!(exec_info.status, "OK");
+        assert_eq!(exec_info.cpuset.as_deref(), Some("0"));
+        assert_eq!(read_zip_file(&zip, "nproc.txt"), "1\n");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_memory_out_of_range() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let uri = format!(
+            "/exec_and_wait/t001?key=test_exec_and_wait_memory_out_of_range&ddl_run=true\
+             &timeout=10&memory={}",
+            u64::MAX / 1024
+        );
+        let response = client.post(uri).header(ContentType::Form).dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let error: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(error["error_code"], "invalid_request");
+        assert!(error["detail"].as_str().unwrap().contains("memory"));
+    }
+
+    fn ask_exec_with_exit_code_messages(key: &str, exit_code_messages: &str) -> ExecInfo {
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from(key).unwrap(),
+            ddl_run: "echo processing; exit 3".into(),
+            params: RunParams::new(),
+            timeout: Some(10),
+            options: ExecAndWaitOptions {
+                exit_code_messages: Some(exit_code_messages.into()),
+                ..Default::default()
+            },
+            inputs: &mut [],
+        };
+        extract_exec_info(&ask_exec_zip(main_rocket(), &req))
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_exit_code_mapped() {
+        let exec_info = ask_exec_with_exit_code_messages(
+            "test_exec_and_wait_exit_code_mapped",
+            r#"{"3": "the image is too dark"}"#,
+        );
+        assert_eq!(exec_info.status, "KO");
+        assert_eq!(exec_info.error, Some("algorithm_reported".into()));
+        assert_eq!(
+            exec_info.algo_info.error_message,
+            Some("3: the image is too dark".into())
+        );
+        assert!(exec_info.algo_info.detail.unwrap().contains("processing"));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_exit_code_unmapped() {
+        let exec_info = ask_exec_with_exit_code_messages(
+            "test_exec_and_wait_exit_code_unmapped",
+            r#"{"4": "the image is too bright"}"#,
+        );
+        assert_eq!(exec_info.status, "KO");
+        let error = exec_info.error.unwrap();
+        assert!(error.starts_with("Non-zero exit code (3)"), "{error}");
+        assert_eq!(exec_info.algo_info.detail, None);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_exit_code_messages_invalid() {
+        let exec_info = ask_exec_with_exit_code_messages(
+            "test_exec_and_wait_exit_code_messages_invalid",
+            r#"{"three": "the image is too dark"}"#,
+        );
+        assert_eq!(exec_info.status, "KO");
+        let error = exec_info.error.unwrap();
+        assert!(error.starts_with("invalid exit_code_messages"), "{error}");
+    }
+
+    #[test]
+    fn test_parse_exit_code_messages() {
+        assert!(parse_exit_code_messages(None).unwrap().is_empty());
+        let messages = parse_exit_code_messages(Some(r#"{"3": "dark", "-1": "crash"}"#)).unwrap();
+        assert_eq!(messages[&3], "dark");
+        assert_eq!(messages[&-1], "crash");
+        assert!(parse_exit_code_messages(Some("[3]")).is_err());
+        let huge = format!(r#"{{"3": "{}"}}"#, "a".repeat(EXIT_CODE_MESSAGES_MAX_BYTES));
+        assert!(matches!(
+            parse_exit_code_messages(Some(&huge)),
+            Err(ExecError::InvalidExitCodeMessages(_))
+        ));
+    }
+
+    fn ask_exec_with_criteria(key: &str, file: Option<&str>, output: Option<&str>) -> Vec<u8> {
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from(key).unwrap(),
+            // the shell swallows the failure of the first command
+            ddl_run: "ls missing; echo done > result.txt; echo finished".into(),
+            params: RunParams::new(),
+            timeout: Some(10),
+            options: ExecAndWaitOptions {
+                success_criteria: SuccessCriteria {
+                    file: file.map(String::from),
+                    output: output.map(String::from),
+                },
+                ..Default::default()
+            },
+            inputs: &mut [],
+        };
+        ask_exec_zip(main_rocket(), &req)
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_success_criteria() {
+        let zip = ask_exec_with_criteria(
+            "test_exec_and_wait_success_criteria",
+            Some("*.txt"),
+            Some("(?m)^finished$"),
+        );
+        assert_eq!(extract_exec_info(&zip).status, "OK");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_success_criteria_missing_file() {
+        let zip = ask_exec_with_criteria(
+            "test_exec_and_wait_success_criteria_missing_file",
+            Some("*.png"),
+            None,
+        );
+        let exec_info = extract_exec_info(&zip);
+        assert_eq!(exec_info.status, "KO");
+        assert_eq!(exec_info.error, Some("success_criteria_not_met".into()));
+        let message = exec_info.algo_info.error_message.unwrap();
+        assert!(message.contains("no file matches \"*.png\""), "{message}");
+        // the outputs are still returned
+        assert_eq!(read_zip_file(&zip, "result.txt"), "done\n");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_success_criteria_unmatched_output() {
+        let zip = ask_exec_with_criteria(
+            "test_exec_and_wait_success_criteria_unmatched_output",
+            None,
+            Some("(?m)^converged$"),
+        );
+        let exec_info = extract_exec_info(&zip);
+        assert_eq!(exec_info.status, "KO");
+        assert_eq!(exec_info.error, Some("success_criteria_not_met".into()));
+        let message = exec_info.algo_info.error_message.unwrap();
+        assert!(message.contains("the output doesn't match"), "{message}");
+    }
+
+    #[rocket::async_test]
+    async fn test_log_pump_slow_consumer() {
+        let polled = Arc::new(AtomicU64::new(0));
+        let logs = futures_util::stream::iter((0..1000).map(|i| {
+            Ok(LogOutput::StdOut {
+                message: format!("{i}\n").into(),
+            })
+        }))
+        .inspect({
+            let polled = polled.clone();
+            move |_| {
+                polled.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        let dropped_bytes = Arc::new(AtomicU64::new(0));
+        let (mut rx, _pump) = spawn_log_pump(logs, 10, dropped_bytes.clone());
+
+        // the stream is drained even though nothing is consumed yet
+        rocket::tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(polled.load(Ordering::Relaxed), 1000);
+
+        let mut received = Vec::new();
+        while let Some(log) = rx.recv().await {
+            rocket::tokio::time::sleep(Duration::from_millis(1)).await;
+            if let LogOutput::StdOut { message } = log {
+                received.push(String::from_utf8_lossy(&message).to_string());
+            }
+        }
+        // the head and the tail are preserved
+        assert_eq!(received.len(), 11);
+        assert_eq!(received.first().unwrap(), "0\n");
+        assert_eq!(received.last().unwrap(), "999\n");
+
+        let total_bytes = (0..1000).map(|i| format!("{i}\n").len()).sum::<usize>();
+        let received_bytes = received.iter().map(String::len).sum::<usize>();
+        assert_eq!(
+            dropped_bytes.load(Ordering::Relaxed),
+            (total_bytes - received_bytes) as u64
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_spawn_cleanup() {
+        let tmpdir = tempfile::TempDir::new().unwrap();
+        let path = tmpdir.path().to_path_buf();
+        for i in 0..20000 {
+            std::fs::write(path.join(format!("{i}.txt")), "").unwrap();
+        }
+
+        let cleanup = spawn_cleanup(tmpdir, Duration::from_secs(60), None);
+        // the removal happens in the background
+        assert!(path.exists());
+        cleanup.await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_timeout_from_deadline() {
+        let config = config::test_config();
+        let now = chrono::Utc::now();
+        let postprocess = chrono::TimeDelta::seconds(config.estimated_postprocess as i64);
+        let mut adjustments = Adjustments::default();
+        let mut timeout = |deadline: chrono::DateTime<chrono::Utc>| {
+            timeout_from_deadline(&config, &deadline.to_rfc3339(), now, &mut adjustments)
+        };
+
+        // generous deadline, capped to max_timeout
+        let deadline = now + chrono::TimeDelta::days(1);
+        assert_eq!(
+            timeout(deadline).unwrap(),
+            Duration::from_secs(config.max_timeout)
+        );
+
+        let deadline = now + postprocess + chrono::TimeDelta::seconds(3);
+        assert_eq!(timeout(deadline).unwrap(), Duration::from_secs(3));
+
+        // skewed clock: too close to the deadline, floored to min_timeout
+        let deadline = now + postprocess + chrono::TimeDelta::milliseconds(10);
+        assert_eq!(
+            timeout(deadline).unwrap(),
+            Duration::from_secs(config.min_timeout)
+        );
+
+        // already passed or unmeetable
+        let deadline = now - chrono::TimeDelta::seconds(1);
+        assert!(matches!(
+            timeout(deadline),
+            Err(ExecError::DeadlineExceeded)
+        ));
+        let deadline = now + postprocess;
+        assert!(matches!(
+            timeout(deadline),
+            Err(ExecError::DeadlineExceeded)
+        ));
+
+        let invalid = timeout_from_deadline(&config, "tomorrow", now, &mut Adjustments::default());
+        assert!(matches!(invalid, Err(ExecError::InvalidDeadline(_))));
+
+        // the capped and the floored deadlines
+        let reasons = adjustments
+            .into_inner()
+            .into_iter()
+            .map(|adjustment| (adjustment.field, adjustment.reason))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            vec![
+                ("deadline".into(), AdjustmentReason::ConfigMax),
+                ("deadline".into(), AdjustmentReason::ConfigMin)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_timeout() {
+        let config = config::test_config();
+        let max_timeout = config.max_timeout;
+        let mut adjustments = Adjustments::default();
+        assert_eq!(
+            resolve_timeout(&config, None, &mut adjustments),
+            Duration::from_secs(max_timeout)
+        );
+        assert_eq!(
+            resolve_timeout(&config, Some(1), &mut adjustments),
+            Duration::from_secs(1)
+        );
+        assert_eq!(adjustments.header(), None);
+
+        assert_eq!(
+            resolve_timeout(&config, Some(max_timeout + 1), &mut adjustments),
+            Duration::from_secs(max_timeout)
+        );
+        let adjustments = adjustments.into_inner();
+        assert_eq!(adjustments.len(), 1);
+        assert_eq!(adjustments[0].requested, (max_timeout + 1).to_string());
+        assert_eq!(adjustments[0].effective, max_timeout.to_string());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_adjustments() {
+        // fail early, the adjustments are reported anyway
+        let figment = rocket::Config::figment().merge(("disk_space_floor", u64::MAX));
+        let client = Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+        let deadline = chrono::Utc::now() + chrono::TimeDelta::days(1);
+        let deadline = deadline.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let uri = format!(
+            "/exec_and_wait/t001?key=test_exec_and_wait_adjustments&ddl_run=true\
+             &timeout=999999&deadline={deadline}"
+        );
+        let response = client.post(uri).header(ContentType::Form).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let header = response.headers().get_one("X-Adjustments").unwrap();
+        assert!(header.starts_with("deadline="));
+        assert!(header.contains(", timeout="));
+        // no docker call before the failure
+        let header = response.headers().get_one("X-Docker-API-Seconds");
+        assert_eq!(header, Some("0.000"));
+
+        let exec_info = extract_exec_info(&response.into_bytes().unwrap());
+        let fields = exec_info
+            .adjustments
+            .iter()
+            .map(|adjustment| (adjustment.field.as_str(), adjustment.reason))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            vec![
+                ("deadline", AdjustmentReason::ConfigMax),
+                ("timeout", AdjustmentReason::ConfigMax)
+            ]
+        );
+        assert_eq!(exec_info.adjustments[1].requested, "999999");
+    }
+
+    #[test]
+    fn test_get_docker_host_config_cpuset() {
+        let config = config::test_config();
+        let outdir = Path::new("/tmp/run");
+
+        let host_config = get_docker_host_config(&config, outdir, None, None, None, None);
+        assert_eq!(host_config.cpuset_cpus, None);
+        assert_eq!(host_config.cpuset_mems, None);
+
+        let cpuset = Cpuset {
+            cpus: "4-7".into(),
+            mems: Some("1".into()),
+        };
+        let host_config = get_docker_host_config(&config, outdir, Some(&cpuset), None, None, None);
+        assert_eq!(host_config.cpuset_cpus.as_deref(), Some("4-7"));
+        assert_eq!(host_config.cpuset_mems.as_deref(), Some("1"));
+        assert_eq!(host_config.binds, Some(vec!["/tmp/run:/workdir".into()]));
+        assert_eq!(host_config.memory, None);
+    }
+
+    #[test]
+    fn test_resolve_memory() {
+        let mut config = config::test_config();
+        let mut adjustments = Adjustments::default();
+        assert_eq!(resolve_memory(&config, None, &mut adjustments), None);
+        assert_eq!(
+            resolve_memory(&config, Some(100), &mut adjustments),
+            Some(100)
+        );
+
+        config.max_memory_mb = Some(1024);
+        assert_eq!(resolve_memory(&config, None, &mut adjustments), Some(1024));
+        assert_eq!(
+            resolve_memory(&config, Some(100), &mut adjustments),
+            Some(100)
+        );
+        assert_eq!(adjustments.header(), None);
+        assert_eq!(
+            resolve_memory(&config, Some(4096), &mut adjustments),
+            Some(1024)
+        );
+        assert_eq!(adjustments.header().unwrap(), "memory=1024");
+
+        let host_config =
+            get_docker_host_config(&config, Path::new("/tmp/run"), None, Some(100), None, None);
+        assert_eq!(host_config.memory, Some(100 * 1024 * 1024));
+        assert_eq!(host_config.memory_swap, host_config.memory);
+        config.swap_mb = 50;
+        let host_config =
+            get_docker_host_config(&config, Path::new("/tmp/run"), None, Some(100), None, None);
+        assert_eq!(host_config.memory, Some(100 * 1024 * 1024));
+        assert_eq!(host_config.memory_swap, Some(150 * 1024 * 1024));
+    }
+
+    #[test]
+    fn test_check_shm_size() {
+        let max = i64::MAX as u64 / (1024 * 1024);
+        assert!(check_shm_size(None).is_ok());
+        assert!(check_shm_size(Some(max)).is_ok());
+        assert!(check_shm_size(Some(max + 1)).is_err());
+        assert!(check_shm_size(Some(u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn test_resolve_shm_size() {
+        let mut config = config::test_config();
+        let mut adjustments = Adjustments::default();
+        assert_eq!(resolve_shm_size(&config, None, &mut adjustments), None);
+        assert_eq!(
+            resolve_shm_size(&config, Some(128), &mut adjustments),
+            Some(128)
+        );
+
+        config.shm_size_mb = Some(256);
+        assert_eq!(resolve_shm_size(&config, None, &mut adjustments), Some(256));
+        assert_eq!(adjustments.header(), None);
+        assert_eq!(
+            resolve_shm_size(&config, Some(1024), &mut adjustments),
+            Some(256)
+        );
+        assert_eq!(adjustments.header().unwrap(), "shm_size=256");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_and_wait_shm_size() {
+        let req = ExecAndWaitRequest {
+            demo_id: DemoID::try_from("t001").unwrap(),
+            key: RunKey::try_from("test_exec_and_wait_shm_size").unwrap(),
+            // above the 64MB of docker
+            ddl_run: "head -c 100000000 /dev/zero > /dev/shm/large".into(),
+            params: RunParams::new(),
+            timeout: Some(20),
+            options: ExecAndWaitOptions {
+                shm_size: Some(128),
+                ..Default::default()
+            },
+            inputs: &mut [],
+        };
+        let figment = rocket::Config::figment().merge(("shm_size_mb", 512));
+        let zip = ask_exec_zip(rocket_from_figment(figment), &req);
+        let exec_info = extract_exec_info(&zip);
+        assert_eq!(exec_info.status, "OK");
+        assert_eq!(exec_info.shm_size_mb, Some(128));
+
+        let req = ExecAndWaitRequest {
+            key: RunKey::try_from("test_exec_and_wait_shm_size_default").unwrap(),
+            options: ExecAndWaitOptions::default(),
+            ..req
+        };
+        let exec_info = ask_exec(&req);
+        assert_eq!(exec_info.status, "KO");
+        assert_eq!(exec_info.shm_size_mb, None);
+    }
+
+    #[test]
+    fn test_resolve_cpus() {
+        let mut config = config::test_config();
+        let mut adjustments = Adjustments::default();
+        assert_eq!(resolve_cpus(&config, None, &mut adjustments), None);
+        assert_eq!(
+            resolve_cpus(&config, Some(1.5), &mut adjustments),
+            Some(1.5)
+        );
+        assert_eq!(resolve_cpus(&config, Some(-1.0), &mut adjustments), None);
+
+        config.cpu_limit = Some(2.0);
+        assert_eq!(resolve_cpus(&config, None, &mut adjustments), Some(2.0));
+        assert_eq!(
+            resolve_cpus(&config, Some(0.5), &mut adjustments),
+            Some(0.5)
+        );
+        assert_eq!(adjustments.header(), None);
+        assert_eq!(
+            resolve_cpus(&config, Some(8.0), &mut adjustments),
+            Some(2.0)
+        );
+        assert_eq!(adjustments.header().unwrap(), "cpus=2");
+
+        let host_config =
+            get_docker_host_config(&config, Path::new("/tmp/run"), None, None, Some(0.5), None);
+        assert_eq!(host_config.nano_cpus, Some(500_000_000));
+        assert_eq!(host_config.cpu_quota, None);
+    }
+
+    #[test]
+    fn test_get_docker_host_config_cpu_quota() {
+        let mut config = config::test_config();
+        let outdir = Path::new("/tmp/run");
+        config.cpu_quota = Some(200_000);
+        let host_config = get_docker_host_config(&config, outdir, None, None, None, Some(256));
+        assert_eq!(host_config.cpu_quota, Some(200_000));
+        assert_eq!(host_config.cpu_period, None);
+        assert_eq!(host_config.cpu_shares, Some(256));
+
+        // the CPUs of the request lower the quota, without nano_cpus
+        config.cpu_period = Some(50_000);
+        let host_config = get_docker_host_config(&config, outdir, None, None, Some(0.5), None);
+        assert_eq!(host_config.cpu_quota, Some(25_000));
+        assert_eq!(host_config.cpu_period, Some(50_000));
+        assert_eq!(host_config.nano_cpus, None);
+        let host_config = get_docker_host_config(&config, outdir, None, None, Some(8.0), None);
+        assert_eq!(host_config.cpu_quota, Some(200_000));
+    }
+
+    #[test]
+    fn test_check_memory() {
+        let mut config = config::test_config();
+        let max = i64::MAX as u64 / (1024 * 1024);
+        assert!(check_memory(&config, None).is_ok());
+        assert!(check_memory(&config, Some(max)).is_ok());
+        assert!(check_memory(&config, Some(max + 1)).is_err());
+        assert!(check_memory(&config, Some(u64::MAX / 1024)).is_err());
+        assert_eq!(mb_to_bytes(max), Some((max * 1024 * 1024) as i64));
+        assert_eq!(mb_to_bytes(u64::MAX / 1024), None);
+
+        // the swap counts
+        config.swap_mb = 50;
+        let Err(ExecAndWaitInternalError::InvalidRequest(detail)) =
+            check_memory(&config, Some(max))
+        else {
+            panic!("a limit out of range was accepted");
+        };
+        assert_eq!(detail, format!("memory must be at most {} MB", max - 50));
+    }
+
+    #[test]
+    fn test_get_docker_binds_selinux() {
+        let mut config = config::test_config();
+        let outdir = Path::new("/tmp/run");
+        let binds = |config: &config::Config| get_docker_binds(config, outdir).unwrap();
+
+        config.selinux_relabel = config::SelinuxRelabel::Off;
+        assert_eq!(binds(&config), vec!["/tmp/run:/workdir"]);
+        config.selinux_relabel = config::SelinuxRelabel::Shared;
+        assert_eq!(binds(&config), vec!["/tmp/run:/workdir:z"]);
+        config.selinux_relabel = config::SelinuxRelabel::Private;
+        assert_eq!(binds(&config), vec!["/tmp/run:/workdir:Z"]);
+    }
+
+    #[test]
+    fn test_selinux_enforcing() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let enforce = tmpdir.path().join("enforce");
+        assert!(!selinux_enforcing(&enforce));
+        std::fs::write(&enforce, "0").unwrap();
+        assert!(!selinux_enforcing(&enforce));
+        std::fs::write(&enforce, "1").unwrap();
+        assert!(selinux_enforcing(&enforce));
+    }
+
+    #[test]
+    fn test_render_environment() {
+        let env = vec!["b=2".to_string(), "a=1".into(), "TOKEN=secret".into()];
+        let secrets = RunParams::from([("TOKEN".into(), ParamValue::String("secret".into()))]);
+        assert_eq!(
+            render_environment(&env, &secrets),
+            "TOKEN=<redacted>\na=1\nb=2\n"
+        );
+    }
+
+    #[test]
+    fn test_belongs_to_instance() {
+        let labels = HashMap::from([(config::INSTANCE_LABEL.to_string(), "prod".to_string())]);
+        assert!(belongs_to_instance(Some(&labels), "prod"));
+        assert!(!belongs_to_instance(Some(&labels), "staging"));
+        assert!(!belongs_to_instance(Some(&HashMap::new()), "prod"));
+        assert!(!belongs_to_instance(None, "prod"));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_zip_root() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        std::fs::write(tmpdir.path().join("a.txt"), "a").unwrap();
+        std::fs::create_dir(tmpdir.path().join("b")).unwrap();
+        std::fs::write(tmpdir.path().join("b").join("c.txt"), "c").unwrap();
+
+        let zip = zip_dir_into_bytes(tmpdir.path(), None, false, None, None).unwrap();
+        assert_eq!(zip_entries(&zip), vec!["a.txt", "b/", "b/c.txt"]);
+
+        let zip = zip_dir_into_bytes(tmpdir.path(), Some("t001_key"), false, None, None).unwrap();
+        assert_eq!(
+            zip_entries(&zip),
+            vec![
+                "t001_key/",
+                "t001_key/a.txt",
+                "t001_key/b/",
+                "t001_key/b/c.txt"
+            ]
+        );
+    }
+
+    fn zip_files(zip: &[u8]) -> Vec<(String, String)> {
+        let reader = std::io::Cursor::new(zip);
+        let mut zip = zip::ZipArchive::new(reader).unwrap();
+        let mut files = Vec::new();
+        for i in 0..zip.len() {
+            let file = zip.by_index(i).unwrap();
+            if file.is_file() {
+                let name = file.name().to_string();
+                files.push((name, std::io::read_to_string(file).unwrap()));
+            }
+        }
+        files.sort();
+        files
+    }
+
+    fn tar_files(tar: &[u8]) -> Vec<(String, String)> {
+        let mut tar = tar::Archive::new(tar);
+        let mut files = Vec::new();
+        for entry in tar.entries().unwrap() {
+            let entry = entry.unwrap();
+            if entry.header().entry_type().is_file() {
+                let name = entry.path().unwrap().to_str().unwrap().to_string();
+                files.push((name, std::io::read_to_string(entry).unwrap()));
+            }
+        }
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_tar_dir_into_bytes() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        std::fs::write(tmpdir.path().join("a.txt"), "a").unwrap();
+        std::fs::write(tmpdir.path().join("a:b.txt"), "ab").unwrap();
+        std::fs::create_dir(tmpdir.path().join("b")).unwrap();
+        std::fs::write(tmpdir.path().join("b").join("c.txt"), "c").unwrap();
+
+        let zip = zip_dir_into_bytes(tmpdir.path(), Some("t001_key"), true, None, None).unwrap();
+        let tar = tar_dir_into_bytes(tmpdir.path(), Some("t001_key"), true, None).unwrap();
+        let files = tar_files(&tar);
+        assert_eq!(files, zip_files(&zip));
+        let names = files
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "t001_key/a.txt",
+                "t001_key/a_b.txt",
+                "t001_key/b/c.txt",
+                "t001_key/zip_warnings.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_zip_compression_level() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let csv = (0..10_000).map(|i| format!("{i},{},{}\n", i * 2, i % 7));
+        std::fs::write(tmpdir.path().join("out.csv"), csv.collect::<String>()).unwrap();
+
+        let stored = zip_dir_into_bytes(tmpdir.path(), None, false, None, None).unwrap();
+        let deflated = zip_dir_into_bytes(tmpdir.path(), None, false, None, Some(6)).unwrap();
+        assert!(
+            deflated.len() * 2 < stored.len(),
+            "{} vs {}",
+            deflated.len(),
+            stored.len()
+        );
+        assert_eq!(zip_files(&deflated), zip_files(&stored));
+    }
+
+    #[test]
+    fn test_zip_normalize_filenames() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        std::fs::write(tmpdir.path().join("a:b.txt"), "a").unwrap();
+        std::fs::write(tmpdir.path().join("a?b.txt"), "a").unwrap();
+        std::fs::create_dir(tmpdir.path().join("out.")).unwrap();
+        std::fs::write(tmpdir.path().join("out.").join("c*.txt"), "c").unwrap();
+
+        let zip = zip_dir_into_bytes(tmpdir.path(), None, false, None, None).unwrap();
+        assert!(zip_entries(&zip).contains(&"a:b.txt".to_string()));
+
+        let zip = zip_dir_into_bytes(tmpdir.path(), Some("t001_key"), true, None, None).unwrap();
+        let mut entries = zip_entries(&zip);
+        entries.retain(|name| name.starts_with("t001_key/a_b"));
+        assert_eq!(entries, vec!["t001_key/a_b.txt", "t001_key/a_b_1.txt"]);
+        assert!(zip_entries(&zip).contains(&"t001_key/out/c_.txt".to_string()));
+
+        let warnings = read_zip_file(&zip, "t001_key/zip_warnings.txt");
+        assert_eq!(warnings.lines().count(), 4);
+        assert!(warnings.contains("renamed \"out./c*.txt\" to \"out/c_.txt\"\n"));
+    }
+
+    #[test]
+    fn test_zip_only_changed_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        std::fs::write(tmpdir.path().join("input_0.png"), "input").unwrap();
+        std::fs::write(tmpdir.path().join("stale.txt"), "stale").unwrap();
+        let before = Snapshot::take(tmpdir.path(), 1000).unwrap();
+
+        std::fs::remove_file(tmpdir.path().join("stale.txt")).unwrap();
+        std::fs::create_dir(tmpdir.path().join("out")).unwrap();
+        std::fs::write(tmpdir.path().join("out").join("result.png"), "result").unwrap();
+        let changed_files = save_changes(&before, tmpdir.path(), 1000).unwrap();
+
+        let zip =
+            zip_dir_into_bytes(tmpdir.path(), None, false, Some(&changed_files), None).unwrap();
+        let mut entries = zip_entries(&zip);
+        entries.sort();
+        assert_eq!(entries, vec!["changes.json", "out/result.png"]);
+        let changes = read_zip_file(&zip, "changes.json");
+        let changes = serde_json::from_str::<snapshot::Changes>(&changes).unwrap();
+        assert_eq!(changes.added, vec!["out/result.png"]);
+        assert_eq!(changes.deleted, vec!["stale.txt"]);
+    }
+
+    #[test]
+    fn test_zip_only_changed_files_normalized() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        std::fs::write(tmpdir.path().join("in:put.txt"), "input").unwrap();
+        let before = Snapshot::take(tmpdir.path(), 1000).unwrap();
+
+        std::fs::write(tmpdir.path().join("in?put.txt"), "result").unwrap();
+        std::fs::create_dir(tmpdir.path().join("out:dir")).unwrap();
+        std::fs::write(tmpdir.path().join("out:dir").join("r*.png"), "result").unwrap();
+        let changed_files = save_changes(&before, tmpdir.path(), 1000).unwrap();
+
+        let zip =
+            zip_dir_into_bytes(tmpdir.path(), None, true, Some(&changed_files), None).unwrap();
+        let mut entries = zip_entries(&zip);
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![
+                "changes.json",
+                "in_put.txt",
+                "out_dir/r_.png",
+                "zip_warnings.txt"
+            ]
+        );
+        // the unchanged input left out is neither renamed nor listed
+        let warnings = read_zip_file(&zip, "zip_warnings.txt");
+        let mut warnings = warnings.lines().collect::<Vec<_>>();
+        warnings.sort();
+        assert_eq!(
+            warnings,
+            vec![
+                "renamed \"in?put.txt\" to \"in_put.txt\"",
+                "renamed \"out:dir/r*.png\" to \"out_dir/r_.png\""
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_image_user() {
+        assert_eq!(
+            check_image_user(Some("ipol:ipol"), false).unwrap(),
+            "ipol:ipol"
+        );
+        assert_eq!(check_image_user(Some("1001"), false).unwrap(), "1001");
+        for root in [None, Some(""), Some("root"), Some("0:0")] {
+            assert!(matches!(
+                check_image_user(root, false),
+                Err(ExecError::RootImageUser(_))
+            ));
+        }
+        assert_eq!(check_image_user(None, true).unwrap(), "root");
+        assert_eq!(check_image_user(Some("0:0"), true).unwrap(), "0:0");
+    }
+
+    #[test]
+    fn test_demo_user_config() {
+        let figment = rocket::Config::figment()
+            .merge(("demos.t001.user", "image-default"))
+            .merge(("demos.t001.allow_root", true))
+            .merge(("demos.t002.user", "1001:1001"));
+        let config = figment.extract::<config::Config>().unwrap();
+        let demo = |id| config.demo(&DemoID::try_from(id).unwrap());
+        assert_eq!(demo("t001").user, Some(config::DemoUser::ImageDefault));
+        assert!(demo("t001").allow_root);
+        assert_eq!(
+            demo("t002").user,
+            Some(config::DemoUser::UidGid("1001:1001".into()))
+        );
+        assert_eq!(demo("t003").user, None);
+        assert!(!demo("t003").allow_root);
+    }
+
+    #[test]
+    fn test_expand_zip_root() {
+        let demo_id = DemoID::try_from("t001").unwrap();
+        let key = RunKey::try_from("abc").unwrap();
+        let expand = |template| expand_zip_root(template, &demo_id, &key);
+        assert_eq!(expand("{key}"), Some("abc".into()));
+        assert_eq!(expand("{demo_id}/{key}"), Some("t001/abc".into()));
+        assert_eq!(expand("../{key} x/"), Some("abc_x".into()));
+        assert_eq!(expand("/../."), None);
     }
 }
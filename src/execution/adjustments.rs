@@ -0,0 +1,84 @@
+use rocket::serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+
+/// Why a value of the request was not used as is.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AdjustmentReason {
+    /// Above a maximum of the config.
+    ConfigMax,
+    /// Below a minimum of the config.
+    ConfigMin,
+}
+
+/// A value of the request which was changed before the run.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct Adjustment {
+    /// Name of the request field.
+    pub field: String,
+    /// Value asked for by the client.
+    pub requested: String,
+    /// Value used for the run.
+    pub effective: String,
+    /// Why the value was changed.
+    pub reason: AdjustmentReason,
+}
+
+/// Collects the adjustments of the request, from every place clamping a value.
+#[derive(Debug, Default)]
+pub struct Adjustments(Vec<Adjustment>);
+
+impl Adjustments {
+    pub fn record(
+        &mut self,
+        field: &str,
+        requested: impl ToString,
+        effective: impl ToString,
+        reason: AdjustmentReason,
+    ) {
+        let adjustment = Adjustment {
+            field: field.into(),
+            requested: requested.to_string(),
+            effective: effective.to_string(),
+            reason,
+        };
+        tracing::debug!("adjusted the request: {adjustment:?}");
+        self.0.push(adjustment);
+    }
+
+    /// Compact form for the `X-Adjustments` header, `None` if nothing was adjusted.
+    pub fn header(&self) -> Option<String> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let header = self
+            .0
+            .iter()
+            .map(|adjustment| format!("{}={}", adjustment.field, adjustment.effective))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Some(header)
+    }
+
+    pub fn into_inner(self) -> Vec<Adjustment> {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let mut adjustments = Adjustments::default();
+        assert_eq!(adjustments.header(), None);
+
+        adjustments.record("timeout", 3600, 600, AdjustmentReason::ConfigMax);
+        adjustments.record("deadline", "x", "y", AdjustmentReason::ConfigMin);
+        assert_eq!(adjustments.header().unwrap(), "timeout=600, deadline=y");
+        let adjustments = adjustments.into_inner();
+        assert_eq!(adjustments[0].requested, "3600");
+        assert_eq!(adjustments[1].reason, AdjustmentReason::ConfigMin);
+    }
+}
@@ -0,0 +1,157 @@
+use std::path::Path;
+
+use regex::{Regex, RegexBuilder};
+use rocket::serde::Deserialize;
+
+/// Maximum size of a compiled output regex, which bounds the matching time.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Maximum number of entries of the run directory looked at for a file glob.
+const MAX_WALKED_ENTRIES: usize = 100_000;
+
+/// Conditions on the results of a run which exited with 0, for the demos whose
+/// scripts don't propagate the failures of the algorithm.
+#[derive(Debug, Clone, Default, FromForm, UriDisplayQuery, Deserialize)]
+pub struct SuccessCriteria {
+    /// Glob of a file which must exist in the run directory.
+    pub file: Option<String>,
+    /// Regex which must match the combined stdout and stderr.
+    pub output: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CriteriaError {
+    #[error("invalid success_criteria.file: {0}")]
+    InvalidGlob(#[from] glob::PatternError),
+    #[error("invalid success_criteria.output: {0}")]
+    InvalidRegex(#[from] regex::Error),
+    #[error("success_criteria_not_met: no file matches {0:?}")]
+    MissingFile(String),
+    #[error("success_criteria_not_met: the output doesn't match {0:?}")]
+    UnmatchedOutput(String),
+}
+
+#[derive(Debug)]
+pub struct CompiledCriteria {
+    file: Option<glob::Pattern>,
+    output: Option<Regex>,
+}
+
+impl SuccessCriteria {
+    /// Compiles the criteria, so that invalid ones are rejected before the run.
+    pub fn compile(&self) -> Result<CompiledCriteria, CriteriaError> {
+        let file = self.file.as_deref().map(glob::Pattern::new).transpose()?;
+        let output = self
+            .output
+            .as_deref()
+            .map(|re| RegexBuilder::new(re).size_limit(REGEX_SIZE_LIMIT).build())
+            .transpose()?;
+        Ok(CompiledCriteria { file, output })
+    }
+}
+
+impl CompiledCriteria {
+    /// Checks the criteria against the run directory and the output of the run.
+    pub fn check(&self, outdir: &Path, output: &str) -> Result<(), CriteriaError> {
+        if let Some(pattern) = &self.file {
+            let options = glob::MatchOptions {
+                require_literal_separator: true,
+                ..Default::default()
+            };
+            let found = walkdir::WalkDir::new(outdir)
+                .into_iter()
+                .filter_map(Result::ok)
+                .take(MAX_WALKED_ENTRIES)
+                .filter(|entry| entry.file_type().is_file())
+                .filter_map(|entry| entry.path().strip_prefix(outdir).ok().map(Path::to_owned))
+                .any(|path| pattern.matches_path_with(&path, options));
+            if !found {
+                return Err(CriteriaError::MissingFile(pattern.to_string()));
+            }
+        }
+        if let Some(regex) = &self.output {
+            if !regex.is_match(output) {
+                return Err(CriteriaError::UnmatchedOutput(regex.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn criteria(file: Option<&str>, output: Option<&str>) -> CompiledCriteria {
+        SuccessCriteria {
+            file: file.map(String::from),
+            output: output.map(String::from),
+        }
+        .compile()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_file_criterion() {
+        let outdir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(outdir.path().join("out")).unwrap();
+        std::fs::write(outdir.path().join("out/result.png"), "").unwrap();
+
+        assert!(criteria(Some("out/*.png"), None)
+            .check(outdir.path(), "")
+            .is_ok());
+        assert!(matches!(
+            criteria(Some("*.png"), None).check(outdir.path(), ""),
+            Err(CriteriaError::MissingFile(_))
+        ));
+        // directories don't count
+        assert!(matches!(
+            criteria(Some("out"), None).check(outdir.path(), ""),
+            Err(CriteriaError::MissingFile(_))
+        ));
+    }
+
+    #[test]
+    fn test_output_criterion() {
+        let outdir = tempfile::TempDir::new().unwrap();
+        let criteria = criteria(None, Some(r"(?m)^done in \d+ iterations$"));
+        assert!(criteria
+            .check(outdir.path(), "starting\ndone in 40 iterations")
+            .is_ok());
+        assert!(matches!(
+            criteria.check(outdir.path(), "starting\nSegmentation fault"),
+            Err(CriteriaError::UnmatchedOutput(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_criteria() {
+        let invalid = SuccessCriteria {
+            file: Some("[".into()),
+            output: None,
+        };
+        assert!(matches!(
+            invalid.compile(),
+            Err(CriteriaError::InvalidGlob(_))
+        ));
+
+        let invalid = SuccessCriteria {
+            file: None,
+            output: Some("(".into()),
+        };
+        assert!(matches!(
+            invalid.compile(),
+            Err(CriteriaError::InvalidRegex(_))
+        ));
+
+        // too large once compiled
+        let huge = SuccessCriteria {
+            file: None,
+            output: Some(r"(\w{1000}){1000}".into()),
+        };
+        assert!(matches!(
+            huge.compile(),
+            Err(CriteriaError::InvalidRegex(_))
+        ));
+    }
+}
@@ -0,0 +1,134 @@
+use std::path::{Path, PathBuf};
+
+/// Number of lines at the end of the output which are looked at for errors.
+const TAIL_LINES: usize = 50;
+
+/// errno messages which usually come from a wrong path in the demo scripts.
+const ERRNO_MESSAGES: &[&str] = &[
+    "No such file or directory",
+    "Permission denied",
+    "Not a directory",
+    "Is a directory",
+    "Read-only file system",
+];
+
+/// Explains the paths of the filesystem errors found in the output of a
+/// failed run, in terms of the run directory mounted at `workdir`.
+///
+/// `inputs` are the paths of the inputs saved into the run directory,
+/// relative to it.
+pub fn diagnostic_hints(output: &str, workdir: &str, inputs: &[PathBuf]) -> Vec<String> {
+    let workdir = Path::new(workdir);
+    let lines = output.lines().collect::<Vec<_>>();
+    let tail = &lines[lines.len().saturating_sub(TAIL_LINES)..];
+
+    let mut hints = Vec::new();
+    for line in tail {
+        if !ERRNO_MESSAGES.iter().any(|msg| line.contains(msg)) {
+            continue;
+        }
+        for path in paths_under(line, workdir) {
+            let Ok(relative) = path.strip_prefix(workdir) else {
+                continue;
+            };
+            let hint = if relative.as_os_str().is_empty() {
+                format!("hint: {path:?} is the run directory")
+            } else {
+                let origin = if inputs.iter().any(|input| input == relative) {
+                    "it is one of the inputs"
+                } else if inputs.iter().any(|input| input.starts_with(relative)) {
+                    "it contains some of the inputs"
+                } else {
+                    "it is not among the inputs"
+                };
+                format!("hint: {path:?} is {relative:?} in the run directory, {origin}")
+            };
+            if !hints.contains(&hint) {
+                hints.push(hint);
+            }
+        }
+    }
+    hints
+}
+
+/// Finds the absolute paths under `workdir` in a line of output, stripped of
+/// the quotes and punctuation surrounding them.
+fn paths_under(line: &str, workdir: &Path) -> Vec<PathBuf> {
+    line.split(|c: char| c.is_whitespace() || "'\"`‘’“”()[]{}<>,;".contains(c))
+        .map(|word| word.trim_end_matches([':', '.']))
+        .filter(|word| word.starts_with('/'))
+        .map(PathBuf::from)
+        .filter(|path| path.starts_with(workdir))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hints(output: &str) -> Vec<String> {
+        let inputs = [
+            PathBuf::from("input_0.png"),
+            PathBuf::from("sub/input_1.txt"),
+        ];
+        diagnostic_hints(output, "/workdir", &inputs)
+    }
+
+    #[test]
+    fn test_no_such_file() {
+        let output =
+            "python: can't open file '/workdir/main.py': [Errno 2] No such file or directory\n";
+        assert_eq!(
+            hints(output),
+            vec![
+                r#"hint: "/workdir/main.py" is "main.py" in the run directory, it is not among the inputs"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_permission_denied() {
+        let output = "bash: /workdir/input_0.png: Permission denied\n";
+        assert_eq!(
+            hints(output),
+            vec![
+                r#"hint: "/workdir/input_0.png" is "input_0.png" in the run directory, it is one of the inputs"#
+            ]
+        );
+
+        let output = "cp: cannot create regular file '/workdir/sub': Permission denied\n";
+        assert_eq!(
+            hints(output),
+            vec![
+                r#"hint: "/workdir/sub" is "sub" in the run directory, it contains some of the inputs"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_directory() {
+        let output = "touch: cannot touch '/workdir/': Read-only file system\n";
+        assert_eq!(
+            hints(output),
+            vec![r#"hint: "/workdir/" is the run directory"#]
+        );
+    }
+
+    #[test]
+    fn test_unrelated_errors() {
+        // paths outside of the run directory, or errors which are not about a path
+        let output = "\
+            bash: /usr/bin/foo: No such file or directory\n\
+            /workdir/run.sh: line 3: syntax error near unexpected token\n\
+            /workdirectory/a: No such file or directory\n";
+        assert!(hints(output).is_empty());
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let output = "\
+            cat: /workdir/a.txt: No such file or directory\n\
+            cat: /workdir/a.txt: No such file or directory\n";
+        assert_eq!(hints(output).len(), 1);
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::{HashMap, HashSet};
+
+/// Characters which are legal on Linux but not in Windows filenames.
+const FORBIDDEN_CHARACTERS: &str = "<>:\"|?*\\";
+
+/// Makes a single path component extractable on Windows: the forbidden and
+/// control characters become `_`, and the trailing dots and spaces are trimmed.
+fn normalize_component(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || FORBIDDEN_CHARACTERS.contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect::<String>();
+    let name = name.trim_end_matches(['.', ' ']);
+    if name.is_empty() {
+        "_".into()
+    } else {
+        name.into()
+    }
+}
+
+/// Adds a numeric suffix before the extension, `a.txt` becoming `a_1.txt`.
+fn with_suffix(name: &str, n: usize) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{stem}_{n}.{ext}"),
+        _ => format!("{name}_{n}"),
+    }
+}
+
+/// Normalizes the paths of the entries of a zip, in the order of a directory
+/// walk (a directory before its content), keeping the normalized paths unique.
+#[derive(Debug, Default)]
+pub struct FilenameNormalizer {
+    used: HashSet<String>,
+    /// Normalized path of each directory walked so far.
+    dirs: HashMap<String, String>,
+    renames: Vec<(String, String)>,
+}
+
+impl FilenameNormalizer {
+    pub fn normalize(&mut self, path: &str, is_dir: bool) -> String {
+        let (parent, name) = match path.rsplit_once('/') {
+            Some((parent, name)) => {
+                let normalized = match self.dirs.get(parent).cloned() {
+                    Some(normalized) => normalized,
+                    None => self.normalize_skipped_dir(parent),
+                };
+                (Some(normalized), name)
+            }
+            None => (None, path),
+        };
+        let join = |name: &str| match &parent {
+            Some(parent) => format!("{parent}/{name}"),
+            None => name.to_string(),
+        };
+
+        let name = normalize_component(name);
+        let mut normalized = join(&name);
+        let mut n = 1;
+        while self.used.contains(&normalized) {
+            normalized = join(&with_suffix(&name, n));
+            n += 1;
+        }
+        self.used.insert(normalized.clone());
+        if is_dir {
+            self.dirs.insert(path.to_string(), normalized.clone());
+        }
+        if normalized != path {
+            self.renames.push((path.to_string(), normalized.clone()));
+        }
+        normalized
+    }
+
+    /// Normalizes a directory left out of the zip, for the paths of its content,
+    /// without listing it in the renames.
+    fn normalize_skipped_dir(&mut self, path: &str) -> String {
+        let renames = self.renames.len();
+        let normalized = self.normalize(path, true);
+        self.renames.truncate(renames);
+        normalized
+    }
+
+    /// Lists the renames, one per line, for `zip_warnings.txt`.
+    pub fn warnings(&self) -> Option<String> {
+        if self.renames.is_empty() {
+            return None;
+        }
+        let warnings = self
+            .renames
+            .iter()
+            .map(|(from, to)| format!("renamed {from:?} to {to:?}\n"))
+            .collect();
+        Some(warnings)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize_component() {
+        assert_eq!(normalize_component("a:b?c*d"), "a_b_c_d");
+        assert_eq!(normalize_component("<a>|\"b\"\\"), "_a___b__");
+        assert_eq!(normalize_component("tab\there\n"), "tab_here_");
+        assert_eq!(normalize_component("result. . "), "result");
+        assert_eq!(normalize_component("..."), "_");
+        assert_eq!(normalize_component("fine-name.png"), "fine-name.png");
+    }
+
+    #[test]
+    fn test_collisions() {
+        let mut normalizer = FilenameNormalizer::default();
+        assert_eq!(normalizer.normalize("a:b.txt", false), "a_b.txt");
+        assert_eq!(normalizer.normalize("a?b.txt", false), "a_b_1.txt");
+        assert_eq!(normalizer.normalize("a_b.txt", false), "a_b_2.txt");
+        assert_eq!(normalizer.normalize("c.", false), "c");
+        assert_eq!(normalizer.normalize("c", false), "c_1");
+        assert_eq!(
+            normalizer.warnings().unwrap(),
+            "renamed \"a:b.txt\" to \"a_b.txt\"\n\
+             renamed \"a?b.txt\" to \"a_b_1.txt\"\n\
+             renamed \"a_b.txt\" to \"a_b_2.txt\"\n\
+             renamed \"c.\" to \"c\"\n\
+             renamed \"c\" to \"c_1\"\n"
+        );
+    }
+
+    #[test]
+    fn test_directories() {
+        let mut normalizer = FilenameNormalizer::default();
+        assert_eq!(normalizer.normalize("out:1", true), "out_1");
+        assert_eq!(normalizer.normalize("out:1/x?.png", false), "out_1/x_.png");
+        assert_eq!(normalizer.normalize("out", true), "out");
+        assert_eq!(normalizer.normalize("out/y.png", false), "out/y.png");
+        assert_eq!(normalizer.warnings().unwrap().lines().count(), 2);
+
+        // a directory left out is normalized for its content, without a rename of its own
+        let mut normalizer = FilenameNormalizer::default();
+        assert_eq!(normalizer.normalize("out:2/z?.png", false), "out_2/z_.png");
+        assert_eq!(normalizer.warnings().unwrap().lines().count(), 1);
+
+        let mut normalizer = FilenameNormalizer::default();
+        assert_eq!(normalizer.normalize("ok.txt", false), "ok.txt");
+        assert_eq!(normalizer.warnings(), None);
+    }
+}
@@ -0,0 +1,214 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use rocket::tokio::fs;
+use rocket::tokio::io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::config::LogfileTruncation;
+
+/// A log file on disk whose size is bounded by `max_bytes`.
+///
+/// Once the cap is reached, the remaining bytes are still accepted (so that the
+/// container stream keeps being consumed) but they are either discarded
+/// (`Truncate`), or only the last ones are kept to be written after the head of
+/// the log (`HeadTail`). The tail goes to a ring file, unlinked, next to the log:
+/// it does not grow the memory of the runner with `max_bytes`. A truncation
+/// marker is appended by `finish` whenever bytes were dropped.
+pub struct CappedLogFile {
+    file: fs::File,
+    dir: PathBuf,
+    max_bytes: Option<u64>,
+    strategy: LogfileTruncation,
+    written: u64,
+    dropped: u64,
+    ring: Option<fs::File>,
+    ring_written: u64,
+}
+
+impl CappedLogFile {
+    pub async fn create(
+        path: &Path,
+        max_bytes: Option<u64>,
+        strategy: LogfileTruncation,
+    ) -> std::io::Result<Self> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        Ok(Self {
+            file: fs::File::create(path).await?,
+            dir,
+            max_bytes,
+            strategy,
+            written: 0,
+            dropped: 0,
+            ring: None,
+            ring_written: 0,
+        })
+    }
+
+    fn head_capacity(&self) -> Option<u64> {
+        self.max_bytes.map(|max| match self.strategy {
+            LogfileTruncation::Truncate => max,
+            LogfileTruncation::HeadTail => max / 2,
+        })
+    }
+
+    fn tail_capacity(&self) -> u64 {
+        match (self.strategy, self.max_bytes) {
+            (LogfileTruncation::HeadTail, Some(max)) => max - max / 2,
+            _ => 0,
+        }
+    }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        let Some(head_capacity) = self.head_capacity() else {
+            self.written += buf.len() as u64;
+            return self.file.write_all(buf).await;
+        };
+
+        let room = head_capacity.saturating_sub(self.written) as usize;
+        let (head, rest) = buf.split_at(room.min(buf.len()));
+        if !head.is_empty() {
+            self.file.write_all(head).await?;
+            self.written += head.len() as u64;
+        }
+        self.write_tail(rest).await
+    }
+
+    /// Writes into the ring file, overwriting its oldest bytes once full.
+    async fn write_tail(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        let capacity = self.tail_capacity();
+        if buf.is_empty() || capacity == 0 {
+            self.dropped += buf.len() as u64;
+            return Ok(());
+        }
+        if self.ring.is_none() {
+            let ring = tempfile::tempfile_in(&self.dir)?;
+            self.ring = Some(fs::File::from_std(ring));
+        }
+        let ring = self.ring.as_mut().unwrap();
+
+        // only the last `capacity` bytes of the chunk can be kept
+        let skipped = buf.len().saturating_sub(capacity as usize);
+        let (skipped_bytes, mut buf) = buf.split_at(skipped);
+        self.ring_written += skipped_bytes.len() as u64;
+        while !buf.is_empty() {
+            let position = self.ring_written % capacity;
+            let len = buf.len().min((capacity - position) as usize);
+            ring.seek(SeekFrom::Start(position)).await?;
+            ring.write_all(&buf[..len]).await?;
+            self.ring_written += len as u64;
+            buf = &buf[len..];
+        }
+        self.dropped = self.ring_written.saturating_sub(capacity);
+        Ok(())
+    }
+
+    /// Writes the truncation marker and the kept tail (if any), and flushes the file.
+    pub async fn finish(mut self) -> std::io::Result<()> {
+        if self.dropped > 0 {
+            let marker = format!(
+                "\n[ipol-demorunner: {} bytes of log truncated]\n",
+                self.dropped
+            );
+            self.file.write_all(marker.as_bytes()).await?;
+        }
+        if let Some(mut ring) = self.ring.take() {
+            let capacity = self.tail_capacity();
+            // the oldest kept byte is at the write position once the ring is full
+            let (oldest, kept) = if self.ring_written > capacity {
+                (self.ring_written % capacity, capacity)
+            } else {
+                (0, self.ring_written)
+            };
+            let first = (capacity - oldest).min(kept);
+            ring.flush().await?;
+            ring.seek(SeekFrom::Start(oldest)).await?;
+            io::copy(&mut (&mut ring).take(first), &mut self.file).await?;
+            ring.seek(SeekFrom::Start(0)).await?;
+            io::copy(&mut ring.take(kept - first), &mut self.file).await?;
+        }
+        self.file.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn write_and_read(
+        max_bytes: Option<u64>,
+        strategy: LogfileTruncation,
+        chunks: &[&[u8]],
+    ) -> String {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("stdout.txt");
+        let mut log = CappedLogFile::create(&path, max_bytes, strategy)
+            .await
+            .unwrap();
+        for chunk in chunks {
+            log.write_all(chunk).await.unwrap();
+        }
+        log.finish().await.unwrap();
+        // the ring file of the tail is not left next to the log
+        assert_eq!(std::fs::read_dir(tmpdir.path()).unwrap().count(), 1);
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn test_uncapped() {
+        let content = write_and_read(None, LogfileTruncation::Truncate, &[b"abc", b"def"]).await;
+        assert_eq!(content, "abcdef");
+    }
+
+    #[rocket::async_test]
+    async fn test_truncate() {
+        let content = write_and_read(
+            Some(4),
+            LogfileTruncation::Truncate,
+            &[b"abc", b"def", b"g"],
+        )
+        .await;
+        assert_eq!(
+            content,
+            "abcd\n[ipol-demorunner: 3 bytes of log truncated]\n"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_head_tail() {
+        let content = write_and_read(
+            Some(4),
+            LogfileTruncation::HeadTail,
+            &[b"abc", b"def", b"g"],
+        )
+        .await;
+        assert_eq!(
+            content,
+            "ab\n[ipol-demorunner: 3 bytes of log truncated]\nfg"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_head_tail_ring() {
+        let content = write_and_read(
+            Some(6),
+            LogfileTruncation::HeadTail,
+            &[b"abcd", b"ef", b"ghij", b"k"],
+        )
+        .await;
+        assert_eq!(
+            content,
+            "abc\n[ipol-demorunner: 5 bytes of log truncated]\nijk"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_under_cap() {
+        let content =
+            write_and_read(Some(10), LogfileTruncation::HeadTail, &[b"abc", b"def"]).await;
+        assert_eq!(content, "abcdef");
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use bollard::container::{ListContainersOptions, LogOutput};
+use bollard::Docker;
+
+use crate::config;
+use crate::model::RunKey;
+
+/// Finds the container of a run of this instance, by the label holding its key.
+async fn find_run_container(
+    docker: &Docker,
+    config: &config::Config,
+    key: &RunKey,
+) -> Result<Option<String>, bollard::errors::Error> {
+    let instance_filter = config.instance_label_filter();
+    let key_filter = format!("{}={}", config::KEY_LABEL, key);
+    let filters = HashMap::from([
+        ("name", vec![config.docker_exec_prefix.as_str()]),
+        ("label", vec![instance_filter.as_str(), key_filter.as_str()]),
+    ]);
+    let containers = docker
+        .list_containers(Some(ListContainersOptions {
+            all: true,
+            filters,
+            ..Default::default()
+        }))
+        .await?;
+    Ok(containers.into_iter().find_map(|container| container.id))
+}
+
+/// Event of the stream of a log chunk, named after its output.
+fn log_event(log: LogOutput) -> Option<(&'static str, String)> {
+    let (stream, message) = match log {
+        LogOutput::StdOut { message } => ("stdout", message),
+        LogOutput::StdErr { message } => ("stderr", message),
+        LogOutput::StdIn { .. } | LogOutput::Console { .. } => return None,
+    };
+    let message = String::from_utf8_lossy(&message);
+    Some((stream, message.trim_end_matches('\n').to_string()))
+}
+
+pub mod http {
+    use bollard::container::LogsOptions;
+    use futures_util::stream::StreamExt;
+    use rocket::http::Status;
+    use rocket::response::stream::{Event, EventStream};
+    use rocket::State;
+
+    use super::{find_run_container, log_event};
+    use crate::config;
+    use crate::daemon;
+    use crate::model::RunKey;
+
+    /// Streams the logs of a running execution as server-sent events, one
+    /// `stdout` or `stderr` event per chunk, until the container exits.
+    #[get("/exec_logs/<key>")]
+    pub async fn exec_logs(
+        key: RunKey,
+        config: &State<config::Config>,
+    ) -> Result<EventStream![], Status> {
+        let docker = daemon::connect(config).map_err(|err| {
+            tracing::error!("couldn't connect to docker: {err}");
+            Status::InternalServerError
+        })?;
+        let id = match find_run_container(&docker, config, &key).await {
+            Ok(Some(id)) => id,
+            Ok(None) => return Err(Status::NotFound),
+            Err(err) => {
+                tracing::error!("couldn't list the containers: {err}");
+                return Err(Status::InternalServerError);
+            }
+        };
+        let options = Some(LogsOptions::<String> {
+            follow: true,
+            stdout: true,
+            stderr: true,
+            ..Default::default()
+        });
+        Ok(EventStream! {
+            let mut logs = docker.logs(&id, options);
+            while let Some(log) = logs.next().await {
+                match log {
+                    Ok(log) => {
+                        if let Some((stream, message)) = log_event(log) {
+                            yield Event::data(message).event(stream);
+                        }
+                    }
+                    Err(err) => {
+                        tracing::debug!("stopped streaming the logs of {id}: {err}");
+                        break;
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::main_rocket;
+    use bytes::Bytes;
+    use rocket::http::{ContentType, Status};
+    use rocket::local::blocking::Client;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_log_event() {
+        let stdout = LogOutput::StdOut {
+            message: Bytes::from("a\n"),
+        };
+        assert_eq!(log_event(stdout), Some(("stdout", "a".into())));
+        let stderr = LogOutput::StdErr {
+            message: Bytes::from("b\nc\n"),
+        };
+        assert_eq!(log_event(stderr), Some(("stderr", "b\nc".into())));
+        let stdin = LogOutput::StdIn {
+            message: Bytes::from("d"),
+        };
+        assert_eq!(log_event(stdin), None);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_logs_unknown_key() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let response = client
+            .get("/exec_logs/test_exec_logs_unknown_key")
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exec_logs() {
+        let run = std::thread::spawn(|| {
+            let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+            // echo a; sleep 3; echo b >&2
+            let uri = "/exec_and_wait/t001?key=test_exec_logs\
+                       &ddl_run=echo%20a%3B%20sleep%203%3B%20echo%20b%20%3E%262";
+            let response = client.post(uri).header(ContentType::Form).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+        });
+
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let start = Instant::now();
+        let events = loop {
+            let response = client.get("/exec_logs/test_exec_logs").dispatch();
+            if response.status() == Status::Ok {
+                break response.into_string().unwrap();
+            }
+            assert_eq!(response.status(), Status::NotFound);
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "the run didn't start"
+            );
+            std::thread::sleep(Duration::from_millis(100));
+        };
+        run.join().unwrap();
+
+        let fields = |event: &str| {
+            let mut fields = event
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+                .collect::<Vec<_>>();
+            fields.sort();
+            fields
+        };
+        let events = events.split("\n\n").map(fields).collect::<Vec<_>>();
+        let expected = |stream: &str, data: &str| {
+            vec![
+                ("data".to_string(), data.to_string()),
+                ("event".to_string(), stream.to_string()),
+            ]
+        };
+        assert!(events.contains(&expected("stdout", "a")), "{events:?}");
+        assert!(events.contains(&expected("stderr", "b")), "{events:?}");
+    }
+}
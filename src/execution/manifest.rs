@@ -0,0 +1,160 @@
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::Path;
+
+use rocket::serde::{Deserialize, Serialize};
+
+/// Bytes read from the start of a file to detect its content type.
+const SNIFF_BYTES: u64 = 4096;
+
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Magic bytes at the start of the files, and their content types.
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"II*\x00", "image/tiff"),
+    (b"MM\x00*", "image/tiff"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"P1", "image/x-portable-bitmap"),
+    (b"P4", "image/x-portable-bitmap"),
+    (b"P2", "image/x-portable-graymap"),
+    (b"P5", "image/x-portable-graymap"),
+    (b"P3", "image/x-portable-pixmap"),
+    (b"P6", "image/x-portable-pixmap"),
+];
+
+/// A file of the results, listed in `manifest.json`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ManifestEntry {
+    /// Path relative to the run directory.
+    pub name: String,
+    pub size: u64,
+    /// Detected from the content, `application/octet-stream` when unknown.
+    pub content_type: String,
+}
+
+/// Detects the content type of the start of a file, from its magic bytes,
+/// or as text when it is UTF-8 without NUL bytes.
+fn detect(head: &[u8]) -> &'static str {
+    for (signature, content_type) in SIGNATURES {
+        // the netpbm magic numbers are followed by a whitespace
+        let netpbm = signature[0] == b'P';
+        let whitespace = head
+            .get(signature.len())
+            .is_some_and(u8::is_ascii_whitespace);
+        if head.starts_with(signature) && (!netpbm || whitespace) {
+            return content_type;
+        }
+    }
+    if head.len() >= 12 && head.starts_with(b"RIFF") {
+        match &head[8..12] {
+            b"WEBP" => return "image/webp",
+            b"WAVE" => return "audio/wav",
+            _ => {}
+        }
+    }
+    if head.is_empty() || head.contains(&0) {
+        return OCTET_STREAM;
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => "text/plain",
+        // a character cut at the end of the head
+        Err(err) if err.error_len().is_none() => "text/plain",
+        Err(_) => OCTET_STREAM,
+    }
+}
+
+/// Content type of a file, from its first bytes only; never fails.
+pub fn sniff(path: &Path) -> &'static str {
+    let mut head = Vec::new();
+    let read =
+        std::fs::File::open(path).and_then(|file| file.take(SNIFF_BYTES).read_to_end(&mut head));
+    match read {
+        Ok(_) => detect(&head),
+        Err(err) => {
+            tracing::debug!("couldn't sniff {path:?}: {err}");
+            OCTET_STREAM
+        }
+    }
+}
+
+/// Lists the files of `dir`, or only those of `only`, sorted by name.
+pub fn entries(dir: &Path, only: Option<&HashSet<String>>) -> Vec<ManifestEntry> {
+    let mut entries = walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| {
+            let name = entry.path().strip_prefix(dir).ok()?.to_str()?.to_string();
+            if only.is_some_and(|only| !only.contains(&name)) {
+                return None;
+            }
+            Some(ManifestEntry {
+                name,
+                size: entry.metadata().ok()?.len(),
+                content_type: sniff(entry.path()).into(),
+            })
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    entries
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR"), "image/png");
+        assert_eq!(
+            detect(b"P5\n512 512\n255\n\x00\xff"),
+            "image/x-portable-graymap"
+        );
+        assert_eq!(detect(b"RIFF\x24\x00\x00\x00WAVEfmt "), "audio/wav");
+        assert_eq!(detect(b"sigma = 1.5\nnoise: 0.2\n"), "text/plain");
+        assert_eq!(detect("débruitage".as_bytes()), "text/plain");
+        // a character cut by the sniffing
+        assert_eq!(detect(&"é".as_bytes()[..1]), "text/plain");
+        assert_eq!(detect(b"Pixels"), "text/plain");
+        assert_eq!(detect(b"\x00\x01\x02\x03"), OCTET_STREAM);
+        assert_eq!(detect(b"\xff\xfe\xfd"), OCTET_STREAM);
+        assert_eq!(detect(b""), OCTET_STREAM);
+    }
+
+    #[test]
+    fn test_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("out")).unwrap();
+        std::fs::write(dir.path().join("out/result"), b"\x89PNG\r\n\x1a\nrest").unwrap();
+        std::fs::write(dir.path().join("stdout.txt"), "done\n").unwrap();
+        // larger than the sniffed head
+        let blob = [b"\x00\x07".as_slice(), &[b'a'; 10_000]].concat();
+        std::fs::write(dir.path().join("blob"), blob).unwrap();
+
+        let entry = |name: &str, size, content_type: &str| ManifestEntry {
+            name: name.into(),
+            size,
+            content_type: content_type.into(),
+        };
+        assert_eq!(
+            entries(dir.path(), None),
+            vec![
+                entry("blob", 10_002, OCTET_STREAM),
+                entry("out/result", 12, "image/png"),
+                entry("stdout.txt", 5, "text/plain"),
+            ]
+        );
+        let only = HashSet::from(["out/result".to_string()]);
+        assert_eq!(
+            entries(dir.path(), Some(&only)),
+            vec![entry("out/result", 12, "image/png")]
+        );
+        assert_eq!(sniff(&dir.path().join("missing")), OCTET_STREAM);
+    }
+}
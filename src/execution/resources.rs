@@ -0,0 +1,155 @@
+use bollard::container::{KillContainerOptions, Stats, StatsOptions};
+use bollard::Docker;
+use futures_util::stream::StreamExt;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::task::JoinHandle;
+
+/// Resources used by a run, saved as `resources.json` in the results.
+///
+/// The figures come from the samples of the docker stats, about one per
+/// second, so the fields are omitted when no sample was taken.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Resources {
+    /// Highest memory usage sampled, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak_memory_bytes: Option<u64>,
+    /// CPU time of the container, in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_seconds: Option<f64>,
+    /// CPU time of the container, in nanoseconds, for `X-CPU-Usage-NS`.
+    #[serde(skip)]
+    pub cpu_usage_ns: Option<u64>,
+    /// Bytes read from and written to the block devices.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_io_bytes: Option<u64>,
+    /// Highest number of processes and threads sampled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak_pids: Option<u64>,
+}
+
+impl Resources {
+    /// Accounts for a sample; the CPU time and the block I/O are cumulative.
+    fn update(
+        &mut self,
+        memory_bytes: Option<u64>,
+        cpu_ns: u64,
+        block_io_bytes: Option<u64>,
+        pids: Option<u64>,
+    ) {
+        if let Some(memory_bytes) = memory_bytes {
+            let peak = self.peak_memory_bytes.unwrap_or_default();
+            self.peak_memory_bytes = Some(peak.max(memory_bytes));
+        }
+        if let Some(pids) = pids.filter(|&pids| pids > 0) {
+            let peak = self.peak_pids.unwrap_or_default();
+            self.peak_pids = Some(peak.max(pids));
+        }
+        if cpu_ns > 0 {
+            self.cpu_seconds = Some(cpu_ns as f64 / 1e9);
+            self.cpu_usage_ns = Some(cpu_ns);
+        }
+        if block_io_bytes.is_some() {
+            self.block_io_bytes = block_io_bytes;
+        }
+    }
+
+    fn update_from_stats(&mut self, stats: &Stats) {
+        let memory = &stats.memory_stats;
+        let memory_bytes = memory.max_usage.or(memory.usage);
+        let block_io_bytes = stats
+            .blkio_stats
+            .io_service_bytes_recursive
+            .as_ref()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter(|entry| {
+                        let op = entry.op.to_ascii_lowercase();
+                        op == "read" || op == "write"
+                    })
+                    .map(|entry| entry.value)
+                    .sum()
+            });
+        self.update(
+            memory_bytes,
+            stats.cpu_stats.cpu_usage.total_usage,
+            block_io_bytes,
+            stats.pids_stats.current,
+        );
+    }
+
+    /// Whether the run reached its limit of processes, so that it can't fork anymore.
+    pub fn reached_pids_limit(&self, pids_limit: Option<u64>) -> bool {
+        matches!((self.peak_pids, pids_limit), (Some(peak), Some(limit)) if peak >= limit)
+    }
+}
+
+/// Samples the stats of a container until it stops.
+///
+/// A container reaching `pids_limit` is killed, as its processes would keep
+/// retrying to fork until the timeout.
+pub fn spawn_sampling(
+    docker: Docker,
+    id: String,
+    pids_limit: Option<u64>,
+) -> JoinHandle<Resources> {
+    rocket::tokio::spawn(async move {
+        let options = Some(StatsOptions {
+            stream: true,
+            one_shot: false,
+        });
+        let mut stream = docker.stats(&id, options);
+        let mut resources = Resources::default();
+        while let Some(stats) = stream.next().await {
+            match stats {
+                Ok(stats) => {
+                    let reached = resources.reached_pids_limit(pids_limit);
+                    resources.update_from_stats(&stats);
+                    if !reached && resources.reached_pids_limit(pids_limit) {
+                        tracing::info!("{id} reached its limit of {pids_limit:?} pids, killing it");
+                        let options = None::<KillContainerOptions<String>>;
+                        if let Err(err) = docker.kill_container(&id, options).await {
+                            tracing::warn!("couldn't kill {id}: {err}");
+                        }
+                    }
+                }
+                Err(err) => {
+                    tracing::debug!("stopped sampling the stats of {id}: {err}");
+                    break;
+                }
+            }
+        }
+        resources
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_update() {
+        let mut resources = Resources::default();
+        // the last samples of a stopped container are empty
+        resources.update(None, 0, None, Some(0));
+        assert_eq!(resources, Resources::default());
+        assert_eq!(serde_json::to_string(&resources).unwrap(), "{}");
+
+        resources.update(Some(1000), 500_000_000, Some(10), Some(3));
+        resources.update(Some(3000), 1_500_000_000, Some(30), Some(12));
+        resources.update(Some(2000), 2_000_000_000, None, None);
+        assert_eq!(
+            resources,
+            Resources {
+                peak_memory_bytes: Some(3000),
+                cpu_seconds: Some(2.0),
+                cpu_usage_ns: Some(2_000_000_000),
+                block_io_bytes: Some(30),
+                peak_pids: Some(12),
+            }
+        );
+        assert!(!resources.reached_pids_limit(None));
+        assert!(!resources.reached_pids_limit(Some(13)));
+        assert!(resources.reached_pids_limit(Some(12)));
+    }
+}
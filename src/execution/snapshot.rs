@@ -0,0 +1,189 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::time::SystemTime;
+
+use rocket::http::uri::fmt::{Formatter, Query, UriDisplay};
+use rocket::serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// What the zip of the results contains.
+#[derive(Debug, Clone, Copy, PartialEq, FromFormField)]
+pub enum OutputMode {
+    /// The whole run directory.
+    Full,
+    /// The files added or modified by the run, and `changes.json`.
+    Diff,
+}
+
+impl UriDisplay<Query> for OutputMode {
+    fn fmt(&self, f: &mut Formatter<'_, Query>) -> std::fmt::Result {
+        f.write_value(match self {
+            OutputMode::Full => "full",
+            OutputMode::Diff => "diff",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct FileState {
+    size: u64,
+    mtime: Option<SystemTime>,
+    /// Only for the files small enough to be hashed.
+    sha256: Option<String>,
+}
+
+impl FileState {
+    fn is_modified(&self, after: &FileState) -> bool {
+        match (&self.sha256, &after.sha256) {
+            (Some(before), Some(after_sha256)) => self.size != after.size || before != after_sha256,
+            _ => self.size != after.size || self.mtime != after.mtime,
+        }
+    }
+}
+
+/// State of the files of a directory, to find what a run changed.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    files: BTreeMap<String, FileState>,
+}
+
+/// Paths of the files changed by a run, relative to the run directory.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub modified: Vec<String>,
+    pub deleted: Vec<String>,
+}
+
+fn sha256(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+impl Snapshot {
+    /// Records the files of `dir`, hashing the ones of at most `hash_max_bytes`
+    /// (the size and the modification time are trusted for the larger ones).
+    pub fn take(dir: &Path, hash_max_bytes: u64) -> std::io::Result<Self> {
+        let mut files = BTreeMap::new();
+        for entry in walkdir::WalkDir::new(dir)
+            .into_iter()
+            .filter_map(Result::ok)
+        {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let Some(path) = entry.path().strip_prefix(dir).ok().and_then(Path::to_str) else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            let size = metadata.len();
+            let sha256 = if size <= hash_max_bytes {
+                Some(sha256(entry.path())?)
+            } else {
+                None
+            };
+            let state = FileState {
+                size,
+                mtime: metadata.modified().ok(),
+                sha256,
+            };
+            files.insert(path.to_string(), state);
+        }
+        Ok(Self { files })
+    }
+
+    /// Lists the files added, modified, and deleted since this snapshot.
+    pub fn diff(&self, after: &Snapshot) -> Changes {
+        let mut changes = Changes::default();
+        for (path, state) in &after.files {
+            match self.files.get(path) {
+                None => changes.added.push(path.clone()),
+                Some(before) if before.is_modified(state) => changes.modified.push(path.clone()),
+                Some(_) => {}
+            }
+        }
+        changes.deleted = self
+            .files
+            .keys()
+            .filter(|path| !after.files.contains_key(*path))
+            .cloned()
+            .collect();
+        changes
+    }
+}
+
+impl Changes {
+    /// The files to be zipped: the added and modified ones.
+    pub fn changed_files(&self) -> HashSet<String> {
+        self.added.iter().chain(&self.modified).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dir = tmpdir.path();
+        std::fs::write(dir.join("untouched.txt"), "a").unwrap();
+        std::fs::write(dir.join("modified.png"), "before").unwrap();
+        std::fs::write(dir.join("deleted.txt"), "c").unwrap();
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub").join("same_size.txt"), "abc").unwrap();
+
+        let before = Snapshot::take(dir, 1000).unwrap();
+        std::fs::write(dir.join("modified.png"), "after!").unwrap();
+        std::fs::write(dir.join("sub").join("same_size.txt"), "xyz").unwrap();
+        std::fs::remove_file(dir.join("deleted.txt")).unwrap();
+        std::fs::write(dir.join("sub").join("added.txt"), "d").unwrap();
+        let after = Snapshot::take(dir, 1000).unwrap();
+
+        let changes = before.diff(&after);
+        assert_eq!(
+            changes,
+            Changes {
+                added: vec!["sub/added.txt".into()],
+                modified: vec!["modified.png".into(), "sub/same_size.txt".into()],
+                deleted: vec!["deleted.txt".into()],
+            }
+        );
+        assert_eq!(
+            changes.changed_files(),
+            HashSet::from([
+                "sub/added.txt".into(),
+                "modified.png".into(),
+                "sub/same_size.txt".into()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_large_files_not_hashed() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("large.bin");
+        std::fs::write(&path, "0123456789").unwrap();
+
+        let before = Snapshot::take(tmpdir.path(), 5).unwrap();
+        assert_eq!(before.files["large.bin"].sha256, None);
+
+        // same size and modification time: trusted to be unchanged
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::fs::write(&path, "9876543210").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        let after = Snapshot::take(tmpdir.path(), 5).unwrap();
+        assert_eq!(before.diff(&after), Changes::default());
+
+        std::fs::write(&path, "01234567890").unwrap();
+        let after = Snapshot::take(tmpdir.path(), 5).unwrap();
+        assert_eq!(before.diff(&after).modified, vec!["large.bin"]);
+    }
+}
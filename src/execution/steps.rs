@@ -0,0 +1,124 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use rocket::serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+
+/// File of the run directory where the script of the steps records their
+/// statuses, removed before the results are sent.
+const STATUS_FILE: &str = ".ipol_steps";
+
+/// Outcome of a step of a `ddl_run` given as a list of commands.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct StepStatus {
+    /// Number of the step, from 1.
+    pub step: usize,
+    pub exit_code: i64,
+    /// Run time of the step, in seconds.
+    pub run_time: f64,
+}
+
+/// The steps of a `ddl_run` given as a JSON array of strings, `None` for a script.
+pub fn parse(ddl_run: &str) -> Option<Vec<String>> {
+    if !ddl_run.trim_start().starts_with('[') {
+        return None;
+    }
+    serde_json::from_str(ddl_run).ok()
+}
+
+/// Script running the steps one after the other, each in a subshell, and
+/// stopping at the first one which fails with its exit code.
+///
+/// The start and the end of each step are taken from `/proc/uptime`, so the
+/// shell must be a POSIX one.
+pub fn script(steps: &[String]) -> String {
+    let mut script = format!(
+        "ipol_step() {{ read ipol_end _ < /proc/uptime; \
+         echo \"$1 $2 $ipol_start $ipol_end\" >> {STATUS_FILE}; }}\n"
+    );
+    for (index, step) in steps.iter().enumerate() {
+        // an empty subshell is a syntax error
+        let step = if step.trim().is_empty() { "true" } else { step };
+        let _ = write!(
+            script,
+            "read ipol_start _ < /proc/uptime\n(\n{step}\n)\n\
+             ipol_code=$?; ipol_step {} $ipol_code; [ $ipol_code -eq 0 ] || exit $ipol_code\n",
+            index + 1
+        );
+    }
+    script
+}
+
+/// Reads the statuses of the steps which ended, and removes their file.
+pub async fn take_statuses(outdir: &Path) -> Vec<StepStatus> {
+    let path = outdir.join(STATUS_FILE);
+    let content = match rocket::tokio::fs::read_to_string(&path).await {
+        Ok(content) => content,
+        Err(err) => {
+            tracing::debug!("no status of the steps: {err}");
+            return Vec::new();
+        }
+    };
+    if let Err(err) = rocket::tokio::fs::remove_file(&path).await {
+        tracing::warn!("couldn't remove {path:?}: {err}");
+    }
+    content.lines().filter_map(parse_status).collect()
+}
+
+fn parse_status(line: &str) -> Option<StepStatus> {
+    let mut fields = line.split_whitespace();
+    let step = fields.next()?.parse().ok()?;
+    let exit_code = fields.next()?.parse().ok()?;
+    let start = fields.next()?.parse::<f64>().ok()?;
+    let end = fields.next()?.parse::<f64>().ok()?;
+    Some(StepStatus {
+        step,
+        exit_code,
+        run_time: (end - start).max(0.0),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let steps = parse(r#"["./prepare.sh", "./run.sh $sigma"]"#).unwrap();
+        assert_eq!(steps, ["./prepare.sh", "./run.sh $sigma"]);
+        assert_eq!(parse("[]"), Some(Vec::new()));
+        // scripts
+        assert_eq!(parse("./run.sh"), None);
+        assert_eq!(parse("[ -f input_0.png ] && ./run.sh"), None);
+        assert_eq!(parse("[1, 2]"), None);
+    }
+
+    #[rocket::async_test]
+    async fn test_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let steps = [
+            "echo a > a.txt".into(),
+            "".into(),
+            "exit 3".into(),
+            "touch c".into(),
+        ];
+        let status = std::process::Command::new("/bin/sh")
+            .args(["-c", &script(&steps)])
+            .current_dir(dir.path())
+            .status()
+            .unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert!(dir.path().join("a.txt").exists());
+        assert!(!dir.path().join("c").exists());
+
+        let statuses = take_statuses(dir.path()).await;
+        let codes = statuses
+            .iter()
+            .map(|status| (status.step, status.exit_code))
+            .collect::<Vec<_>>();
+        assert_eq!(codes, [(1, 0), (2, 0), (3, 3)]);
+        assert!(statuses.iter().all(|status| status.run_time >= 0.0));
+        assert!(!dir.path().join(STATUS_FILE).exists());
+        assert_eq!(take_statuses(dir.path()).await, Vec::new());
+    }
+}
@@ -0,0 +1,287 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::{Deserialize, Serialize};
+
+/// Where the run time of a run comes from.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimingSource {
+    /// The start and finish timestamps of the container.
+    Docker,
+    /// The monotonic clock of the demorunner.
+    Monotonic,
+}
+
+/// Run time of a run, saved as `timing.json` in the results.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Timing {
+    /// Run time reported, in seconds.
+    pub run_time: f64,
+    pub source: TimingSource,
+    /// Run time from the timestamps of docker, in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub docker_run_time: Option<f64>,
+    /// Time from the start of the container to the end of its logs, in seconds.
+    pub monotonic_run_time: f64,
+    /// Time spent waiting on the docker daemon during the run, in seconds.
+    #[serde(default)]
+    pub docker_api_seconds: f64,
+    /// Time from the receipt of the request to the start of the container.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_latency: Option<StartLatency>,
+}
+
+/// Time from the receipt of a request to the start of its container, in
+/// seconds, with the part of each step of the preparation of the run.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StartLatency {
+    pub total: f64,
+    /// Receiving the request and its uploaded files.
+    pub upload: f64,
+    /// Waiting for an execution slot.
+    pub queue_wait: f64,
+    /// Saving the inputs into the run directory.
+    pub inputs: f64,
+    /// Docker calls, up to the start of the container.
+    pub docker_api: f64,
+    /// The rest, such as the disk reservation, the wait for a GPU or the pull of the image.
+    pub other: f64,
+}
+
+/// When a request was received, before its body, set by the `request_timer` fairing.
+#[derive(Debug, Clone, Copy)]
+pub struct ReceivedAt(pub Instant);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ReceivedAt {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(*req.local_cache(|| ReceivedAt(Instant::now())))
+    }
+}
+
+/// Measures the steps of the preparation of a run, for its `StartLatency`.
+#[derive(Debug, Default)]
+pub struct StartClock {
+    pub received_at: Option<Instant>,
+    pub upload: Duration,
+    pub queue_wait: Duration,
+    pub inputs: Duration,
+}
+
+impl StartClock {
+    /// The latency up to now, the container having just started after
+    /// `docker_api` of docker calls.
+    pub fn latency(&self, docker_api: Duration) -> Option<StartLatency> {
+        let total = self.received_at?.elapsed();
+        let measured = self.upload + self.queue_wait + self.inputs + docker_api;
+        Some(StartLatency {
+            total: total.as_secs_f64(),
+            upload: self.upload.as_secs_f64(),
+            queue_wait: self.queue_wait.as_secs_f64(),
+            inputs: self.inputs.as_secs_f64(),
+            docker_api: docker_api.as_secs_f64(),
+            other: total.saturating_sub(measured).as_secs_f64(),
+        })
+    }
+}
+
+impl Timing {
+    /// Whether the timestamps of docker were discarded for disagreeing with the monotonic clock.
+    pub fn skewed(&self) -> bool {
+        self.docker_run_time.is_some() && self.source == TimingSource::Monotonic
+    }
+}
+
+/// Chooses the run time of docker, unless it is missing or disagrees with the
+/// monotonic measurement by more than both `factor` times and `offset`.
+///
+/// The monotonic measurement is a little longer than the run, as it includes
+/// the start of the container and the reading of its last logs.
+pub fn reconcile(
+    docker: Option<Duration>,
+    monotonic: Duration,
+    factor: f64,
+    offset: Duration,
+) -> Timing {
+    let source = match docker {
+        Some(docker) => {
+            let (shorter, longer) = if docker < monotonic {
+                (docker, monotonic)
+            } else {
+                (monotonic, docker)
+            };
+            let difference = longer - shorter;
+            let ratio = longer.as_secs_f64() / shorter.as_secs_f64();
+            if difference > offset && ratio > factor {
+                TimingSource::Monotonic
+            } else {
+                TimingSource::Docker
+            }
+        }
+        None => TimingSource::Monotonic,
+    };
+    let run_time = match (source, docker) {
+        (TimingSource::Docker, Some(docker)) => docker,
+        _ => monotonic,
+    };
+    Timing {
+        run_time: run_time.as_secs_f64(),
+        source,
+        docker_run_time: docker.map(|docker| docker.as_secs_f64()),
+        monotonic_run_time: monotonic.as_secs_f64(),
+        docker_api_seconds: 0.0,
+        start_latency: None,
+    }
+}
+
+/// A docker call which didn't answer within `docker_call_timeout`.
+#[derive(Debug, thiserror::Error)]
+#[error("the docker call {operation} didn't answer within {}s", .timeout.as_secs_f64())]
+pub struct ApiTimeout {
+    pub operation: &'static str,
+    pub timeout: Duration,
+}
+
+/// Awaits a docker call for at most `timeout`, adding the time waited to `total`.
+pub async fn timed_call<T>(
+    operation: &'static str,
+    timeout: Duration,
+    total: &mut Duration,
+    call: impl Future<Output = T>,
+) -> Result<T, ApiTimeout> {
+    let start = Instant::now();
+    let result = rocket::tokio::time::timeout(timeout, call).await;
+    let elapsed = start.elapsed();
+    *total += elapsed;
+    tracing::debug!("the docker call {operation} took {elapsed:?}");
+    result.map_err(|_| ApiTimeout { operation, timeout })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FACTOR: f64 = 2.0;
+    const OFFSET: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_start_latency() {
+        assert_eq!(StartClock::default().latency(Duration::ZERO), None);
+
+        let clock = StartClock {
+            received_at: Some(Instant::now() - Duration::from_secs(2)),
+            upload: Duration::from_millis(300),
+            queue_wait: Duration::from_millis(1000),
+            inputs: Duration::from_millis(200),
+        };
+        let latency = clock.latency(Duration::from_millis(400)).unwrap();
+        assert!(latency.total >= 2.0 && latency.total < 2.5, "{latency:?}");
+        assert_eq!(
+            (
+                latency.upload,
+                latency.queue_wait,
+                latency.inputs,
+                latency.docker_api
+            ),
+            (0.3, 1.0, 0.2, 0.4)
+        );
+        let sum = latency.upload + latency.queue_wait + latency.inputs + latency.docker_api;
+        assert!(
+            (sum + latency.other - latency.total).abs() < 1e-6,
+            "{latency:?}"
+        );
+        assert!(latency.other >= 0.1 - 1e-6, "{latency:?}");
+    }
+
+    #[test]
+    fn test_reconcile_consistent() {
+        let timing = reconcile(
+            Some(Duration::from_millis(2900)),
+            Duration::from_millis(3100),
+            FACTOR,
+            OFFSET,
+        );
+        assert_eq!(timing.source, TimingSource::Docker);
+        assert_eq!(timing.run_time, 2.9);
+        assert!(!timing.skewed());
+
+        // far apart in ratio, but not in seconds
+        let timing = reconcile(
+            Some(Duration::from_millis(10)),
+            Duration::from_secs(1),
+            FACTOR,
+            OFFSET,
+        );
+        assert_eq!(timing.source, TimingSource::Docker);
+    }
+
+    #[test]
+    fn test_reconcile_skewed() {
+        // a clock step of two hours during a run of three seconds
+        let timing = reconcile(
+            Some(Duration::from_secs(2 * 3600 + 3)),
+            Duration::from_millis(3100),
+            FACTOR,
+            OFFSET,
+        );
+        assert_eq!(timing.source, TimingSource::Monotonic);
+        assert_eq!(timing.run_time, 3.1);
+        assert_eq!(timing.docker_run_time, Some(7203.0));
+        assert!(timing.skewed());
+
+        // a step backwards
+        let timing = reconcile(
+            Some(Duration::ZERO),
+            Duration::from_secs(60),
+            FACTOR,
+            OFFSET,
+        );
+        assert_eq!(timing.source, TimingSource::Monotonic);
+        assert_eq!(timing.run_time, 60.0);
+    }
+
+    #[rocket::async_test]
+    async fn test_timed_call() {
+        let mut total = Duration::ZERO;
+        let call = async { 1 };
+        let result = timed_call("create", Duration::from_secs(1), &mut total, call).await;
+        assert_eq!(result.unwrap(), 1);
+
+        // a slow create
+        let call = rocket::tokio::time::sleep(Duration::from_secs(5));
+        let timeout = Duration::from_millis(100);
+        let err = timed_call("create", timeout, &mut total, call)
+            .await
+            .unwrap_err();
+        assert_eq!(err.operation, "create");
+        assert_eq!(
+            err.to_string(),
+            "the docker call create didn't answer within 0.1s"
+        );
+        assert!(
+            total >= timeout && total < Duration::from_secs(1),
+            "{total:?}"
+        );
+
+        let call = rocket::tokio::time::sleep(Duration::from_millis(100));
+        timed_call("start", Duration::from_secs(1), &mut total, call)
+            .await
+            .unwrap();
+        assert!(total >= 2 * timeout, "{total:?}");
+    }
+
+    #[test]
+    fn test_reconcile_missing() {
+        let timing = reconcile(None, Duration::from_millis(3100), FACTOR, OFFSET);
+        assert_eq!(timing.source, TimingSource::Monotonic);
+        assert_eq!(timing.run_time, 3.1);
+        assert!(!timing.skewed());
+        let timing = serde_json::to_value(&timing).unwrap();
+        assert_eq!(timing["source"], "monotonic");
+        assert!(timing.get("docker_run_time").is_none());
+    }
+}
@@ -0,0 +1,331 @@
+use rocket::serde::{Deserialize, Serialize};
+
+use super::adjustments::{AdjustmentReason, Adjustments};
+use super::criteria::{CriteriaError, SuccessCriteria};
+use super::{
+    apply_defaults, check_network_disabled, check_priority, check_run_command, check_timeout,
+    parse_exit_code_messages, resolve_timeout, ExecAndWaitInternalError, ExecAndWaitOptions,
+};
+use crate::compilation::dockerfile_in_sources;
+use crate::config;
+use crate::model::{DDLBuild, DDLRun, DemoID, EnvValue, RunParams, RunParamsSchema, ToEnvVec};
+use crate::names;
+
+/// What a demo would send to run and build, checked without doing either.
+#[derive(Debug, Default, Deserialize)]
+pub struct ValidateRequest {
+    /// Demo whose config applies, for its defaults and its names.
+    demo_id: Option<DemoID>,
+    ddl_run: Option<DDLRun>,
+    /// JSON array of the arguments of the run, as in the query of `exec_and_wait`.
+    ddl_run_exec: Option<String>,
+    #[serde(default)]
+    allow_empty_run: bool,
+    timeout: Option<u64>,
+    #[serde(default)]
+    parameters: RunParams,
+    params_schema: Option<RunParamsSchema>,
+    priority: Option<u8>,
+    network_disabled: Option<bool>,
+    #[serde(default)]
+    success_criteria: SuccessCriteria,
+    exit_code_messages: Option<String>,
+    ddl_build: Option<DDLBuild>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// The request would be refused, or the run fail.
+    Error,
+    /// The request would be accepted, but not used as is.
+    Warning,
+}
+
+/// A complaint about a field of a `ValidateRequest`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: String,
+    /// Path of the offending field, such as `parameters.sigma`.
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Validation {
+    /// Whether there is no error, the warnings aside.
+    pub valid: bool,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Default)]
+struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    fn push(&mut self, severity: Severity, code: &str, field: &str, message: impl ToString) {
+        self.0.push(Diagnostic {
+            severity,
+            code: code.into(),
+            field: field.into(),
+            message: message.to_string(),
+        });
+    }
+
+    fn check(&mut self, code: &str, field: &str, result: Result<(), ExecAndWaitInternalError>) {
+        match result {
+            Ok(()) => {}
+            Err(ExecAndWaitInternalError::InvalidRequest(message)) => {
+                self.push(Severity::Error, code, field, message)
+            }
+            Err(err) => self.push(Severity::Error, code, field, err),
+        }
+    }
+}
+
+/// Runs the checks of `exec_and_wait` and of the compilations on a request,
+/// reporting all the errors and the warnings instead of the first one.
+pub fn validate(config: &config::Config, req: ValidateRequest) -> Validation {
+    let mut diagnostics = Diagnostics::default();
+
+    let options = ExecAndWaitOptions {
+        ddl_run_exec: req.ddl_run_exec.clone(),
+        allow_empty_run: req.allow_empty_run,
+        ..Default::default()
+    };
+    let field = match (&req.ddl_run, &req.ddl_run_exec) {
+        (None, Some(_)) => "ddl_run_exec",
+        _ => "ddl_run",
+    };
+    let run_command = check_run_command(req.ddl_run.as_deref(), &options);
+    diagnostics.check("invalid_run_command", field, run_command);
+    diagnostics.check("invalid_timeout", "timeout", check_timeout(req.timeout));
+    diagnostics.check("invalid_priority", "priority", check_priority(req.priority));
+    let network_disabled = check_network_disabled(config, req.network_disabled);
+    diagnostics.check(
+        "invalid_network_disabled",
+        "network_disabled",
+        network_disabled,
+    );
+    if let Err(err) = req.success_criteria.compile() {
+        let field = match err {
+            CriteriaError::InvalidGlob(_) => "success_criteria.file",
+            _ => "success_criteria.output",
+        };
+        diagnostics.push(Severity::Error, "invalid_success_criteria", field, err);
+    }
+    if let Err(err) = parse_exit_code_messages(req.exit_code_messages.as_deref()) {
+        let (code, field) = ("invalid_exit_code_messages", "exit_code_messages");
+        diagnostics.push(Severity::Error, code, field, err);
+    }
+
+    let mut adjustments = Adjustments::default();
+    resolve_timeout(config, req.timeout, &mut adjustments);
+    for adjustment in adjustments.into_inner() {
+        let bound = match adjustment.reason {
+            AdjustmentReason::ConfigMax => "the maximum of the config",
+            AdjustmentReason::ConfigMin => "the minimum of the config",
+        };
+        let message = format!(
+            "{} {} would be {}, {bound}",
+            adjustment.field, adjustment.requested, adjustment.effective
+        );
+        let field = adjustment.field;
+        diagnostics.push(Severity::Warning, "adjusted", &field, message);
+    }
+
+    let mut params = req.parameters;
+    if let Some(demo_id) = &req.demo_id {
+        apply_defaults(&mut params, &config.demo(demo_id).defaults);
+        if let Err(err) = names::image_name(config, demo_id) {
+            diagnostics.push(Severity::Error, "invalid_demo_id", "demo_id", err);
+        }
+        if !config.demo_allowed(demo_id) {
+            let message = format!("the demo {demo_id} is not run by this instance");
+            diagnostics.push(Severity::Error, "demo_not_allowed", "demo_id", message);
+        }
+    }
+    let arrays = match &req.demo_id {
+        Some(demo_id) => config.param_array_format(demo_id),
+        None => config.param_array_format,
+    };
+    let mut param_names = params.keys().collect::<Vec<_>>();
+    param_names.sort();
+    for name in param_names {
+        let field = format!("parameters.{name}");
+        let value = EnvValue(&params[name], arrays).to_string();
+        if let Some(reason) = RunParams::unexported_reason(name, &value) {
+            let message = format!("{name} is not passed to the run: {reason}");
+            diagnostics.push(Severity::Warning, "param_not_exported", &field, message);
+        } else if config.env_vars.contains_key(name) {
+            let message = format!("{name} is set by the config, which takes precedence");
+            diagnostics.push(Severity::Warning, "param_overridden", &field, message);
+        }
+    }
+
+    if let Some(schema) = &req.params_schema {
+        for error in schema.check(&params) {
+            let field = format!("parameters.{}", error.field);
+            diagnostics.push(Severity::Error, "invalid_param", &field, error.message);
+        }
+    }
+
+    if let Some(ddl_build) = &req.ddl_build {
+        if !dockerfile_in_sources(&ddl_build.dockerfile) {
+            let message = format!(
+                "the dockerfile {:?} is not a relative path inside the sources",
+                ddl_build.dockerfile
+            );
+            let (code, field) = ("invalid_dockerfile", "ddl_build.dockerfile");
+            diagnostics.push(Severity::Error, code, field, message);
+        }
+    }
+
+    let diagnostics = diagnostics.0;
+    Validation {
+        valid: !diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error),
+        diagnostics,
+    }
+}
+
+pub mod http {
+    use rocket::serde::json::Json;
+    use rocket::State;
+
+    use super::{ValidateRequest, Validation};
+    use crate::config;
+
+    /// Reports what `exec_and_wait` and the compilations would complain about,
+    /// without building or running anything.
+    #[post("/validate", data = "<req>")]
+    pub fn validate(
+        req: Json<ValidateRequest>,
+        config: &State<config::Config>,
+    ) -> Json<Validation> {
+        Json(super::validate(config, req.into_inner()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{main_rocket, rocket_from_figment};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::blocking::Client;
+
+    fn ask_validate(client: &Client, body: &serde_json::Value) -> Validation {
+        let response = client
+            .post("/validate")
+            .header(ContentType::JSON)
+            .body(body.to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json().unwrap()
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_validate_clean() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let body = serde_json::json!({
+            "demo_id": "t001",
+            "ddl_run": "echo $sigma",
+            "timeout": 10,
+            "parameters": {"sigma": 1.5},
+            "ddl_build": {
+                "url": "https://github.com/kidanger/ipol-demo-zero",
+                "rev": "master",
+                "dockerfile": ".ipol/Dockerfile",
+            },
+        });
+        let validation = ask_validate(&client, &body);
+        assert_eq!(
+            validation,
+            Validation {
+                valid: true,
+                diagnostics: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_validate_messy() {
+        let figment = rocket::Config::figment()
+            .merge(("network_disabled", true))
+            .merge(("max_timeout", 60))
+            .merge(("env_vars", serde_json::json!({"TOKEN": "secret"})));
+        let client = Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+        let body = serde_json::json!({
+            "demo_id": "t001",
+            "ddl_run": "true",
+            "ddl_run_exec": "[\"true\"]",
+            "timeout": 3600,
+            "priority": 11,
+            "network_disabled": false,
+            "parameters": {"PATH": "/tmp", "a=b": 1, "nul": "a\u{0}b", "TOKEN": "x", "ok": [1, 2]},
+            "params_schema": {"a=b": {"min": 2}, "ok": {"max": 10}},
+            "success_criteria": {"file": "[", "output": "("},
+            "exit_code_messages": "{\"three\": \"x\"}",
+            "ddl_build": {"url": "", "rev": "", "dockerfile": "../Dockerfile"},
+        });
+        let validation = ask_validate(&client, &body);
+        assert!(!validation.valid);
+        let found = validation
+            .diagnostics
+            .iter()
+            .map(|diagnostic| {
+                (
+                    diagnostic.severity,
+                    diagnostic.code.as_str(),
+                    diagnostic.field.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        use Severity::{Error, Warning};
+        assert_eq!(
+            found,
+            [
+                (Error, "invalid_run_command", "ddl_run"),
+                (Error, "invalid_priority", "priority"),
+                (Error, "invalid_network_disabled", "network_disabled"),
+                (Error, "invalid_success_criteria", "success_criteria.file"),
+                (Error, "invalid_exit_code_messages", "exit_code_messages"),
+                (Warning, "adjusted", "timeout"),
+                (Warning, "param_not_exported", "parameters.PATH"),
+                (Warning, "param_overridden", "parameters.TOKEN"),
+                (Warning, "param_not_exported", "parameters.a=b"),
+                (Warning, "param_not_exported", "parameters.nul"),
+                (Error, "invalid_param", "parameters.a=b"),
+                (Error, "invalid_param", "parameters.ok"),
+                (Error, "invalid_dockerfile", "ddl_build.dockerfile"),
+            ]
+        );
+        let message = |field: &str| {
+            let diagnostic = validation.diagnostics.iter().find(|d| d.field == field);
+            diagnostic.unwrap().message.clone()
+        };
+        assert!(message("ddl_run").contains("mutually exclusive"));
+        assert_eq!(
+            message("timeout"),
+            "timeout 3600 would be 60, the maximum of the config"
+        );
+        assert!(message("parameters.nul").contains("NUL byte"));
+        assert_eq!(message("parameters.ok"), "[1,2] is not a number");
+
+        // the other errors, each alone
+        let body = serde_json::json!({"ddl_run_exec": "[]", "timeout": 0});
+        let validation = ask_validate(&client, &body);
+        let fields = validation
+            .diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.field.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(fields, ["ddl_run_exec", "timeout"]);
+        let body = serde_json::json!({"ddl_run": "true", "success_criteria": {"output": "("}});
+        let validation = ask_validate(&client, &body);
+        assert_eq!(validation.diagnostics[0].field, "success_criteria.output");
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rocket::form::{FromFormField, ValueField};
+use rocket::http::uri::fmt::{Formatter, Query, UriDisplay};
+use rocket::tokio::sync::Notify;
+use rocket::tokio::time::{timeout_at, Instant};
+
+use crate::config;
+
+/// GPUs asked by a run, in the syntax of `docker run --gpus`: a count such
+/// as `2`, or `device=0,2` for the device ids.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GpuSelection {
+    Count(usize),
+    Devices(Vec<String>),
+}
+
+impl TryFrom<&str> for GpuSelection {
+    type Error = &'static str;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s.strip_prefix("device=") {
+            Some(ids) => {
+                let ids = ids.split(',').map(|id| id.trim().to_string());
+                let ids = ids.collect::<Vec<_>>();
+                if ids.iter().any(String::is_empty) {
+                    return Err("invalid device ids, expected e.g. device=0,2");
+                }
+                Ok(GpuSelection::Devices(ids))
+            }
+            None => s
+                .parse()
+                .map(GpuSelection::Count)
+                .map_err(|_| "invalid gpus, expected a count or device=<ids>"),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromFormField<'r> for GpuSelection {
+    fn from_value(field: ValueField<'r>) -> rocket::form::Result<'r, Self> {
+        Self::try_from(field.value).map_err(|e| rocket::form::Error::validation(e).into())
+    }
+}
+
+impl UriDisplay<Query> for GpuSelection {
+    fn fmt(&self, f: &mut Formatter<'_, Query>) -> std::fmt::Result {
+        match self {
+            GpuSelection::Count(count) => f.write_value(count.to_string()),
+            GpuSelection::Devices(ids) => f.write_value(format!("device={}", ids.join(","))),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum GpuError {
+    #[error("{requested} GPUs requested, only {available} configured")]
+    TooMany { requested: usize, available: usize },
+    #[error("GPUs not in the configured pool: {}", .0.join(", "))]
+    UnknownDevices(Vec<String>),
+    #[error("IPOLNoGpuAvailable: {0} GPU(s) requested, not enough of them free")]
+    Unavailable(usize),
+}
+
+/// GPUs needed by a run: `count` of the `candidates`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GpuDemand {
+    pub candidates: Vec<String>,
+    pub count: usize,
+}
+
+/// GPUs needed by a run: the ones of `selection`, which must be in the
+/// configured `gpus`, or any one of them.
+pub fn demand(
+    config: &config::Config,
+    selection: Option<&GpuSelection>,
+) -> Result<GpuDemand, GpuError> {
+    let pool = &config.gpus;
+    match selection {
+        None => Ok(GpuDemand {
+            candidates: pool.clone(),
+            count: pool.len().min(1),
+        }),
+        Some(GpuSelection::Count(count)) => {
+            if *count > pool.len() {
+                return Err(GpuError::TooMany {
+                    requested: *count,
+                    available: pool.len(),
+                });
+            }
+            Ok(GpuDemand {
+                candidates: pool.clone(),
+                count: *count,
+            })
+        }
+        Some(GpuSelection::Devices(ids)) => {
+            let unknown = ids.iter().filter(|id| !pool.contains(id));
+            let unknown = unknown.cloned().collect::<Vec<_>>();
+            if !unknown.is_empty() {
+                return Err(GpuError::UnknownDevices(unknown));
+            }
+            let mut candidates = Vec::new();
+            for id in ids {
+                if !candidates.contains(id) {
+                    candidates.push(id.clone());
+                }
+            }
+            let count = candidates.len();
+            Ok(GpuDemand { candidates, count })
+        }
+    }
+}
+
+/// GPUs assigned to the runs in progress, so that simultaneous runs don't
+/// share a device.
+#[derive(Debug, Default)]
+pub struct GpuAllocator {
+    in_use: Arc<Mutex<HashSet<String>>>,
+    released: Arc<Notify>,
+}
+
+/// GPUs assigned to a run, released when dropped.
+#[derive(Debug)]
+pub struct GpuAllocation {
+    pub ids: Vec<String>,
+    in_use: Arc<Mutex<HashSet<String>>>,
+    released: Arc<Notify>,
+}
+
+impl Drop for GpuAllocation {
+    fn drop(&mut self) {
+        if self.ids.is_empty() {
+            return;
+        }
+        let mut in_use = self.in_use.lock().unwrap();
+        for id in &self.ids {
+            in_use.remove(id);
+        }
+        self.released.notify_waiters();
+    }
+}
+
+impl GpuAllocator {
+    /// Device ids of the GPUs assigned to the runs in progress.
+    pub fn in_use(&self) -> HashSet<String> {
+        self.in_use.lock().unwrap().clone()
+    }
+
+    fn try_allocate(&self, demand: &GpuDemand) -> Option<GpuAllocation> {
+        let mut in_use = self.in_use.lock().unwrap();
+        let free = demand.candidates.iter().filter(|id| !in_use.contains(*id));
+        let ids = free.take(demand.count).cloned().collect::<Vec<_>>();
+        if ids.len() < demand.count {
+            return None;
+        }
+        in_use.extend(ids.iter().cloned());
+        Some(GpuAllocation {
+            ids,
+            in_use: self.in_use.clone(),
+            released: self.released.clone(),
+        })
+    }
+
+    /// Assigns free GPUs to a run, waiting at most `wait` for the other runs
+    /// to release them.
+    pub async fn allocate(
+        &self,
+        demand: &GpuDemand,
+        wait: Duration,
+    ) -> Result<GpuAllocation, GpuError> {
+        let deadline = Instant::now() + wait;
+        loop {
+            // listen before trying, not to miss a release in between
+            let released = self.released.notified();
+            rocket::tokio::pin!(released);
+            released.as_mut().enable();
+            if let Some(allocation) = self.try_allocate(demand) {
+                return Ok(allocation);
+            }
+            if timeout_at(deadline, released).await.is_err() {
+                return Err(GpuError::Unavailable(demand.count));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_selection() {
+        let parse = GpuSelection::try_from;
+        assert_eq!(parse("2"), Ok(GpuSelection::Count(2)));
+        assert_eq!(
+            parse("device=0,2"),
+            Ok(GpuSelection::Devices(vec!["0".into(), "2".into()]))
+        );
+        assert_eq!(
+            parse("device=GPU-3a9f"),
+            Ok(GpuSelection::Devices(vec!["GPU-3a9f".into()]))
+        );
+        assert!(parse("all").is_err());
+        assert!(parse("device=").is_err());
+        assert!(parse("device=0,,1").is_err());
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_demand() {
+        let mut config = config::test_config();
+        let any = demand(&config, None).unwrap();
+        assert_eq!(any.count, 0);
+
+        config.gpus = ids(&["0", "1", "2"]);
+        let any = demand(&config, None).unwrap();
+        assert_eq!((any.candidates, any.count), (config.gpus.clone(), 1));
+        let two = GpuSelection::Count(2);
+        assert_eq!(demand(&config, Some(&two)).unwrap().count, 2);
+        let devices = GpuSelection::Devices(ids(&["2", "0", "2"]));
+        let devices = demand(&config, Some(&devices)).unwrap();
+        assert_eq!((devices.candidates, devices.count), (ids(&["2", "0"]), 2));
+
+        let four = GpuSelection::Count(4);
+        assert_eq!(
+            demand(&config, Some(&four)),
+            Err(GpuError::TooMany {
+                requested: 4,
+                available: 3
+            })
+        );
+        let unknown = GpuSelection::Devices(vec!["1".into(), "7".into()]);
+        let err = demand(&config, Some(&unknown)).unwrap_err();
+        assert_eq!(err, GpuError::UnknownDevices(vec!["7".into()]));
+        assert_eq!(err.to_string(), "GPUs not in the configured pool: 7");
+    }
+
+    #[rocket::async_test]
+    async fn test_allocate() {
+        let allocator = GpuAllocator::default();
+        let pool = ids(&["0", "1"]);
+        let one = GpuDemand {
+            candidates: pool.clone(),
+            count: 1,
+        };
+        let now = Duration::ZERO;
+
+        let first = allocator.allocate(&one, now).await.unwrap();
+        let second = allocator.allocate(&one, now).await.unwrap();
+        assert_eq!(
+            (first.ids.clone(), second.ids.clone()),
+            (ids(&["0"]), ids(&["1"]))
+        );
+        assert_eq!(allocator.in_use().len(), 2);
+        let err = allocator.allocate(&one, now).await.unwrap_err();
+        assert_eq!(err, GpuError::Unavailable(1));
+        assert!(err.to_string().starts_with("IPOLNoGpuAvailable"));
+
+        drop(first);
+        let third = allocator.allocate(&one, now).await.unwrap();
+        assert_eq!(third.ids, ids(&["0"]));
+        let device = GpuDemand {
+            candidates: ids(&["1"]),
+            count: 1,
+        };
+        assert!(allocator.allocate(&device, now).await.is_err());
+        drop(second);
+        assert_eq!(
+            allocator.allocate(&device, now).await.unwrap().ids,
+            ids(&["1"])
+        );
+
+        let none = GpuDemand {
+            candidates: pool,
+            count: 0,
+        };
+        assert!(allocator.allocate(&none, now).await.unwrap().ids.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn test_allocate_wait() {
+        let allocator = Arc::new(GpuAllocator::default());
+        let one = GpuDemand {
+            candidates: ids(&["0"]),
+            count: 1,
+        };
+        let first = allocator.allocate(&one, Duration::ZERO).await.unwrap();
+
+        let waiting = {
+            let (allocator, one) = (allocator.clone(), one.clone());
+            rocket::tokio::spawn(async move {
+                let start = Instant::now();
+                let allocation = allocator.allocate(&one, Duration::from_secs(10)).await;
+                (
+                    allocation.map(|allocation| allocation.ids.clone()),
+                    start.elapsed(),
+                )
+            })
+        };
+        rocket::tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(first);
+        let (allocation, waited) = waiting.await.unwrap();
+        assert_eq!(allocation.unwrap(), ids(&["0"]));
+        assert!(waited >= Duration::from_millis(200) && waited < Duration::from_secs(5));
+        assert!(allocator.in_use().is_empty());
+
+        let _held = allocator.allocate(&one, Duration::ZERO).await.unwrap();
+        let start = Instant::now();
+        let result = allocator.allocate(&one, Duration::from_millis(100)).await;
+        assert_eq!(result.unwrap_err(), GpuError::Unavailable(1));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,47 @@
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::daemon::DaemonStatus;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HealthResponse {
+    /// `OK`, or `degraded` when the docker daemon is unreachable or outdated.
+    status: String,
+    docker: DaemonStatus,
+}
+
+pub mod http {
+    use rocket::serde::json::Json;
+    use rocket::State;
+
+    use crate::daemon::DaemonStatus;
+    use crate::health::HealthResponse;
+
+    #[get("/health")]
+    pub fn health(docker: &State<DaemonStatus>) -> Json<HealthResponse> {
+        let degraded = docker.error.is_some() || docker.outdated;
+        Json(HealthResponse {
+            status: if degraded { "degraded" } else { "OK" }.into(),
+            docker: docker.inner().clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rocket_from_figment;
+    use rocket::http::Status;
+    use rocket::local::blocking::Client;
+
+    #[test]
+    fn test_health() {
+        let figment = rocket::Config::figment().merge(("docker_min_version", "1000.0"));
+        let client = Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+        let response = client.get("/health").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let health = response.into_json::<HealthResponse>().unwrap();
+        // either no daemon or an outdated one
+        assert_eq!(health.status, "degraded");
+        assert_eq!(health.docker.min_version, Some("1000.0".into()));
+    }
+}
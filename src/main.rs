@@ -1,15 +1,31 @@
+use std::process::ExitCode;
+
+use clap::Parser;
+use rocket::figment::Figment;
 use rocket::{Build, Rocket};
 use tracing_subscriber::EnvFilter;
 
 #[macro_use]
 extern crate rocket;
 
+mod binds;
+mod cli;
 mod compilation;
 mod config;
+mod daemon;
+mod devices;
+mod disk;
 mod execution;
+mod gpus;
+mod health;
 mod model;
+mod names;
+mod numa;
 mod ping;
+mod queue;
+mod schemas;
 mod shutdown;
+mod upload;
 mod workload;
 
 #[get("/")]
@@ -18,29 +34,73 @@ const fn index() -> &'static str {
 }
 
 fn main_rocket() -> Rocket<Build> {
-    rocket::build()
+    rocket_from_figment(rocket::Config::figment())
+}
+
+fn rocket_from_figment(figment: Figment) -> Rocket<Build> {
+    rocket::custom(figment)
         .mount(
             "/",
             routes![
                 index,
                 ping::http::ping,
+                health::http::health,
                 shutdown::shutdown,
                 workload::get_workload,
+                queue::get_queue_depth,
+                queue::get_queue,
                 compilation::ensure_compilation,
-                execution::http::exec_and_wait
+                compilation::ensure_compilations,
+                execution::http::exec_and_wait,
+                execution::http::cancel_exec,
+                execution::logs::http::exec_logs,
+                execution::validate::http::validate,
+                upload::http::create_upload,
+                upload::http::upload_chunk,
+                upload::http::complete_upload,
+                schemas::get_schema
             ],
         )
+        .manage(upload::UploadSessions::default())
+        .manage(disk::DiskReservations::default())
+        .manage(gpus::GpuAllocator::default())
+        .attach(execution::request_timer())
         .attach(config::load_rocket_config())
+        .attach(queue::execution_slots())
+        .attach(compilation::build_locks())
+        .attach(numa::numa_check())
+        .attach(devices::device_check())
+        .attach(binds::binds_check())
+        .attach(daemon::daemon_check())
+        .attach(execution::instance_check())
+        .attach(execution::selinux_check())
 }
 
-#[launch]
-fn _main() -> _ {
+#[rocket::main]
+async fn main() -> ExitCode {
+    let cli = cli::Cli::parse();
     let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::TRACE)
         .with_env_filter(env_filter)
         .init();
-    main_rocket()
+
+    match cli.command.unwrap_or(cli::Command::Serve) {
+        cli::Command::Serve => match main_rocket().launch().await {
+            Ok(_) => ExitCode::SUCCESS,
+            Err(err) => {
+                tracing::error!("{err}");
+                ExitCode::FAILURE
+            }
+        },
+        command => match cli::run(command, &rocket::Config::figment()).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                eprintln!("error: {err}");
+                ExitCode::FAILURE
+            }
+        },
+    }
 }
 
 #[cfg(test)]
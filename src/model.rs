@@ -1,11 +1,16 @@
 use rocket::serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
 use std::collections::HashMap;
 
+mod color;
 mod demoid;
 mod runkey;
+mod schema;
 
+pub use color::Color;
 pub use demoid::DemoID;
 pub use runkey::RunKey;
+pub use schema::{ParamBounds, ParamError, RunParamsSchema};
 
 pub type DDLRun = String;
 pub type RunParams = HashMap<String, ParamValue>;
@@ -18,14 +23,58 @@ pub struct DDLBuild {
     pub dockerfile: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
 #[serde(untagged)]
 pub enum ParamValue {
     Bool(bool),
     PosInt(u64),
     NegInt(i64),
     Float(f64),
+    /// A colour, given as `{"r": 255, "g": 0, "b": 0}` or `#ff0000`; the strings
+    /// which are not CSS hex colours stay strings.
+    Color(Color),
     String(String),
+    /// A list, such as the sizes of a sequence of kernels, possibly nested.
+    Array(Vec<ParamValue>),
+}
+
+/// How the array parameters are written in the environment of the runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArrayFormat {
+    /// The elements separated by spaces, e.g. `3 5 7`, the nested arrays being
+    /// written in JSON, e.g. `[1,2] [3]`.
+    #[default]
+    Spaces,
+    /// The whole array in JSON, e.g. `[3,5,7]`.
+    Json,
+}
+
+impl ParamValue {
+    /// Infers the type of a parameter given as text: an integer, a float, a
+    /// boolean, or else a string.
+    ///
+    /// A number is only inferred when it is written back as the same text,
+    /// so that `007` or `1e3` reach the demo as typed.
+    pub fn infer(value: &str) -> Self {
+        match value.parse::<u64>() {
+            Ok(v) if v.to_string() == value => return ParamValue::PosInt(v),
+            _ => {}
+        }
+        match value.parse::<i64>() {
+            Ok(v) if v.to_string() == value => return ParamValue::NegInt(v),
+            _ => {}
+        }
+        match value.parse::<f64>() {
+            Ok(v) if v.is_finite() && v.to_string() == value => return ParamValue::Float(v),
+            _ => {}
+        }
+        match value {
+            "true" => ParamValue::Bool(true),
+            "false" => ParamValue::Bool(false),
+            _ => ParamValue::String(value.into()),
+        }
+    }
 }
 
 impl std::fmt::Display for ParamValue {
@@ -35,7 +84,31 @@ impl std::fmt::Display for ParamValue {
             ParamValue::PosInt(v) => write!(f, "{}", v),
             ParamValue::NegInt(v) => write!(f, "{}", v),
             ParamValue::Float(v) => write!(f, "{}", v),
+            ParamValue::Color(v) => write!(f, "{}", v),
             ParamValue::String(v) => write!(f, "{}", v),
+            ParamValue::Array(v) => {
+                let elements = v.iter().map(|element| match element {
+                    ParamValue::Array(_) => EnvValue(element, ArrayFormat::Json).to_string(),
+                    _ => element.to_string(),
+                });
+                write!(f, "{}", elements.collect::<Vec<_>>().join(" "))
+            }
+        }
+    }
+}
+
+/// The value of a parameter in the environment of a run, its arrays being
+/// written in the given format.
+pub struct EnvValue<'a>(pub &'a ParamValue, pub ArrayFormat);
+
+impl std::fmt::Display for EnvValue<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvValue(value @ ParamValue::Array(_), ArrayFormat::Json) => {
+                let json = serde_json::to_string(value).map_err(|_| std::fmt::Error)?;
+                write!(f, "{}", json)
+            }
+            EnvValue(value, _) => write!(f, "{}", value),
         }
     }
 }
@@ -57,24 +130,109 @@ pub trait ToEnvVec {
             "NODE_PATH",
             "IPOL_DEMOID",
             "IPOL_KEY",
+            "IPOL_CPUSET",
         ];
         !INVALID_NAMES.contains(&name) && !name.contains('=')
     }
 
-    fn to_env_vec(&self, demo_id: &DemoID, key: &RunKey) -> Vec<String>;
+    /// Why a parameter is left out of the environment, `None` if it is passed.
+    fn unexported_reason(name: &str, value: &str) -> Option<&'static str> {
+        if !Self::is_valid_param_name(name) {
+            return Some("its name is reserved or contains '='");
+        }
+        if value.contains('\0') {
+            return Some("its value contains a NUL byte");
+        }
+        None
+    }
+
+    /// The environment of a run, the parameters being passed byte for byte as
+    /// `name=value`; they are never interpolated into `ddl_run`.
+    ///
+    /// The parameters with a NUL byte, which can't be in an environment, are left out.
+    fn to_env_vec(&self, demo_id: &DemoID, key: &RunKey, arrays: ArrayFormat) -> Vec<String>;
 }
 
 impl ToEnvVec for RunParams {
-    fn to_env_vec(&self, demo_id: &DemoID, key: &RunKey) -> Vec<String> {
+    fn to_env_vec(&self, demo_id: &DemoID, key: &RunKey, arrays: ArrayFormat) -> Vec<String> {
         let env = [
             ("IPOL_DEMOID", demo_id.to_string()),
             ("IPOL_KEY", key.to_string()),
         ];
         self.iter()
-            .filter(|(name, _)| Self::is_valid_param_name(name))
-            .map(|(name, value)| (name.as_ref(), value.to_string()))
+            .map(|(name, value)| (name.as_ref(), EnvValue(value, arrays).to_string()))
+            .filter(|(name, value)| Self::unexported_reason(name, value).is_none())
             .chain(env)
             .map(|(name, value)| format!("{}={}", name, value))
             .collect()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_infer_param_value() {
+        assert_eq!(ParamValue::infer("1"), ParamValue::PosInt(1));
+        assert_eq!(ParamValue::infer("-1"), ParamValue::NegInt(-1));
+        assert_eq!(ParamValue::infer("2.5"), ParamValue::Float(2.5));
+        assert_eq!(ParamValue::infer("1e3"), ParamValue::String("1e3".into()));
+        assert_eq!(ParamValue::infer("007"), ParamValue::String("007".into()));
+        assert_eq!(ParamValue::infer("+5"), ParamValue::String("+5".into()));
+        assert_eq!(ParamValue::infer("1.0"), ParamValue::String("1.0".into()));
+        assert_eq!(ParamValue::infer("-0.25"), ParamValue::Float(-0.25));
+        assert_eq!(ParamValue::infer("true"), ParamValue::Bool(true));
+        assert_eq!(ParamValue::infer("false"), ParamValue::Bool(false));
+        assert_eq!(ParamValue::infer("abc"), ParamValue::String("abc".into()));
+        assert_eq!(ParamValue::infer("inf"), ParamValue::String("inf".into()));
+        assert_eq!(ParamValue::infer("True"), ParamValue::String("True".into()));
+        assert_eq!(ParamValue::infer(""), ParamValue::String("".into()));
+    }
+
+    #[test]
+    fn test_to_env_vec_verbatim() {
+        let demo_id = DemoID::try_from("t001").unwrap();
+        let key = RunKey::try_from("abc").unwrap();
+        let value = "$HOME `id` 'a' \"b\" \\ é字\n";
+        let params = RunParams::from([
+            ("s".into(), ParamValue::String(value.into())),
+            ("nul".into(), ParamValue::String("a\0b".into())),
+        ]);
+        let env = params.to_env_vec(&demo_id, &key, ArrayFormat::Spaces);
+        assert!(env.contains(&format!("s={value}")));
+        assert!(!env.iter().any(|var| var.starts_with("nul=")));
+    }
+
+    #[test]
+    fn test_array_param_value() {
+        let json = r#"{"sizes": [3, 5, 7], "nested": [[1, -2], [0.5], "a b", true, []]}"#;
+        let params: RunParams = serde_json::from_str(json).unwrap();
+        let sizes = ParamValue::Array(vec![
+            ParamValue::PosInt(3),
+            ParamValue::PosInt(5),
+            ParamValue::PosInt(7),
+        ]);
+        assert_eq!(params["sizes"], sizes);
+        let nested = ParamValue::Array(vec![
+            ParamValue::Array(vec![ParamValue::PosInt(1), ParamValue::NegInt(-2)]),
+            ParamValue::Array(vec![ParamValue::Float(0.5)]),
+            ParamValue::String("a b".into()),
+            ParamValue::Bool(true),
+            ParamValue::Array(vec![]),
+        ]);
+        assert_eq!(params["nested"], nested);
+        // round trip
+        let json = serde_json::to_string(&params).unwrap();
+        assert_eq!(serde_json::from_str::<RunParams>(&json).unwrap(), params);
+
+        let demo_id = DemoID::try_from("t001").unwrap();
+        let key = RunKey::try_from("abc").unwrap();
+        let env = params.to_env_vec(&demo_id, &key, ArrayFormat::Spaces);
+        assert!(env.contains(&"sizes=3 5 7".to_string()));
+        assert!(env.contains(&"nested=[1,-2] [0.5] a b true []".to_string()));
+        let env = params.to_env_vec(&demo_id, &key, ArrayFormat::Json);
+        assert!(env.contains(&"sizes=[3,5,7]".to_string()));
+        assert!(env.contains(&r#"nested=[[1,-2],[0.5],"a b",true,[]]"#.to_string()));
+    }
+}
@@ -0,0 +1,201 @@
+use rocket::form::{FromFormField, ValueField};
+use rocket::serde::{de, Deserialize, Deserializer, Serialize};
+use schemars::JsonSchema;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// A colour parameter, such as the fill colour of a mask.
+///
+/// Given in JSON either as `{"r": 255, "g": 0, "b": 0}`, with an optional `a`,
+/// or as a CSS hex string `#RRGGBB` or `#RRGGBBAA`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub a: Option<u8>,
+}
+
+impl Color {
+    /// Parses `#RRGGBB` or `#RRGGBBAA`.
+    fn from_hex(s: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid colour {s:?}, expected #RRGGBB or #RRGGBBAA");
+        let hex = s.strip_prefix('#').ok_or_else(invalid)?;
+        if !matches!(hex.len(), 6 | 8) || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+        Ok(Color {
+            r: component(0)?,
+            g: component(2)?,
+            b: component(4)?,
+            a: if hex.len() == 8 {
+                Some(component(6)?)
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Parses `R,G,B` or `R,G,B,A`, each component being between 0 and 255.
+    fn from_components(s: &str) -> Result<Self, String> {
+        let components = s
+            .split(',')
+            .map(|component| {
+                let component = component.trim();
+                let value = component
+                    .parse::<u64>()
+                    .map_err(|_| format!("invalid colour component {component:?}"))?;
+                u8::try_from(value)
+                    .map_err(|_| format!("colour component {value} is out of 0 to 255"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        match components[..] {
+            [r, g, b] => Ok(Color { r, g, b, a: None }),
+            [r, g, b, a] => Ok(Color {
+                r,
+                g,
+                b,
+                a: Some(a),
+            }),
+            _ => Err(format!("invalid colour {s:?}, expected R,G,B or R,G,B,A")),
+        }
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    /// Parses a colour written as `#RRGGBB`, `#RRGGBBAA`, `R,G,B` or `R,G,B,A`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('#') {
+            Self::from_hex(s)
+        } else {
+            Self::from_components(s)
+        }
+    }
+}
+
+/// `R,G,B` or `R,G,B,A`, as given to the demos.
+impl Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{},{}", self.r, self.g, self.b)?;
+        if let Some(a) = self.a {
+            write!(f, ",{a}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Hex(String),
+            Components {
+                r: u8,
+                g: u8,
+                b: u8,
+                #[serde(default)]
+                a: Option<u8>,
+            },
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Hex(hex) => Color::from_hex(&hex).map_err(de::Error::custom),
+            Repr::Components { r, g, b, a } => Ok(Color { r, g, b, a }),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromFormField<'r> for Color {
+    fn from_value(field: ValueField<'r>) -> rocket::form::Result<'r, Self> {
+        field
+            .value
+            .parse()
+            .map_err(|e: String| rocket::form::Error::validation(e).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{ArrayFormat, DemoID, ParamValue, RunKey, RunParams, ToEnvVec};
+    use rocket::form::Form;
+
+    const RED: Color = Color {
+        r: 255,
+        g: 0,
+        b: 0,
+        a: None,
+    };
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!("#ff0000".parse(), Ok(RED));
+        assert_eq!("255, 0, 0".parse(), Ok(RED));
+        let translucent = Color {
+            a: Some(128),
+            ..RED
+        };
+        assert_eq!("#FF000080".parse(), Ok(translucent));
+        assert_eq!("255,0,0,128".parse(), Ok(translucent));
+        assert_eq!(
+            "256,0,0".parse::<Color>(),
+            Err("colour component 256 is out of 0 to 255".into())
+        );
+        assert!("#ff00".parse::<Color>().is_err());
+        assert!("#gg0000".parse::<Color>().is_err());
+        assert!("255,0".parse::<Color>().is_err());
+        assert!("-1,0,0".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn test_color_form_field() {
+        #[derive(Debug, FromForm)]
+        struct Fill {
+            fill: Color,
+        }
+        assert_eq!(Form::<Fill>::parse("fill=255,0,0").unwrap().fill, RED);
+        assert_eq!(Form::<Fill>::parse("fill=%23ff0000").unwrap().fill, RED);
+        let errors = Form::<Fill>::parse("fill=255,0,300").unwrap_err();
+        assert!(errors.to_string().contains("out of 0 to 255"), "{errors}");
+    }
+
+    #[test]
+    fn test_color_param() {
+        let json = r##"{
+            "hex": "#ff0000",
+            "rgba": {"r": 1, "g": 2, "b": 3, "a": 4},
+            "rgb": {"r": 1, "g": 2, "b": 3},
+            "text": "#red"
+        }"##;
+        let params: RunParams = serde_json::from_str(json).unwrap();
+        assert_eq!(params["hex"], ParamValue::Color(RED));
+        let rgba = Color {
+            r: 1,
+            g: 2,
+            b: 3,
+            a: Some(4),
+        };
+        assert_eq!(params["rgba"], ParamValue::Color(rgba));
+        assert_eq!(params["text"], ParamValue::String("#red".into()));
+        let out_of_range = r#"{"c": {"r": 256, "g": 0, "b": 0}}"#;
+        assert!(serde_json::from_str::<RunParams>(out_of_range).is_err());
+
+        // echoed in the structured form
+        let echoed = serde_json::to_value(&params["rgb"]).unwrap();
+        assert_eq!(echoed, serde_json::json!({"r": 1, "g": 2, "b": 3}));
+        let json = serde_json::to_string(&params).unwrap();
+        assert_eq!(serde_json::from_str::<RunParams>(&json).unwrap(), params);
+
+        let demo_id = DemoID::try_from("t001").unwrap();
+        let key = RunKey::try_from("abc").unwrap();
+        let env = params.to_env_vec(&demo_id, &key, ArrayFormat::Spaces);
+        assert!(env.contains(&"hex=255,0,0".to_string()));
+        assert!(env.contains(&"rgba=1,2,3,4".to_string()));
+        assert!(env.contains(&"rgb=1,2,3".to_string()));
+    }
+}
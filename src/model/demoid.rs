@@ -1,8 +1,10 @@
 use regex::Regex;
+use rocket::serde::{Deserialize, Serialize};
 use rocket::{http::uri::fmt::UriDisplay, request::FromParam};
 use std::fmt::Display;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String")]
 pub struct DemoID(String);
 
 impl Display for DemoID {
@@ -31,6 +33,14 @@ impl TryFrom<&str> for DemoID {
     }
 }
 
+impl TryFrom<String> for DemoID {
+    type Error = &'static str;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.as_str().try_into()
+    }
+}
+
 impl<'a> FromParam<'a> for DemoID {
     type Error = &'a str;
 
@@ -0,0 +1,136 @@
+use rocket::serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use std::collections::HashMap;
+
+use super::{ParamValue, RunParams};
+
+/// Bounds of a numeric parameter, any of which may be left out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ParamBounds {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// The value must be a multiple of it from `min`, or from 0 without `min`.
+    pub step: Option<f64>,
+}
+
+impl ParamBounds {
+    /// Why `value` breaks the bounds, if it does.
+    fn check(&self, value: f64) -> Option<String> {
+        if let Some(step) = self.step.filter(|step| !(step.is_finite() && *step > 0.0)) {
+            return Some(format!("invalid bounds: step {step} is not positive"));
+        }
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min > max {
+                return Some(format!("invalid bounds: minimum {min} above maximum {max}"));
+            }
+        }
+        if let Some(min) = self.min.filter(|min| value < *min) {
+            return Some(format!("{value} is below the minimum {min}"));
+        }
+        if let Some(max) = self.max.filter(|max| value > *max) {
+            return Some(format!("{value} is above the maximum {max}"));
+        }
+        if let Some(step) = self.step {
+            let base = self.min.unwrap_or(0.0);
+            let steps = (value - base) / step;
+            // the usual decimal steps, such as 0.1, aren't exact in binary
+            if (steps - steps.round()).abs() > 1e-9 * steps.abs().max(1.0) {
+                return Some(format!("{value} is not a multiple of {step} from {base}"));
+            }
+        }
+        None
+    }
+}
+
+/// The bounds of the numeric parameters of a run, by name.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(transparent)]
+pub struct RunParamsSchema(pub HashMap<String, ParamBounds>);
+
+/// A parameter refused by a `RunParamsSchema`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ParamError {
+    pub field: String,
+    pub message: String,
+}
+
+impl RunParamsSchema {
+    /// The parameters which break their bounds, sorted by name; the parameters
+    /// without bounds and the bounds without parameters are ignored.
+    pub fn check(&self, params: &RunParams) -> Vec<ParamError> {
+        let mut errors = self
+            .0
+            .iter()
+            .filter_map(|(name, bounds)| {
+                let message = match params.get(name)? {
+                    ParamValue::PosInt(value) => bounds.check(*value as f64),
+                    ParamValue::NegInt(value) => bounds.check(*value as f64),
+                    ParamValue::Float(value) => bounds.check(*value),
+                    value => {
+                        let value = serde_json::to_string(value).unwrap_or_default();
+                        Some(format!("{value} is not a number"))
+                    }
+                }?;
+                Some(ParamError {
+                    field: name.clone(),
+                    message,
+                })
+            })
+            .collect::<Vec<_>>();
+        errors.sort_by(|a, b| a.field.cmp(&b.field));
+        errors
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check(schema: serde_json::Value, params: serde_json::Value) -> Vec<(String, String)> {
+        let schema: RunParamsSchema = serde_json::from_value(schema).unwrap();
+        let params: RunParams = serde_json::from_value(params).unwrap();
+        schema
+            .check(&params)
+            .into_iter()
+            .map(|error| (error.field, error.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_params_schema() {
+        let schema = serde_json::json!({
+            "sigma": {"min": 0, "max": 100},
+            "scale": {"min": 1, "step": 0.5},
+            "offset": {"min": -10, "max": -1},
+            "angle": {"step": 0.1},
+            "absent": {"min": 0},
+        });
+        let params = serde_json::json!({
+            "sigma": 15.5, "scale": 3, "offset": -5, "angle": 0.3, "unbounded": -3,
+        });
+        assert_eq!(check(schema.clone(), params), []);
+
+        let params = serde_json::json!({
+            "sigma": 101, "scale": 1.25, "offset": -11, "angle": "wide",
+        });
+        let expected = [
+            ("angle", "\"wide\" is not a number"),
+            ("offset", "-11 is below the minimum -10"),
+            ("scale", "1.25 is not a multiple of 0.5 from 1"),
+            ("sigma", "101 is above the maximum 100"),
+        ];
+        let expected = expected.map(|(field, message)| (field.to_string(), message.to_string()));
+        assert_eq!(check(schema, params), expected);
+
+        let schema = serde_json::json!({"a": {"step": 0}, "b": {"min": 2, "max": 1}});
+        let errors = check(schema, serde_json::json!({"a": 1, "b": 1}));
+        assert!(errors
+            .iter()
+            .all(|(_, message)| message.starts_with("invalid bounds")));
+        assert_eq!(errors.len(), 2);
+
+        let typo = serde_json::json!({"sigma": {"minimum": 0}});
+        assert!(serde_json::from_value::<RunParamsSchema>(typo).is_err());
+    }
+}
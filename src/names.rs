@@ -0,0 +1,288 @@
+use std::path::PathBuf;
+
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+use crate::config;
+use crate::model::{DemoID, RunKey};
+
+/// Maximum length of a path component (`NAME_MAX`).
+pub const PATH_COMPONENT_MAX: usize = 255;
+/// Maximum length of a path (`PATH_MAX`).
+pub const PATH_MAX: usize = 4096;
+/// Maximum length of the name of a docker image, registry included.
+pub const IMAGE_NAME_MAX: usize = 255;
+/// Budget of the name of a container, docker itself not enforcing any.
+pub const CONTAINER_NAME_MAX: usize = 255;
+
+/// Length of the hash which replaces the end of an invalid or too long name.
+const HASH_LEN: usize = 12;
+
+/// Longest file of a compilation directory built by the runner.
+const COMPILATION_FILE: &str = "build.log";
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum NameError {
+    #[error(
+        "{component} leaves less than {min} bytes for the demo in the {budget} bytes of {target}"
+    )]
+    TooLong {
+        component: &'static str,
+        target: &'static str,
+        budget: usize,
+        min: usize,
+    },
+    #[error("{component} makes an invalid {target}: {name:?}")]
+    Invalid {
+        component: &'static str,
+        target: &'static str,
+        name: String,
+    },
+}
+
+fn short_hash(name: &str) -> String {
+    let mut hex = hex::encode(Sha256::digest(name.as_bytes()));
+    hex.truncate(HASH_LEN);
+    hex
+}
+
+/// Returns `name` if it is valid and fits in `budget` bytes, otherwise its
+/// sanitized beginning followed by a hash of the whole of it.
+///
+/// `sanitize` must map to ASCII characters, and `budget` be at least `HASH_LEN`.
+fn fit(name: &str, budget: usize, valid: bool, sanitize: impl Fn(char) -> char) -> String {
+    if valid && name.len() <= budget {
+        return name.to_string();
+    }
+    let hash = short_hash(name);
+    let mut beginning = name.chars().map(sanitize).collect::<String>();
+    beginning.truncate(budget.saturating_sub(HASH_LEN + 1));
+    let beginning = beginning.trim_matches(|c: char| !c.is_ascii_alphanumeric());
+    if beginning.is_empty() {
+        hash
+    } else {
+        format!("{beginning}-{hash}")
+    }
+}
+
+fn check_budget(
+    component: &'static str,
+    target: &'static str,
+    budget: usize,
+    used: usize,
+) -> Result<usize, NameError> {
+    let left = budget.saturating_sub(used);
+    if left < HASH_LEN {
+        return Err(NameError::TooLong {
+            component,
+            target,
+            budget,
+            min: HASH_LEN,
+        });
+    }
+    Ok(left)
+}
+
+/// Directory of the sources and the build log of a demo.
+pub fn compilation_dir(config: &config::Config, demo_id: &DemoID) -> Result<PathBuf, NameError> {
+    let target = "the compilation directory";
+    let root = &config.compilation_root;
+    // the root, the separators and the longest file in the directory
+    let used = root.len() + 2 + COMPILATION_FILE.len();
+    let left = check_budget("compilation_root", target, PATH_MAX, used)?;
+    let budget = left.min(PATH_COMPONENT_MAX);
+    let name = fit(demo_id.as_ref(), budget, true, |c| {
+        if c.is_ascii_alphanumeric() {
+            c
+        } else {
+            '_'
+        }
+    });
+    Ok(PathBuf::from(root).join(name))
+}
+
+fn is_valid_image_component(name: &str) -> bool {
+    lazy_static::lazy_static! {
+        static ref RE: Regex = Regex::new(r"^[a-z0-9]+(?:(?:[._]|__|-*)[a-z0-9]+)*$").unwrap();
+    }
+    RE.is_match(name)
+}
+
+/// Name of the docker image of a demo, without its tag.
+pub fn image_name(config: &config::Config, demo_id: &DemoID) -> Result<String, NameError> {
+    let target = "the image name";
+    let registry = config
+        .registry_url
+        .as_ref()
+        .map_or(String::new(), |url| (url.clone() + "/"));
+    let prefix = &config.docker_image_prefix;
+    let used = registry.len() + prefix.len();
+    let component = if registry.is_empty() {
+        "docker_image_prefix"
+    } else {
+        "registry_url and docker_image_prefix"
+    };
+    let budget = check_budget(component, target, IMAGE_NAME_MAX, used)?;
+
+    let demo = demo_id.as_ref();
+    let valid = is_valid_image_component(&format!("{prefix}{demo}"));
+    let demo = fit(demo, budget, valid, |c| {
+        if c.is_ascii_alphanumeric() {
+            c.to_ascii_lowercase()
+        } else {
+            '-'
+        }
+    });
+    let name = format!("{prefix}{demo}");
+    if !is_valid_image_component(&name) {
+        return Err(NameError::Invalid {
+            component: "docker_image_prefix",
+            target,
+            name,
+        });
+    }
+    Ok(format!("{registry}{name}"))
+}
+
+fn is_valid_container_name(name: &str) -> bool {
+    lazy_static::lazy_static! {
+        static ref RE: Regex = Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9_.-]+$").unwrap();
+    }
+    RE.is_match(name)
+}
+
+/// Name of the container of a run.
+pub fn container_name(
+    config: &config::Config,
+    demo_id: &DemoID,
+    key: &RunKey,
+) -> Result<String, NameError> {
+    let target = "the container name";
+    let prefix = &config.docker_exec_prefix;
+    let budget = check_budget(
+        "docker_exec_prefix",
+        target,
+        CONTAINER_NAME_MAX,
+        prefix.len(),
+    )?;
+
+    let run = format!("{demo_id}-{key}");
+    let valid = is_valid_container_name(&format!("{prefix}{run}"));
+    let run = fit(&run, budget, valid, |c| {
+        if c.is_ascii_alphanumeric() || c == '-' {
+            c
+        } else {
+            '_'
+        }
+    });
+    let name = format!("{prefix}{run}");
+    if !is_valid_container_name(&name) {
+        return Err(NameError::Invalid {
+            component: "docker_exec_prefix",
+            target,
+            name,
+        });
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::seq::SliceRandom;
+    use rand::Rng;
+
+    /// Word characters of various lengths in UTF-8.
+    const ALPHABET: &[char] = &['a', 'Z', '0', '_', 'é', 'ß', 'Σ', '字', '𝔸'];
+
+    fn random_word(rng: &mut impl Rng, max_len: usize) -> String {
+        let len = rng.gen_range(1..=max_len);
+        (0..len).map(|_| *ALPHABET.choose(rng).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_short_ascii_names_unchanged() {
+        let config = config::test_config();
+        let demo_id = DemoID::try_from("t001").unwrap();
+        let key = RunKey::try_from("abc_1").unwrap();
+        assert_eq!(
+            compilation_dir(&config, &demo_id).unwrap(),
+            PathBuf::from(&config.compilation_root).join("t001")
+        );
+        assert_eq!(
+            image_name(&config, &demo_id).unwrap(),
+            format!("{}t001", config.docker_image_prefix)
+        );
+        assert_eq!(
+            container_name(&config, &demo_id, &key).unwrap(),
+            format!("{}t001-abc_1", config.docker_exec_prefix)
+        );
+    }
+
+    #[test]
+    fn test_names_within_limits() {
+        let mut rng = rand::thread_rng();
+        let mut config = config::test_config();
+        for _ in 0..500 {
+            config.compilation_root = format!("/{}", "r".repeat(rng.gen_range(0..3000)));
+            config.docker_image_prefix = "p-".repeat(rng.gen_range(0..100));
+            config.docker_exec_prefix = "e-".repeat(rng.gen_range(1..100));
+            config.registry_url = rng
+                .gen_bool(0.5)
+                .then(|| format!("registry.example.org:{}", rng.gen_range(1..65536)));
+            let demo_id = DemoID::try_from(random_word(&mut rng, 600).as_str()).unwrap();
+            let key = RunKey::try_from(random_word(&mut rng, 600).as_str()).unwrap();
+
+            let dir = compilation_dir(&config, &demo_id).unwrap();
+            let name = dir.file_name().unwrap().to_str().unwrap();
+            assert!(name.len() <= PATH_COMPONENT_MAX, "{name}");
+            assert!(dir.join(COMPILATION_FILE).to_str().unwrap().len() <= PATH_MAX);
+
+            let image = image_name(&config, &demo_id).unwrap();
+            assert!(image.len() <= IMAGE_NAME_MAX, "{image}");
+            let component = image.rsplit('/').next().unwrap();
+            assert!(is_valid_image_component(component), "{image}");
+
+            let container = container_name(&config, &demo_id, &key).unwrap();
+            assert!(container.len() <= CONTAINER_NAME_MAX, "{container}");
+            assert!(is_valid_container_name(&container), "{container}");
+
+            // deterministic, so that the compilation and the runs agree
+            assert_eq!(compilation_dir(&config, &demo_id).unwrap(), dir);
+            assert_eq!(image_name(&config, &demo_id).unwrap(), image);
+        }
+    }
+
+    #[test]
+    fn test_distinct_ids_distinct_names() {
+        let config = config::test_config();
+        let image = |id: &str| image_name(&config, &DemoID::try_from(id).unwrap()).unwrap();
+        assert_ne!(image("démo"), image("dèmo"));
+        assert_ne!(image("Demo"), image("demo"));
+        assert!(image("Demo").starts_with(&format!("{}demo-", config.docker_image_prefix)));
+        assert_ne!(image(&"a".repeat(300)), image(&"a".repeat(301)));
+    }
+
+    #[test]
+    fn test_budget_errors() {
+        let mut config = config::test_config();
+        let demo_id = DemoID::try_from("t001").unwrap();
+        let key = RunKey::try_from("abc").unwrap();
+
+        config.compilation_root = "r".repeat(PATH_MAX);
+        config.docker_image_prefix = "p".repeat(IMAGE_NAME_MAX);
+        config.docker_exec_prefix = "e".repeat(CONTAINER_NAME_MAX);
+        let err = compilation_dir(&config, &demo_id).unwrap_err();
+        assert!(err.to_string().starts_with("compilation_root"), "{err}");
+        let err = image_name(&config, &demo_id).unwrap_err();
+        assert!(err.to_string().starts_with("docker_image_prefix"), "{err}");
+        let err = container_name(&config, &demo_id, &key).unwrap_err();
+        assert!(err.to_string().starts_with("docker_exec_prefix"), "{err}");
+
+        config.docker_image_prefix = "Upper-".into();
+        assert!(matches!(
+            image_name(&config, &demo_id),
+            Err(NameError::Invalid { .. })
+        ));
+    }
+}
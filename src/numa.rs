@@ -0,0 +1,403 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::config;
+use crate::model::DemoID;
+
+/// Where the kernel describes the NUMA nodes of the host.
+const NODE_ROOT: &str = "/sys/devices/system/node";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumaNode {
+    pub id: u32,
+    /// CPUs of the node, in the cpuset list format.
+    pub cpus: String,
+}
+
+/// CPUs and memory nodes a run is pinned to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cpuset {
+    pub cpus: String,
+    pub mems: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum NumaError {
+    #[error("demo {demo_id}: unknown NUMA node {node}")]
+    UnknownNode { demo_id: String, node: u32 },
+    #[error("demo {demo_id}: invalid cpuset {cpuset:?}")]
+    InvalidCpuset { demo_id: String, cpuset: String },
+    #[error("cpuset_cpus: invalid or unknown cpuset {0:?}")]
+    InvalidDefaultCpuset(String),
+    #[error("demo {demo_id}: the cpuset {cpuset:?} doesn't exist on this host")]
+    UnknownCpuset { demo_id: String, cpuset: String },
+}
+
+/// Parses a cpuset list such as `0-3,8,10-11`.
+pub fn parse_cpulist(list: &str) -> Option<BTreeSet<u32>> {
+    let mut ids = BTreeSet::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start.trim().parse().ok()?, end.trim().parse().ok()?),
+            None => {
+                let id = range.trim().parse().ok()?;
+                (id, id)
+            }
+        };
+        if start > end {
+            return None;
+        }
+        ids.extend(start..=end);
+    }
+    Some(ids)
+}
+
+/// Lists the NUMA nodes described under `root` (`/sys/devices/system/node`).
+pub fn host_nodes(root: &Path) -> std::io::Result<Vec<NumaNode>> {
+    let mut nodes = Vec::new();
+    for entry in std::fs::read_dir(root)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(id) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("node"))
+            .and_then(|id| id.parse().ok())
+        else {
+            continue;
+        };
+        let cpus = std::fs::read_to_string(entry.path().join("cpulist"))?;
+        nodes.push(NumaNode {
+            id,
+            cpus: cpus.trim().to_string(),
+        });
+    }
+    nodes.sort_by_key(|node| node.id);
+    Ok(nodes)
+}
+
+/// Checks that the cpusets of the config and of the demo configs exist on the host.
+pub fn validate(config: &config::Config, nodes: &[NumaNode]) -> Result<(), NumaError> {
+    let node_ids = nodes.iter().map(|node| node.id).collect::<BTreeSet<_>>();
+    let cpus = nodes
+        .iter()
+        .filter_map(|node| parse_cpulist(&node.cpus))
+        .flatten()
+        .collect::<BTreeSet<_>>();
+
+    let check = |demo_id: &str, cpuset: &str, existing: &BTreeSet<u32>| {
+        let Some(ids) = parse_cpulist(cpuset) else {
+            return Err(NumaError::InvalidCpuset {
+                demo_id: demo_id.into(),
+                cpuset: cpuset.into(),
+            });
+        };
+        if !ids.is_subset(existing) {
+            return Err(NumaError::UnknownCpuset {
+                demo_id: demo_id.into(),
+                cpuset: cpuset.into(),
+            });
+        }
+        Ok(())
+    };
+
+    if let Some(cpuset) = &config.cpuset_cpus {
+        if check("", cpuset, &cpus).is_err() {
+            return Err(NumaError::InvalidDefaultCpuset(cpuset.clone()));
+        }
+    }
+    for (demo_id, demo) in &config.demos {
+        if let Some(node) = demo.numa_node {
+            if !node_ids.contains(&node) {
+                return Err(NumaError::UnknownNode {
+                    demo_id: demo_id.clone(),
+                    node,
+                });
+            }
+        }
+        if let Some(cpuset) = &demo.cpuset_cpus {
+            check(demo_id, cpuset, &cpus)?;
+        }
+        if let Some(cpuset) = &demo.cpuset_mems {
+            check(demo_id, cpuset, &node_ids)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Balance {
+    /// Number of runs in progress on each node.
+    running: Vec<usize>,
+    /// Node to favour on a tie, so that the runs alternate between the nodes.
+    next: usize,
+}
+
+/// NUMA nodes of the host, and the runs currently assigned to each of them.
+#[derive(Debug)]
+pub struct NumaAssignments {
+    nodes: Vec<NumaNode>,
+    balance: Arc<Mutex<Balance>>,
+}
+
+/// Assignment of a run to a NUMA node, released when dropped.
+#[derive(Debug)]
+pub struct NumaAssignment {
+    balance: Arc<Mutex<Balance>>,
+    node: usize,
+}
+
+impl Drop for NumaAssignment {
+    fn drop(&mut self) {
+        let mut balance = self.balance.lock().unwrap();
+        balance.running[self.node] -= 1;
+    }
+}
+
+impl NumaAssignments {
+    pub fn new(nodes: Vec<NumaNode>) -> Self {
+        let balance = Balance {
+            running: vec![0; nodes.len()],
+            next: 0,
+        };
+        Self {
+            nodes,
+            balance: Arc::new(Mutex::new(balance)),
+        }
+    }
+
+    /// Assigns a run to the node with the fewest runs in progress.
+    fn assign(&self) -> Option<(Cpuset, NumaAssignment)> {
+        let mut balance = self.balance.lock().unwrap();
+        let count = self.nodes.len();
+        let node = (0..count)
+            .map(|i| (balance.next + i) % count)
+            .min_by_key(|&i| balance.running[i])?;
+        balance.running[node] += 1;
+        balance.next = (node + 1) % count;
+        let cpuset = self.node_cpuset(&self.nodes[node]);
+        let assignment = NumaAssignment {
+            balance: self.balance.clone(),
+            node,
+        };
+        Some((cpuset, assignment))
+    }
+
+    fn node_cpuset(&self, node: &NumaNode) -> Cpuset {
+        Cpuset {
+            cpus: node.cpus.clone(),
+            mems: Some(node.id.to_string()),
+        }
+    }
+
+    /// Chooses the cpuset of a run: the one of the demo config if any, else
+    /// the least loaded node when `numa_balancing` is enabled, else the
+    /// `cpuset_cpus` of the config.
+    pub fn cpuset(
+        &self,
+        config: &config::Config,
+        demo_id: &DemoID,
+    ) -> Option<(Cpuset, Option<NumaAssignment>)> {
+        let demo = config.demo(demo_id);
+        if let Some(cpus) = demo.cpuset_cpus {
+            let cpuset = Cpuset {
+                cpus,
+                mems: demo.cpuset_mems,
+            };
+            return Some((cpuset, None));
+        }
+        if let Some(id) = demo.numa_node {
+            let node = self.nodes.iter().find(|node| node.id == id)?;
+            return Some((self.node_cpuset(node), None));
+        }
+        if config.numa_balancing {
+            if let Some((cpuset, assignment)) = self.assign() {
+                return Some((cpuset, Some(assignment)));
+            }
+        }
+        let cpuset = Cpuset {
+            cpus: config.cpuset_cpus.clone()?,
+            mems: None,
+        };
+        Some((cpuset, None))
+    }
+}
+
+/// NUMA nodes of this host, none if they can't be listed.
+pub fn discover_nodes() -> Vec<NumaNode> {
+    match host_nodes(Path::new(NODE_ROOT)) {
+        Ok(nodes) => nodes,
+        Err(err) => {
+            tracing::info!("couldn't list the NUMA nodes: {err}");
+            Vec::new()
+        }
+    }
+}
+
+/// Discovers the NUMA nodes of the host and checks the cpusets of the config,
+/// aborting the launch if they don't exist.
+pub fn numa_check() -> rocket::fairing::AdHoc {
+    rocket::fairing::AdHoc::try_on_ignite("NUMA check", |rocket| {
+        Box::pin(async move {
+            let nodes = discover_nodes();
+            if let Some(config) = rocket.state::<config::Config>() {
+                if let Err(err) = validate(config, &nodes) {
+                    tracing::error!("{err}");
+                    return Err(rocket);
+                }
+                if config.numa_balancing && nodes.is_empty() {
+                    tracing::warn!("numa_balancing is enabled but no NUMA node was found");
+                }
+            }
+            Ok(rocket.manage(NumaAssignments::new(nodes)))
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn two_nodes() -> Vec<NumaNode> {
+        vec![
+            NumaNode {
+                id: 0,
+                cpus: "0-3".into(),
+            },
+            NumaNode {
+                id: 1,
+                cpus: "4-7".into(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_parse_cpulist() {
+        assert_eq!(
+            parse_cpulist("0-2,8,10-11\n"),
+            Some(BTreeSet::from([0, 1, 2, 8, 10, 11]))
+        );
+        assert_eq!(parse_cpulist(""), Some(BTreeSet::new()));
+        assert_eq!(parse_cpulist("3-1"), None);
+        assert_eq!(parse_cpulist("a"), None);
+    }
+
+    #[test]
+    fn test_host_nodes() {
+        let root = tempfile::TempDir::new().unwrap();
+        for (node, cpus) in [("node1", "4-7\n"), ("node0", "0-3\n")] {
+            std::fs::create_dir(root.path().join(node)).unwrap();
+            std::fs::write(root.path().join(node).join("cpulist"), cpus).unwrap();
+        }
+        std::fs::create_dir(root.path().join("power")).unwrap();
+        std::fs::write(root.path().join("possible"), "0-1\n").unwrap();
+
+        assert_eq!(host_nodes(root.path()).unwrap(), two_nodes());
+    }
+
+    #[test]
+    fn test_demo_config() {
+        let figment = rocket::Config::figment()
+            .merge(("demos.33.numa_node", 1))
+            .merge(("demos.34.cpuset_cpus", "0-1"))
+            .merge(("demos.34.cpuset_mems", "0"));
+        let config = figment.extract::<config::Config>().unwrap();
+
+        let demo = config.demo(&DemoID::try_from("33").unwrap());
+        assert_eq!(demo.numa_node, Some(1));
+        let demo = config.demo(&DemoID::try_from("34").unwrap());
+        assert_eq!(demo.cpuset_cpus.as_deref(), Some("0-1"));
+        assert_eq!(demo.cpuset_mems.as_deref(), Some("0"));
+        let demo = config.demo(&DemoID::try_from("35").unwrap());
+        assert_eq!(demo, config::DemoConfig::default());
+
+        assert!(validate(&config, &two_nodes()).is_ok());
+        assert!(matches!(
+            validate(&config, &two_nodes()[..1]),
+            Err(NumaError::UnknownNode { node: 1, .. })
+        ));
+
+        let figment = rocket::Config::figment().merge(("demos.33.cpuset_cpus", "6-9"));
+        let config = figment.extract::<config::Config>().unwrap();
+        assert!(matches!(
+            validate(&config, &two_nodes()),
+            Err(NumaError::UnknownCpuset { .. })
+        ));
+    }
+
+    #[test]
+    fn test_numa_balancing() {
+        let mut config = config::test_config();
+        config.numa_balancing = true;
+        let assignments = NumaAssignments::new(two_nodes());
+        let demo_id = DemoID::try_from("t001").unwrap();
+        let cpuset = || {
+            let (cpuset, assignment) = assignments.cpuset(&config, &demo_id).unwrap();
+            (cpuset.mems.unwrap(), assignment.unwrap())
+        };
+
+        // the runs alternate between the nodes
+        let (node, first) = cpuset();
+        assert_eq!(node, "0");
+        let (node, second) = cpuset();
+        assert_eq!(node, "1");
+        let (node, third) = cpuset();
+        assert_eq!(node, "0");
+
+        // the least loaded node is preferred
+        drop(second);
+        let (node, _fourth) = cpuset();
+        assert_eq!(node, "1");
+        drop(first);
+        drop(third);
+        let (node, _fifth) = cpuset();
+        assert_eq!(node, "0");
+    }
+
+    #[test]
+    fn test_pinned_demo() {
+        let mut config = config::test_config();
+        config.numa_balancing = true;
+        config.demos.insert(
+            "t001".into(),
+            config::DemoConfig {
+                numa_node: Some(1),
+                ..Default::default()
+            },
+        );
+        let assignments = NumaAssignments::new(two_nodes());
+
+        let (cpuset, assignment) = assignments
+            .cpuset(&config, &DemoID::try_from("t001").unwrap())
+            .unwrap();
+        assert_eq!(
+            cpuset,
+            Cpuset {
+                cpus: "4-7".into(),
+                mems: Some("1".into()),
+            }
+        );
+        assert!(assignment.is_none());
+
+        config.numa_balancing = false;
+        let cpuset = assignments.cpuset(&config, &DemoID::try_from("t002").unwrap());
+        assert!(cpuset.is_none());
+
+        config.cpuset_cpus = Some("2-3".into());
+        let (cpuset, _) = assignments
+            .cpuset(&config, &DemoID::try_from("t002").unwrap())
+            .unwrap();
+        assert_eq!(cpuset.cpus, "2-3");
+        let (cpuset, _) = assignments
+            .cpuset(&config, &DemoID::try_from("t001").unwrap())
+            .unwrap();
+        assert_eq!(cpuset.cpus, "4-7");
+
+        assert!(validate(&config, &two_nodes()).is_ok());
+        config.cpuset_cpus = Some("8".into());
+        assert!(matches!(
+            validate(&config, &two_nodes()),
+            Err(NumaError::InvalidDefaultCpuset(_))
+        ));
+    }
+}
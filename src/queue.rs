@@ -0,0 +1,467 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::sync::oneshot;
+use rocket::tokio::time::{timeout_at, Instant};
+use rocket::State;
+
+use crate::config;
+
+/// Priority of the runs which don't ask for one, among 1 to 10.
+pub const DEFAULT_PRIORITY: u8 = 5;
+
+/// A run waiting for an execution slot.
+#[derive(Debug)]
+struct Waiter {
+    priority: u8,
+    /// Arrival order, first come first served among equal priorities.
+    seq: u64,
+    demo_id: String,
+    key: String,
+    /// Longest the run may take once it has a slot.
+    run_time: Duration,
+    slot: oneshot::Sender<ExecutionSlot>,
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+#[derive(Debug, Default)]
+struct Slots {
+    running: usize,
+    waiting: BinaryHeap<Waiter>,
+    next_seq: u64,
+    /// Expected ends of the runs holding a slot, by slot id.
+    ends: HashMap<u64, Instant>,
+    next_id: u64,
+}
+
+impl Slots {
+    /// Records a slot taken by a run expected to end within `run_time`.
+    fn hold(&mut self, run_time: Duration) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ends.insert(id, Instant::now() + run_time);
+        id
+    }
+
+    /// Time until a run holding a slot is expected to release it.
+    fn retry_after(&self) -> Option<Duration> {
+        let end = self.ends.values().min()?;
+        Some(end.saturating_duration_since(Instant::now()))
+    }
+
+    fn full(&self, max: usize) -> QueueFull {
+        QueueFull {
+            max,
+            retry_after: self.retry_after(),
+        }
+    }
+}
+
+/// Bounds the number of simultaneous executions to `max_concurrent_executions`,
+/// the runs waiting for a slot getting it by priority.
+#[derive(Debug)]
+pub struct ExecutionSlots {
+    slots: Arc<Mutex<Slots>>,
+    /// The configured maximum, 0 for no limit.
+    max: usize,
+}
+
+/// An execution slot held by a run, given to the next waiting run when dropped.
+#[derive(Debug)]
+pub struct ExecutionSlot {
+    /// `None` once handed over.
+    slots: Option<Arc<Mutex<Slots>>>,
+    id: u64,
+}
+
+impl Drop for ExecutionSlot {
+    fn drop(&mut self) {
+        let Some(shared) = self.slots.take() else {
+            return;
+        };
+        let mut slots = shared.lock().unwrap();
+        slots.ends.remove(&self.id);
+        while let Some(waiter) = slots.waiting.pop() {
+            let slot = ExecutionSlot {
+                slots: Some(shared.clone()),
+                id: slots.hold(waiter.run_time),
+            };
+            match waiter.slot.send(slot) {
+                Ok(()) => return,
+                // the waiter gave up, and the slot is still ours
+                Err(mut slot) => {
+                    slot.slots = None;
+                    slots.ends.remove(&slot.id);
+                }
+            }
+        }
+        slots.running -= 1;
+    }
+}
+
+/// A run waiting in the queue, for `GET /queue`.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct QueuedRun {
+    pub demo_id: String,
+    pub key: String,
+    pub priority: u8,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+#[error("{max} executions already in progress")]
+pub struct QueueFull {
+    pub max: usize,
+    /// When the first of the runs in progress is expected to end.
+    pub retry_after: Option<Duration>,
+}
+
+impl ExecutionSlots {
+    pub fn new(max: usize) -> Self {
+        ExecutionSlots {
+            slots: Arc::default(),
+            max,
+        }
+    }
+
+    /// Takes a slot for an execution, waiting at most `wait` for the runs in
+    /// progress to release one, behind the waiting runs of higher priority.
+    ///
+    /// The run is expected to release its slot within `run_time`, for the
+    /// retry hints of the runs refused meanwhile.
+    pub async fn acquire(
+        &self,
+        priority: u8,
+        demo_id: &str,
+        key: &str,
+        wait: Duration,
+        run_time: Duration,
+    ) -> Result<ExecutionSlot, QueueFull> {
+        let deadline = Instant::now() + wait;
+        let mut slot = {
+            let mut slots = self.slots.lock().unwrap();
+            // the waiting runs would have been given the free slots
+            if self.max == 0 || slots.running < self.max {
+                slots.running += 1;
+                return Ok(ExecutionSlot {
+                    slots: Some(self.slots.clone()),
+                    id: slots.hold(run_time),
+                });
+            }
+            if wait.is_zero() {
+                return Err(slots.full(self.max));
+            }
+            let (tx, rx) = oneshot::channel();
+            let seq = slots.next_seq;
+            slots.next_seq += 1;
+            slots.waiting.push(Waiter {
+                priority,
+                seq,
+                demo_id: demo_id.into(),
+                key: key.into(),
+                run_time,
+                slot: tx,
+            });
+            rx
+        };
+        match timeout_at(deadline, &mut slot).await {
+            Ok(Ok(slot)) => Ok(slot),
+            _ => {
+                // a slot may have been given at the last moment
+                slot.close();
+                slot.try_recv()
+                    .map_err(|_| self.slots.lock().unwrap().full(self.max))
+            }
+        }
+    }
+
+    /// Number of executions holding a slot.
+    pub fn running(&self) -> usize {
+        self.slots.lock().unwrap().running
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Runs waiting for a slot, in the order they will get one.
+    pub fn queued(&self) -> Vec<QueuedRun> {
+        let slots = self.slots.lock().unwrap();
+        let waiting = slots
+            .waiting
+            .iter()
+            .filter(|waiter| !waiter.slot.is_closed());
+        let mut waiting = waiting.collect::<Vec<_>>();
+        waiting.sort_by(|a, b| b.cmp(a));
+        waiting
+            .into_iter()
+            .map(|waiter| QueuedRun {
+                demo_id: waiter.demo_id.clone(),
+                key: waiter.key.clone(),
+                priority: waiter.priority,
+            })
+            .collect()
+    }
+}
+
+/// Execution slots of the demos with their own `max_concurrent_executions`.
+#[derive(Debug, Default)]
+pub struct DemoExecutionSlots(HashMap<String, ExecutionSlots>);
+
+impl DemoExecutionSlots {
+    pub fn new(config: &config::Config) -> Self {
+        let slots = config.demos.iter().filter_map(|(demo_id, demo)| {
+            let max = demo.max_concurrent_executions.filter(|&max| max > 0)?;
+            Some((demo_id.clone(), ExecutionSlots::new(max)))
+        });
+        DemoExecutionSlots(slots.collect())
+    }
+
+    /// Slots of a demo, `None` when only the global limit applies.
+    pub fn get(&self, demo_id: &str) -> Option<&ExecutionSlots> {
+        self.0.get(demo_id)
+    }
+}
+
+/// Manages the execution slots of `max_concurrent_executions`, global and per demo.
+pub fn execution_slots() -> rocket::fairing::AdHoc {
+    rocket::fairing::AdHoc::on_ignite("Execution slots", |rocket| {
+        Box::pin(async move {
+            let Some(config) = rocket.state::<config::Config>() else {
+                return rocket
+                    .manage(ExecutionSlots::new(0))
+                    .manage(DemoExecutionSlots::default());
+            };
+            let slots = ExecutionSlots::new(config.max_concurrent_executions);
+            let demo_slots = DemoExecutionSlots::new(config);
+            rocket.manage(slots).manage(demo_slots)
+        })
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct QueueDepth {
+    /// Executions in progress.
+    pub running: usize,
+    /// `max_concurrent_executions`, 0 for no limit.
+    pub max_concurrent_executions: usize,
+}
+
+#[get("/queue_depth")]
+pub fn get_queue_depth(slots: &State<ExecutionSlots>) -> Json<QueueDepth> {
+    Json(QueueDepth {
+        running: slots.running(),
+        max_concurrent_executions: slots.max(),
+    })
+}
+
+#[get("/queue")]
+pub fn get_queue(slots: &State<ExecutionSlots>) -> Json<Vec<QueuedRun>> {
+    Json(slots.queued())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rocket_from_figment;
+    use rocket::http::Status;
+    use rocket::local::blocking::Client;
+
+    const NOW: Duration = Duration::ZERO;
+    const RUN_TIME: Duration = Duration::from_secs(10);
+
+    fn assert_full(err: QueueFull, max: usize) {
+        assert_eq!(err.max, max);
+        let retry_after = err.retry_after.expect("a run in progress");
+        assert!(retry_after <= RUN_TIME, "{retry_after:?}");
+    }
+
+    #[rocket::async_test]
+    async fn test_execution_slots() {
+        let slots = ExecutionSlots::new(2);
+        let first = slots.acquire(5, "t001", "a", NOW, RUN_TIME).await.unwrap();
+        let _second = slots.acquire(5, "t001", "b", NOW, RUN_TIME).await.unwrap();
+        assert_eq!(slots.running(), 2);
+        let err = slots
+            .acquire(5, "t001", "c", NOW, RUN_TIME)
+            .await
+            .unwrap_err();
+        assert_full(err, 2);
+        drop(first);
+        assert_eq!(slots.running(), 1);
+        assert!(slots.acquire(5, "t001", "c", NOW, RUN_TIME).await.is_ok());
+
+        let unlimited = ExecutionSlots::new(0);
+        let mut held = Vec::new();
+        for _ in 0..100 {
+            held.push(
+                unlimited
+                    .acquire(5, "t001", "a", NOW, RUN_TIME)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!((unlimited.running(), unlimited.max()), (100, 0));
+    }
+
+    #[rocket::async_test]
+    async fn test_execution_slots_priority() {
+        let slots = Arc::new(ExecutionSlots::new(1));
+        let running = slots
+            .acquire(5, "t001", "running", NOW, RUN_TIME)
+            .await
+            .unwrap();
+
+        let (order_tx, mut order) = rocket::tokio::sync::mpsc::unbounded_channel();
+        let mut waiting = Vec::new();
+        for (key, priority) in [("low", 2), ("first", 5), ("high", 9), ("second", 5)] {
+            let (slots, order_tx) = (slots.clone(), order_tx.clone());
+            waiting.push(rocket::tokio::spawn(async move {
+                let wait = Duration::from_secs(10);
+                let slot = slots
+                    .acquire(priority, "t001", key, wait, RUN_TIME)
+                    .await
+                    .unwrap();
+                order_tx.send(key).unwrap();
+                drop(slot);
+            }));
+            // arrive in this order
+            while slots.queued().iter().all(|run| run.key != key) {
+                rocket::tokio::task::yield_now().await;
+            }
+        }
+        let queued = slots.queued();
+        let keys = queued
+            .iter()
+            .map(|run| run.key.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["high", "first", "second", "low"]);
+        assert_eq!(queued[0].priority, 9);
+
+        drop(running);
+        for task in waiting {
+            task.await.unwrap();
+        }
+        let mut served = Vec::new();
+        while let Ok(key) = order.try_recv() {
+            served.push(key);
+        }
+        assert_eq!(served, ["high", "first", "second", "low"]);
+        assert_eq!(slots.running(), 0);
+    }
+
+    #[rocket::async_test]
+    async fn test_execution_slots_retry_after() {
+        let slots = ExecutionSlots::new(2);
+        let long = slots
+            .acquire(5, "t001", "long", NOW, RUN_TIME)
+            .await
+            .unwrap();
+        let short = Duration::from_secs(3);
+        let _short = slots.acquire(5, "t001", "short", NOW, short).await.unwrap();
+        let err = slots
+            .acquire(5, "t001", "c", NOW, RUN_TIME)
+            .await
+            .unwrap_err();
+        let retry_after = err.retry_after.unwrap();
+        assert!(retry_after <= short, "{retry_after:?}");
+
+        // the slot of the run which ended is handed over with its own end
+        drop(long);
+        let _next = slots
+            .acquire(5, "t001", "next", NOW, RUN_TIME)
+            .await
+            .unwrap();
+        let err = slots
+            .acquire(5, "t001", "d", NOW, RUN_TIME)
+            .await
+            .unwrap_err();
+        assert!(err.retry_after.unwrap() <= short);
+        assert_eq!(slots.slots.lock().unwrap().ends.len(), 2);
+    }
+
+    #[rocket::async_test]
+    async fn test_execution_slots_wait() {
+        let slots = ExecutionSlots::new(1);
+        let _running = slots
+            .acquire(5, "t001", "running", NOW, RUN_TIME)
+            .await
+            .unwrap();
+        let start = Instant::now();
+        let wait = Duration::from_millis(100);
+        let err = slots
+            .acquire(5, "t001", "a", wait, RUN_TIME)
+            .await
+            .unwrap_err();
+        assert_full(err, 1);
+        assert!(start.elapsed() >= wait);
+        // gave up, so not in the queue anymore
+        assert!(slots.queued().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn test_demo_execution_slots() {
+        let mut config = config::test_config();
+        for (demo_id, max) in [("t001", Some(1)), ("t002", Some(0)), ("t003", None)] {
+            let demo = config::DemoConfig {
+                max_concurrent_executions: max,
+                ..Default::default()
+            };
+            config.demos.insert(demo_id.into(), demo);
+        }
+        let demo_slots = DemoExecutionSlots::new(&config);
+        assert!(demo_slots.get("t002").is_none());
+        assert!(demo_slots.get("t003").is_none());
+        assert!(demo_slots.get("t004").is_none());
+
+        let slots = demo_slots.get("t001").unwrap();
+        let _held = slots.acquire(5, "t001", "a", NOW, RUN_TIME).await.unwrap();
+        let err = slots
+            .acquire(5, "t001", "b", NOW, RUN_TIME)
+            .await
+            .unwrap_err();
+        assert_full(err, 1);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_get_queue_depth() {
+        let figment = rocket::Config::figment().merge(("max_concurrent_executions", 3));
+        let client = Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+        let response = client.get("/queue_depth").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_json::<QueueDepth>(),
+            Some(QueueDepth {
+                running: 0,
+                max_concurrent_executions: 3,
+            })
+        );
+        let response = client.get("/queue").dispatch();
+        assert_eq!(response.into_json::<Vec<QueuedRun>>(), Some(Vec::new()));
+    }
+}
@@ -0,0 +1,70 @@
+use rocket::serde::json::Json;
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+
+use crate::compilation::CompilationResponse;
+use crate::execution::{AlgoInfo, ExecInfo};
+
+/// Names of the published schemas.
+pub const SCHEMAS: &[&str] = &["exec_info", "algo_info", "compilation_response"];
+
+pub fn schema(name: &str) -> Option<RootSchema> {
+    match name {
+        "exec_info" => Some(schema_for!(ExecInfo)),
+        "algo_info" => Some(schema_for!(AlgoInfo)),
+        "compilation_response" => Some(schema_for!(CompilationResponse)),
+        _ => None,
+    }
+}
+
+#[get("/schemas/<name>")]
+pub fn get_schema(name: &str) -> Option<Json<RootSchema>> {
+    let name = name.strip_suffix(".json")?;
+    schema(name).map(Json)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::main_rocket;
+    use rocket::http::Status;
+    use rocket::local::blocking::Client;
+    use std::path::PathBuf;
+
+    fn fixture_path(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/schemas")
+            .join(format!("{name}.json"))
+    }
+
+    /// Compares the schemas against the checked-in fixtures, so that changes of the
+    /// responses are deliberate. Run with `UPDATE_SCHEMAS=1` to update the fixtures.
+    #[test]
+    fn test_schemas_fixtures() {
+        for name in SCHEMAS {
+            let schema = serde_json::to_value(schema(name).unwrap()).unwrap();
+            let path = fixture_path(name);
+            if std::env::var_os("UPDATE_SCHEMAS").is_some() {
+                let content = serde_json::to_string_pretty(&schema).unwrap();
+                std::fs::write(&path, content + "\n").unwrap();
+                continue;
+            }
+            let fixture = std::fs::read_to_string(&path).unwrap();
+            let fixture: serde_json::Value = serde_json::from_str(&fixture).unwrap();
+            assert_eq!(schema, fixture, "the schema {name} changed");
+        }
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_get_schema() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let response = client.get("/schemas/exec_info.json").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let schema: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(schema["title"], "ExecInfo");
+
+        let response = client.get("/schemas/unknown.json").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
@@ -1,15 +1,151 @@
+use std::time::Duration;
+
+use rocket::response::Responder;
 use rocket::serde::json::Json;
-use rocket::serde::Serialize;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::State;
 
-#[derive(Debug, Serialize)]
+use crate::config;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShutdownRequest {
+    /// Hostname or `instance_id` of the node, to avoid stopping the wrong one.
+    confirm: String,
+    /// Time left to drain the node before stopping it.
+    #[serde(default)]
+    delay_seconds: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ShutdownResponse {
     status: String,
+    message: String,
+    /// RFC3339 timestamp of the stop.
+    shutdown_at: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ShutdownError {
+    #[error("invalid shutdown request: {0}")]
+    InvalidRequest(String),
+    #[error("{0:?} is neither the hostname nor the instance_id of this node")]
+    WrongIdentity(String),
+}
+
+#[derive(Debug, Serialize)]
+struct ShutdownErrorResponse {
+    detail: String,
+}
+
+impl<'r> Responder<'r, 'static> for ShutdownError {
+    fn respond_to(self, req: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        let detail = self.to_string();
+        rocket::Response::build_from(Json(ShutdownErrorResponse { detail }).respond_to(req)?)
+            .status(rocket::http::Status::BadRequest)
+            .ok()
+    }
+}
+
+fn hostname() -> Option<String> {
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").ok()?;
+    Some(hostname.trim().to_string())
+}
+
+fn is_node_identity(config: &config::Config, confirm: &str) -> bool {
+    confirm == config.instance_id || hostname().is_some_and(|hostname| confirm == hostname)
 }
 
-#[get("/shutdown")]
-pub fn shutdown(shutdown: rocket::Shutdown) -> Json<ShutdownResponse> {
-    shutdown.notify();
-    Json(ShutdownResponse {
+#[post("/shutdown", data = "<req>")]
+pub fn shutdown(
+    req: Result<Json<ShutdownRequest>, rocket::serde::json::Error<'_>>,
+    config: &State<config::Config>,
+    shutdown: rocket::Shutdown,
+) -> Result<Json<ShutdownResponse>, ShutdownError> {
+    let req = req.map_err(|err| ShutdownError::InvalidRequest(err.to_string()))?;
+    if !is_node_identity(config, &req.confirm) {
+        return Err(ShutdownError::WrongIdentity(req.confirm.clone()));
+    }
+
+    let delay = Duration::from_secs(req.delay_seconds);
+    let shutdown_at = chrono::Utc::now() + delay;
+    let message = if delay.is_zero() {
+        shutdown.notify();
+        "the node is shutting down".to_string()
+    } else {
+        rocket::tokio::spawn(async move {
+            rocket::tokio::time::sleep(delay).await;
+            shutdown.notify();
+        });
+        format!("the node will shut down in {} seconds", req.delay_seconds)
+    };
+    tracing::warn!("shutdown requested: {message}");
+
+    Ok(Json(ShutdownResponse {
         status: "OK".into(),
-    })
+        message,
+        shutdown_at: shutdown_at.to_rfc3339(),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::main_rocket;
+    use rocket::http::{ContentType, Status};
+    use rocket::local::blocking::Client;
+
+    fn ask_shutdown(client: &Client, body: &str) -> (Status, String) {
+        let response = client
+            .post("/shutdown")
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch();
+        (response.status(), response.into_string().unwrap())
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_shutdown() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let instance_id = &client
+            .rocket()
+            .state::<config::Config>()
+            .unwrap()
+            .instance_id;
+
+        let body = format!(r#"{{"confirm": "{instance_id}", "delay_seconds": 3600}}"#);
+        let (status, body) = ask_shutdown(&client, &body);
+        assert_eq!(status, Status::Ok);
+        let response = serde_json::from_str::<ShutdownResponse>(&body).unwrap();
+        assert_eq!(response.status, "OK");
+        assert_eq!(response.message, "the node will shut down in 3600 seconds");
+        assert!(chrono::DateTime::parse_from_rfc3339(&response.shutdown_at).is_ok());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_shutdown_wrong_method() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let response = client.get("/shutdown").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_shutdown_missing_confirmation() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let (status, _) = ask_shutdown(&client, "");
+        assert_eq!(status, Status::BadRequest);
+        let (status, _) = ask_shutdown(&client, r#"{"delay_seconds": 10}"#);
+        assert_eq!(status, Status::BadRequest);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_shutdown_wrong_identity() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let (status, body) = ask_shutdown(&client, r#"{"confirm": "another-node"}"#);
+        assert_eq!(status, Status::BadRequest);
+        assert!(body.contains("another-node"));
+    }
 }
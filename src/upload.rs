@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::distributions::{Alphanumeric, DistString};
+use rocket::response::Responder;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::fs;
+use rocket::tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use rocket::tokio::sync::Mutex as AsyncMutex;
+use sha2::{Digest, Sha256};
+
+use crate::config;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadRequest {
+    filename: String,
+    size: u64,
+    sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UploadStatus {
+    id: String,
+    size: u64,
+    received: u64,
+    completed: bool,
+}
+
+#[derive(Debug)]
+struct UploadSession {
+    id: String,
+    request: UploadRequest,
+    path: PathBuf,
+    received: u64,
+    completed: bool,
+    last_activity: Instant,
+}
+
+impl UploadSession {
+    fn status(&self) -> UploadStatus {
+        UploadStatus {
+            id: self.id.clone(),
+            size: self.request.size,
+            received: self.received,
+            completed: self.completed,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UploadError {
+    #[error("unknown upload session {0}")]
+    UnknownSession(String),
+    #[error("invalid filename")]
+    InvalidFilename,
+    #[error("missing chunk: the next expected offset is {0}")]
+    MissingChunk(u64),
+    #[error("the chunk exceeds the declared size ({0} bytes)")]
+    TooLarge(u64),
+    #[error("incomplete upload ({received}/{size} bytes)")]
+    Incomplete { received: u64, size: u64 },
+    #[error("sha256 mismatch: expected {expected}, got {actual}")]
+    HashMismatch { expected: String, actual: String },
+    #[error("{0}")]
+    LimitExceeded(String),
+    #[error("io: {0}")]
+    IO(#[from] std::io::Error),
+}
+
+#[derive(Debug, Serialize)]
+struct UploadErrorResponse {
+    detail: String,
+}
+
+impl<'r> Responder<'r, 'static> for UploadError {
+    fn respond_to(self, req: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        use rocket::http::Status;
+        let status = match self {
+            UploadError::UnknownSession(_) => Status::NotFound,
+            UploadError::MissingChunk(_) | UploadError::Incomplete { .. } => Status::Conflict,
+            UploadError::InvalidFilename | UploadError::TooLarge(_) => Status::BadRequest,
+            UploadError::HashMismatch { .. } => Status::UnprocessableEntity,
+            UploadError::LimitExceeded(_) => Status::PayloadTooLarge,
+            UploadError::IO(_) => Status::InternalServerError,
+        };
+        let detail = self.to_string();
+        rocket::Response::build_from(Json(UploadErrorResponse { detail }).respond_to(req)?)
+            .status(status)
+            .ok()
+    }
+}
+
+/// Upload sessions, used to send large inputs in several chunks before an execution.
+#[derive(Debug, Default)]
+pub struct UploadSessions {
+    sessions: Mutex<HashMap<String, LiveSession>>,
+}
+
+/// A session of the map, with its declared size readable without locking it.
+#[derive(Debug)]
+struct LiveSession {
+    size: u64,
+    session: Arc<AsyncMutex<UploadSession>>,
+}
+
+impl UploadSessions {
+    fn get(&self, id: &str) -> Result<Arc<AsyncMutex<UploadSession>>, UploadError> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .get(id)
+            .map(|live| live.session.clone())
+            .ok_or_else(|| UploadError::UnknownSession(id.into()))
+    }
+
+    /// Removes the sessions which were inactive for longer than `upload_ttl`.
+    fn remove_expired(&self, config: &config::Config) {
+        let ttl = Duration::from_secs(config.upload_ttl);
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|id, live| {
+            // a session being written to is not expired
+            let Ok(session) = live.session.try_lock() else {
+                return true;
+            };
+            if session.last_activity.elapsed() < ttl {
+                return true;
+            }
+            tracing::info!("upload session {id} expired");
+            if let Err(err) = std::fs::remove_file(&session.path) {
+                tracing::warn!("couldn't remove {:?}: {err}", session.path);
+            }
+            false
+        });
+    }
+
+    async fn create(
+        &self,
+        config: &config::Config,
+        request: UploadRequest,
+    ) -> Result<UploadStatus, UploadError> {
+        self.remove_expired(config);
+
+        let filename = Path::new(&request.filename);
+        if request.filename.is_empty() || filename.is_absolute() {
+            return Err(UploadError::InvalidFilename);
+        }
+        let size = request.size;
+        let max_file = config.max_input_file_bytes;
+        if max_file > 0 && size > max_file {
+            return Err(UploadError::LimitExceeded(format!(
+                "the upload of {size} bytes is above max_input_file_bytes ({max_file})"
+            )));
+        }
+
+        let id = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+        let root = PathBuf::from(&config.upload_root);
+        fs::create_dir_all(&root).await?;
+        let path = root.join(&id);
+
+        let session = UploadSession {
+            id: id.clone(),
+            request,
+            path: path.clone(),
+            received: 0,
+            completed: false,
+            last_activity: Instant::now(),
+        };
+        let status = session.status();
+        {
+            // the declared sizes of the live sessions are reserved on the disk
+            let mut sessions = self.sessions.lock().unwrap();
+            let max_total = config.max_total_input_bytes;
+            let sizes = sessions.values().map(|live| live.size);
+            let total = sizes.fold(size, u64::saturating_add);
+            if max_total > 0 && total > max_total {
+                return Err(UploadError::LimitExceeded(format!(
+                    "the uploads would reach {total} bytes, above max_total_input_bytes ({max_total})"
+                )));
+            }
+            tracing::debug!("created upload session {session:?}");
+            let session = Arc::new(AsyncMutex::new(session));
+            sessions.insert(id.clone(), LiveSession { size, session });
+        }
+        if let Err(err) = fs::File::create(&path).await {
+            self.sessions.lock().unwrap().remove(&id);
+            return Err(err.into());
+        }
+        Ok(status)
+    }
+
+    async fn write_chunk(
+        &self,
+        config: &config::Config,
+        id: &str,
+        offset: u64,
+        chunk: &[u8],
+    ) -> Result<UploadStatus, UploadError> {
+        self.remove_expired(config);
+        let session = self.get(id)?;
+        let mut session = session.lock().await;
+        session.last_activity = Instant::now();
+
+        // chunks may be sent again (idempotent), but not with a gap
+        if offset > session.received {
+            return Err(UploadError::MissingChunk(session.received));
+        }
+        let end = offset + chunk.len() as u64;
+        if end > session.request.size {
+            return Err(UploadError::TooLarge(session.request.size));
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .open(&session.path)
+            .await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(chunk).await?;
+        file.flush().await?;
+
+        session.received = session.received.max(end);
+        Ok(session.status())
+    }
+
+    async fn complete(
+        &self,
+        config: &config::Config,
+        id: &str,
+    ) -> Result<UploadStatus, UploadError> {
+        self.remove_expired(config);
+        let session = self.get(id)?;
+        let mut session = session.lock().await;
+        session.last_activity = Instant::now();
+
+        if session.received != session.request.size {
+            return Err(UploadError::Incomplete {
+                received: session.received,
+                size: session.request.size,
+            });
+        }
+
+        let path = session.path.clone();
+        let actual = rocket::tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+            let mut file = std::fs::File::open(path)?;
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut file, &mut hasher)?;
+            Ok(hex::encode(hasher.finalize()))
+        })
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Interrupted, e))??;
+
+        let expected = session.request.sha256.to_lowercase();
+        if actual != expected {
+            return Err(UploadError::HashMismatch { expected, actual });
+        }
+
+        session.completed = true;
+        Ok(session.status())
+    }
+
+    /// Declared size of the file of a session.
+    pub async fn size(&self, id: &str) -> Result<u64, UploadError> {
+        let session = self.get(id)?;
+        let size = session.lock().await.request.size;
+        Ok(size)
+    }
+
+    /// Removes a completed session, returning the path of its file and its filename.
+    pub async fn take_completed(&self, id: &str) -> Result<(PathBuf, String), UploadError> {
+        let session = self.get(id)?;
+        let session = session.lock().await;
+        if !session.completed {
+            return Err(UploadError::Incomplete {
+                received: session.received,
+                size: session.request.size,
+            });
+        }
+        self.sessions.lock().unwrap().remove(id);
+        Ok((session.path.clone(), session.request.filename.clone()))
+    }
+}
+
+pub mod http {
+    use rocket::data::{Data, Limits, ToByteUnit};
+    use rocket::http::Status;
+    use rocket::response::status;
+    use rocket::serde::json::Json;
+    use rocket::State;
+
+    use super::{UploadError, UploadRequest, UploadSessions, UploadStatus};
+    use crate::config;
+
+    #[post("/uploads", data = "<req>")]
+    pub async fn create_upload(
+        req: Json<UploadRequest>,
+        sessions: &State<UploadSessions>,
+        config: &State<config::Config>,
+    ) -> Result<status::Custom<Json<UploadStatus>>, UploadError> {
+        let status = sessions.create(config, req.into_inner()).await?;
+        Ok(status::Custom(Status::Created, Json(status)))
+    }
+
+    #[put("/uploads/<id>/chunk?<offset>", data = "<chunk>")]
+    pub async fn upload_chunk(
+        id: &str,
+        offset: u64,
+        chunk: Data<'_>,
+        limits: &Limits,
+        sessions: &State<UploadSessions>,
+        config: &State<config::Config>,
+    ) -> Result<Json<UploadStatus>, UploadError> {
+        let limit = limits.get("file").unwrap_or(1.mebibytes());
+        let chunk = chunk.open(limit).into_bytes().await?;
+        if !chunk.is_complete() {
+            return Err(UploadError::TooLarge(limit.as_u64()));
+        }
+        let status = sessions.write_chunk(config, id, offset, &chunk).await?;
+        Ok(Json(status))
+    }
+
+    #[post("/uploads/<id>/complete")]
+    pub async fn complete_upload(
+        id: &str,
+        sessions: &State<UploadSessions>,
+        config: &State<config::Config>,
+    ) -> Result<Json<UploadStatus>, UploadError> {
+        let status = sessions.complete(config, id).await?;
+        Ok(Json(status))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{main_rocket, rocket_from_figment};
+    use rocket::http::Status;
+    use rocket::local::blocking::Client;
+
+    const ABCDEF_SHA256: &str = "bef57ec7f53a6d40beb640a780a639c83bc29ac8a9816f1fc6c5c6dcd93c4721";
+
+    fn create_session(client: &Client, sha256: &str) -> String {
+        let request = UploadRequest {
+            filename: "input_0.txt".into(),
+            size: 6,
+            sha256: sha256.into(),
+        };
+        let response = client.post("/uploads").json(&request).dispatch();
+        assert_eq!(response.status(), Status::Created);
+        response.into_json::<UploadStatus>().unwrap().id
+    }
+
+    fn send_chunk(client: &Client, id: &str, offset: u64, chunk: &str) -> (Status, String) {
+        let response = client
+            .put(format!("/uploads/{id}/chunk?offset={offset}"))
+            .body(chunk)
+            .dispatch();
+        (response.status(), response.into_string().unwrap())
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_upload_resume() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let id = create_session(&client, ABCDEF_SHA256);
+
+        // the first chunk is missing: the client must resume from offset 0
+        let (status, body) = send_chunk(&client, &id, 3, "def");
+        assert_eq!(status, Status::Conflict);
+        assert!(body.contains("the next expected offset is 0"));
+
+        assert_eq!(send_chunk(&client, &id, 0, "abc").0, Status::Ok);
+        // sending the same chunk again is harmless
+        assert_eq!(send_chunk(&client, &id, 0, "abc").0, Status::Ok);
+        assert_eq!(send_chunk(&client, &id, 3, "def").0, Status::Ok);
+        assert_eq!(send_chunk(&client, &id, 6, "g").0, Status::BadRequest);
+
+        let response = client.post(format!("/uploads/{id}/complete")).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let status = response.into_json::<UploadStatus>().unwrap();
+        assert!(status.completed);
+        assert_eq!(status.received, 6);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_upload_hash_mismatch() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let id = create_session(&client, &"0".repeat(64));
+
+        let response = client.post(format!("/uploads/{id}/complete")).dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+
+        assert_eq!(send_chunk(&client, &id, 0, "abcdef").0, Status::Ok);
+        let response = client.post(format!("/uploads/{id}/complete")).dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert!(response.into_string().unwrap().contains(ABCDEF_SHA256));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_upload_limits() {
+        let request = UploadRequest {
+            filename: "input_0.txt".into(),
+            size: 6,
+            sha256: ABCDEF_SHA256.into(),
+        };
+
+        let figment = rocket::Config::figment().merge(("max_input_file_bytes", 5));
+        let client = Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+        let response = client.post("/uploads").json(&request).dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains("the upload of 6 bytes is above max_input_file_bytes (5)"));
+
+        // the second session would make the live ones reach 12 bytes
+        let figment = rocket::Config::figment().merge(("max_total_input_bytes", 10));
+        let client = Client::tracked(rocket_from_figment(figment)).expect("valid rocket instance");
+        create_session(&client, ABCDEF_SHA256);
+        let response = client.post("/uploads").json(&request).dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains("the uploads would reach 12 bytes, above max_total_input_bytes (10)"));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_upload_unknown_session() {
+        let client = Client::tracked(main_rocket()).expect("valid rocket instance");
+        let response = client.post("/uploads/unknown/complete").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
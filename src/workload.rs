@@ -1,10 +1,34 @@
-use rocket::http::Status;
-use rocket::response::status;
+use rocket::http::Header;
 use rocket::serde::json::Json;
+use rocket::State;
+
+use crate::disk::DiskReservations;
+
+#[derive(Responder)]
+#[response(status = 200)]
+pub struct WorkloadResponse {
+    workload: Json<f32>,
+    /// Disk space reserved by the runs in progress (in bytes).
+    disk_reserved: Header<'static>,
+    /// Free disk space not reserved by any run (in bytes).
+    disk_free: Header<'static>,
+}
 
 #[get("/workload")]
-pub fn get_workload() -> status::Custom<Json<f32>> {
-    status::Custom(Status::Ok, Json(1.0))
+pub fn get_workload(disks: &State<DiskReservations>) -> WorkloadResponse {
+    let reserved = disks.reserved();
+    let free = match disks.available(&std::env::temp_dir()) {
+        Ok(free) => free.to_string(),
+        Err(err) => {
+            tracing::warn!("workload: couldn't get the free disk space: {err}");
+            "unknown".into()
+        }
+    };
+    WorkloadResponse {
+        workload: Json(1.0),
+        disk_reserved: Header::new("X-Disk-Reserved", reserved.to_string()),
+        disk_free: Header::new("X-Disk-Free", free),
+    }
 }
 
 #[cfg(test)]
@@ -19,6 +43,8 @@ mod test {
         let client = Client::tracked(main_rocket()).expect("valid rocket instance");
         let response = client.get("/workload").dispatch();
         assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("X-Disk-Reserved"), Some("0"));
+        assert!(response.headers().get_one("X-Disk-Free").is_some());
         assert_eq!(response.into_json(), Some(1.0));
     }
 }